@@ -0,0 +1,161 @@
+//! A/B slots.
+//!
+//! Subvolumes that come in pairs, such as `rootfs_a` and `rootfs_b`, are
+//! one base name in each of two slots.  The metadata records which slot
+//! is active, so that an update can go to the other one and the switch
+//! between them is a single commit.  Which subvolumes are paired is only
+//! a matter of their names; nothing stops one side of a pair from being
+//! deleted or resized on its own.
+//!
+//! Making a slot active puts it on trial for a number of boots.  Each boot
+//! is counted against it by `boot_attempt` until one is marked successful;
+//! if the tries run out first, the other slot becomes active again, as
+//! long as it is still bootable itself.  A slot that was never put on
+//! trial counts as successful.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{CreateOptions, MapperError, SuperPartition};
+
+/// Boots a slot gets to be marked successful in, unless told otherwise
+pub const DEFAULT_BOOT_TRIES: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Slot {
+    #[default]
+    A,
+    B,
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    /// Name of the subvolume for `base` in this slot
+    pub fn subvol(self, base: &str) -> String {
+        format!("{}_{}", base, self)
+    }
+}
+
+/// How booting from a slot has gone
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootState {
+    /// Boots left before falling back to the other slot, until one is
+    /// marked successful
+    pub tries_remaining: u32,
+    pub successful: bool,
+}
+
+/// Boot states by slot, for slots that have been put on trial
+pub(crate) type BootStates = BTreeMap<Slot, BootState>;
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Slot::A => write!(f, "a"),
+            Slot::B => write!(f, "b"),
+        }
+    }
+}
+
+impl FromStr for Slot {
+    type Err = MapperError;
+
+    fn from_str(s: &str) -> Result<Self, MapperError> {
+        match s {
+            "a" | "A" => Ok(Slot::A),
+            "b" | "B" => Ok(Slot::B),
+            _ => Err(MapperError::InvalidArgument(format!("bad slot {:?}: expected a or b", s))),
+        }
+    }
+}
+
+impl SuperPartition {
+    /// Slot in use, A unless set otherwise
+    pub fn active_slot(&self) -> Slot {
+        self.active_slot.unwrap_or_default()
+    }
+
+    /// Make `slot` the one in use, on trial for `tries` boots
+    pub fn set_active_slot(&mut self, slot: Slot, tries: u32) -> Result<(), MapperError> {
+        self.check_writable()?;
+        if tries == 0 {
+            return Err(MapperError::InvalidArgument("a slot needs at least one try".to_string()));
+        }
+        self.active_slot = Some(slot);
+        self.boot.insert(slot, BootState { tries_remaining: tries, successful: false });
+        self.commit()
+    }
+
+    pub fn boot_state(&self, slot: Slot) -> BootState {
+        self.boot.get(&slot).copied().unwrap_or(BootState { tries_remaining: 0, successful: true })
+    }
+
+    /// Count a boot against the active slot, first falling back to the
+    /// other one if the active slot has run out of tries, and return the
+    /// slot to boot from.  With neither slot bootable, the active one is
+    /// returned regardless.
+    pub fn boot_attempt(&mut self) -> Result<Slot, MapperError> {
+        self.check_writable()?;
+        let active = self.active_slot();
+        for slot in [active, active.other()] {
+            let state = self.boot_state(slot);
+            if state.successful {
+                if slot != active {
+                    self.active_slot = Some(slot);
+                    self.commit()?;
+                }
+                return Ok(slot);
+            }
+            if state.tries_remaining > 0 {
+                self.active_slot = Some(slot);
+                self.boot.insert(slot, BootState { tries_remaining: state.tries_remaining - 1, ..state });
+                self.commit()?;
+                return Ok(slot);
+            }
+        }
+        Ok(active)
+    }
+
+    /// Record that the active slot booted, so it stays active
+    pub fn mark_boot_successful(&mut self) -> Result<(), MapperError> {
+        self.check_writable()?;
+        match self.boot.get_mut(&self.active_slot()) {
+            Some(state) if !state.successful => state.successful = true,
+            _ => return Ok(()),
+        }
+        self.commit()
+    }
+
+    /// Base names with a subvolume in both slots, sorted
+    pub fn slotted(&self) -> Vec<&str> {
+        let mut bases: Vec<_> = self.subvols.keys()
+            .filter_map(|name| name.strip_suffix("_a"))
+            .filter(|base| self.subvols.contains_key(&Slot::B.subvol(base)))
+            .collect();
+        bases.sort();
+        bases
+    }
+
+    /// Create the subvolumes for `base` in both slots, each of `size`
+    /// bytes, returning the paths of their device nodes
+    pub fn create_slotted(&mut self, base: &str, size: u64, options: &CreateOptions) -> Result<[PathBuf; 2], MapperError> {
+        let names = [Slot::A.subvol(base), Slot::B.subvol(base)];
+        let mut transaction = self.transaction();
+        for name in &names {
+            transaction.create_with(name, size, options)?;
+        }
+        transaction.commit()?;
+        Ok(names.map(|name| self.subvol_path(&name)))
+    }
+}
@@ -0,0 +1,198 @@
+//! Choosing free blocks for new extents.
+//!
+//! Allocation walks the free holes on the member devices in an order picked
+//! by an `AllocationStrategy`, taking as much of each hole as is still
+//! needed until the request is satisfied.  The order decides how many
+//! extents a subvolume ends up split into, and how the remaining free
+//! space is left fragmented.
+
+use std::fmt::Debug;
+
+use crate::{Extent, MapperError, SuperPartition, Wipe};
+
+/// Policy for which free holes an allocation is carved out of
+pub trait AllocationStrategy: Debug + Sync {
+    /// Arrange `holes` in the order they should be drawn from to find
+    /// `needed` blocks.  Holes may be dropped, but not altered.
+    fn order(&self, holes: Vec<Extent>, needed: u64) -> Vec<Extent>;
+}
+
+/// Take holes in device order.  Fast and predictable, but splits large
+/// requests into many extents once the device is fragmented.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirstFit;
+
+/// Use the smallest hole that fits the whole request, keeping large holes
+/// intact for later.  If nothing fits, span the largest holes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BestFit;
+
+/// Use the largest hole if it fits the whole request, leaving the biggest
+/// possible remainder.  If nothing fits, fall back to device order.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WorstFit;
+
+/// Always draw from the largest holes first, minimising the number of
+/// extents even when the request has to be split.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LargestHoleFirst;
+
+impl AllocationStrategy for FirstFit {
+    fn order(&self, holes: Vec<Extent>, _needed: u64) -> Vec<Extent> {
+        holes
+    }
+}
+
+impl AllocationStrategy for BestFit {
+    fn order(&self, holes: Vec<Extent>, needed: u64) -> Vec<Extent> {
+        let best = holes.iter()
+            .filter(|h| h.block_length >= needed)
+            .min_by_key(|h| h.block_length);
+        match best {
+            Some(best) => vec![best.clone()],
+            None => LargestHoleFirst.order(holes, needed),
+        }
+    }
+}
+
+impl AllocationStrategy for WorstFit {
+    fn order(&self, holes: Vec<Extent>, needed: u64) -> Vec<Extent> {
+        match holes.iter().max_by_key(|h| h.block_length) {
+            Some(worst) if worst.block_length >= needed => vec![worst.clone()],
+            _ => holes,
+        }
+    }
+}
+
+impl AllocationStrategy for LargestHoleFirst {
+    fn order(&self, mut holes: Vec<Extent>, _needed: u64) -> Vec<Extent> {
+        // Stable, so equal holes are still taken in device order
+        holes.sort_by_key(|h| std::cmp::Reverse(h.block_length));
+        holes
+    }
+}
+
+/// How the space for a new subvolume is found
+#[derive(Debug, Clone, Copy)]
+pub struct CreateOptions {
+    pub strategy: &'static dyn AllocationStrategy,
+    /// Require a single extent, and keep it that way on resize
+    pub contiguous: bool,
+    /// Byte boundary every extent must start on and be a multiple of,
+    /// such as the erase block size of flash.  Zero for none; otherwise it
+    /// must be a multiple of the block size.
+    pub alignment: u64,
+    /// Clear the ends of the new subvolume before it is activated, so
+    /// nothing mistakes it for whatever last used its blocks
+    pub wipe: Option<Wipe>,
+    /// Member device to take nothing from, as when it is being evacuated
+    pub avoid_device: Option<u32>,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        Self {
+            strategy: &FirstFit,
+            contiguous: false,
+            alignment: 0,
+            wipe: None,
+            avoid_device: None,
+        }
+    }
+}
+
+impl SuperPartition {
+    /// Find free blocks for `needed` blocks worth of data, without
+    /// reserving them
+    pub(crate) fn allocate(&self, needed: u64) -> Result<Vec<Extent>, MapperError> {
+        self.allocate_with(needed, &CreateOptions::default())
+    }
+
+    /// Like `allocate`, using the policy in `options`
+    pub(crate) fn allocate_with(&self, needed: u64, options: &CreateOptions) -> Result<Vec<Extent>, MapperError> {
+        let mut holes = self.free_extents();
+        holes.retain(|h| Some(h.device) != options.avoid_device);
+        if options.alignment != 0 {
+            if !options.alignment.is_multiple_of(self.iosize) {
+                return Err(MapperError::InvalidArgument(format!("alignment {} is not a multiple of the block size {}",
+                    options.alignment, self.iosize)));
+            }
+            let align = options.alignment / self.iosize;
+            holes = holes.into_iter().filter_map(|h| {
+                let start = h.block_offset.next_multiple_of(align);
+                let end = h.block_offset + h.block_length;
+                let length = end.saturating_sub(start) / align * align;
+                (length > 0).then_some(Extent {
+                    device: h.device,
+                    block_offset: start,
+                    block_length: length,
+                })
+            }).collect();
+        }
+        if options.contiguous {
+            let largest = holes.iter().map(|h| h.block_length).max().unwrap_or(0);
+            holes.retain(|h| h.block_length >= needed);
+            holes = options.strategy.order(holes, needed);
+            holes.truncate(1);
+            if holes.is_empty() {
+                return Err(MapperError::NoContiguousSpace { needed, largest });
+            }
+        } else {
+            holes = options.strategy.order(holes, needed);
+        }
+        self.allocate_from(holes, needed)
+    }
+
+    /// Make sure every block in `extents` is unallocated, and that they
+    /// don't overlap each other
+    pub(crate) fn check_free(&self, extents: &[Extent]) -> Result<(), MapperError> {
+        if extents.is_empty() {
+            return Err(MapperError::InvalidArgument("no extents given".to_string()));
+        }
+        let holes = self.free_extents();
+        let mut sorted: Vec<_> = extents.iter().collect();
+        sorted.sort();
+        for (i, e) in sorted.iter().enumerate() {
+            if e.block_length == 0 {
+                return Err(MapperError::InvalidArgument(format!("extent at block {} is empty", e.block_offset)));
+            }
+            if sorted.get(i + 1).is_some_and(|next| next.device == e.device && next.block_offset < e.block_offset + e.block_length) {
+                return Err(MapperError::InvalidArgument(format!("extents at blocks {} and {} overlap",
+                    e.block_offset, sorted[i + 1].block_offset)));
+            }
+            let free = holes.iter().any(|h| h.device == e.device && h.block_offset <= e.block_offset
+                && e.block_offset + e.block_length <= h.block_offset + h.block_length);
+            if !free {
+                return Err(MapperError::InvalidArgument(format!("blocks {} to {} of device {} are not free",
+                    e.block_offset, e.block_offset + e.block_length - 1, e.device)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Take `needed` blocks from `holes`, in the order given
+    pub(crate) fn allocate_from(&self, holes: Vec<Extent>, needed: u64) -> Result<Vec<Extent>, MapperError> {
+        let mut my_extents = vec![];
+        let mut size_blocks = needed;
+
+        for hole in holes {
+            if size_blocks == 0 {
+                break;
+            }
+
+            let extent = Extent {
+                device: hole.device,
+                block_offset: hole.block_offset,
+                block_length: std::cmp::min(hole.block_length, size_blocks),
+            };
+
+            size_blocks -= extent.block_length;
+            my_extents.push(extent);
+        }
+
+        if size_blocks > 0 {
+            return Err(MapperError::NoSpace { needed, available: needed - size_blocks });
+        }
+        Ok(my_extents)
+    }
+}
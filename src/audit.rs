@@ -0,0 +1,133 @@
+//! Comparing the DM devices the kernel has with what the metadata
+//! describes.
+//!
+//! Tables can drift from the metadata when someone edits them by hand with
+//! dmsetup, when a crash leaves a reload half done, or when a subvolume is
+//! deleted by a process that could not tear its devices down.  Auditing
+//! only reads; nothing is changed on the device or in the kernel.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::dm::{table_lines, Target, DM_UUID_PREFIX};
+use crate::{is_reserved, MapperError, SuperPartition};
+
+/// One way the live DM devices differ from the metadata
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Drift {
+    /// Part of an active subvolume's stack is missing
+    MissingLayer { subvol: String, device: String },
+    /// A device maps something other than what the metadata describes.
+    /// Tables are given one line per target.
+    Table { subvol: String, device: String, expected: Vec<String>, live: Vec<String> },
+    /// A device is writable when it should be read-only, or the reverse
+    ReadOnly { subvol: String, device: String, expected: bool },
+    /// A device has the right name but was not created for this subvolume
+    Uuid { subvol: String, device: String, expected: Option<String>, live: Option<String> },
+    /// A device created for this super partition that no subvolume
+    /// accounts for
+    Stale { device: String, uuid: String },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Drift::MissingLayer { subvol, device } => write!(f, "{}: {} is missing", subvol, device),
+            Drift::Table { subvol, device, expected, live } => {
+                write!(f, "{}: {} has table [{}], expected [{}]", subvol, device, live.join(", "), expected.join(", "))
+            }
+            Drift::ReadOnly { subvol, device, expected: true } => write!(f, "{}: {} is writable", subvol, device),
+            Drift::ReadOnly { subvol, device, expected: false } => write!(f, "{}: {} is read-only", subvol, device),
+            Drift::Uuid { subvol, device, expected, live } => {
+                write!(f, "{}: {} has UUID {}, expected {}", subvol, device,
+                    live.as_deref().unwrap_or("none"), expected.as_deref().unwrap_or("none"))
+            }
+            Drift::Stale { device, uuid } => write!(f, "{} ({}) belongs to no subvolume", device, uuid),
+        }
+    }
+}
+
+impl SuperPartition {
+    /// Compare the DM devices for every subvolume with the tables the
+    /// metadata says they should have.  A subvolume with no devices at all
+    /// is inactive and not drift.
+    pub fn audit(&self) -> Result<Vec<Drift>, MapperError> {
+        let dm = self.dm()?;
+        let mut drift = vec![];
+        let mut ours = BTreeSet::new();
+
+        let mut names: Vec<_> = self.subvols.keys().filter(|name| !is_reserved(name)).collect();
+        names.sort();
+        for name in names {
+            let sv = &self.subvols[name];
+            let layers = self.layers(name, sv);
+            let device_names: Vec<_> = layers.iter().map(|l| self.layer_name(name, l.suffix)).collect();
+            ours.extend(device_names.iter().cloned());
+
+            let mut present = vec![];
+            for device in &device_names {
+                present.push(dm.exists(device)?);
+            }
+            if !present.contains(&true) {
+                continue;
+            }
+
+            for ((layer, device), present) in layers.iter().zip(&device_names).zip(present) {
+                if !present {
+                    drift.push(Drift::MissingLayer { subvol: name.clone(), device: device.clone() });
+                    continue;
+                }
+                let info = dm.info(device)?;
+                let live = dm.table(device)?;
+
+                let expected_uuid = self.layer_uuid(name, layer.suffix);
+                let live_uuid = info.uuid;
+                if expected_uuid.is_some() && live_uuid != expected_uuid {
+                    drift.push(Drift::Uuid {
+                        subvol: name.clone(),
+                        device: device.clone(),
+                        expected: expected_uuid,
+                        live: live_uuid,
+                    });
+                }
+
+                let read_only = self.load_read_only(name, layer.suffix);
+                if info.read_only != read_only {
+                    drift.push(Drift::ReadOnly { subvol: name.clone(), device: device.clone(), expected: read_only });
+                }
+
+                // Stacked targets refer to the devices beneath them, so the
+                // expected table can only be built if those are there.  A
+                // member file with no loop device yet can't be what a live
+                // table maps, so none is attached for it.
+                let expected = match &layer.target {
+                    Target::Linear(extents) => self.linear_table(extents, false),
+                    target => self.table(Some(&*dm), target),
+                };
+                let Ok(expected) = expected else { continue };
+                if live != expected {
+                    drift.push(Drift::Table {
+                        subvol: name.clone(),
+                        device: device.clone(),
+                        expected: table_lines(&expected),
+                        live: table_lines(&live),
+                    });
+                }
+            }
+        }
+
+        if !self.uuid.is_empty() {
+            let prefix = format!("{}{}-", DM_UUID_PREFIX, self.uuid);
+            for device in dm.list()? {
+                let Some(uuid) = dm.info(&device)?.uuid else { continue };
+                if uuid.starts_with(&prefix) && !ours.contains(&device) {
+                    drift.push(Drift::Stale { device, uuid });
+                }
+            }
+        }
+        Ok(drift)
+    }
+}
@@ -0,0 +1,60 @@
+//! Saving the metadata elsewhere and writing it back.
+//!
+//! A dump is the metadata as plain JSON, whatever encoding the device
+//! uses.  Restoring one rewrites both replicas from it with fresh CRCs and a
+//! generation newer than anything still on the device, so it also recovers
+//! a device whose replicas are both damaged.
+
+use std::fs::File;
+
+use crate::{format, get_io_size, label, load_both_metadata, lock_device, MapperError, SuperPartition, DEFAULT_IO_SIZE};
+
+impl SuperPartition {
+    /// The metadata as pretty-printed JSON, suitable for `restore`
+    pub fn dump(&self) -> String {
+        serde_json::to_string_pretty(self).expect("json to_string")
+    }
+
+    /// Overwrite the metadata on `device` with a dump.  The subvolumes are
+    /// not activated; open the device afterwards for that.
+    pub fn restore(device: String, json: &str) -> Result<Self, MapperError> {
+        Self::restore_to(device, json, false)
+    }
+
+    /// Like `restore`, but recording the writes in a plan rather than
+    /// making them
+    pub fn restore_dry_run(device: String, json: &str) -> Result<Self, MapperError> {
+        Self::restore_to(device, json, true)
+    }
+
+    fn restore_to(device: String, json: &str, dry_run: bool) -> Result<Self, MapperError> {
+        let mut meta = format::decode_json(json)?;
+        let lock = lock_device(&device, true)?;
+        let mut blockdev = File::open(&device)?;
+
+        // The replicas have to end up where open will look for them
+        let label = label::read_label(&mut blockdev)?;
+        let readable = match &label {
+            Some(label) => label.iosize == meta.iosize,
+            None => meta.iosize == get_io_size(&device)? || meta.iosize == DEFAULT_IO_SIZE,
+        };
+        if !readable {
+            return Err(MapperError::InvalidArgument(format!("dump block size {} does not match the device", meta.iosize)));
+        }
+
+        // Whatever survives on the device must not win over the restore
+        let (meta1, meta2) = load_both_metadata(&mut blockdev, meta.iosize).unwrap_or((None, None));
+        for old in [meta1, meta2].into_iter().flatten() {
+            meta.generation = std::cmp::max(meta.generation, old.generation);
+        }
+
+        meta.bind(device, label)?;
+        meta.lock = Some(lock);
+        if dry_run {
+            meta.start_plan();
+        }
+        meta.write_replica(2)?;
+        meta.write_replica(1)?;
+        Ok(meta)
+    }
+}
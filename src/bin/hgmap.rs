@@ -1,57 +1,2436 @@
-use std::env::{self, Args};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::fd::{AsFd, AsRawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use mercury_mapper::SuperPartition;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use devicemapper::{errors, DmError};
+use nix::errno::Errno;
+use nix::unistd;
+use serde::Serialize;
 
-fn adopt(mut args: Args) {
-    let device = args.next().expect("no device provided");
-    let name = args.next().expect("no name provided");
-    let size_bytes = args.next().expect("no size provided");
-    let size_bytes: u64 = size_bytes.parse().expect("size not a number");
+use mercury_mapper::{ApplyOptions, BestFit, BootState, CheckRepairs, CreateOptions, CryptParams, DEFAULT_BOOT_TRIES, Encoding, Extent, ExternalMeta, FirstFit, FormatOptions, InitramfsFlavor, KeySource, LargestHoleFirst,
+    Layout, MapperError, MountPoint, NodeAccess, probe_filesystem_size, ReadOptions, Repair, ReplicaPlacement, ReplicaState, ScriptDm, Size, Slot, SuperPartition, UpdateManifest, Wipe, WorstFit, WriteOptions};
 
-    let mut sp = SuperPartition::adopt(device, name, size_bytes).expect("adopt");
-    sp.commit().expect("commit");
+/// Manage subvolumes on a super partition
+#[derive(Parser)]
+#[command(name = "hgmap", version, after_help = EXIT_STATUS)]
+struct Cli {
+    /// Print what would be done instead of doing it
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Change the metadata on an image file as usual, but rather than
+    /// setting up DM devices, write the dmsetup commands that would to
+    /// standard output, sending everything else to standard error
+    #[arg(long, global = true, conflicts_with = "dry_run")]
+    simulate: bool,
+    /// Print JSON instead of text from list, usage, check, audit, info and
+    /// map
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
 }
 
-fn open(mut args: Args) {
-    let device = args.next().expect("no device provided");
+#[derive(Subcommand)]
+enum Command {
+    /// Turn a partition into a super partition, keeping its contents, or
+    /// regions of it, as subvolumes, or a GPT disk, keeping each partition
+    Adopt(AdoptArgs),
+    /// Turn a super partition back into a GPT disk, with a partition for
+    /// each subvolume, and wipe the metadata
+    ExportGpt(DeviceArgs),
+    /// Show or change whether a shadow GPT mirrors the subvolumes
+    HybridGpt(HybridGptArgs),
+    /// Create an empty super partition, discarding whatever the device held
+    Format(FormatArgs),
+    /// Activate every subvolume
+    Open(OpenArgs),
+    /// Check the metadata for consistency, optionally repairing it
+    Check(CheckArgs),
+    /// Compare the live DM devices with the metadata
+    Audit(DeviceArgs),
+    /// Discard all unallocated space
+    Trim(DeviceArgs),
+    /// Bring the subvolumes in line with a layout file
+    Apply(ApplyArgs),
+    /// List the changes needed to match a layout file or another device
+    Diff(DiffArgs),
+    /// Activate some subvolumes, along with what they stack on
+    Activate(NamesArgs),
+    /// Deactivate some subvolumes
+    Deactivate(NamesArgs),
+    /// Deactivate every subvolume
+    Down(DownArgs),
+    /// Rewrite the metadata in the current format version, or given a
+    /// second device, recreate the super partition and all its subvolumes
+    /// there
+    Migrate(MigrateArgs),
+    /// Switch the metadata encoding
+    SetEncoding(SetEncodingArgs),
+    /// Change how many blocks each metadata replica has
+    SetMetadataBlocks(SetMetadataBlocksArgs),
+    /// Change what DM device names start with
+    SetDmPrefix(SetDmPrefixArgs),
+    /// Move the metadata to the new end of a device that has grown, so the
+    /// space gained can be used
+    Grow(DeviceArgs),
+    /// Move the metadata in so the device can be shrunk
+    Shrink(ShrinkArgs),
+    /// Add a member device to allocate from
+    AddDevice(AddDeviceArgs),
+    /// Move every extent off a member device onto the others
+    Evacuate(MemberArgs),
+    /// Remove an evacuated member device
+    RemoveDevice(MemberArgs),
+    /// Create a subvolume
+    Create(CreateArgs),
+    /// Create a subvolume on explicitly chosen blocks
+    CreateAt(CreateAtArgs),
+    /// Record data kept outside the super partition, such as a GPT
+    /// partition, as a subvolume with no extents
+    RegisterExternal(RegisterExternalArgs),
+    /// Delete a subvolume
+    Delete(DeleteArgs),
+    /// Grow or shrink a subvolume
+    Resize(ResizeArgs),
+    /// Create, delete and resize subvolumes in one transaction
+    Batch(BatchArgs),
+    /// Rename a subvolume
+    Rename(RenameArgs),
+    /// Copy an image into a subvolume
+    Write(WriteArgs),
+    /// Copy the contents of a subvolume out
+    Read(ReadArgs),
+    /// Record the SHA-256 of what subvolumes hold
+    Hash(NamesArgs),
+    /// Check subvolumes against their recorded SHA-256, every one that
+    /// has one if none are named
+    Verify(NamesArgs),
+    /// Write the images an update manifest lists, all or none of them
+    ApplyUpdate(ApplyUpdateArgs),
+    /// Show or change the active A/B slot
+    Slot(SlotArgs),
+    /// Count a boot against the active slot, falling back to the other
+    /// one if it is out of tries, and print the slot to boot
+    BootAttempt(DeviceArgs),
+    /// Record that the active slot booted
+    MarkBootSuccessful(DeviceArgs),
+    /// Print the dm-mod.create= kernel parameter that maps subvolumes at
+    /// boot, every plain one if none are named
+    Bootargs(NamesArgs),
+    /// Print the DM tables of a subvolume as dmsetup shows them, built
+    /// from the metadata
+    Table(TableArgs),
+    /// Generate a dracut or initramfs-tools hook that activates
+    /// subvolumes at early boot, every one if none are named
+    Initramfs(InitramfsArgs),
+    /// Take a snapshot of a subvolume
+    Snapshot(SnapshotArgs),
+    /// Create a subvolume holding a full copy of another, or sharing its
+    /// data copy-on-write
+    Clone(CloneArgs),
+    /// Copy a subvolume to another super partition
+    Copy(CopyArgs),
+    /// Create an encrypted subvolume
+    CreateCrypt(CreateCryptArgs),
+    /// Create a subvolume with per-sector checksums
+    CreateIntegrity(SizedSubvolArgs),
+    /// Create a subvolume mirrored across two legs
+    CreateMirror(SizedSubvolArgs),
+    /// Show the health of a mirrored subvolume
+    MirrorStatus(SubvolArgs),
+    /// Create a subvolume checked against a hash tree
+    CreateVerity(SizedSubvolArgs),
+    /// Build the hash tree of a verity subvolume and print its root hash
+    VerityFormat(SubvolArgs),
+    /// Create a thin pool
+    CreatePool(CreatePoolArgs),
+    /// Create a thin volume in a pool
+    CreateThin(CreateThinArgs),
+    /// Move fragmented subvolumes into contiguous space
+    Defrag(DefragArgs),
+    /// Report fragmentation
+    Frag(DeviceArgs),
+    /// Draw which blocks of each device the subvolumes hold, and where
+    /// the holes are, or with --json list them all in bytes
+    Map(MapArgs),
+    /// List the block devices holding super partitions
+    Scan,
+    /// Print the metadata as JSON
+    Dump(DeviceArgs),
+    /// Overwrite the metadata with a dump
+    Restore(RestoreArgs),
+    /// Go back to the layout before the last change
+    Rollback(DeviceArgs),
+    /// List, configure or restore earlier generations of the metadata
+    History(HistoryArgs),
+    /// List the subvolumes
+    List(DeviceArgs),
+    /// Show how the space is used
+    Usage(DeviceArgs),
+    /// Show everything about one subvolume
+    Info(SubvolArgs),
+    /// Record the version of a subvolume's contents
+    SetVersion(SetVersionArgs),
+    /// Show, set or remove a subvolume's tags
+    Tag(TagArgs),
+    /// Make a subvolume read-only, or writable again
+    SetReadOnly(SwitchArgs),
+    /// Protect a subvolume from deletion and resizing
+    SetProtected(SwitchArgs),
+    /// Print udev rules for the subvolumes' device nodes
+    UdevRules(DeviceArgs),
+    /// Set the name of the super partition
+    SetName(SetNameArgs),
+    /// Set the ownership and permissions of a subvolume's device node
+    SetAccess(SetAccessArgs),
+    /// Set where a subvolume's filesystem is mounted, or clear it
+    SetMount(SetMountArgs),
+    /// Set the GPT partition type a subvolume is exported with, or clear it
+    SetTypeGuid(SetTypeGuidArgs),
+    /// Generate systemd mount units, or fstab lines, for the subvolumes
+    /// that have a mount point
+    GenUnits(GenUnitsArgs),
+    /// Activate a subvolume if need be and mount its filesystem
+    Mount(MountArgs),
+}
+
+const EXIT_STATUS: &str = "\
+Exit status:
+  0  success
+  1  check, audit or diff found something
+  2  bad arguments
+  3  any other failure
+  4  no such subvolume
+  5  not enough space
+  6  metadata corrupt or missing
+  7  device busy, or locked by another process
+  8  permission denied, or the subvolume is protected";
+
+/// Exit status for each kind of failure, as listed in `EXIT_STATUS`.  Bad
+/// arguments are left to clap, which exits with 2 for them.
+#[derive(Clone, Copy)]
+enum Exit {
+    Problems = 1,
+    Failure = 3,
+    NotFound = 4,
+    NoSpace = 5,
+    Corrupt = 6,
+    Busy = 7,
+    PermissionDenied = 8,
+}
+
+impl From<&MapperError> for Exit {
+    fn from(e: &MapperError) -> Self {
+        match e {
+            MapperError::NotFound(_) => Exit::NotFound,
+            MapperError::NoSpace { .. } | MapperError::NoContiguousSpace { .. } => Exit::NoSpace,
+            MapperError::MetadataCorrupt(_) | MapperError::BadExtents(_) | MapperError::CrcMismatch { .. }
+                | MapperError::NoMetadata => Exit::Corrupt,
+            MapperError::DeviceBusy(_) | MapperError::Locked(_) | MapperError::ConcurrentModification { .. } => Exit::Busy,
+            MapperError::Protected(_) => Exit::PermissionDenied,
+            MapperError::Io(e) => Exit::from_errno(e.raw_os_error().unwrap_or_default()),
+            // devicemapper has its own version of nix, so only the number
+            // of its errno carries over
+            MapperError::Dm(DmError::Core(errors::Error::Ioctl(_, _, _, errno))) => Exit::from_errno(**errno as i32),
+            _ => Exit::Failure,
+        }
+    }
+}
+
+impl Exit {
+    fn from_errno(errno: i32) -> Self {
+        match Errno::from_raw(errno) {
+            Errno::EBUSY => Exit::Busy,
+            Errno::EACCES | Errno::EPERM => Exit::PermissionDenied,
+            _ => Exit::Failure,
+        }
+    }
+}
+
+/// Why a command did not succeed
+enum Failure {
+    /// check, audit or diff found something, and has said what
+    Problems,
+    Error(MapperError),
+}
+
+impl From<MapperError> for Failure {
+    fn from(e: MapperError) -> Self {
+        Failure::Error(e)
+    }
+}
+
+type Outcome = Result<(), Failure>;
+
+/// Set by `--dry-run`
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+fn dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Where `--simulate` writes its script: what was standard output before
+/// standard error took its place
+static SCRIPT: OnceLock<File> = OnceLock::new();
+
+fn simulate() -> Option<&'static File> {
+    SCRIPT.get()
+}
+
+/// Send standard output to the script and everything else printed to
+/// standard error
+fn start_script() -> Result<(), MapperError> {
+    let script = File::from(io::stdout().as_fd().try_clone_to_owned()?);
+    unistd::dup2(io::stderr().as_raw_fd(), io::stdout().as_raw_fd()).map_err(io::Error::from)?;
+    let _ = SCRIPT.set(script);
+    Ok(())
+}
+
+/// Set by `--json`
+static JSON: AtomicBool = AtomicBool::new(false);
+
+fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+fn print_json(value: &impl Serialize) {
+    println!("{}", serde_json::to_string_pretty(value).expect("json"));
+}
+
+/// A super partition opened by a command that changes it.  On a dry run
+/// it prints what would have been done once the command is finished with
+/// it.
+struct Session(SuperPartition);
+
+impl Deref for Session {
+    type Target = SuperPartition;
+
+    fn deref(&self) -> &SuperPartition {
+        &self.0
+    }
+}
+
+impl DerefMut for Session {
+    fn deref_mut(&mut self) -> &mut SuperPartition {
+        &mut self.0
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            return;
+        }
+        if let Some(plan) = self.0.take_plan() {
+            print!("{}", plan);
+        }
+    }
+}
+
+/// Open `device` for a command that changes it, activating every
+/// subvolume.  A dry run leaves activation to the commands that are
+/// about it, so plans only show what the command itself changes.
+fn open_device(device: String) -> Result<Session, MapperError> {
+    if let Some(script) = simulate() {
+        return Ok(Session(SuperPartition::open_with_dm(device, Rc::new(ScriptDm::new(Box::new(script))))?));
+    }
+    match dry_run() {
+        true => Ok(Session(SuperPartition::open_dry_run(device)?)),
+        false => Ok(Session(SuperPartition::open(device)?)),
+    }
+}
+
+/// Open `device` for a command that changes it, leaving the subvolumes
+/// inactive
+fn open_device_inactive(device: String) -> Result<Session, MapperError> {
+    if let Some(script) = simulate() {
+        return Ok(Session(SuperPartition::open_inactive_with_dm(device, Rc::new(ScriptDm::new(Box::new(script))))?));
+    }
+    match dry_run() {
+        true => Ok(Session(SuperPartition::open_dry_run(device)?)),
+        false => Ok(Session(SuperPartition::open_inactive(device)?)),
+    }
+}
+
+#[derive(Args)]
+struct DeviceArgs {
+    /// Block device holding the super partition
+    device: String,
+}
+
+#[derive(Args)]
+struct SubvolArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+}
+
+#[derive(Args)]
+struct SizedSubvolArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Size in bytes
+    size: u64,
+}
+
+#[derive(Args)]
+struct NamesArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume names
+    names: Vec<String>,
+}
+
+#[derive(Args)]
+struct AdoptArgs {
+    /// Block device holding the partition
+    device: String,
+    /// Name for the subvolume covering the existing contents
+    #[arg(required_unless_present_any = ["gpt", "regions"])]
+    name: Option<String>,
+    /// Bytes of existing contents at the start of the device.  Leave out
+    /// to keep the whole of the ext4, f2fs, squashfs, EROFS or FAT
+    /// filesystem found there.
+    size: Option<u64>,
+    /// Adopt a whole disk partitioned with GPT, with a subvolume for each
+    /// partition, then wipe the partition table
+    #[arg(long, conflicts_with_all = ["name", "size"])]
+    gpt: bool,
+    /// Keep the bytes from OFFSET to OFFSET+LENGTH as subvolume NAME, for
+    /// each region given, instead of the start of the device
+    #[arg(long = "region", value_name = "NAME:OFFSET:LENGTH", value_parser = parse_region,
+        conflicts_with_all = ["name", "size", "gpt"])]
+    regions: Vec<(String, u64, u64)>,
+}
+
+fn parse_region(s: &str) -> Result<(String, u64, u64), String> {
+    let (rest, length) = s.rsplit_once(':').ok_or("expected NAME:OFFSET:LENGTH")?;
+    let (name, offset) = rest.rsplit_once(':').ok_or("expected NAME:OFFSET:LENGTH")?;
+    let number = |f: &str| f.parse::<u64>().map_err(|e| format!("{:?}: {}", f, e));
+    Ok((name.to_string(), number(offset)?, number(length)?))
+}
+
+fn adopt(args: AdoptArgs) -> Outcome {
+    let options = FormatOptions { dry_run: dry_run(), ..FormatOptions::default() };
+    if args.gpt {
+        // Already committed, before the partition table was wiped
+        Session(SuperPartition::adopt_gpt(args.device, &options)?);
+        return Ok(());
+    }
+    if !args.regions.is_empty() {
+        let mut sp = Session(SuperPartition::adopt_regions(args.device, &args.regions, &options)?);
+        sp.commit()?;
+        return Ok(());
+    }
+    let Some(name) = args.name else { unreachable!("required by clap") };
+    let detected = probe_filesystem_size(&args.device)?;
+    let size = match (args.size, detected) {
+        (Some(size), Some(fs_size)) if size < fs_size => {
+            return Err(MapperError::InvalidArgument(format!("the filesystem on {} takes {} bytes; \
+                adopting {} would cut it short", args.device, fs_size, size)).into());
+        }
+        (Some(size), _) => size,
+        (None, Some(fs_size)) => fs_size,
+        (None, None) => {
+            return Err(MapperError::InvalidArgument(format!("no filesystem recognised on {}; give the size to keep",
+                args.device)).into());
+        }
+    };
+    let mut sp = Session(SuperPartition::adopt_with(args.device, name, size, &options)?);
+    sp.commit()?;
+    Ok(())
+}
+
+fn export_gpt(args: DeviceArgs) -> Outcome {
+    let mut sp = open_device_inactive(args.device)?;
+    for (number, name) in sp.export_gpt()? {
+        if !dry_run() {
+            println!("{}: {}", number, name);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("action").args(["enable", "disable"])))]
+struct HybridGptArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Rewrite a GPT at the start of the device on every commit, with an
+    /// entry for each contiguous subvolume
+    #[arg(long)]
+    enable: bool,
+    /// Stop keeping the GPT, and wipe it
+    #[arg(long)]
+    disable: bool,
+}
+
+fn hybrid_gpt(args: HybridGptArgs) -> Outcome {
+    if !args.enable && !args.disable {
+        let sp = SuperPartition::open_readonly(args.device)?;
+        println!("{}", if sp.hybrid_gpt() { "enabled" } else { "disabled" });
+        return Ok(());
+    }
+    let mut sp = open_device(args.device)?;
+    match args.enable {
+        true => sp.enable_hybrid_gpt()?,
+        false => sp.disable_hybrid_gpt()?,
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct FormatArgs {
+    /// Block device to format
+    device: String,
+    #[command(flatten)]
+    replicas: ReplicaArgs,
+}
+
+/// Where a newly formatted super partition keeps its metadata
+#[derive(Args)]
+struct ReplicaArgs {
+    /// Put a label at the start of the device, with the replicas after it
+    /// and at the end
+    #[arg(long, conflicts_with = "offsets")]
+    begin_end: bool,
+    /// Put the replicas at these block offsets, with a label in front
+    #[arg(long, value_name = "FIRST,SECOND", value_parser = parse_offsets)]
+    offsets: Option<[u64; 2]>,
+    /// Blocks in each metadata replica
+    #[arg(long, value_name = "BLOCKS")]
+    metadata_blocks: Option<u64>,
+}
+
+fn parse_offsets(s: &str) -> Result<[u64; 2], String> {
+    let (a, b) = s.split_once(',').ok_or("expected FIRST,SECOND")?;
+    let offset = |s: &str| s.parse::<u64>().map_err(|e| format!("offset {:?}: {}", s, e));
+    Ok([offset(a)?, offset(b)?])
+}
+
+impl ReplicaArgs {
+    fn options(&self) -> FormatOptions {
+        let mut options = FormatOptions { dry_run: dry_run(), ..FormatOptions::default() };
+        if self.begin_end {
+            options.placement = ReplicaPlacement::BeginEnd;
+        }
+        if let Some(offsets) = self.offsets {
+            options.placement = ReplicaPlacement::Offsets(offsets);
+        }
+        if let Some(blocks) = self.metadata_blocks {
+            options.metadata_blocks = blocks;
+        }
+        options
+    }
+}
+
+fn format(args: FormatArgs) -> Outcome {
+    let mut sp = Session(SuperPartition::format(args.device, &args.replicas.options())?);
+    sp.commit()?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct MigrateArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Block device to recreate it on, discarding whatever that holds
+    dest: Option<String>,
+    #[command(flatten)]
+    replicas: ReplicaArgs,
+}
+
+fn migrate_to(source: String, dest: String, replicas: &ReplicaArgs) -> Outcome {
+    let src = open_device_inactive(source)?;
+    let mut dst = Session(SuperPartition::format(dest, &replicas.options())?);
+    let show = !dry_run() && io::stderr().is_terminal();
+    let mut show_progress = |name: &str, copied| {
+        if show {
+            eprint!("\r{}: {} bytes", name, copied);
+        }
+    };
+    let names = src.migrate_to(&mut dst, &mut show_progress);
+    if show {
+        eprintln!();
+    }
+    for name in names? {
+        if !dry_run() {
+            println!("Migrated {}", name);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct OpenArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Rewrite a corrupt or stale metadata replica
+    #[arg(long)]
+    repair: bool,
+    /// Fail rather than wait if another process has the device open
+    #[arg(long)]
+    no_wait: bool,
+}
+
+fn open(args: OpenArgs) -> Outcome {
+    let sp = if dry_run() {
+        let sp = open_device(args.device)?;
+        sp.activate_all()?;
+        sp
+    } else if args.no_wait && simulate().is_none() {
+        Session(SuperPartition::try_open(args.device)?)
+    } else {
+        open_device(args.device)?
+    };
+    if !args.repair {
+        return Ok(());
+    }
+    let repair = sp.repair_replicas()?;
+    match repair {
+        Some(Repair { slot, previous: ReplicaState::Corrupt }) => println!("Rewrote corrupt metadata replica {}", slot),
+        Some(Repair { slot, previous: ReplicaState::Stale { generation } }) => {
+            println!("Rewrote metadata replica {} from generation {}", slot, generation)
+        }
+        None => println!("Both metadata replicas are current"),
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct CheckArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Make every repair
+    #[arg(long)]
+    repair: bool,
+    /// Drop extents that collide or run off their device, along with the
+    /// rest of their subvolume
+    #[arg(long)]
+    drop_bad_extents: bool,
+    /// Make the metadata entry cover the metadata blocks again
+    #[arg(long)]
+    rebuild_metadata: bool,
+    /// Rewrite a corrupt or stale replica
+    #[arg(long)]
+    resync_replicas: bool,
+}
+
+fn check(args: CheckArgs) -> Outcome {
+    let mut repairs = match args.repair {
+        true => CheckRepairs::all(),
+        false => CheckRepairs::default(),
+    };
+    repairs.drop_bad_extents |= args.drop_bad_extents;
+    repairs.rebuild_metadata_entry |= args.rebuild_metadata;
+    repairs.resync_replicas |= args.resync_replicas;
+    // Without repairs, checking is already a dry run of them
+    if dry_run() {
+        repairs = CheckRepairs::default();
+    }
+
+    let report = SuperPartition::check(args.device, &repairs)?;
+    if json() {
+        print_json(&report);
+        return match report.is_clean() {
+            true => Ok(()),
+            false => Err(Failure::Problems),
+        };
+    }
+    for problem in &report.repaired {
+        println!("repaired: {}", problem);
+    }
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    if !report.is_clean() {
+        return Err(Failure::Problems);
+    }
+    println!("Generation {} is consistent", report.generation);
+    Ok(())
+}
+
+fn audit(args: DeviceArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let drift = sp.audit()?;
+    if json() {
+        print_json(&drift);
+    } else {
+        for d in &drift {
+            println!("{}", d);
+        }
+    }
+    if !drift.is_empty() {
+        return Err(Failure::Problems);
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct ApplyArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// TOML file listing the subvolumes that should exist
+    layout: PathBuf,
+    /// Resize subvolumes whose size differs from the layout
+    #[arg(long)]
+    resize: bool,
+    /// Delete subvolumes the layout doesn't mention
+    #[arg(long)]
+    remove_extras: bool,
+}
+
+fn apply(args: ApplyArgs) -> Outcome {
+    let options = ApplyOptions {
+        resize: args.resize,
+        remove_extras: args.remove_extras,
+    };
+
+    let layout = Layout::load(&args.layout)?;
+    let mut sp = open_device(args.device)?;
+    let report = sp.apply_layout(&layout, &options)?;
+    for change in &report.applied {
+        println!("{}", change);
+    }
+    for change in &report.skipped {
+        println!("skipped: {}", change);
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Layout file, or another device holding a super partition
+    target: String,
+}
+
+fn diff(args: DiffArgs) -> Outcome {
+    let layout = if mercury_mapper::probe(&args.target) {
+        SuperPartition::open_readonly(args.target)?.layout()
+    } else {
+        Layout::load(args.target.as_ref())?
+    };
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let changes = sp.diff_layout(&layout);
+    for change in &changes {
+        println!("{}", change);
+    }
+    if !changes.is_empty() {
+        return Err(Failure::Problems);
+    }
+    Ok(())
+}
+
+fn trim(args: DeviceArgs) -> Outcome {
+    let sp = open_device_inactive(args.device)?;
+    let trimmed = sp.trim()?;
+    println!("Discarded {} bytes of free space", trimmed);
+    Ok(())
+}
+
+fn activate(args: NamesArgs) -> Outcome {
+    let sp = open_device_inactive(args.device)?;
+    for name in args.names {
+        sp.activate(&name)?;
+    }
+    Ok(())
+}
+
+fn deactivate(args: NamesArgs) -> Outcome {
+    let sp = open_device_inactive(args.device)?;
+    for name in args.names {
+        sp.deactivate(&name)?;
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct DownArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Remove devices that are in use as soon as they are closed
+    #[arg(long)]
+    force: bool,
+}
+
+fn down(args: DownArgs) -> Outcome {
+    let sp = open_device_inactive(args.device)?;
+    sp.deactivate_all(args.force)?;
+    Ok(())
+}
+
+fn migrate(args: MigrateArgs) -> Outcome {
+    if let Some(dest) = args.dest {
+        return migrate_to(args.device, dest, &args.replicas);
+    }
+    let replicas = &args.replicas;
+    if replicas.begin_end || replicas.offsets.is_some() || replicas.metadata_blocks.is_some() {
+        return Err(MapperError::InvalidArgument("replica placement needs a device to migrate to".to_string()).into());
+    }
+    let mut sp = open_device(args.device)?;
+    let from = sp.format_version();
+    if sp.migrate()? {
+        println!("Migrated metadata from format version {} to {}", from, sp.format_version());
+    } else {
+        println!("Metadata already at format version {}", from);
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct AddDeviceArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Block device to add
+    member: String,
+}
+
+fn add_device(args: AddDeviceArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.add_device(args.member)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct MemberArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Member device
+    member: String,
+}
+
+fn grow(args: DeviceArgs) -> Outcome {
+    Session(SuperPartition::grow(args.device, dry_run())?);
+    Ok(())
+}
+
+#[derive(Args)]
+struct ShrinkArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Size in bytes the device is to be shrunk to, with an optional K, M,
+    /// G, T or P suffix
+    size: Size,
+}
+
+fn shrink(args: ShrinkArgs) -> Outcome {
+    let Size::Bytes(size) = args.size else {
+        return Err(MapperError::InvalidArgument("give the new size of the device in bytes".to_string()).into());
+    };
+    let mut sp = open_device_inactive(args.device)?;
+    sp.shrink(size)?;
+    if !dry_run() {
+        println!("The device can now be shrunk to {} bytes", size);
+    }
+    Ok(())
+}
+
+fn evacuate(args: MemberArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    for name in sp.evacuate(&args.member)? {
+        if !dry_run() {
+            println!("Moved {}", name);
+        }
+    }
+    Ok(())
+}
+
+fn remove_device(args: MemberArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.remove_device(&args.member)?;
+    Ok(())
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EncodingArg {
+    Json,
+    Cbor,
+}
+
+#[derive(Args)]
+struct SetEncodingArgs {
+    /// Block device holding the super partition
+    device: String,
+    encoding: EncodingArg,
+}
+
+fn set_encoding(args: SetEncodingArgs) -> Outcome {
+    let encoding = match args.encoding {
+        EncodingArg::Json => Encoding::Json,
+        EncodingArg::Cbor => Encoding::Cbor,
+    };
+
+    let mut sp = open_device(args.device)?;
+    sp.set_metadata_encoding(encoding);
+    sp.commit()?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct SetDmPrefixArgs {
+    /// Block device holding the super partition
+    device: String,
+    prefix: String,
+}
+
+fn set_dm_prefix(args: SetDmPrefixArgs) -> Outcome {
+    let mut sp = open_device_inactive(args.device)?;
+    sp.set_dm_prefix(&args.prefix)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct SetMetadataBlocksArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Blocks in each metadata replica
+    blocks: u64,
+}
+
+fn set_metadata_blocks(args: SetMetadataBlocksArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.set_metadata_blocks(args.blocks)?;
+    Ok(())
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum WipeArg {
+    /// Write zeroes
+    Zero,
+    /// Discard the blocks
+    Discard,
+}
+
+impl From<WipeArg> for Wipe {
+    fn from(arg: WipeArg) -> Self {
+        match arg {
+            WipeArg::Zero => Wipe::Zero,
+            WipeArg::Discard => Wipe::Discard,
+        }
+    }
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("strategy").args(["first_fit", "best_fit", "worst_fit", "largest_first"])))]
+struct CreateArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Size in bytes, with an optional K, M, G, T or P suffix, or as N% of
+    /// the device or N%FREE of the free space
+    size: Size,
+    /// Take the first hole that is big enough
+    #[arg(long)]
+    first_fit: bool,
+    /// Take the smallest hole that is big enough
+    #[arg(long)]
+    best_fit: bool,
+    /// Take the largest hole
+    #[arg(long)]
+    worst_fit: bool,
+    /// Fill the largest holes first
+    #[arg(long)]
+    largest_first: bool,
+    /// Allocate a single extent
+    #[arg(long)]
+    contiguous: bool,
+    /// Align the extents to this many bytes
+    #[arg(long, value_name = "BYTES")]
+    align: Option<u64>,
+    /// Clear the start and end of the subvolume
+    #[arg(long, value_name = "HOW", num_args = 0..=1, default_missing_value = "zero")]
+    wipe: Option<WipeArg>,
+    /// Wait for the device node to appear
+    #[arg(long)]
+    wait: bool,
+    /// Create a pair of subvolumes, NAME_a and NAME_b, one for each A/B
+    /// slot
+    #[arg(long)]
+    ab: bool,
+    /// Make a filesystem on the new subvolume and record its type
+    #[arg(long, value_enum, value_name = "TYPE")]
+    mkfs: Option<MkfsArg>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MkfsArg {
+    Ext4,
+    Xfs,
+    F2fs,
+}
+
+impl MkfsArg {
+    fn fstype(self) -> &'static str {
+        match self {
+            MkfsArg::Ext4 => "ext4",
+            MkfsArg::Xfs => "xfs",
+            MkfsArg::F2fs => "f2fs",
+        }
+    }
+}
+
+/// Run mkfs for `fstype` on the node of subvolume `name` once it appears,
+/// and record the type.  Only the record is planned on a dry run, and a
+/// simulation leaves mkfs to the script.
+fn make_filesystem(sp: &mut SuperPartition, name: &str, fstype: &str) -> Result<(), MapperError> {
+    if let Some(mut script) = simulate() {
+        writeln!(script, "mkfs.{} -q '{}'", fstype, sp.subvol_path(name).display())?;
+    } else if !dry_run() {
+        let path = sp.wait_for_subvol(name, Duration::from_secs(10))?;
+        let command = format!("mkfs.{}", fstype);
+        let status = process::Command::new(&command).arg("-q").arg(&path).status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("{} {} failed: {}", command, path.display(), status)).into());
+        }
+    }
+    sp.set_filesystem(name, Some(fstype))
+}
+
+fn create(args: CreateArgs) -> Outcome {
+    let mut options = CreateOptions {
+        contiguous: args.contiguous,
+        alignment: args.align.unwrap_or_default(),
+        wipe: args.wipe.map(Wipe::from),
+        ..CreateOptions::default()
+    };
+    if args.first_fit {
+        options.strategy = &FirstFit;
+    } else if args.best_fit {
+        options.strategy = &BestFit;
+    } else if args.worst_fit {
+        options.strategy = &WorstFit;
+    } else if args.largest_first {
+        options.strategy = &LargestHoleFirst;
+    }
+
+    let mut sp = open_device(args.device)?;
+    let size_bytes = sp.resolve_size(args.size, None)?;
+    let (names, paths) = match args.ab {
+        true => {
+            let names = vec![Slot::A.subvol(&args.name), Slot::B.subvol(&args.name)];
+            (names, sp.create_slotted(&args.name, size_bytes, &options)?.to_vec())
+        }
+        false => (vec![args.name.clone()], vec![sp.create_subvol_with(args.name.clone(), size_bytes, &options)?]),
+    };
+    if args.wait && !dry_run() && simulate().is_none() {
+        for name in &names {
+            sp.wait_for_subvol(name, Duration::from_secs(10))?;
+        }
+    }
+    if let Some(mkfs) = args.mkfs {
+        for name in &names {
+            make_filesystem(&mut sp, name, mkfs.fstype())?;
+        }
+    }
+    for path in paths {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct CreateAtArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Extents in blocks, in the order they are mapped
+    #[arg(required = true, value_name = "[DEVICE:]START:LENGTH", value_parser = parse_extent)]
+    extents: Vec<Extent>,
+}
+
+fn parse_extent(s: &str) -> Result<Extent, String> {
+    let fields = s.split(':')
+        .map(|f| f.parse::<u64>().map_err(|e| format!("{:?}: {}", f, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    match fields[..] {
+        [start, length] => Ok(Extent::new(0, start, length)),
+        [device, start, length] => {
+            let device = u32::try_from(device).map_err(|e| format!("device {}: {}", device, e))?;
+            Ok(Extent::new(device, start, length))
+        }
+        _ => Err("expected [DEVICE:]START:LENGTH".to_string()),
+    }
+}
+
+fn create_at(args: CreateAtArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    let path = sp.create_subvol_at(args.name, args.extents)?;
+    println!("{}", path.display());
+    Ok(())
+}
+
+#[derive(Args)]
+struct RegisterExternalArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Name for the record
+    name: String,
+    #[arg(default_value = "")]
+    version: String,
+    #[arg(long, default_value = "")]
+    author: String,
+    /// Defaults to now
+    #[arg(long, default_value = "")]
+    timedate: String,
+}
+
+fn register_external(args: RegisterExternalArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    let meta = ExternalMeta { version: args.version, author: args.author, timedate: args.timedate };
+    sp.register_external(&args.name, &meta)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct DeleteArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Delete even if protected or in use
+    #[arg(long)]
+    force: bool,
+    /// Clear the freed blocks
+    #[arg(long, value_name = "HOW", num_args = 0..=1, default_missing_value = "zero")]
+    shred: Option<WipeArg>,
+}
+
+fn delete(args: DeleteArgs) -> Outcome {
+    let name = args.name;
+    let mut sp = open_device(args.device)?;
+    if !sp.subvols.contains_key(&name) {
+        return Err(MapperError::NotFound(name).into());
+    }
+    if let Some(how) = args.shred {
+        sp.delete_subvol_secure(&name, how.into(), args.force)?;
+    } else if args.force {
+        sp.force_delete_subvol(&name)?;
+    } else {
+        sp.delete_subvol_by_name(&name)?;
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+enum BatchOp {
+    Create(String, u64),
+    Delete(String),
+    Resize(String, u64),
+}
+
+fn parse_batch_op(s: &str) -> Result<BatchOp, String> {
+    let size = |s: &str| s.parse::<u64>().map_err(|e| format!("size {:?}: {}", s, e));
+    match s.split(':').collect::<Vec<_>>()[..] {
+        ["create", name, size_bytes] => Ok(BatchOp::Create(name.to_string(), size(size_bytes)?)),
+        ["delete", name] => Ok(BatchOp::Delete(name.to_string())),
+        ["resize", name, size_bytes] => Ok(BatchOp::Resize(name.to_string(), size(size_bytes)?)),
+        _ => Err("expected create:NAME:SIZE, delete:NAME or resize:NAME:SIZE".to_string()),
+    }
+}
+
+#[derive(Args)]
+struct BatchArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Operations to stage, in order
+    #[arg(value_name = "OP", value_parser = parse_batch_op)]
+    ops: Vec<BatchOp>,
+}
+
+fn batch(args: BatchArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    let mut txn = sp.transaction();
+    for op in args.ops {
+        match &op {
+            BatchOp::Create(name, size) => txn.create(name, *size)?,
+            BatchOp::Delete(name) => txn.delete(name)?,
+            BatchOp::Resize(name, size) => txn.resize(name, *size)?,
+        };
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct ResizeArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// New size in bytes, with an optional K, M, G, T or P suffix, or as
+    /// N% of the device, or N%FREE to grow by that share of the free space
+    size: Size,
+    /// Resize even if protected, and shrink without asking
+    #[arg(long)]
+    force: bool,
+}
+
+fn resize(args: ResizeArgs) -> Outcome {
+    let (name, force) = (args.name, args.force);
+    let mut sp = open_device(args.device)?;
+    let cur_size = match sp.subvols().find(|sv| sv.name == name) {
+        Some(sv) => sv.size,
+        None => return Err(MapperError::NotFound(name).into()),
+    };
+    let size_bytes = sp.resolve_size(args.size, Some(&name))?;
+
+    if size_bytes >= cur_size {
+        if force {
+            sp.force_resize_subvol(&name, size_bytes)?;
+        } else {
+            sp.resize_subvol(&name, size_bytes)?;
+        }
+        return Ok(());
+    }
+
+    if !force {
+        eprintln!("Shrinking {} from {} to {} bytes discards everything past the new size.", name, cur_size, size_bytes);
+        eprint!("Re-enter the new size to confirm: ");
+        let mut confirm = String::new();
+        io::stdin().read_line(&mut confirm).map_err(MapperError::from)?;
+        let confirmed = confirm.trim().parse().ok().and_then(|size| sp.resolve_size(size, Some(&name)).ok());
+        if confirmed != Some(size_bytes) {
+            return Err(MapperError::InvalidArgument("size not confirmed, not shrinking".to_string()).into());
+        }
+    }
+    sp.shrink_subvol(&name, size_bytes, force)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct RenameArgs {
+    /// Block device holding the super partition
+    device: String,
+    old: String,
+    new: String,
+}
+
+fn rename(args: RenameArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.rename_subvol(&args.old, &args.new)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct WriteArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Image to write; standard input if left out or -
+    image: Option<PathBuf>,
+    /// Read the subvolume back afterwards to check it, and record its
+    /// SHA-256
+    #[arg(long)]
+    verify: bool,
+    /// Carry on with an interrupted write.  A file is picked up where the
+    /// write got to; standard input has to start there.
+    #[arg(long)]
+    resume: bool,
+}
+
+fn write(args: WriteArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    let offset = match args.resume {
+        true => {
+            let offset = sp.interrupted_write(&args.name)
+                .ok_or_else(|| MapperError::InvalidArgument(format!("no interrupted write to {} to resume", args.name)))?;
+            eprintln!("Resuming at byte {}", offset);
+            offset
+        }
+        false => 0,
+    };
+
+    let (image, length): (Box<dyn Read>, _) = match args.image.filter(|path| path.as_os_str() != "-") {
+        Some(path) => {
+            let mut file = File::open(path).map_err(MapperError::from)?;
+            // Block devices and pipes have no length to go by
+            let meta = file.metadata().map_err(MapperError::from)?;
+            let length = meta.is_file().then(|| meta.len().saturating_sub(offset));
+            file.seek(SeekFrom::Start(offset)).map_err(MapperError::from)?;
+            (Box::new(file), length)
+        }
+        None => (Box::new(io::stdin().lock()), None),
+    };
+
+    let total = length.map(|len| format!(" of {}", len)).unwrap_or_default();
+    let mut show_progress = |written| eprint!("\r{}{} bytes", written, total);
+    let mut options = WriteOptions { length, verify: args.verify, resume: args.resume, ..WriteOptions::default() };
+    if io::stderr().is_terminal() {
+        options.progress = Some(&mut show_progress);
+    }
+
+    let written = sp.write_image_with(&args.name, image, &mut options);
+    if options.progress.is_some() {
+        eprintln!();
+    }
+    println!("Wrote {} bytes to {}", written?, args.name);
+    if let Some(digest) = sp.subvol_info(&args.name).and_then(|info| info.digest) {
+        println!("Verified, SHA-256 {}", digest.sha256);
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct ReadArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// File to write to; standard output if left out or -
+    output: Option<PathBuf>,
+    /// Read no more than this many bytes
+    #[arg(long, value_name = "BYTES")]
+    length: Option<u64>,
+    /// Stop at the end of the filesystem the subvolume holds
+    #[arg(long)]
+    filesystem: bool,
+}
+
+fn read(args: ReadArgs) -> Outcome {
+    let output: Box<dyn Write> = match args.output.filter(|path| path.as_os_str() != "-") {
+        Some(path) => Box::new(File::create(path).map_err(MapperError::from)?),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    let mut show_progress = |read| eprint!("\r{} bytes", read);
+    let mut options = ReadOptions { length: args.length, filesystem: args.filesystem, ..ReadOptions::default() };
+    if io::stderr().is_terminal() {
+        options.progress = Some(&mut show_progress);
+    }
+
+    let sp = open_device_inactive(args.device)?;
+    let read = sp.read_image_with(&args.name, output, &mut options);
+    if options.progress.is_some() {
+        eprintln!();
+    }
+    read?;
+    Ok(())
+}
+
+fn hash(args: NamesArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    for name in args.names {
+        let digest = sp.hash_subvol(&name)?;
+        println!("{}: {}", name, digest.sha256);
+    }
+    Ok(())
+}
+
+fn verify(args: NamesArgs) -> Outcome {
+    let sp = open_device_inactive(args.device)?;
+    let names = match args.names.is_empty() {
+        true => sp.subvols().filter(|info| info.digest.is_some()).map(|info| info.name.to_string()).collect(),
+        false => args.names,
+    };
+    let mut outcome = Ok(());
+    for name in names {
+        match sp.verify_subvol(&name)? {
+            true => println!("{}: OK", name),
+            false => {
+                println!("{}: MISMATCH", name);
+                outcome = Err(Failure::Problems);
+            }
+        }
+    }
+    outcome
+}
+
+#[derive(Args)]
+struct ApplyUpdateArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// JSON file listing each subvolume's image and its SHA-256
+    manifest: PathBuf,
+}
+
+fn apply_update(args: ApplyUpdateArgs) -> Outcome {
+    let manifest = UpdateManifest::load(&args.manifest)?;
+    let mut sp = open_device(args.device)?;
+    let updated = sp.apply_update(&manifest)?;
+    for (name, image) in updated.iter().zip(&manifest.images) {
+        println!("Updated {} from {}", name, image.image.display());
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct SlotArgs {
+    #[command(subcommand)]
+    command: SlotCommand,
+}
+
+#[derive(Subcommand)]
+enum SlotCommand {
+    /// Print the active slot, or the subvolume in it for a base name
+    Get(SlotNameArgs),
+    /// Print the inactive slot, or the subvolume in it for a base name
+    Other(SlotNameArgs),
+    /// Make a slot the active one, on trial until a boot from it is
+    /// marked successful
+    Set(SlotSetArgs),
+    /// Show the active slot and how booting from each has gone
+    Status(DeviceArgs),
+}
+
+#[derive(Args)]
+struct SlotNameArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Base name of a pair of subvolumes
+    base: Option<String>,
+}
+
+#[derive(Args)]
+struct SlotSetArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// a or b
+    slot: Slot,
+    /// Boots it gets before falling back to the other slot
+    #[arg(long, default_value_t = DEFAULT_BOOT_TRIES)]
+    tries: u32,
+}
+
+#[derive(Serialize)]
+struct SlotStatus {
+    active: Slot,
+    a: BootState,
+    b: BootState,
+}
+
+fn slot(args: SlotArgs) -> Outcome {
+    let (args, other) = match args.command {
+        SlotCommand::Get(args) => (args, false),
+        SlotCommand::Other(args) => (args, true),
+        SlotCommand::Set(args) => {
+            let mut sp = open_device(args.device)?;
+            sp.set_active_slot(args.slot, args.tries)?;
+            return Ok(());
+        }
+        SlotCommand::Status(args) => return slot_status(args),
+    };
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let slot = match other {
+        true => sp.active_slot().other(),
+        false => sp.active_slot(),
+    };
+    match args.base {
+        Some(base) => {
+            let name = slot.subvol(&base);
+            if sp.subvol_info(&name).is_none() {
+                return Err(MapperError::NotFound(name).into());
+            }
+            println!("{}", name);
+        }
+        None => println!("{}", slot),
+    }
+    Ok(())
+}
+
+fn slot_status(args: DeviceArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let status = SlotStatus {
+        active: sp.active_slot(),
+        a: sp.boot_state(Slot::A),
+        b: sp.boot_state(Slot::B),
+    };
+    if json() {
+        print_json(&status);
+        return Ok(());
+    }
+    for (slot, state) in [(Slot::A, status.a), (Slot::B, status.b)] {
+        let active = if slot == status.active { "active, " } else { "" };
+        match state.successful {
+            true => println!("{}: {}successful", slot, active),
+            false => println!("{}: {}{} tries left", slot, active, state.tries_remaining),
+        }
+    }
+    Ok(())
+}
+
+fn boot_attempt(args: DeviceArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    println!("{}", sp.boot_attempt()?);
+    Ok(())
+}
+
+fn mark_boot_successful(args: DeviceArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.mark_boot_successful()?;
+    Ok(())
+}
+
+fn bootargs(args: NamesArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let names: Vec<_> = args.names.iter().map(String::as_str).collect();
+    println!("{}", sp.bootargs(&names)?);
+    Ok(())
+}
+
+#[derive(Args)]
+struct TableArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Show keys from key files rather than zeros, as dmsetup --showkeys
+    #[arg(long)]
+    showkeys: bool,
+}
+
+/// A subvolume that is one device prints like `dmsetup table NAME`; a
+/// stack prints like `dmsetup table`, each line after its device's name
+fn table(args: TableArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let tables = sp.tables(&args.name, args.showkeys)?;
+    let stacked = tables.len() > 1;
+    for (device, lines) in tables {
+        for line in lines {
+            match stacked {
+                true => println!("{}: {}", device, line),
+                false => println!("{}", line),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FlavorArg {
+    Dracut,
+    InitramfsTools,
+}
+
+#[derive(Args)]
+struct InitramfsArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolumes, or base names of A/B pairs, to activate
+    names: Vec<String>,
+    /// Initramfs generator to write the hook for
+    #[arg(long, value_enum, default_value = "initramfs-tools")]
+    flavor: FlavorArg,
+    /// Write the files under this directory, such as
+    /// /usr/lib/dracut/modules.d or /etc/initramfs-tools, rather than
+    /// printing them
+    #[arg(long, value_name = "DIR")]
+    output: Option<PathBuf>,
+}
+
+fn initramfs(args: InitramfsArgs) -> Outcome {
+    let flavor = match args.flavor {
+        FlavorArg::Dracut => InitramfsFlavor::Dracut,
+        FlavorArg::InitramfsTools => InitramfsFlavor::InitramfsTools,
+    };
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let names: Vec<_> = args.names.iter().map(String::as_str).collect();
+    let files = sp.initramfs_hook(flavor, &names)?;
+    let Some(dir) = args.output else {
+        for file in &files {
+            println!("==> {} <==", file.path);
+            print!("{}", file.contents);
+        }
+        return Ok(());
+    };
+    for file in &files {
+        let path = dir.join(file.path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(MapperError::from)?;
+        }
+        fs::write(&path, &file.contents).map_err(MapperError::from)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).map_err(MapperError::from)?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct SnapshotArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume to take the snapshot of
+    origin: String,
+    /// Name for the snapshot
+    name: String,
+    /// Bytes set aside for changes to either side
+    cow_size: u64,
+}
+
+fn snapshot(args: SnapshotArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.snapshot_subvol(&args.origin, &args.name, args.cow_size)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct CloneArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume to copy
+    source: String,
+    /// Name for the copy
+    name: String,
+    /// Share the data through a snapshot, setting aside this much for
+    /// changes to either side, rather than copying it
+    #[arg(long, value_name = "SIZE")]
+    cow: Option<Size>,
+}
+
+fn clone(args: CloneArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    let path = match args.cow {
+        Some(size) => {
+            let cow_size = sp.resolve_size(size, None)?;
+            sp.cow_clone_subvol(&args.source, &args.name, cow_size)?
+        }
+        None => sp.clone_subvol(&args.source, &args.name)?,
+    };
+    if !dry_run() {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct CopyArgs {
+    /// Subvolume to copy, as DEVICE:NAME
+    source: String,
+    /// Super partition to copy it to, as DEVICE or DEVICE:NAME; the name
+    /// stays the same if left out
+    dest: String,
+    /// Read the copy back afterwards to check it
+    #[arg(long)]
+    verify: bool,
+}
+
+fn copy(args: CopyArgs) -> Outcome {
+    let bad = |what: &str| MapperError::InvalidArgument(format!("expected {}", what));
+    let (src_device, src_name) = args.source.rsplit_once(':').ok_or_else(|| bad("DEVICE:NAME to copy from"))?;
+    // Paths such as /dev/disk/by-path ones can have colons of their own
+    let (dst_device, dst_name) = match Path::new(&args.dest).exists() {
+        true => (args.dest.as_str(), src_name),
+        false => args.dest.rsplit_once(':').ok_or_else(|| bad("DEVICE or DEVICE:NAME to copy to"))?,
+    };
+    let same = |a: &str, b: &str| match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    };
+    if same(src_device, dst_device) {
+        return Err(MapperError::InvalidArgument("both are on the same super partition; use clone".to_string()).into());
+    }
+
+    let src = open_device_inactive(src_device.to_string())?;
+    let mut dst = open_device(dst_device.to_string())?;
+    let total = src.subvol_info(src_name).map(|info| info.size).unwrap_or_default();
+    let mut show_progress = |copied| eprint!("\r{} of {} bytes", copied, total);
+    let mut options = WriteOptions { verify: args.verify, ..WriteOptions::default() };
+    if io::stderr().is_terminal() && !dry_run() {
+        options.progress = Some(&mut show_progress);
+    }
+
+    let copied = src.copy_subvol_to(src_name, &mut dst, dst_name, &mut options);
+    if options.progress.is_some() {
+        eprintln!();
+    }
+    let copied = copied?;
+    if !dry_run() {
+        println!("Copied {} bytes to {}:{}", copied, dst_device, dst_name);
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct CreateCryptArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Size in bytes
+    size: u64,
+    /// Where the key comes from
+    #[arg(value_name = "keyring:DESCRIPTION|keyfile:PATH", value_parser = parse_key)]
+    key: KeySource,
+    /// Cipher specification, as dm-crypt takes it
+    cipher: Option<String>,
+    /// Key size in bits
+    key_size: Option<u32>,
+}
+
+fn parse_key(s: &str) -> Result<KeySource, String> {
+    if let Some(desc) = s.strip_prefix("keyring:") {
+        Ok(KeySource::Keyring(desc.to_string()))
+    } else if let Some(path) = s.strip_prefix("keyfile:") {
+        Ok(KeySource::KeyFile(path.to_string()))
+    } else {
+        Err("expected keyring:<description> or keyfile:<path>".to_string())
+    }
+}
+
+fn create_crypt(args: CreateCryptArgs) -> Outcome {
+    let mut crypt = CryptParams::new(args.key);
+    if let Some(cipher) = args.cipher {
+        crypt.cipher = cipher;
+    }
+    if let Some(key_size) = args.key_size {
+        crypt.key_size = key_size;
+    }
+
+    let mut sp = open_device(args.device)?;
+    sp.create_encrypted_subvol(args.name, args.size, crypt)?;
+    Ok(())
+}
+
+fn create_integrity(args: SizedSubvolArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.create_integrity_subvol(args.name, args.size)?;
+    Ok(())
+}
+
+fn create_mirror(args: SizedSubvolArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.create_mirrored_subvol(args.name, args.size)?;
+    Ok(())
+}
+
+fn mirror_status(args: SubvolArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let status = sp.mirror_status(&args.name)?;
+    let legs: String = status.legs_alive.iter().map(|alive| if *alive { 'A' } else { 'D' }).collect();
+    println!("legs: {}", legs);
+    println!("synced: {}/{} regions", status.synced_regions, status.total_regions);
+    if status.is_degraded() {
+        println!("DEGRADED");
+    }
+    Ok(())
+}
+
+fn create_verity(args: SizedSubvolArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.create_verity_subvol(args.name, args.size)?;
+    Ok(())
+}
+
+fn verity_format(args: SubvolArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    let root_hash = sp.verity_format(&args.name)?;
+    println!("{}", root_hash);
+    Ok(())
+}
+
+#[derive(Args)]
+struct CreatePoolArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Pool name
+    name: String,
+    /// Bytes of space for the thin volumes
+    data_size: u64,
+    /// Bytes for the pool's own metadata
+    metadata_size: u64,
+}
+
+fn create_pool(args: CreatePoolArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.create_thin_pool(&args.name, args.data_size, args.metadata_size)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct CreateThinArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Pool to provision from
+    pool: String,
+    /// Thin volume name
+    name: String,
+    /// Virtual size in bytes
+    size: u64,
+}
+
+fn create_thin(args: CreateThinArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.create_thin(&args.pool, &args.name, args.size)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct DefragArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Finish an interrupted relocation
+    #[arg(long, conflicts_with = "rollback")]
+    resume: bool,
+    /// Abandon an interrupted relocation
+    #[arg(long)]
+    rollback: bool,
+}
+
+fn defrag(args: DefragArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    if args.resume {
+        sp.resume_defrag()?;
+    } else if args.rollback {
+        sp.rollback_defrag()?;
+    } else {
+        if let Some(name) = sp.pending_relocation() {
+            let pending = format!("relocation of {} was interrupted; use --resume or --rollback", name);
+            return Err(MapperError::InvalidArgument(pending).into());
+        }
+        for name in sp.defrag()? {
+            println!("Relocated {}", name);
+        }
+    }
+    Ok(())
+}
+
+fn frag(args: DeviceArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let report = sp.fragmentation();
+    let bs = sp.block_size();
+
+    println!("{:<24} {:>8}", "SUBVOL", "EXTENTS");
+    for sv in &report.subvols {
+        println!("{:<24} {:>8}", sv.name, sv.extent_count);
+    }
+    println!();
+    println!("Free space: {} bytes in {} holes", report.free_blocks * bs, report.free_extents);
+    println!("Largest free extent: {} bytes", report.largest_free_extent * bs);
+    println!("Free space fragmentation: {:.0}%", report.free_fragmentation * 100.0);
+    if report.defrag_recommended() {
+        println!("Defrag recommended, would consolidate: {}", report.defrag_candidates.join(", "));
+    } else {
+        println!("Defrag would not help");
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct MapArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Characters in the bar drawn for each device
+    #[arg(long, default_value_t = 64)]
+    width: u64,
+}
 
-    SuperPartition::open(device).expect("open");
+/// Characters standing for owners on a map when neither case of the first
+/// letter of the name is still unused
+const MAP_KEYS: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+/// Each character of a bar shows what holds most of the blocks it stands
+/// for, so runs much shorter than that can be hidden by their neighbours
+fn map(args: MapArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    if json() {
+        print_json(&sp.export_extents());
+        return Ok(());
+    }
+    let regions = sp.regions();
+    let bs = sp.block_size();
+
+    let mut owners = BTreeMap::new();
+    for r in &regions {
+        *owners.entry(r.owner.as_deref()).or_insert(0) += r.block_length;
+    }
+    let mut keys: Vec<(Option<&str>, char, u64)> = vec![];
+    for (owner, blocks) in owners {
+        let key = match owner {
+            None => '.',
+            Some("metadata") => '#',
+            Some(name) => {
+                let unused = |c: &char| MAP_KEYS.contains(*c) && !keys.iter().any(|(_o, key, _blocks)| key == c);
+                let first = name.chars().next().unwrap_or('?');
+                [first.to_ascii_lowercase(), first.to_ascii_uppercase()].into_iter().find(unused)
+                    .or_else(|| MAP_KEYS.chars().find(unused))
+                    .unwrap_or('?')
+            }
+        };
+        keys.push((owner, key, blocks));
+    }
+    let key = |owner: Option<&str>| keys.iter().find(|(o, _key, _blocks)| *o == owner).map_or('?', |(_o, key, _blocks)| *key);
+
+    for (index, device) in sp.devices().enumerate() {
+        let regions: Vec<_> = regions.iter().filter(|r| r.device == index as u32).collect();
+        let total: u64 = regions.iter().map(|r| r.block_length).sum();
+        let width = args.width.clamp(1, total.max(1));
+        let bar: String = (0..width).map(|cell| {
+            let (start, end) = (cell * total / width, (cell + 1) * total / width);
+            regions.iter()
+                .map(|r| (r, (r.block_offset + r.block_length).min(end).saturating_sub(r.block_offset.max(start))))
+                .max_by_key(|(_r, overlap)| *overlap)
+                .map_or(' ', |(r, _overlap)| key(r.owner.as_deref()))
+        }).collect();
+        println!("{}: {} bytes, about {} per character", device, total * bs, total * bs / width);
+        println!("|{}|", bar);
+    }
+    println!();
+    for (owner, key, blocks) in &keys {
+        println!("{} {:<24} {:>16}", key, owner.unwrap_or("free"), blocks * bs);
+    }
+    Ok(())
 }
 
-fn create(mut args: Args) {
-    let device = args.next().expect("no device provided");
-    let name = args.next().expect("no name provided");
-    let size_bytes = args.next().expect("no size provided");
-    let size_bytes: u64 = size_bytes.parse().expect("size not a number");
+fn scan() -> Outcome {
+    for device in mercury_mapper::scan() {
+        println!("{} {}", device, mercury_mapper::probe_uuid(&device).unwrap_or_default());
+    }
+    Ok(())
+}
+
+fn dump(args: DeviceArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    println!("{}", sp.dump());
+    Ok(())
+}
 
-    let mut sp = SuperPartition::open(device).expect("open");
-    sp.create_subvol(name, size_bytes).expect("create");
-    sp.commit().expect("commit");
+#[derive(Args)]
+struct RestoreArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// File written by dump
+    dump: PathBuf,
 }
 
-fn delete(mut args: Args) {
-    let device = args.next().expect("no device provided");
-    let name = args.next().expect("no name provided");
+fn restore(args: RestoreArgs) -> Outcome {
+    let json = std::fs::read_to_string(args.dump).map_err(MapperError::from)?;
+    let restored = match dry_run() {
+        true => SuperPartition::restore_dry_run(args.device, &json),
+        false => SuperPartition::restore(args.device, &json),
+    };
+    // Printing the plan of a dry run
+    drop(Session(restored?));
+    Ok(())
+}
+
+fn rollback(args: DeviceArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    let from = sp.generation();
+    sp.rollback()?;
+    println!("Rolled back from generation {} to the previous layout", from);
+    Ok(())
+}
 
-    let mut sp = SuperPartition::open(device).expect("open");
-    if let Some(sv) = sp.subvols.get(&name) {
-        sp.delete_subvol(sv.clone()).expect("failed to delete");
-        sp.commit().expect("commit");
+#[derive(Args)]
+#[command(group(ArgGroup::new("action").args(["enable", "disable", "restore"])))]
+struct HistoryArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Keep this many earlier generations
+    #[arg(long, value_name = "ENTRIES")]
+    enable: Option<u64>,
+    /// Stop keeping earlier generations
+    #[arg(long)]
+    disable: bool,
+    /// Go back to an earlier generation
+    #[arg(long, value_name = "GENERATION")]
+    restore: Option<u32>,
+}
+
+fn history(args: HistoryArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    if let Some(entries) = args.enable {
+        sp.enable_history(entries)?;
+    } else if args.disable {
+        sp.disable_history()?;
+    } else if let Some(generation) = args.restore {
+        sp.restore_generation(generation)?;
     } else {
-        eprintln!("No such subvolume");
+        println!("{:>10} {:>12} {:>8}", "GENERATION", "COMMITTED", "SUBVOLS");
+        for entry in sp.history()? {
+            println!("{:>10} {:>12} {:>8}", entry.generation, entry.committed_at, entry.subvol_count);
+        }
+    }
+    Ok(())
+}
+
+fn list(args: DeviceArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    if json() {
+        print_json(&sp.subvols().collect::<Vec<_>>());
+        return Ok(());
+    }
+
+    println!("{:<24} {:<36} {:>16} {:>8} {:<16} {:<16} TIMEDATE", "NAME", "UUID", "SIZE", "EXTENTS", "VERSION", "AUTHOR");
+    for sv in sp.subvols() {
+        println!("{:<24} {:<36} {:>16} {:>8} {:<16} {:<16} {}", sv.name, sv.uuid, sv.size, sv.extent_count,
+            sv.version, sv.author, sv.timedate);
     }
+    Ok(())
 }
 
-pub fn main () {
-    let mut args = env::args();
-    let _argv0 = args.next().unwrap();
-    let command = args.next().expect("no command provided");
+#[derive(Args)]
+struct SetVersionArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    version: String,
+    #[arg(long)]
+    author: Option<String>,
+    #[arg(long)]
+    timedate: Option<String>,
+}
 
-    match command.as_ref() {
-        "adopt" => adopt(args),
-        "open" => open(args),
-        "create" => create(args),
-        "delete" => delete(args),
-        _ => eprintln!("Unknown command: {}", command)
+fn set_version(args: SetVersionArgs) -> Outcome {
+    let name = args.name;
+    let mut sp = open_device(args.device)?;
+    sp.set_version(&name, &args.version)?;
+    if let Some(author) = args.author {
+        sp.set_author(&name, &author)?;
+    }
+    if let Some(timedate) = args.timedate {
+        sp.set_timedate(&name, &timedate)?;
+    }
+    Ok(())
+}
+
+fn info(args: SubvolArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let info = sp.subvol_info(&args.name).ok_or_else(|| MapperError::NotFound(args.name.clone()))?;
+    if json() {
+        print_json(&info);
+        return Ok(());
+    }
+    println!("{:<13}{}", "Name:", info.name);
+    println!("{:<13}{}", "UUID:", info.uuid);
+    println!("{:<13}{}", "Size:", info.size);
+    println!("{:<13}{}", "Extents:", info.extent_count);
+    println!("{:<13}{}", "Version:", info.version);
+    println!("{:<13}{}", "Author:", info.author);
+    println!("{:<13}{}", "Timedate:", info.timedate);
+    println!("{:<13}{}", "Read-only:", if info.read_only { "yes" } else { "no" });
+    println!("{:<13}{}", "Protected:", if info.protected { "yes" } else { "no" });
+    if info.external {
+        println!("{:<13}yes", "External:");
+    }
+    if let Some(origin) = info.snapshot_of {
+        println!("{:<13}{}", "Snapshot of:", origin);
+    }
+    if let Some(digest) = info.digest {
+        println!("{:<13}{} ({} bytes)", "SHA-256:", digest.sha256, digest.length);
     }
-}
\ No newline at end of file
+    if let Some(fstype) = info.filesystem {
+        println!("{:<13}{}", "Filesystem:", fstype);
+    }
+    if let Some(type_guid) = info.type_guid {
+        println!("{:<13}{}", "Type GUID:", type_guid);
+    }
+    if let Some(mount) = info.mount {
+        let options = if mount.options.is_empty() { String::new() } else { format!(" ({})", mount.options) };
+        println!("{:<13}{} {}{}{}", "Mount:", mount.path, mount.fstype, options,
+            if mount.automount { ", on access" } else { "" });
+    }
+    if let Some(written) = sp.interrupted_write(info.name) {
+        println!("{:<13}write stopped after {} bytes", "Interrupted:", written);
+    }
+    let mut tags: Vec<_> = info.tags.iter().collect();
+    tags.sort();
+    for (key, value) in tags {
+        println!("{:<13}{}={}", "Tag:", key, value);
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Switch {
+    On,
+    Off,
+}
+
+#[derive(Args)]
+struct SwitchArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    state: Switch,
+}
+
+fn set_read_only(args: SwitchArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.set_read_only(&args.name, args.state == Switch::On)?;
+    Ok(())
+}
+
+fn set_protected(args: SwitchArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.set_protected(&args.name, args.state == Switch::On)?;
+    Ok(())
+}
+
+#[derive(Clone)]
+enum TagChange {
+    Set(String, String),
+    Remove(String),
+}
+
+fn parse_tag_change(s: &str) -> Result<TagChange, String> {
+    match s.split_once('=') {
+        Some((key, value)) => Ok(TagChange::Set(key.to_string(), value.to_string())),
+        None => match s.strip_prefix('-') {
+            Some(key) => Ok(TagChange::Remove(key.to_string())),
+            None => Err("expected KEY=VALUE or -KEY".to_string()),
+        },
+    }
+}
+
+#[derive(Args)]
+struct TagArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Tags to set, or to remove; with none the tags are listed
+    #[arg(value_name = "KEY=VALUE|-KEY", allow_hyphen_values = true, value_parser = parse_tag_change)]
+    changes: Vec<TagChange>,
+}
+
+fn tag(args: TagArgs) -> Outcome {
+    let name = args.name;
+    if args.changes.is_empty() {
+        let sp = SuperPartition::open_readonly(args.device)?;
+        let info = sp.subvol_info(&name).ok_or_else(|| MapperError::NotFound(name.clone()))?;
+        let mut tags: Vec<_> = info.tags.iter().collect();
+        tags.sort();
+        for (key, value) in tags {
+            println!("{}={}", key, value);
+        }
+        return Ok(());
+    }
+
+    let mut sp = open_device(args.device)?;
+    for change in args.changes {
+        match change {
+            TagChange::Set(key, value) => sp.set_tag(&name, &key, &value)?,
+            TagChange::Remove(key) => {
+                sp.remove_tag(&name, &key)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn udev_rules(args: DeviceArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    print!("{}", sp.udev_rules());
+    Ok(())
+}
+
+#[derive(Args)]
+struct SetNameArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Short name, used for the directory of device links
+    name: String,
+}
+
+fn set_name(args: SetNameArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.set_name(&args.name)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct SetAccessArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    #[arg(long)]
+    owner: Option<String>,
+    #[arg(long)]
+    group: Option<String>,
+    /// Permissions, in octal
+    #[arg(long, value_parser = parse_mode)]
+    mode: Option<u32>,
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|e| format!("not an octal number: {}", e))
+}
+
+fn set_access(args: SetAccessArgs) -> Outcome {
+    let access = NodeAccess {
+        owner: args.owner,
+        group: args.group,
+        mode: args.mode,
+    };
+
+    let mut sp = open_device(args.device)?;
+    sp.set_node_access(&args.name, access)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct SetMountArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Absolute path to mount it on; leave out to clear the mount point
+    path: Option<String>,
+    /// Filesystem type, if not the one recorded for the subvolume
+    #[arg(requires = "path")]
+    fstype: Option<String>,
+    /// Comma-separated mount options
+    #[arg(long, default_value = "")]
+    options: String,
+    /// Mount it when first accessed rather than at boot
+    #[arg(long)]
+    automount: bool,
+}
+
+fn set_mount(args: SetMountArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    let mount = match args.path {
+        Some(path) => {
+            let fstype = args.fstype
+                .or_else(|| sp.subvol_info(&args.name)?.filesystem.map(str::to_string))
+                .ok_or_else(|| MapperError::InvalidArgument(format!("no filesystem type given or recorded for {}", args.name)))?;
+            Some(MountPoint { path, fstype, options: args.options, automount: args.automount })
+        }
+        None => None,
+    };
+    sp.set_mount_point(&args.name, mount)?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct SetTypeGuidArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Partition type GUID; leave out to go back to Linux filesystem data
+    guid: Option<String>,
+}
+
+fn set_type_guid(args: SetTypeGuidArgs) -> Outcome {
+    let mut sp = open_device(args.device)?;
+    sp.set_type_guid(&args.name, args.guid.as_deref())?;
+    Ok(())
+}
+
+#[derive(Args)]
+struct GenUnitsArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Print fstab lines rather than units
+    #[arg(long)]
+    fstab: bool,
+    /// Write the units to this directory, such as /etc/systemd/system,
+    /// rather than printing them
+    #[arg(long, value_name = "DIR", conflicts_with = "fstab")]
+    output: Option<PathBuf>,
+}
+
+fn gen_units(args: GenUnitsArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    if args.fstab {
+        print!("{}", sp.fstab());
+        return Ok(());
+    }
+    let units = sp.mount_units();
+    let Some(dir) = args.output else {
+        for unit in &units {
+            println!("==> {} <==", unit.name);
+            print!("{}", unit.contents);
+        }
+        return Ok(());
+    };
+    for unit in &units {
+        let path = dir.join(&unit.name);
+        fs::write(&path, &unit.contents).map_err(MapperError::from)?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct MountArgs {
+    /// Block device holding the super partition
+    device: String,
+    /// Subvolume name
+    name: String,
+    /// Directory to mount it on
+    mountpoint: PathBuf,
+    /// Filesystem type, if not the one found on the subvolume
+    #[arg(long = "type", short = 't')]
+    fstype: Option<String>,
+    /// Comma-separated mount options
+    #[arg(long, short = 'o')]
+    options: Option<String>,
+}
+
+fn mount(args: MountArgs) -> Outcome {
+    let sp = open_device_inactive(args.device)?;
+    let info = sp.subvol_info(&args.name)
+        .ok_or_else(|| MapperError::NotFound(args.name.clone()))?;
+    let recorded = info.filesystem.map(str::to_string);
+    let read_only = info.read_only;
+    if dry_run() {
+        return Ok(());
+    }
+    // Detecting activates it if need be, so it is done even with a type given
+    let detected = sp.detect_filesystem(&args.name)?;
+    let path = sp.wait_for_subvol(&args.name, Duration::from_secs(10))?;
+    let fstype = args.fstype
+        .or_else(|| detected.map(str::to_string))
+        .or(recorded)
+        .ok_or_else(|| MapperError::InvalidArgument(format!("no filesystem found on {}", args.name)))?;
+
+    let mut options: Vec<&str> = args.options.iter().map(String::as_str).collect();
+    if read_only {
+        options.push("ro");
+    }
+    let mut command = process::Command::new("mount");
+    command.arg("-t").arg(&fstype);
+    if !options.is_empty() {
+        command.arg("-o").arg(options.join(","));
+    }
+    let status = command.arg(&path).arg(&args.mountpoint).status().map_err(MapperError::from)?;
+    if !status.success() {
+        return Err(MapperError::from(io::Error::other(
+            format!("mount {} on {} failed: {}", path.display(), args.mountpoint.display(), status))).into());
+    }
+    println!("Mounted {} ({}) on {}", args.name, fstype, args.mountpoint.display());
+    Ok(())
+}
+
+fn usage(args: DeviceArgs) -> Outcome {
+    let sp = SuperPartition::open_readonly(args.device)?;
+    let usage = sp.usage();
+    if json() {
+        print_json(&usage);
+        return Ok(());
+    }
+    let bs = usage.block_size;
+    let used = usage.total_blocks - usage.free_blocks;
+
+    println!("{:<24} {:>16} {:>16} {:>16} {:>5}", "DEVICE", "SIZE", "USED", "FREE", "USE%");
+    println!("{:<24} {:>16} {:>16} {:>16} {:>4}%", sp.device(), usage.total_blocks * bs,
+        used * bs, usage.free_blocks * bs, used * 100 / usage.total_blocks.max(1));
+    println!("Largest free extent: {} bytes", usage.largest_free_extent * bs);
+    println!();
+
+    println!("{:<24} {:>16} {:>5}", "SUBVOL", "SIZE", "USE%");
+    for sv in &usage.subvols {
+        println!("{:<24} {:>16} {:>4}%", sv.name, sv.blocks * bs, sv.blocks * 100 / usage.total_blocks.max(1));
+    }
+    Ok(())
+}
+
+pub fn main () -> ExitCode {
+    let cli = Cli::parse();
+    DRY_RUN.store(cli.dry_run, Ordering::Relaxed);
+    if cli.simulate {
+        if let Err(e) = start_script() {
+            eprintln!("hgmap: {}", e);
+            return ExitCode::from(Exit::from(&e) as u8);
+        }
+    }
+    JSON.store(cli.json, Ordering::Relaxed);
+
+    let outcome = match cli.command {
+        Command::Adopt(args) => adopt(args),
+        Command::ExportGpt(args) => export_gpt(args),
+        Command::HybridGpt(args) => hybrid_gpt(args),
+        Command::Format(args) => format(args),
+        Command::Open(args) => open(args),
+        Command::Check(args) => check(args),
+        Command::Audit(args) => audit(args),
+        Command::Trim(args) => trim(args),
+        Command::Apply(args) => apply(args),
+        Command::Diff(args) => diff(args),
+        Command::Activate(args) => activate(args),
+        Command::Deactivate(args) => deactivate(args),
+        Command::Down(args) => down(args),
+        Command::Migrate(args) => migrate(args),
+        Command::SetEncoding(args) => set_encoding(args),
+        Command::SetMetadataBlocks(args) => set_metadata_blocks(args),
+        Command::SetDmPrefix(args) => set_dm_prefix(args),
+        Command::AddDevice(args) => add_device(args),
+        Command::Grow(args) => grow(args),
+        Command::Shrink(args) => shrink(args),
+        Command::Evacuate(args) => evacuate(args),
+        Command::RemoveDevice(args) => remove_device(args),
+        Command::Create(args) => create(args),
+        Command::CreateAt(args) => create_at(args),
+        Command::RegisterExternal(args) => register_external(args),
+        Command::Delete(args) => delete(args),
+        Command::Resize(args) => resize(args),
+        Command::Batch(args) => batch(args),
+        Command::Rename(args) => rename(args),
+        Command::Write(args) => write(args),
+        Command::Read(args) => read(args),
+        Command::Hash(args) => hash(args),
+        Command::Verify(args) => verify(args),
+        Command::ApplyUpdate(args) => apply_update(args),
+        Command::Slot(args) => slot(args),
+        Command::BootAttempt(args) => boot_attempt(args),
+        Command::MarkBootSuccessful(args) => mark_boot_successful(args),
+        Command::Bootargs(args) => bootargs(args),
+        Command::Table(args) => table(args),
+        Command::Initramfs(args) => initramfs(args),
+        Command::Snapshot(args) => snapshot(args),
+        Command::Clone(args) => clone(args),
+        Command::Copy(args) => copy(args),
+        Command::CreateCrypt(args) => create_crypt(args),
+        Command::CreateIntegrity(args) => create_integrity(args),
+        Command::CreateMirror(args) => create_mirror(args),
+        Command::MirrorStatus(args) => mirror_status(args),
+        Command::CreateVerity(args) => create_verity(args),
+        Command::VerityFormat(args) => verity_format(args),
+        Command::CreatePool(args) => create_pool(args),
+        Command::CreateThin(args) => create_thin(args),
+        Command::Defrag(args) => defrag(args),
+        Command::Frag(args) => frag(args),
+        Command::Map(args) => map(args),
+        Command::Scan => scan(),
+        Command::Dump(args) => dump(args),
+        Command::Restore(args) => restore(args),
+        Command::Rollback(args) => rollback(args),
+        Command::History(args) => history(args),
+        Command::List(args) => list(args),
+        Command::Usage(args) => usage(args),
+        Command::Info(args) => info(args),
+        Command::SetVersion(args) => set_version(args),
+        Command::Tag(args) => tag(args),
+        Command::SetReadOnly(args) => set_read_only(args),
+        Command::SetProtected(args) => set_protected(args),
+        Command::UdevRules(args) => udev_rules(args),
+        Command::SetName(args) => set_name(args),
+        Command::SetAccess(args) => set_access(args),
+        Command::SetMount(args) => set_mount(args),
+        Command::SetTypeGuid(args) => set_type_guid(args),
+        Command::Mount(args) => mount(args),
+        Command::GenUnits(args) => gen_units(args),
+    };
+    let exit = match outcome {
+        Ok(()) => return ExitCode::SUCCESS,
+        Err(Failure::Problems) => Exit::Problems,
+        Err(Failure::Error(e)) => {
+            eprintln!("hgmap: {}", e);
+            Exit::from(&e)
+        }
+    };
+    ExitCode::from(exit as u8)
+}
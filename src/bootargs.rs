@@ -0,0 +1,55 @@
+//! Kernel command lines that map subvolumes without userspace.
+//!
+//! With `dm-mod.create=`, the kernel sets up DM devices itself before it
+//! mounts the root filesystem, so a root on a subvolume needs no
+//! initramfs.  Each device is given as its name, UUID, minor, flags and
+//! table, with devices separated by semicolons and table lines by commas.
+//! Member devices appear in the tables by device number, as they are on
+//! the running system, so the command line has to be generated again if
+//! they could be numbered differently at boot.
+
+use crate::dm::table_lines;
+use crate::{is_reserved, MapperError, SuperPartition};
+
+impl SuperPartition {
+    /// The `dm-mod.create=` parameter mapping subvolumes `names`, or every
+    /// plain subvolume if none are given.  The base name of an A/B pair
+    /// stands for its subvolume in the active slot.  Only plain
+    /// subvolumes can be mapped this way, since the kernel has no keys
+    /// for encryption and stacked devices would have to refer to each
+    /// other by number.
+    pub fn bootargs(&self, names: &[&str]) -> Result<String, MapperError> {
+        let mut names: Vec<String> = names.iter()
+            .map(|name| match !self.subvols.contains_key(*name) && self.slotted().contains(name) {
+                true => self.active_slot().subvol(name),
+                false => name.to_string(),
+            })
+            .collect();
+        if names.is_empty() {
+            names = self.subvols.iter()
+                .filter(|(name, sv)| !is_reserved(name) && sv.is_plain() && !self.has_snapshots(name))
+                .map(|(name, _sv)| name.clone())
+                .collect();
+            names.sort();
+        }
+
+        let mut devices = vec![];
+        for name in &names {
+            self.check_not_reserved(name)?;
+            let sv = self.subvols.get(name)
+                .ok_or_else(|| MapperError::NotFound(name.clone()))?;
+            if !sv.is_plain() || self.has_snapshots(name) {
+                return Err(MapperError::InvalidArgument(format!("{} is not a plain subvolume", name)));
+            }
+            let device = self.layer_name(name, None);
+            if device.contains([',', ';', '"']) {
+                return Err(MapperError::InvalidArgument(format!("{} cannot be given on a kernel command line", device)));
+            }
+            let uuid = self.layer_uuid(name, None).unwrap_or_default();
+            let flags = if sv.read_only { "ro" } else { "rw" };
+            let table = table_lines(&self.linear_table(&sv.extents, true)?).join(",");
+            devices.push(format!("{},{},,{},{}", device, uuid, flags, table));
+        }
+        Ok(format!("dm-mod.create=\"{}\"", devices.join(";")))
+    }
+}
@@ -0,0 +1,173 @@
+//! Growing and shrinking the super partition along with its device.
+//!
+//! Without a label the metadata replicas are looked for in the last blocks
+//! of the device, so once the device has grown they are no longer where
+//! anything expects them.  `grow` finds them by scanning back from the new
+//! end for the signature that ends every metadata block, writes both
+//! replicas afresh in the new last blocks and wipes the old ones, leaving
+//! everything in between free.  With a label the replicas stay where the
+//! label says, and the new space is usable as soon as the device has
+//! grown.
+//!
+//! Shrinking goes the other way round: the replicas are moved in to the
+//! new end first, provided nothing is allocated beyond it, and the device
+//! can be made smaller afterwards.  Until it is, the metadata is not at
+//! the end of the device, and `grow` is what finds it again.
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::probe::{has_signature, SIGNATURE_SIZE};
+use crate::{get_io_size, label, lock_device, slots, MapperError, SuperPartition, DEFAULT_IO_SIZE, METADATA_SUBVOL};
+
+/// The newest metadata in the two slots that end at block `end`
+fn replicas_ending_at(f: &mut File, end: u64, iosize: u64) -> Result<Option<SuperPartition>, MapperError> {
+    let count = slots::probe_slot_blocks(f, end, iosize)?;
+    if end < 2 * count {
+        return Ok(None);
+    }
+    let newest = [end - count, end - 2 * count].into_iter()
+        .filter_map(|start| slots::read_replica(f, start, count, iosize).ok())
+        .filter(|meta| meta.iosize == iosize)
+        .max_by_key(|meta| meta.generation);
+    Ok(newest)
+}
+
+/// Metadata left behind at the old end of a device that has grown
+fn find_stranded(f: &mut File, device: &str) -> Result<SuperPartition, MapperError> {
+    let size = f.seek(SeekFrom::End(0))?;
+    let mut candidates = vec![get_io_size(device)?];
+    if candidates[0] != DEFAULT_IO_SIZE {
+        candidates.push(DEFAULT_IO_SIZE);
+    }
+    for iosize in candidates {
+        let mut tail = [0; SIGNATURE_SIZE];
+        for end in (2..size / iosize).rev() {
+            f.seek(SeekFrom::Start(end * iosize - SIGNATURE_SIZE as u64))?;
+            f.read_exact(&mut tail)?;
+            if !has_signature(&tail) {
+                continue;
+            }
+            if let Some(meta) = replicas_ending_at(f, end, iosize)? {
+                return Ok(meta);
+            }
+        }
+    }
+    Err(MapperError::NoMetadata)
+}
+
+impl SuperPartition {
+    /// Open the super partition on `device` once the device has grown,
+    /// moving the metadata replicas to its new end so the space gained can
+    /// be allocated.  Subvolumes are left inactive as by `open_inactive`.
+    /// A device that hasn't grown, or has a label, is just opened; with
+    /// `dry_run` the moves are planned and nothing is written.
+    pub fn grow(device: String, dry_run: bool) -> Result<Self, MapperError> {
+        let lock = lock_device(&device, true)?;
+        let mut f = File::open(&device)?;
+        if label::read_label(&mut f)?.is_some() || Self::load_unchecked(device.clone()).is_ok() {
+            return match dry_run {
+                true => Self::open_dry_run(device),
+                false => Self::open_unactivated(device, lock),
+            };
+        }
+
+        let mut meta = find_stranded(&mut f, &device)?;
+        let old_metadata = meta.subvols[METADATA_SUBVOL].extents.clone();
+        meta.bind_devices(device, None)?;
+        let new_metadata = meta.metadata_regions();
+        meta.subvols.get_mut(METADATA_SUBVOL).expect("metadata entry").extents = new_metadata.clone();
+        meta.check_extents()?;
+        meta.lock = Some(lock);
+        if dry_run {
+            meta.start_plan();
+        }
+
+        // Both replicas are in place before the old ones go, and the old
+        // ones are only wiped where the new ones don't overlap them
+        meta.write_replica(1)?;
+        meta.write_replica(2)?;
+        let new_start = new_metadata[0].block_offset;
+        for e in old_metadata.iter().filter(|e| e.block_offset < new_start) {
+            let end = (e.block_offset + e.block_length).min(new_start);
+            meta.zero_range(0, e.block_offset * meta.iosize, (end - e.block_offset) * meta.iosize)?;
+        }
+        meta.refresh_shadow_gpt()?;
+        meta.recover_intent()?;
+        Ok(meta)
+    }
+
+    /// Move the metadata replicas so that the super partition ends
+    /// `new_size` bytes into its device, ready for the device to be
+    /// shrunk to that.  Fails with `ShrinkBlocked`, naming what is in the
+    /// way, if anything is allocated past the new end.  Nothing more can
+    /// be committed afterwards; open it again once the device is smaller.
+    pub fn shrink(&mut self, new_size: u64) -> Result<(), MapperError> {
+        self.check_writable()?;
+        let new_blocks = new_size / self.iosize;
+        if new_blocks >= self.device_blocks[0] {
+            return Err(MapperError::InvalidArgument(format!("{} bytes is not smaller than the device", new_size)));
+        }
+        let limit = match &self.label {
+            // Replicas placed by a label can't move, so they have to fit
+            Some(label) => {
+                let end = label.slots.iter().map(|start| start + label.slot_blocks).max().expect("two slots");
+                if end > new_blocks {
+                    return Err(MapperError::InvalidArgument(format!("the label puts a replica past {} bytes", new_size)));
+                }
+                new_blocks
+            }
+            None => new_blocks.checked_sub(2 * self.metadata_blocks)
+                .ok_or_else(|| MapperError::InvalidArgument(format!("{} bytes leaves no room for the metadata", new_size)))?,
+        };
+
+        let in_the_way: Vec<_> = self.owned_extents().into_iter()
+            .filter(|(owner, e)| owner != METADATA_SUBVOL && e.device == 0)
+            .collect();
+        let highest = in_the_way.iter().map(|(_owner, e)| e.block_offset + e.block_length).max().unwrap_or(0);
+        if highest > limit {
+            let mut blockers: Vec<_> = in_the_way.into_iter()
+                .filter(|(_owner, e)| e.block_offset + e.block_length > limit)
+                .map(|(owner, _e)| owner)
+                .collect();
+            blockers.sort();
+            blockers.dedup();
+            return Err(MapperError::ShrinkBlocked {
+                size: new_size,
+                needed: (highest + new_blocks - limit) * self.iosize,
+                blockers,
+            });
+        }
+        if self.label.is_some() {
+            self.device_blocks[0] = new_blocks;
+            return self.refresh_shadow_gpt();
+        }
+
+        let old_metadata = self.subvols[METADATA_SUBVOL].extents.clone();
+        self.device_blocks[0] = new_blocks;
+        let new_metadata = self.metadata_regions();
+        self.subvols.get_mut(METADATA_SUBVOL).expect("metadata entry").extents = new_metadata.clone();
+        self.write_replica(1)?;
+        self.write_replica(2)?;
+        let new_end = new_metadata[0].block_offset + new_metadata[0].block_length;
+        for e in &old_metadata {
+            let start = e.block_offset.max(new_end);
+            let end = e.block_offset + e.block_length;
+            if start < end {
+                self.zero_range(0, start * self.iosize, (end - start) * self.iosize)?;
+            }
+        }
+        self.refresh_shadow_gpt()?;
+        self.read_only = true;
+        Ok(())
+    }
+
+    /// Bring a shadow GPT in line with where the metadata is now, as the
+    /// replicas were moved without a commit
+    fn refresh_shadow_gpt(&self) -> Result<(), MapperError> {
+        match self.hybrid_gpt {
+            true => self.write_shadow_gpt(),
+            false => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Offline consistency checking, and repair of what can be repaired.
+//!
+//! The check reads the metadata the way `open` would, but carries on
+//! past problems that would make `open` refuse it, collecting everything
+//! wrong with the replicas, extents, names and bookkeeping.  Each kind of
+//! repair is asked for separately, since some of them give up data: an
+//! extent that collides with something else is dropped from its
+//! subvolume, along with every extent after it, so what remains still
+//! lines up with the start of the subvolume.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::validate::{intersection, ExtentProblem, HISTORY_OWNER, RELOCATION_OWNER};
+use crate::{is_reserved, lock_device, Extent, MapperError, ReplicaState, SuperPartition, METADATA_SUBVOL};
+
+/// One inconsistency found by `SuperPartition::check`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum Problem {
+    /// A replica is unreadable, or more than one generation behind the
+    /// other.  `slot` is 1 for the replica at the end of the device.
+    Replica { slot: u64, state: ReplicaState },
+    Extent(ExtentProblem),
+    /// A subvolume name that could not be created today
+    Name { name: String, reason: String },
+    /// A snapshot or thin volume whose origin or pool is missing
+    MissingDependency { name: String, depends_on: String },
+    /// The metadata entry does not reserve exactly the blocks holding the
+    /// metadata, so free space is miscounted
+    MetadataEntry,
+}
+
+/// Which problems `SuperPartition::check` should repair
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckRepairs {
+    /// Drop extents that overlap, cover the metadata or are off their
+    /// device, along with the rest of their subvolume beyond them
+    pub drop_bad_extents: bool,
+    /// Make the metadata entry cover the metadata blocks again
+    pub rebuild_metadata_entry: bool,
+    /// Rewrite a corrupt or stale replica
+    pub resync_replicas: bool,
+}
+
+/// Outcome of `SuperPartition::check`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CheckReport {
+    /// Generation that was checked
+    pub generation: u32,
+    /// Problems that remain
+    pub problems: Vec<Problem>,
+    /// Problems that were found and repaired
+    pub repaired: Vec<Problem>,
+}
+
+impl CheckRepairs {
+    /// Every repair
+    pub fn all() -> Self {
+        CheckRepairs {
+            drop_bad_extents: true,
+            rebuild_metadata_entry: true,
+            resync_replicas: true,
+        }
+    }
+
+    fn any(&self) -> bool {
+        self.drop_bad_extents || self.rebuild_metadata_entry || self.resync_replicas
+    }
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Replica { slot, state: ReplicaState::Corrupt } => {
+                write!(f, "metadata replica {} is corrupt", slot)
+            }
+            Problem::Replica { slot, state: ReplicaState::Stale { generation } } => {
+                write!(f, "metadata replica {} is stale at generation {}", slot, generation)
+            }
+            Problem::Extent(problem) => write!(f, "{}", problem),
+            Problem::Name { name, reason } => write!(f, "name {:?} is invalid: {}", name, reason),
+            Problem::MissingDependency { name, depends_on } => {
+                write!(f, "{} depends on {}, which does not exist", name, depends_on)
+            }
+            Problem::MetadataEntry => write!(f, "the {} entry does not match the metadata blocks", METADATA_SUBVOL),
+        }
+    }
+}
+
+impl SuperPartition {
+    /// Check the super partition on `device` for consistency, making the
+    /// repairs asked for in `repairs`.  Without repairs nothing is written
+    /// and no lock is taken.  DM devices are left alone either way, so
+    /// subvolumes losing extents should be inactive.
+    pub fn check(device: String, repairs: &CheckRepairs) -> Result<CheckReport, MapperError> {
+        let lock = match repairs.any() {
+            true => Some(lock_device(&device, true)?),
+            false => None,
+        };
+        let mut sp = Self::load_unchecked(device)?;
+        sp.on_disk = Some(sp.generation);
+        sp.read_only = lock.is_none();
+        sp.lock = lock;
+
+        let found = sp.problems()?;
+        if !repairs.any() {
+            return Ok(CheckReport {
+                generation: sp.generation,
+                problems: found,
+                repaired: vec![],
+            });
+        }
+
+        let mut changed = false;
+        if repairs.drop_bad_extents {
+            changed |= sp.drop_bad_extents(&found);
+        }
+        if repairs.rebuild_metadata_entry && found.contains(&Problem::MetadataEntry) {
+            sp.rebuild_metadata_entry();
+            changed = true;
+        }
+        if changed {
+            // Lands in the corrupt replica if there is one
+            sp.commit()?;
+        }
+        if repairs.resync_replicas && sp.problems()?.iter().any(|p| matches!(p, Problem::Replica { .. })) {
+            sp.repair_replicas()?;
+        }
+
+        let problems = sp.problems()?;
+        let repaired = found.into_iter().filter(|p| !problems.contains(p)).collect();
+        Ok(CheckReport {
+            generation: sp.generation,
+            problems,
+            repaired,
+        })
+    }
+
+    /// Everything wrong with this layout and the replicas holding it
+    fn problems(&self) -> Result<Vec<Problem>, MapperError> {
+        let mut problems = vec![];
+
+        // Commits alternate between the replicas, so one a generation
+        // behind is how they are normally left
+        let states = self.with_store(false, |blockdev| self.replica_states(blockdev))?;
+        for (slot, state) in states.into_iter().enumerate() {
+            match state {
+                Some(ReplicaState::Stale { generation }) if generation + 1 >= self.generation => {}
+                Some(state) => problems.push(Problem::Replica { slot: slot as u64 + 1, state }),
+                None => {}
+            }
+        }
+
+        problems.extend(self.validate_extents().problems.into_iter().map(Problem::Extent));
+
+        let mut names: Vec<_> = self.subvols.keys().filter(|name| !is_reserved(name)).collect();
+        names.sort();
+        for name in names {
+            if let Some(reason) = self.name_problem(name) {
+                problems.push(Problem::Name { name: name.clone(), reason });
+            }
+            if let Some(dep) = self.subvols[name].depends_on().filter(|dep| !self.subvols.contains_key(*dep)) {
+                problems.push(Problem::MissingDependency { name: name.clone(), depends_on: dep.to_string() });
+            }
+        }
+
+        let mut entry = self.subvols.get(METADATA_SUBVOL).map(|sv| sv.extents.clone()).unwrap_or_default();
+        entry.sort();
+        if merge(entry) != merge(self.metadata_regions()) {
+            problems.push(Problem::MetadataEntry);
+        }
+        Ok(problems)
+    }
+
+    /// Cut each subvolume short at its first extent with a problem, and
+    /// drop bookkeeping whose extent has one.  Returns whether anything
+    /// changed.
+    fn drop_bad_extents(&mut self, found: &[Problem]) -> bool {
+        let mut bad: Vec<(&str, &Extent)> = vec![];
+        for problem in found {
+            match problem {
+                // The extent that sorts first keeps its blocks
+                Problem::Extent(ExtentProblem::Overlap { second, extent, .. }) => bad.push((second, extent)),
+                Problem::Extent(ExtentProblem::Metadata { owner, extent })
+                | Problem::Extent(ExtentProblem::OutOfBounds { owner, extent, .. })
+                | Problem::Extent(ExtentProblem::NoSuchDevice { owner, extent }) => bad.push((owner, extent)),
+                _ => {}
+            }
+        }
+
+        let mut cut: BTreeMap<String, usize> = BTreeMap::new();
+        let mut changed = false;
+        for (owner, extent) in bad {
+            if owner == HISTORY_OWNER {
+                changed |= self.history.take().is_some();
+                continue;
+            }
+            if owner.starts_with(RELOCATION_OWNER) {
+                // The old extents are still authoritative, so forgetting
+                // the relocation just abandons it
+                changed |= self.relocation.take().is_some();
+                continue;
+            }
+            // Only the subvolume's own data extents can be cut; the extra
+            // extents of pools, hash trees and mirror legs are left
+            let Some(sv) = self.subvols.get(owner) else { continue };
+            let Some(index) = sv.extents.iter().position(|e| intersection(e, extent).is_some()) else { continue };
+            let entry = cut.entry(owner.to_string()).or_insert(index);
+            *entry = (*entry).min(index);
+        }
+        for (name, index) in cut {
+            if let Some(sv) = self.subvols.get_mut(&name) {
+                sv.extents.truncate(index);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn rebuild_metadata_entry(&mut self) {
+        let regions = self.metadata_regions();
+        match self.subvols.get_mut(METADATA_SUBVOL) {
+            Some(sv) => sv.extents = regions,
+            None => {
+                let sv = crate::SubVolume::new(regions, self.iosize);
+                self.subvols.insert(METADATA_SUBVOL.to_string(), sv);
+            }
+        }
+    }
+}
+
+/// Sorted extents with adjacent ones joined up
+fn merge(extents: Vec<Extent>) -> Vec<Extent> {
+    let mut merged: Vec<Extent> = vec![];
+    for e in extents {
+        match merged.last_mut() {
+            Some(last) if last.device == e.device && last.block_offset + last.block_length == e.block_offset => {
+                last.block_length += e.block_length;
+            }
+            _ => merged.push(e),
+        }
+    }
+    merged
+}
@@ -0,0 +1,243 @@
+//! Full copies of subvolumes.
+//!
+//! A clone gets extents of its own, as many blocks as the original, and
+//! the original's data is copied across the member devices in large
+//! chunks, without going through device mapper.  An active original is
+//! flushed and suspended for the copy so that the clone is a consistent
+//! point-in-time copy, and resumed afterwards.  Nothing is recorded until
+//! the copy is complete, so a clone interrupted by a crash leaves only
+//! free space behind.
+//!
+//! A copy-on-write clone is ready at once instead: it is a snapshot of
+//! the original, sharing its data, with free space set aside for the
+//! chunks either side changes afterwards.  Being a snapshot it is rebuilt
+//! on top of the original by `open`, and becomes invalid if that space
+//! runs out.
+//!
+//! Copying to another super partition goes through the device nodes
+//! instead, so the copy gets the data as the subvolume reads, and is a
+//! plain subvolume whatever the original stacks on its extents.  Nothing
+//! stops the original from being written meanwhile, so it should be left
+//! alone, or better still read-only, until the copy is done.
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+
+use crate::image::NODE_TIMEOUT;
+use crate::{CreateOptions, Extent, MapperError, Step, SubVolume, SuperPartition, WriteOptions};
+
+/// Bytes copied at a time
+const COPY_CHUNK: u64 = 1 << 20;
+
+impl SuperPartition {
+    /// Create `dst` as a copy of the plain subvolume `src`, returning the
+    /// path its device node will have.  The descriptive fields, the
+    /// recorded filesystem, partition type and digest, and the node access
+    /// come along; the mount point and protection do not, so the two don't
+    /// fight over a directory and the copy can be thrown away freely.
+    pub fn clone_subvol(&mut self, src: &str, dst: &str) -> Result<PathBuf, MapperError> {
+        self.check_writable()?;
+        self.check_not_reserved(src)?;
+        let orig = self.subvols.get(src)
+            .ok_or_else(|| MapperError::NotFound(src.to_string()))?
+            .clone();
+        if !orig.is_plain() {
+            return Err(MapperError::InvalidArgument(format!("{} is not a plain subvol; only those can be cloned", src)));
+        }
+        if self.pending_relocation() == Some(src) {
+            return Err(MapperError::InvalidArgument(format!("{} is being relocated; resume or roll back defrag first", src)));
+        }
+
+        let options = CreateOptions {
+            contiguous: orig.contiguous,
+            alignment: orig.alignment,
+            ..CreateOptions::default()
+        };
+        let mut sv = self.new_subvol_with(dst, orig.size_bytes(), &options)?;
+        copy_fields(&mut sv, &orig);
+
+        self.quiesced(src, || self.copy_extents(&orig.extents, &sv.extents))?;
+
+        let path = self.subvol_path(dst);
+        self.insert_subvol(dst.to_string(), sv)?;
+        Ok(path)
+    }
+
+    /// Create `dst` as a copy-on-write clone of `src`, sharing its data
+    /// through a snapshot with `cow_size` bytes for changes to either.
+    /// The same fields come along as for `clone_subvol`.
+    pub fn cow_clone_subvol(&mut self, src: &str, dst: &str, cow_size: u64) -> Result<PathBuf, MapperError> {
+        self.snapshot_subvol_with(src, dst, cow_size, copy_fields)?;
+        Ok(self.subvol_path(dst))
+    }
+
+    /// Create `dst_name` in `dst` and copy subvolume `name` into it,
+    /// activating `name` if needed, and returning how many bytes were
+    /// copied.  The version, author, tags, recorded filesystem, partition
+    /// type and digest come along.  `options` is used as for
+    /// `write_image_with`, with the length and raw set; should the copy
+    /// fail, `dst_name` is deleted again.  Encrypted subvolumes are refused
+    /// rather than copied in the clear.
+    pub fn copy_subvol_to(&self, name: &str, dst: &mut SuperPartition, dst_name: &str, options: &mut WriteOptions)
+            -> Result<u64, MapperError> {
+        self.check_not_reserved(name)?;
+        self.check_not_external(name)?;
+        let orig = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        if orig.thin_pool.is_some() {
+            return Err(MapperError::InvalidArgument(format!("{} is a thin pool; copy its volumes instead", name)));
+        }
+        if orig.crypt.is_some() {
+            return Err(MapperError::InvalidArgument(format!("{} is encrypted and would be copied in the clear", name)));
+        }
+        // Device mapper would take the devices of one for the other's
+        let device = dst.layer_name(dst_name, None);
+        if self.subvols.keys().any(|other| self.layer_name(other, None) == device) {
+            return Err(MapperError::InvalidArgument(format!("{} is already a DM device here; pick another name or DM prefix", device)));
+        }
+        let length = orig.size_bytes();
+
+        // A dry run only needs the writes planned, not the data read
+        let source: Box<dyn Read> = match dst.is_dry_run() {
+            true => Box::new(io::repeat(0).take(length)),
+            false => {
+                if !self.dm_active(name)? {
+                    self.activate(name)?;
+                }
+                let node = File::open(self.wait_for_subvol(name, NODE_TIMEOUT)?)?;
+                posix_fadvise(node.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)
+                    .map_err(io::Error::from)?;
+                Box::new(node)
+            }
+        };
+
+        dst.create_subvol(dst_name.to_string(), length)?;
+        options.length = Some(length);
+        options.raw = true;
+        let copied = dst.write_image_with(dst_name, source, options).and_then(|copied| {
+            let sv = dst.subvol_mut(dst_name)?;
+            sv.version = orig.version.clone();
+            sv.author = orig.author.clone();
+            sv.tags = orig.tags.clone();
+            sv.filesystem = orig.filesystem.clone();
+            sv.type_guid = orig.type_guid.clone();
+            // A digest of the copy from verifying it is as good
+            if sv.digest.is_none() {
+                sv.digest = orig.digest.clone();
+            }
+            dst.commit()?;
+            Ok(copied)
+        });
+        if copied.is_err() {
+            let _ = dst.delete_subvol_by_name(dst_name);
+        }
+        copied
+    }
+
+    /// Run `f` with subvolume `name` flushed and suspended if it is
+    /// active, so that nothing changes its extents meanwhile
+    pub(crate) fn quiesced<T>(&self, name: &str, f: impl FnOnce() -> Result<T, MapperError>) -> Result<T, MapperError> {
+        let sv = &self.subvols[name];
+        let active = !self.is_dry_run() && self.dm_active(name)?;
+        if active {
+            File::open(self.subvol_path(name))?.sync_all()?;
+            self.suspend_dm(name, sv)?;
+        }
+        let result = f();
+        if active {
+            self.resume_dm(name, sv)?;
+        }
+        result
+    }
+
+    /// Copy the blocks mapped by `from` to those mapped by `to`, in order.
+    /// Both must cover the same number of blocks.
+    pub(crate) fn copy_extents(&self, from: &[Extent], to: &[Extent]) -> Result<(), MapperError> {
+        self.copy_extents_to(from, self, to, &mut |_copied| ())
+    }
+
+    /// Copy the data mapped by `from` here to what `to` maps in `dst`, in
+    /// order, calling `progress` with the bytes copied so far after each
+    /// chunk.  `to` must cover at least as many bytes as `from`; the
+    /// writes are planned if `dst` is a dry run.
+    pub(crate) fn copy_extents_to(&self, from: &[Extent], dst: &SuperPartition, to: &[Extent],
+            progress: &mut dyn FnMut(u64)) -> Result<(), MapperError> {
+        if dst.is_dry_run() {
+            for e in to {
+                dst.plan_step(|| Step::Write {
+                    path: dst.device_path(e.device).to_string(),
+                    offset: e.block_offset * dst.iosize,
+                    len: e.block_length * dst.iosize,
+                });
+            }
+            return Ok(());
+        }
+
+        let mut sources = HashMap::new();
+        let mut dests = HashMap::new();
+        for e in from {
+            if let Entry::Vacant(slot) = sources.entry(e.device) {
+                let file = File::open(self.device_path(e.device))?;
+                // Writes through device mapper bypass the member's page
+                // cache, so anything cached there may be stale
+                posix_fadvise(file.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)
+                    .map_err(io::Error::from)?;
+                slot.insert(file);
+            }
+        }
+        for e in to {
+            if let Entry::Vacant(slot) = dests.entry(e.device) {
+                slot.insert(OpenOptions::new().write(true).open(dst.device_path(e.device))?);
+            }
+        }
+
+        // Each side as device, byte offset and length, since the two block
+        // sizes can differ
+        let bytes = |e: &Extent, iosize: u64| (e.device, e.block_offset * iosize, e.block_length * iosize);
+        let mut dst_ranges = to.iter().map(|e| bytes(e, dst.iosize));
+        let mut d = dst_ranges.next();
+        let mut buf = vec![0; COPY_CHUNK as usize];
+        let mut copied = 0;
+        for (device, mut offset, mut length) in from.iter().map(|e| bytes(e, self.iosize)) {
+            while length > 0 {
+                let (dst_device, dst_offset, dst_length) = d.as_mut().expect("less space to copy to than from");
+                let n = length.min(*dst_length).min(COPY_CHUNK);
+                let buf = &mut buf[..n as usize];
+                sources[&device].read_exact_at(buf, offset)?;
+                dests[dst_device].write_all_at(buf, *dst_offset)?;
+
+                offset += n;
+                length -= n;
+                *dst_offset += n;
+                *dst_length -= n;
+                copied += n;
+                progress(copied);
+                if *dst_length == 0 {
+                    d = dst_ranges.next();
+                }
+            }
+        }
+        for file in dests.values() {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Carry what a clone keeps over from `orig` to `sv`
+fn copy_fields(sv: &mut SubVolume, orig: &SubVolume) {
+    sv.version = orig.version.clone();
+    sv.author = orig.author.clone();
+    sv.tags = orig.tags.clone();
+    sv.filesystem = orig.filesystem.clone();
+    sv.type_guid = orig.type_guid.clone();
+    sv.digest = orig.digest.clone();
+    sv.access = orig.access.clone();
+    sv.read_only = orig.read_only;
+}
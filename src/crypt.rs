@@ -0,0 +1,70 @@
+//! Encrypted subvolumes using dm-crypt.
+//!
+//! The subvolume's extents are mapped by `<name>-enc` as usual, holding
+//! ciphertext, and a crypt target stacked on top presents the plaintext
+//! under the subvolume's own name.  Only the cipher parameters and where to
+//! find the key are stored in the metadata, never the key itself.
+
+use std::fs::File;
+use std::io::Read;
+
+use serde::{Deserialize, Serialize};
+
+use crate::verity::hex;
+use crate::{MapperError, SuperPartition};
+
+pub(crate) const CRYPT_SUFFIX: &str = "enc";
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub struct CryptParams {
+    /// Cipher specification in dm-crypt syntax, e.g. "aes-xts-plain64"
+    pub cipher: String,
+    /// Key length in bytes
+    pub key_size: u32,
+    pub key: KeySource,
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySource {
+    /// A `logon` key with this description in the kernel keyring
+    Keyring(String),
+    /// A file holding the raw key bytes, read at activation time
+    KeyFile(String),
+}
+
+impl CryptParams {
+    /// AES-XTS with a 512-bit key, matching cryptsetup's LUKS2 default
+    pub fn new(key: KeySource) -> Self {
+        Self {
+            cipher: "aes-xts-plain64".to_string(),
+            key_size: 64,
+            key,
+        }
+    }
+
+    /// The key argument of a crypt table line
+    pub(crate) fn table_key(&self) -> Result<String, MapperError> {
+        match &self.key {
+            KeySource::Keyring(desc) => Ok(format!(":{}:logon:{}", self.key_size, desc)),
+            KeySource::KeyFile(path) => {
+                let mut key = vec![0; self.key_size as usize];
+                File::open(path)?.read_exact(&mut key)?;
+                Ok(hex(&key))
+            }
+        }
+    }
+}
+
+impl SuperPartition {
+    /// Create a subvolume of `size` bytes whose contents are encrypted
+    /// on the backing device
+    pub fn create_encrypted_subvol(&mut self, name: String, size: u64, crypt: CryptParams) -> Result<(), MapperError> {
+        // Fail early rather than after the metadata has been committed
+        crypt.table_key()?;
+
+        let mut sv = self.new_subvol(&name, size)?;
+        sv.crypt = Some(crypt);
+        self.insert_subvol(name, sv)
+    }
+}
@@ -0,0 +1,265 @@
+//! Consolidating fragmented subvolumes into single extents.
+//!
+//! A subvolume is relocated by copying its blocks into one free hole big
+//! enough for all of them, then switching its extents over.  Progress is
+//! kept in a relocation journal in the metadata, which also reserves the
+//! destination.  The old extents stay authoritative until the switch is
+//! committed, so after a crash the relocation can either be resumed from the
+//! last checkpoint or simply rolled back.  A subvolume with a relocation in
+//! progress is left inactive by `open` so nothing can modify it meanwhile.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use serde::{Deserialize, Serialize};
+
+use crate::{is_reserved, BestFit, CreateOptions, Extent, MapperError, Step, SubVolume, SuperPartition};
+
+/// Blocks copied between commits of the journal
+const CHECKPOINT_BLOCKS: u64 = 64;
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub(crate) struct Relocation {
+    pub(crate) subvol: String,
+    /// Where the subvolume's data is going
+    pub(crate) to: Extent,
+    /// Blocks known to have been copied
+    copied: u64,
+}
+
+/// How fragmented the subvolumes and free space are, as reported by
+/// `SuperPartition::fragmentation`
+#[derive(Serialize,Debug,Clone)]
+pub struct FragReport<'a> {
+    pub subvols: Vec<SubVolumeFrag<'a>>,
+    pub free_blocks: u64,
+    pub free_extents: usize,
+    pub largest_free_extent: u64,
+    /// Share of free space outside the largest hole, from 0 when it is all
+    /// in one piece towards 1 as it is scattered
+    pub free_fragmentation: f64,
+    /// Subvolumes that defrag could consolidate right now
+    pub defrag_candidates: Vec<&'a str>,
+}
+
+#[derive(Serialize,Debug,Clone)]
+pub struct SubVolumeFrag<'a> {
+    pub name: &'a str,
+    pub extent_count: usize,
+}
+
+impl FragReport<'_> {
+    /// Whether running defrag would reduce the number of extents
+    pub fn defrag_recommended(&self) -> bool {
+        !self.defrag_candidates.is_empty()
+    }
+}
+
+impl SuperPartition {
+    /// Report extent counts and free space fragmentation
+    pub fn fragmentation(&self) -> FragReport<'_> {
+        let mut subvols: Vec<_> = self.subvols.iter().map(|(name, sv)| {
+            SubVolumeFrag {
+                name,
+                extent_count: sv.extent_count(),
+            }
+        }).collect();
+        subvols.sort_by_key(|f| f.name);
+
+        let holes = self.free_extents();
+        let free_blocks: u64 = holes.iter().map(|h| h.block_length).sum();
+        let largest = holes.iter().map(|h| h.block_length).max().unwrap_or(0);
+        let free_fragmentation = match free_blocks {
+            0 => 0.0,
+            n => 1.0 - largest as f64 / n as f64,
+        };
+
+        let mut defrag_candidates: Vec<_> = self.subvols.iter()
+            .filter(|(name, sv)| !is_reserved(name) && sv.extent_count() > 1)
+            .filter(|(_name, sv)| sv.thin_pool.is_none() && sv.thin.is_none())
+            .filter(|(_name, sv)| sv.size_blocks() <= largest)
+            .map(|(name, _sv)| name.as_str())
+            .collect();
+        defrag_candidates.sort();
+
+        FragReport {
+            subvols,
+            free_blocks,
+            free_extents: holes.len(),
+            largest_free_extent: largest,
+            free_fragmentation,
+            defrag_candidates,
+        }
+    }
+
+    /// Subvolume with an interrupted relocation, which must be resumed or
+    /// rolled back before it can be used again
+    pub fn pending_relocation(&self) -> Option<&str> {
+        self.relocation.as_ref().map(|r| r.subvol.as_str())
+    }
+
+    /// Relocate every fragmented subvolume for which a large enough hole
+    /// exists.  Returns the names of the subvolumes that were moved.
+    pub fn defrag(&mut self) -> Result<Vec<String>, MapperError> {
+        if let Some(name) = self.pending_relocation() {
+            return Err(MapperError::InvalidArgument(format!("relocation of {} is pending", name)));
+        }
+
+        let mut names: Vec<_> = self.subvols.iter()
+            .filter(|(name, sv)| !is_reserved(name) && sv.extent_count() > 1)
+            .filter(|(_name, sv)| sv.thin_pool.is_none() && sv.thin.is_none())
+            .map(|(name, _sv)| name.clone())
+            .collect();
+        names.sort();
+
+        let mut moved = vec![];
+        for name in names {
+            if self.defrag_subvol(&name)? {
+                moved.push(name);
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Relocate `name` into a single extent, returning false if there is no
+    /// hole big enough
+    pub fn defrag_subvol(&mut self, name: &str) -> Result<bool, MapperError> {
+        if let Some(name) = self.pending_relocation() {
+            return Err(MapperError::InvalidArgument(format!("relocation of {} is pending", name)));
+        }
+        self.check_not_reserved(name)?;
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        if sv.thin_pool.is_some() || sv.thin.is_some() {
+            return Err(MapperError::InvalidArgument("cannot relocate thin pools or volumes".to_string()));
+        }
+        if sv.extent_count() <= 1 {
+            return Ok(true);
+        }
+
+        let options = CreateOptions {
+            strategy: &BestFit,
+            contiguous: true,
+            alignment: sv.alignment,
+            ..CreateOptions::default()
+        };
+        let to = match self.allocate_with(sv.size_blocks(), &options) {
+            Ok(mut extents) => extents.remove(0),
+            Err(MapperError::NoContiguousSpace { .. }) => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        self.relocation = Some(Relocation {
+            subvol: name.to_string(),
+            to,
+            copied: 0,
+        });
+        self.commit()?;
+        self.resume_defrag()?;
+        Ok(true)
+    }
+
+    /// Finish the pending relocation, copying whatever had not been
+    /// checkpointed yet
+    pub fn resume_defrag(&mut self) -> Result<(), MapperError> {
+        let reloc = self.relocation.clone()
+            .ok_or_else(|| MapperError::InvalidArgument("no relocation pending".to_string()))?;
+        let name = reloc.subvol.as_str();
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?
+            .clone();
+
+        // Hold off all I/O while the blocks are moved.  After a crash the
+        // subvolume was never activated, so there is nothing to suspend.
+        let active = self.dm_active(name)?;
+        if active {
+            self.suspend_dm(name, &sv)?;
+        }
+
+        let planned = self.plan_step(|| Step::Write {
+            path: self.device_path(reloc.to.device).to_string(),
+            offset: (reloc.to.block_offset + reloc.copied) * self.iosize,
+            len: (sv.size_blocks() - reloc.copied) * self.iosize,
+        });
+        if !planned {
+            if let Err(e) = self.copy_relocated(&reloc, &sv) {
+                // The old extents are still authoritative and the table
+                // still maps them, so the subvolume can carry on as it was
+                if active {
+                    let _ = self.resume_dm(name, &sv);
+                }
+                return Err(e);
+            }
+        }
+
+        let old = sv;
+        let sv = self.subvols.get_mut(name).expect("subvol vanished");
+        sv.extents = vec![reloc.to.clone()];
+        let sv = sv.clone();
+        let relocation = self.relocation.take();
+        if let Err(e) = self.commit() {
+            // Still the old layout on disk too, with the copy resumable
+            self.subvols.insert(name.to_string(), old.clone());
+            self.relocation = relocation;
+            if active {
+                let _ = self.resume_dm(name, &old);
+            }
+            return Err(e);
+        }
+
+        if active {
+            self.reload_dm(name, &sv)?;
+        } else {
+            self.create_dm(name, &sv)?;
+        }
+        Ok(())
+    }
+
+    /// Copy the blocks of `sv` that `reloc` has not checkpointed yet to
+    /// their new home, committing a checkpoint every so often
+    fn copy_relocated(&mut self, reloc: &Relocation, sv: &SubVolume) -> Result<(), MapperError> {
+        let mut dest = OpenOptions::new().write(true).open(self.device_path(reloc.to.device))?;
+        let mut buf = vec![0; self.iosize as usize];
+        let mut pos = 0;
+        for e in &sv.extents {
+            let mut src = File::open(self.device_path(e.device))?;
+            // Writes through device mapper bypass the member's page cache,
+            // so anything cached there may be stale
+            posix_fadvise(src.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)
+                .map_err(io::Error::from)?;
+            for block in 0..e.block_length {
+                if pos >= reloc.copied {
+                    src.seek(SeekFrom::Start((e.block_offset + block) * self.iosize))?;
+                    src.read_exact(&mut buf)?;
+                    dest.seek(SeekFrom::Start((reloc.to.block_offset + pos) * self.iosize))?;
+                    dest.write_all(&buf)?;
+                }
+                pos += 1;
+
+                if pos > reloc.copied && pos % CHECKPOINT_BLOCKS == 0 {
+                    dest.sync_all()?;
+                    self.relocation.as_mut().expect("relocation vanished").copied = pos;
+                    self.commit()?;
+                }
+            }
+        }
+        dest.sync_all()?;
+        Ok(())
+    }
+
+    /// Abandon the pending relocation, leaving the subvolume where it was
+    pub fn rollback_defrag(&mut self) -> Result<(), MapperError> {
+        let reloc = self.relocation.take()
+            .ok_or_else(|| MapperError::InvalidArgument("no relocation pending".to_string()))?;
+        self.commit()?;
+
+        if let Some(sv) = self.subvols.get(&reloc.subvol) {
+            if !self.dm_active(&reloc.subvol)? {
+                self.create_dm(&reloc.subvol, sv)?;
+            }
+        }
+        Ok(())
+    }
+}
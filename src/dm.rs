@@ -0,0 +1,623 @@
+//! Building and loading the device-mapper stacks behind subvolumes.
+//!
+//! A subvolume is activated as one or more DM devices.  The topmost one
+//! carries the subvolume's name behind the super partition's DM prefix;
+//! any devices beneath it add a suffix describing their role.  DM UUIDs
+//! start with `HGMAP-` and the super partition UUID, followed by the
+//! subvolume's UUID and the same suffix.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::crypt::{CryptParams, CRYPT_SUFFIX};
+use crate::integrity::{INTEGRITY_SUFFIX, JOURNAL_SECTORS, TAG_SIZE};
+use crate::mirror::{LEG_SUFFIXES, REGION_SECTORS};
+use crate::snapshot::{COW_SUFFIX, REAL_SUFFIX};
+use crate::thin::{TDATA_SUFFIX, TMETA_SUFFIX};
+use crate::verity::{VDATA_SUFFIX, VERITY_BLOCK_SIZE, VHASH_SUFFIX};
+use crate::{is_reserved, DmBackend, Extent, KeySource, MapperError, Step, SubVolume, SuperPartition};
+
+/// A DM table as start, length, target type and parameters for each
+/// target, in sectors
+pub type RawTable = Vec<(u64, u64, String, String)>;
+
+/// `table` as dmsetup would show it, one line per target
+pub(crate) fn table_lines(table: &RawTable) -> Vec<String> {
+    table.iter()
+        .map(|(start, length, target, params)| format!("{} {} {} {}", start, length, target, params))
+        .collect()
+}
+
+/// Where udev puts the nodes for DM devices, by name
+pub(crate) const DEV_MAPPER: &str = "/dev/mapper";
+
+/// How often to look for a device node while waiting for udev
+const NODE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Prefix for DM device names in newly created super partitions
+pub(crate) const DEFAULT_DM_PREFIX: &str = "hg-";
+
+/// Every suffix a device beneath the top of a stack can have
+const LAYER_SUFFIXES: [&str; 10] = [CRYPT_SUFFIX, INTEGRITY_SUFFIX, REAL_SUFFIX, COW_SUFFIX, TMETA_SUFFIX,
+    TDATA_SUFFIX, VDATA_SUFFIX, VHASH_SUFFIX, LEG_SUFFIXES[0], LEG_SUFFIXES[1]];
+
+/// Longest DM name the kernel accepts, without the trailing NUL
+const DM_NAME_MAX: usize = 127;
+
+/// Start of the UUID given to every DM device we create, so other tools
+/// can tell which ones are ours
+pub(crate) const DM_UUID_PREFIX: &str = "HGMAP-";
+
+/// What a single DM device in a subvolume's stack maps to
+pub(crate) enum Target {
+    /// Concatenation of extents on the backing device
+    Linear(Vec<Extent>),
+    /// Passes I/O through to `real`, copying chunks out to any snapshots
+    /// before they are overwritten
+    SnapshotOrigin { real: String, sectors: u64 },
+    /// Point-in-time view of `real` with changes kept in `cow`
+    Snapshot { real: String, cow: String, sectors: u64 },
+    /// Shared pool of blocks for thin volumes
+    ThinPool { metadata: String, data: String, block_sectors: u64, sectors: u64 },
+    /// Thinly provisioned volume `id` inside `pool`
+    Thin { pool: String, id: u32, sectors: u64 },
+    /// Per-sector checksums over `lower`, kept within `lower` itself
+    Integrity { lower: String, sectors: u64 },
+    /// Transparent encryption of `lower`
+    Crypt { params: CryptParams, lower: String, sectors: u64 },
+    /// Identical copies of the data on each of `legs`
+    Mirror { legs: Vec<String>, sectors: u64 },
+    /// Read-only view of `data`, checked against the hash tree in `hash`
+    Verity { data: String, hash: String, data_blocks: u64, root_hash: String, salt: String },
+}
+
+pub(crate) struct Layer {
+    /// None for the top of the stack
+    pub suffix: Option<&'static str>,
+    pub target: Target,
+}
+
+impl SuperPartition {
+    /// Name of the DM device for one layer of a subvolume's stack
+    pub(crate) fn layer_name(&self, name: &str, suffix: Option<&str>) -> String {
+        match suffix {
+            Some(suffix) => format!("{}{}-{}", self.dm_prefix, name, suffix),
+            None => format!("{}{}", self.dm_prefix, name),
+        }
+    }
+
+    /// Make sure `name` is free and would give DM names the kernel accepts
+    /// for every layer it could ever have
+    pub(crate) fn check_new_name(&self, name: &str) -> Result<(), MapperError> {
+        if self.subvols.contains_key(name) {
+            return Err(MapperError::AlreadyExists(name.to_string()));
+        }
+        let reason = match is_reserved(name) {
+            true => Some("it is reserved for internal use".to_string()),
+            false => self.name_problem(name),
+        };
+        match reason {
+            Some(reason) => Err(MapperError::InvalidName { name: name.to_string(), reason }),
+            None => Ok(()),
+        }
+    }
+
+    /// Why `name` can't be used for DM devices alongside the other
+    /// subvolumes, if it can't
+    pub(crate) fn name_problem(&self, name: &str) -> Option<String> {
+        if name.is_empty() {
+            return Some("it is empty".to_string());
+        }
+        if name == "." || name == ".." || name.contains('/') {
+            return Some("it is not usable as a file name under /dev/mapper".to_string());
+        }
+        if name.chars().any(char::is_control) {
+            return Some("it contains control characters".to_string());
+        }
+        let longest_suffix = LAYER_SUFFIXES.iter().map(|s| s.len() + 1).max().unwrap_or(0);
+        let max_len = DM_NAME_MAX - self.dm_prefix.len() - longest_suffix;
+        if name.len() > max_len {
+            return Some(format!("it is longer than {} bytes", max_len));
+        }
+        // One subvolume's devices must not be able to take the names of
+        // another's lower layers
+        for existing in self.subvols.keys().filter(|existing| *existing != name) {
+            let clash = LAYER_SUFFIXES.iter().any(|suffix| {
+                name.strip_suffix(suffix).and_then(|n| n.strip_suffix('-')) == Some(existing)
+                    || existing.strip_suffix(suffix).and_then(|e| e.strip_suffix('-')) == Some(name)
+            });
+            if clash {
+                return Some(format!("its DM devices could clash with those of {}", existing));
+            }
+        }
+        None
+    }
+
+    /// Path of the device node for subvolume `name` once it is active
+    pub fn subvol_path(&self, name: &str) -> PathBuf {
+        Path::new(DEV_MAPPER).join(self.layer_name(name, None))
+    }
+
+    /// Wait up to `timeout` for udev to create the device node for an
+    /// active subvolume, returning its path
+    pub fn wait_for_subvol(&self, name: &str, timeout: Duration) -> Result<PathBuf, MapperError> {
+        if !self.subvols.contains_key(name) {
+            return Err(MapperError::NotFound(name.to_string()));
+        }
+        let path = self.subvol_path(name);
+        let deadline = Instant::now() + timeout;
+        while !path.exists() {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut,
+                    format!("{} did not appear", path.display())).into());
+            }
+            thread::sleep(NODE_POLL_INTERVAL);
+        }
+        Ok(path)
+    }
+
+    /// The table of each DM device making up subvolume `name`, from the
+    /// bottom of the stack up, one line per target as dmsetup shows them.
+    /// They are built from the metadata, so they are what activation
+    /// would load; `audit` tells whether the live ones still match.
+    /// Devices beneath that are active are referred to by number, others
+    /// by node, and member files with no loop device yet by their path.
+    /// Keys from key files are shown as zeros unless `show_keys` is set.
+    pub fn tables(&self, name: &str, show_keys: bool) -> Result<Vec<(String, Vec<String>)>, MapperError> {
+        self.check_not_reserved(name)?;
+        let sv = self.subvols.get(name).ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        let dm = self.dm().ok();
+        let mut tables = vec![];
+        for layer in self.layers(name, sv) {
+            let mut table = match &layer.target {
+                Target::Linear(extents) => self.linear_table(extents, false)?,
+                target => self.table(dm.as_deref(), target).or_else(|_e| self.table(None, target))?,
+            };
+            if let Target::Crypt { params: CryptParams { key: KeySource::KeyFile(_), .. }, .. } = &layer.target {
+                if !show_keys {
+                    for (_start, _length, _target, params) in &mut table {
+                        let mut fields: Vec<_> = params.split(' ').map(str::to_string).collect();
+                        fields[1] = "0".repeat(fields[1].len());
+                        *params = fields.join(" ");
+                    }
+                }
+            }
+            tables.push((self.layer_name(name, layer.suffix), table_lines(&table)));
+        }
+        Ok(tables)
+    }
+
+    /// What DM device names start with
+    pub fn dm_prefix(&self) -> &str {
+        &self.dm_prefix
+    }
+
+    /// Change what DM device names start with.  Every subvolume has to be
+    /// inactive, as their devices would otherwise be left behind under
+    /// the old names.
+    pub fn set_dm_prefix(&mut self, prefix: &str) -> Result<(), MapperError> {
+        for name in self.subvols.keys() {
+            if self.dm_active(name)? {
+                return Err(MapperError::DeviceBusy(self.layer_name(name, None)));
+            }
+        }
+        self.dm_prefix = prefix.to_string();
+        self.commit()
+    }
+
+    /// The DM devices making up `sv`, from the bottom of the stack up
+    pub(crate) fn layers(&self, name: &str, sv: &SubVolume) -> Vec<Layer> {
+        if let Some(origin) = &sv.snapshot_of {
+            return self.snapshot_layers(name, sv, origin);
+        }
+        if let Some(pool) = &sv.thin_pool {
+            return self.pool_layers(name, sv, pool);
+        }
+        if let Some(thin) = &sv.thin {
+            return self.thin_layers(thin);
+        }
+        if let Some(verity) = &sv.verity {
+            return self.verity_layers(name, sv, verity);
+        }
+        if let Some(mirror) = &sv.mirror {
+            return self.mirror_layers(name, sv, mirror);
+        }
+        if self.has_snapshots(name) {
+            return self.origin_layers(name, sv);
+        }
+
+        let mut layers = vec![Layer {
+            suffix: None,
+            target: Target::Linear(sv.extents.clone()),
+        }];
+        if sv.integrity.is_some() {
+            self.push_layer(name, &mut layers, INTEGRITY_SUFFIX, |lower| Target::Integrity {
+                lower,
+                sectors: sv.size_bytes() / 512,
+            });
+        }
+        if let Some(crypt) = &sv.crypt {
+            self.push_layer(name, &mut layers, CRYPT_SUFFIX, |lower| Target::Crypt {
+                params: crypt.clone(),
+                lower,
+                sectors: sv.size_bytes() / 512,
+            });
+        }
+        layers
+    }
+
+    /// Stack a new device on top of `layers`, moving the current top of
+    /// the stack aside under `suffix`
+    fn push_layer(&self, name: &str, layers: &mut Vec<Layer>, suffix: &'static str, target: impl FnOnce(String) -> Target) {
+        let top = layers.last_mut().expect("empty stack");
+        top.suffix = Some(suffix);
+        let lower = self.layer_name(name, Some(suffix));
+        layers.push(Layer {
+            suffix: None,
+            target: target(lower),
+        });
+    }
+
+    /// DM UUID for one layer of subvolume `name`, tying it to this super
+    /// partition and to the subvolume's own UUID, so it survives renames
+    pub(crate) fn layer_uuid(&self, name: &str, suffix: Option<&str>) -> Option<String> {
+        let sv_uuid = self.subvols.get(name).map(|sv| sv.uuid.as_str())?;
+        if self.uuid.is_empty() || sv_uuid.is_empty() {
+            return None;
+        }
+        Some(match suffix {
+            Some(suffix) => format!("{}{}-{}-{}", DM_UUID_PREFIX, self.uuid, sv_uuid, suffix),
+            None => format!("{}{}-{}", DM_UUID_PREFIX, self.uuid, sv_uuid),
+        })
+    }
+
+    /// A linear table mapping `extents`, attaching loop devices to member
+    /// files if `attach` is set
+    pub(crate) fn linear_table(&self, extents: &[Extent], attach: bool) -> Result<RawTable, MapperError> {
+        let iosize = self.iosize;
+        let members = (0..self.members.len() as u32 + 1)
+            .map(|index| self.member_ref(index, attach))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut table = vec![];
+        let mut start = 0;
+        for e in extents {
+            if e.block_length == 0 {
+                continue;
+            }
+
+            let params = format!("{} {}", members[e.device as usize], e.block_offset * iosize / 512);
+            table.push((start * iosize / 512, e.block_length * iosize / 512, "linear".to_string(), params));
+
+            start += e.block_length;
+        }
+
+        Ok(table)
+    }
+
+    /// How the table of a device stacked on top of DM device `name` refers
+    /// to it: as `dm` says, normally by device number, or without `dm` by
+    /// the path of its node, for tables planned before the device exists
+    fn lower_ref(dm: Option<&dyn DmBackend>, name: &str) -> Result<String, MapperError> {
+        match dm {
+            Some(dm) => dm.device_ref(name),
+            None => Ok(Path::new(DEV_MAPPER).join(name).display().to_string()),
+        }
+    }
+
+    pub(crate) fn table(&self, dm: Option<&dyn DmBackend>, target: &Target) -> Result<RawTable, MapperError> {
+        let table = match target {
+            // Only a table that might be loaded needs loop devices
+            Target::Linear(extents) => self.linear_table(extents, dm.is_some())?,
+            Target::SnapshotOrigin { real, sectors } => {
+                let real = Self::lower_ref(dm, real)?;
+                vec![(0, *sectors, "snapshot-origin".to_string(), real.to_string())]
+            }
+            Target::Snapshot { real, cow, sectors } => {
+                let real = Self::lower_ref(dm, real)?;
+                let cow = Self::lower_ref(dm, cow)?;
+                // Persistent exception store with 4KiB chunks
+                let params = format!("{} {} P 8", real, cow);
+                vec![(0, *sectors, "snapshot".to_string(), params)]
+            }
+            Target::ThinPool { metadata, data, block_sectors, sectors } => {
+                let metadata = Self::lower_ref(dm, metadata)?;
+                let data = Self::lower_ref(dm, data)?;
+                // No low water mark, and no optional features
+                let params = format!("{} {} {} 0 0", metadata, data, block_sectors);
+                vec![(0, *sectors, "thin-pool".to_string(), params)]
+            }
+            Target::Thin { pool, id, sectors } => {
+                let pool = Self::lower_ref(dm, pool)?;
+                vec![(0, *sectors, "thin".to_string(), format!("{} {}", pool, id))]
+            }
+            Target::Integrity { lower, sectors } => {
+                let lower = Self::lower_ref(dm, lower)?;
+                // Journaled mode, with tags for data already on the device
+                // computed in the background after formatting
+                let params = format!("{} 0 {} J 3 internal_hash:crc32c journal_sectors:{} recalculate",
+                    lower, TAG_SIZE, JOURNAL_SECTORS);
+                vec![(0, *sectors, "integrity".to_string(), params)]
+            }
+            Target::Crypt { params, lower, sectors } => {
+                let lower = Self::lower_ref(dm, lower)?;
+                let params = format!("{} {} 0 {} 0", params.cipher, params.table_key()?, lower);
+                vec![(0, *sectors, "crypt".to_string(), params)]
+            }
+            Target::Mirror { legs, sectors } => {
+                let legs = legs.iter()
+                    .map(|leg| Ok(format!("{} 0", Self::lower_ref(dm, leg)?)))
+                    .collect::<Result<Vec<_>, MapperError>>()?;
+                // In-memory region log, and fail legs out on I/O errors
+                // rather than stalling
+                let params = format!("core 1 {} {} {} 1 handle_errors", REGION_SECTORS, legs.len(), legs.join(" "));
+                vec![(0, *sectors, "mirror".to_string(), params)]
+            }
+            Target::Verity { data, hash, data_blocks, root_hash, salt } => {
+                let data = Self::lower_ref(dm, data)?;
+                let hash = Self::lower_ref(dm, hash)?;
+                // Format version 1 with no superblock, the tree starting at
+                // the first block of the hash device
+                let params = format!("1 {} {} {bs} {bs} {} 0 sha256 {} {}", data, hash, data_blocks,
+                    root_hash, salt, bs = VERITY_BLOCK_SIZE);
+                vec![(0, data_blocks * VERITY_BLOCK_SIZE / 512, "verity".to_string(), params)]
+            }
+        };
+        Ok(table)
+    }
+
+    /// Create the DM device for one layer of subvolume `subvol` and load
+    /// its table, leaving it suspended if `resume` is false.  An existing
+    /// device of the same name is reused.
+    pub(crate) fn create_layer(&self, dm: &dyn DmBackend, subvol: &str, suffix: Option<&str>, target: &Target, resume: bool)
+        -> Result<(), MapperError>
+    {
+        let table = self.table(Some(dm), target)?;
+        let name = self.layer_name(subvol, suffix);
+        let read_only = self.load_read_only(subvol, suffix);
+
+        if dm.exists(&name)? {
+            // Left behind by an earlier open, or one that crashed.  Keep it
+            // if it already maps what the metadata says, otherwise switch
+            // it over; the new table takes effect when it is resumed.
+            if dm.table(&name)? != table || dm.info(&name)?.read_only != read_only {
+                dm.load(&name, &table, read_only)?;
+            }
+            if resume {
+                dm.resume(&name)?;
+            }
+            return Ok(());
+        }
+
+        dm.create(&name, self.layer_uuid(subvol, suffix).as_deref())?;
+        if let Err(e) = dm.load(&name, &table, read_only) {
+            let _ = dm.remove(&name, false);
+            return Err(e);
+        }
+        if resume {
+            dm.resume(&name)?;
+        }
+        Ok(())
+    }
+
+    /// Load a new table into an existing device.  The caller is
+    /// responsible for suspending and resuming around it.
+    pub(crate) fn load_layer(&self, dm: &dyn DmBackend, subvol: &str, suffix: Option<&str>, target: &Target) -> Result<(), MapperError> {
+        let table = self.table(Some(dm), target)?;
+        dm.load(&self.layer_name(subvol, suffix), &table, self.load_read_only(subvol, suffix))
+    }
+
+    /// Whether a table for one layer of `subvol` is loaded read-only.  The
+    /// top of a read-only subvolume's stack is, so the kernel refuses
+    /// writes to it.
+    pub(crate) fn load_read_only(&self, subvol: &str, suffix: Option<&str>) -> bool {
+        suffix.is_none() && self.subvols.get(subvol).is_some_and(|sv| sv.read_only)
+    }
+
+    /// Mark a subvolume read-only, or writable again.  An active
+    /// subvolume's table is reloaded to match straight away.
+    pub fn set_read_only(&mut self, name: &str, read_only: bool) -> Result<(), MapperError> {
+        self.check_not_reserved(name)?;
+        let sv = self.subvols.get_mut(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        if sv.read_only == read_only {
+            return Ok(());
+        }
+        sv.read_only = read_only;
+        self.commit()?;
+        if self.dm_active(name)? {
+            self.reload_dm(name, &self.subvols[name])?;
+        }
+        Ok(())
+    }
+
+    /// Suspend, flush and remove a DM device if it exists
+    pub(crate) fn remove_layer(dm: &dyn DmBackend, name: &str) -> Result<(), MapperError> {
+        if !dm.exists(name)? {
+            return Ok(());
+        }
+
+        // Suspending flushes any outstanding I/O to the backing device
+        dm.suspend(name)?;
+        if let Err(e) = dm.remove(name, false) {
+            // Someone opened it in the meantime; leave it usable
+            let _ = dm.resume(name);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn create_dm(&self, name: &str, sv: &SubVolume) -> Result<(), MapperError> {
+        self.check_writable()?;
+        if is_reserved(name) || sv.external {
+            return Ok(());
+        }
+        if self.is_dry_run() {
+            return self.plan_layers(name, &self.layers(name, sv));
+        }
+        let dm = self.dm()?;
+        for layer in self.layers(name, sv) {
+            self.create_layer(&*dm, name, layer.suffix, &layer.target, true)?;
+        }
+        Ok(())
+    }
+
+    /// Swap the tables of an active subvolume for ones matching `sv`
+    pub(crate) fn reload_dm(&self, name: &str, sv: &SubVolume) -> Result<(), MapperError> {
+        self.check_writable()?;
+        if self.is_dry_run() {
+            return self.plan_layers(name, &self.layers(name, sv));
+        }
+        let dm = self.dm()?;
+        let layers = self.layers(name, sv);
+
+        for layer in layers.iter().rev() {
+            dm.suspend(&self.layer_name(name, layer.suffix))?;
+        }
+        let mut loaded = Ok(());
+        for layer in &layers {
+            loaded = self.load_layer(&*dm, name, layer.suffix, &layer.target);
+            if loaded.is_err() {
+                break;
+            }
+        }
+        // Resume even if a load failed, so the old tables stay usable.
+        // On success this makes the new tables live.
+        for layer in &layers {
+            dm.resume(&self.layer_name(name, layer.suffix))?;
+        }
+        loaded
+    }
+
+    /// Suspend every device in the stack for `name`, from the top down.
+    /// Dry runs leave this out of the plan, since the devices are resumed
+    /// again by whatever reloads them.
+    pub(crate) fn suspend_dm(&self, name: &str, sv: &SubVolume) -> Result<(), MapperError> {
+        self.check_writable()?;
+        if self.is_dry_run() {
+            return Ok(());
+        }
+        let dm = self.dm()?;
+        for layer in self.layers(name, sv).iter().rev() {
+            dm.suspend(&self.layer_name(name, layer.suffix))?;
+        }
+        Ok(())
+    }
+
+    /// Resume every device in the stack for `name` after `suspend_dm`
+    pub(crate) fn resume_dm(&self, name: &str, sv: &SubVolume) -> Result<(), MapperError> {
+        if self.is_dry_run() {
+            return Ok(());
+        }
+        let dm = self.dm()?;
+        for layer in &self.layers(name, sv) {
+            dm.resume(&self.layer_name(name, layer.suffix))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove_dm(&self, name: &str) -> Result<(), MapperError> {
+        self.check_writable()?;
+        let sv = match self.subvols.get(name) {
+            Some(sv) => sv,
+            None => return Ok(()),
+        };
+        if self.dm_in_use(name)? {
+            return Err(MapperError::DeviceBusy(name.to_string()));
+        }
+        if self.is_dry_run() {
+            return self.plan_removal(name, sv, false);
+        }
+
+        let dm = self.dm()?;
+        for layer in self.layers(name, sv).iter().rev() {
+            Self::remove_layer(&*dm, &self.layer_name(name, layer.suffix))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the DM devices for `name` even if they are held open.  Any
+    /// that are busy go away once their last user closes them.
+    pub(crate) fn remove_dm_deferred(&self, name: &str) -> Result<(), MapperError> {
+        self.check_writable()?;
+        let sv = match self.subvols.get(name) {
+            Some(sv) => sv,
+            None => return Ok(()),
+        };
+        if self.is_dry_run() {
+            return self.plan_removal(name, sv, true);
+        }
+
+        let dm = self.dm()?;
+        for layer in self.layers(name, sv).iter().rev() {
+            let layer_name = self.layer_name(name, layer.suffix);
+            if dm.exists(&layer_name)? {
+                dm.remove(&layer_name, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rename the DM devices for `old`, returning false if there were none
+    pub(crate) fn rename_dm(&self, old: &str, new: &str, suffixes: &[Option<&str>]) -> Result<bool, MapperError> {
+        self.check_writable()?;
+        if self.is_dry_run() {
+            if !self.dm_active(old)? {
+                return Ok(false);
+            }
+            for suffix in suffixes {
+                self.plan_step(|| Step::Rename { from: self.layer_name(old, *suffix), to: self.layer_name(new, *suffix) });
+            }
+            return Ok(true);
+        }
+        let dm = self.dm()?;
+        let mut renamed = false;
+        for suffix in suffixes {
+            let old_layer = self.layer_name(old, *suffix);
+            if !dm.exists(&old_layer)? {
+                continue;
+            }
+            dm.rename(&old_layer, &self.layer_name(new, *suffix))?;
+            renamed = true;
+        }
+        Ok(renamed)
+    }
+
+    /// Record removing the devices for `name`, if it is active
+    fn plan_removal(&self, name: &str, sv: &SubVolume, deferred: bool) -> Result<(), MapperError> {
+        if self.dm_active(name)? {
+            for layer in self.layers(name, sv).iter().rev() {
+                self.plan_step(|| Step::Remove { device: self.layer_name(name, layer.suffix), deferred });
+            }
+        }
+        Ok(())
+    }
+
+    /// Device mapper, for finding out what is active.  A dry run can be
+    /// made without privileges, and then everything counts as inactive.
+    fn query_dm(&self) -> Result<Option<Rc<dyn DmBackend>>, MapperError> {
+        match self.dm() {
+            Ok(dm) => Ok(Some(dm)),
+            Err(_) if self.is_dry_run() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether the DM device for `name` exists
+    pub(crate) fn dm_active(&self, name: &str) -> Result<bool, MapperError> {
+        let Some(dm) = self.query_dm()? else { return Ok(false) };
+        dm.exists(&self.layer_name(name, None))
+    }
+
+    /// Whether the DM device for `name` exists and is held open
+    pub(crate) fn dm_in_use(&self, name: &str) -> Result<bool, MapperError> {
+        let Some(dm) = self.query_dm()? else { return Ok(false) };
+        let layer_name = self.layer_name(name, None);
+        if !dm.exists(&layer_name)? {
+            return Ok(false);
+        }
+        Ok(dm.info(&layer_name)?.open_count > 0)
+    }
+}
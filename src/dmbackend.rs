@@ -0,0 +1,474 @@
+//! What device mapper is reached through.
+//!
+//! Every DM ioctl a super partition makes goes through a `DmBackend`.  By
+//! default that is `KernelDm`, which needs /dev/mapper/control and
+//! privileges; `set_dm_backend` swaps in another, such as a `MockDm`, which
+//! keeps its devices in memory and records each call, so the order in
+//! which stacks are created, reloaded and removed can be checked where
+//! there is no device mapper at all.  Devices are named as they are in
+//! the kernel, prefix and suffixes included.
+
+use std::cell::{OnceCell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+
+use devicemapper::{DM, DevId, DmFlags, DmName, DmOptions, DmUuid};
+
+use crate::{MapperError, RawTable, SuperPartition};
+
+/// What device mapper says about one of its devices
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmDeviceInfo {
+    pub major: u32,
+    pub minor: u32,
+    pub uuid: Option<String>,
+    pub open_count: i32,
+    pub read_only: bool,
+}
+
+/// The device-mapper operations a super partition uses.  Loading a table
+/// leaves it inactive until the device is next resumed, as in the kernel.
+pub trait DmBackend: fmt::Debug {
+    fn exists(&self, name: &str) -> Result<bool, MapperError>;
+    fn info(&self, name: &str) -> Result<DmDeviceInfo, MapperError>;
+    /// Create device `name` with no table
+    fn create(&self, name: &str, uuid: Option<&str>) -> Result<(), MapperError>;
+    fn load(&self, name: &str, table: &RawTable, read_only: bool) -> Result<(), MapperError>;
+    /// The live table of `name`
+    fn table(&self, name: &str) -> Result<RawTable, MapperError>;
+    /// The status lines of the targets of `name`
+    fn status(&self, name: &str) -> Result<RawTable, MapperError>;
+    fn suspend(&self, name: &str) -> Result<(), MapperError>;
+    /// Resume `name`, making a table loaded since it was last resumed live
+    fn resume(&self, name: &str) -> Result<(), MapperError>;
+    /// Remove `name`, or if `deferred`, once it is no longer open
+    fn remove(&self, name: &str, deferred: bool) -> Result<(), MapperError>;
+    fn rename(&self, from: &str, to: &str) -> Result<(), MapperError>;
+    fn message(&self, name: &str, message: &str) -> Result<(), MapperError>;
+    /// Names of every device
+    fn list(&self) -> Result<Vec<String>, MapperError>;
+
+    /// How a table refers to device `name`; by its device number unless
+    /// the backend knows better
+    fn device_ref(&self, name: &str) -> Result<String, MapperError> {
+        let info = self.info(name)?;
+        Ok(format!("{}:{}", info.major, info.minor))
+    }
+
+    /// How a table refers to member device `index` of a super partition,
+    /// found at `path`, or `None` to go by its device number
+    fn member_ref(&self, _index: u32, _path: &str) -> Result<Option<String>, MapperError> {
+        Ok(None)
+    }
+}
+
+/// Device mapper in the running kernel
+pub struct KernelDm {
+    dm: DM,
+}
+
+impl KernelDm {
+    pub fn new() -> Result<Self, MapperError> {
+        Ok(Self { dm: DM::new()? })
+    }
+}
+
+impl fmt::Debug for KernelDm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KernelDm").finish_non_exhaustive()
+    }
+}
+
+impl DmBackend for KernelDm {
+    fn exists(&self, name: &str) -> Result<bool, MapperError> {
+        Ok(devicemapper::device_exists(&self.dm, DmName::new(name)?)?)
+    }
+
+    fn info(&self, name: &str) -> Result<DmDeviceInfo, MapperError> {
+        let info = self.dm.device_info(&DevId::Name(DmName::new(name)?))?;
+        Ok(DmDeviceInfo {
+            major: info.device().major,
+            minor: info.device().minor,
+            uuid: info.uuid().map(|u| u.to_string()),
+            open_count: info.open_count(),
+            read_only: info.flags().contains(DmFlags::DM_READONLY),
+        })
+    }
+
+    fn create(&self, name: &str, uuid: Option<&str>) -> Result<(), MapperError> {
+        let uuid = uuid.map(DmUuid::new).transpose()?;
+        self.dm.device_create(DmName::new(name)?, uuid, DmOptions::default())?;
+        Ok(())
+    }
+
+    fn load(&self, name: &str, table: &RawTable, read_only: bool) -> Result<(), MapperError> {
+        let options = match read_only {
+            true => DmOptions::default().set_flags(DmFlags::DM_READONLY),
+            false => DmOptions::default(),
+        };
+        self.dm.table_load(&DevId::Name(DmName::new(name)?), table, options)?;
+        Ok(())
+    }
+
+    fn table(&self, name: &str) -> Result<RawTable, MapperError> {
+        let options = DmOptions::default().set_flags(DmFlags::DM_STATUS_TABLE);
+        Ok(self.dm.table_status(&DevId::Name(DmName::new(name)?), options)?.1)
+    }
+
+    fn status(&self, name: &str) -> Result<RawTable, MapperError> {
+        Ok(self.dm.table_status(&DevId::Name(DmName::new(name)?), DmOptions::default())?.1)
+    }
+
+    fn suspend(&self, name: &str) -> Result<(), MapperError> {
+        // Suspending flushes any outstanding I/O to the devices beneath
+        self.dm.device_suspend(&DevId::Name(DmName::new(name)?), DmOptions::default().set_flags(DmFlags::DM_SUSPEND))?;
+        Ok(())
+    }
+
+    fn resume(&self, name: &str) -> Result<(), MapperError> {
+        self.dm.device_suspend(&DevId::Name(DmName::new(name)?), DmOptions::default())?;
+        Ok(())
+    }
+
+    fn remove(&self, name: &str, deferred: bool) -> Result<(), MapperError> {
+        let options = match deferred {
+            true => DmOptions::default().set_flags(DmFlags::DM_DEFERRED_REMOVE),
+            false => DmOptions::default(),
+        };
+        self.dm.device_remove(&DevId::Name(DmName::new(name)?), options)?;
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), MapperError> {
+        self.dm.device_rename(DmName::new(from)?, &DevId::Name(DmName::new(to)?))?;
+        Ok(())
+    }
+
+    fn message(&self, name: &str, message: &str) -> Result<(), MapperError> {
+        self.dm.target_msg(&DevId::Name(DmName::new(name)?), None, message)?;
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>, MapperError> {
+        Ok(self.dm.list_devices()?.into_iter().map(|(name, _devnum, _event)| name.to_string()).collect())
+    }
+}
+
+/// One call made on a `MockDm`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DmCall {
+    Create { name: String, uuid: Option<String> },
+    /// A table load, one line per target
+    Load { name: String, table: Vec<String>, read_only: bool },
+    Suspend { name: String },
+    Resume { name: String },
+    Remove { name: String, deferred: bool },
+    Rename { from: String, to: String },
+    Message { name: String, message: String },
+}
+
+#[derive(Debug, Clone, Default)]
+struct MockDevice {
+    minor: u32,
+    uuid: Option<String>,
+    live: RawTable,
+    live_read_only: bool,
+    /// Loaded but not yet resumed
+    inactive: Option<(RawTable, bool)>,
+    suspended: bool,
+    open_count: i32,
+    removing: bool,
+}
+
+/// Device mapper kept in memory, recording the calls that change it.
+/// Devices get major number 253 and minors in the order they are created.
+#[derive(Debug, Default)]
+pub struct MockDm {
+    devices: RefCell<BTreeMap<String, MockDevice>>,
+    calls: RefCell<Vec<DmCall>>,
+    next_minor: RefCell<u32>,
+}
+
+impl MockDm {
+    /// Major number of every mock device
+    pub const MAJOR: u32 = 253;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every call that changed something, oldest first
+    pub fn calls(&self) -> Vec<DmCall> {
+        self.calls.borrow().clone()
+    }
+
+    /// Forget the calls made so far
+    pub fn clear_calls(&self) {
+        self.calls.borrow_mut().clear();
+    }
+
+    /// Live table of each device, by name
+    pub fn tables(&self) -> BTreeMap<String, Vec<String>> {
+        self.devices.borrow().iter()
+            .map(|(name, dev)| (name.clone(), crate::dm::table_lines(&dev.live)))
+            .collect()
+    }
+
+    /// Pretend `name` is held open `count` times, as by a mount.  A device
+    /// whose removal was deferred goes once this drops to zero.
+    pub fn set_open_count(&self, name: &str, count: i32) -> Result<(), MapperError> {
+        let mut devices = self.devices.borrow_mut();
+        let dev = devices.get_mut(name).ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        dev.open_count = count;
+        if count == 0 && dev.removing {
+            devices.remove(name);
+        }
+        Ok(())
+    }
+
+    fn record(&self, call: DmCall) {
+        self.calls.borrow_mut().push(call);
+    }
+
+    fn with_device<T>(&self, name: &str, f: impl FnOnce(&mut MockDevice) -> T) -> Result<T, MapperError> {
+        let mut devices = self.devices.borrow_mut();
+        let dev = devices.get_mut(name).ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        Ok(f(dev))
+    }
+}
+
+impl DmBackend for MockDm {
+    fn exists(&self, name: &str) -> Result<bool, MapperError> {
+        Ok(self.devices.borrow().contains_key(name))
+    }
+
+    fn info(&self, name: &str) -> Result<DmDeviceInfo, MapperError> {
+        self.with_device(name, |dev| DmDeviceInfo {
+            major: Self::MAJOR,
+            minor: dev.minor,
+            uuid: dev.uuid.clone(),
+            open_count: dev.open_count,
+            read_only: dev.live_read_only,
+        })
+    }
+
+    fn create(&self, name: &str, uuid: Option<&str>) -> Result<(), MapperError> {
+        let mut devices = self.devices.borrow_mut();
+        if devices.contains_key(name) {
+            return Err(MapperError::AlreadyExists(name.to_string()));
+        }
+        let mut next_minor = self.next_minor.borrow_mut();
+        devices.insert(name.to_string(), MockDevice {
+            minor: *next_minor,
+            uuid: uuid.map(str::to_string),
+            ..MockDevice::default()
+        });
+        *next_minor += 1;
+        self.record(DmCall::Create { name: name.to_string(), uuid: uuid.map(str::to_string) });
+        Ok(())
+    }
+
+    fn load(&self, name: &str, table: &RawTable, read_only: bool) -> Result<(), MapperError> {
+        self.with_device(name, |dev| dev.inactive = Some((table.clone(), read_only)))?;
+        self.record(DmCall::Load { name: name.to_string(), table: crate::dm::table_lines(table), read_only });
+        Ok(())
+    }
+
+    fn table(&self, name: &str) -> Result<RawTable, MapperError> {
+        self.with_device(name, |dev| dev.live.clone())
+    }
+
+    /// There are no targets to report on, so this is the live table
+    fn status(&self, name: &str) -> Result<RawTable, MapperError> {
+        self.table(name)
+    }
+
+    fn suspend(&self, name: &str) -> Result<(), MapperError> {
+        self.with_device(name, |dev| dev.suspended = true)?;
+        self.record(DmCall::Suspend { name: name.to_string() });
+        Ok(())
+    }
+
+    fn resume(&self, name: &str) -> Result<(), MapperError> {
+        self.with_device(name, |dev| {
+            dev.suspended = false;
+            if let Some((table, read_only)) = dev.inactive.take() {
+                dev.live = table;
+                dev.live_read_only = read_only;
+            }
+        })?;
+        self.record(DmCall::Resume { name: name.to_string() });
+        Ok(())
+    }
+
+    fn remove(&self, name: &str, deferred: bool) -> Result<(), MapperError> {
+        let open_count = self.with_device(name, |dev| dev.open_count)?;
+        match (open_count, deferred) {
+            (0, _) => {
+                self.devices.borrow_mut().remove(name);
+            }
+            (_, true) => self.with_device(name, |dev| dev.removing = true)?,
+            (_, false) => return Err(MapperError::DeviceBusy(name.to_string())),
+        }
+        self.record(DmCall::Remove { name: name.to_string(), deferred });
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), MapperError> {
+        let mut devices = self.devices.borrow_mut();
+        if devices.contains_key(to) {
+            return Err(MapperError::AlreadyExists(to.to_string()));
+        }
+        let dev = devices.remove(from).ok_or_else(|| MapperError::NotFound(from.to_string()))?;
+        devices.insert(to.to_string(), dev);
+        self.record(DmCall::Rename { from: from.to_string(), to: to.to_string() });
+        Ok(())
+    }
+
+    fn message(&self, name: &str, message: &str) -> Result<(), MapperError> {
+        self.with_device(name, |_dev| ())?;
+        self.record(DmCall::Message { name: name.to_string(), message: message.to_string() });
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<String>, MapperError> {
+        Ok(self.devices.borrow().keys().cloned().collect())
+    }
+}
+
+impl SuperPartition {
+    /// Reach device mapper through `backend` from now on, instead of the
+    /// kernel's
+    pub fn set_dm_backend(&mut self, backend: Rc<dyn DmBackend>) {
+        self.dm_backend = OnceCell::from(backend);
+    }
+
+    /// Device mapper, as set by `set_dm_backend` or else the kernel's,
+    /// opened on first use and kept from then on
+    pub(crate) fn dm(&self) -> Result<Rc<dyn DmBackend>, MapperError> {
+        if let Some(backend) = self.dm_backend.get() {
+            return Ok(backend.clone());
+        }
+        let kernel: Rc<dyn DmBackend> = Rc::new(KernelDm::new()?);
+        Ok(self.dm_backend.get_or_init(|| kernel).clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{format, reopen, MIB};
+
+    /// The calls made since the last look, as "op device"
+    fn ops(dm: &MockDm) -> Vec<String> {
+        let ops = dm.calls().into_iter().map(|call| match call {
+            DmCall::Create { name, .. } => format!("create {}", name),
+            DmCall::Load { name, .. } => format!("load {}", name),
+            DmCall::Suspend { name } => format!("suspend {}", name),
+            DmCall::Resume { name } => format!("resume {}", name),
+            DmCall::Remove { name, .. } => format!("remove {}", name),
+            DmCall::Rename { from, to } => format!("rename {} {}", from, to),
+            DmCall::Message { name, message } => format!("message {} {}", name, message),
+        }).collect();
+        dm.clear_calls();
+        ops
+    }
+
+    #[test]
+    fn create_loads_and_resumes_a_new_device() {
+        let (mut sp, dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create");
+        assert_eq!(ops(&dm), ["create hg-a", "load hg-a", "resume hg-a"]);
+        assert_eq!(dm.tables()["hg-a"], ["0 8192 linear 0:0 0"]);
+        assert!(dm.info("hg-a").expect("info").uuid.is_some_and(|uuid| uuid.starts_with("HGMAP-")));
+    }
+
+    #[test]
+    fn resize_reloads_between_suspend_and_resume() {
+        let (mut sp, dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create");
+        ops(&dm);
+        sp.resize_subvol("a", 8 * MIB).expect("resize");
+        assert_eq!(ops(&dm), ["suspend hg-a", "load hg-a", "resume hg-a"]);
+        assert_eq!(dm.tables()["hg-a"], ["0 16384 linear 0:0 0"]);
+    }
+
+    #[test]
+    fn delete_removes_the_device() {
+        let (mut sp, dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create");
+        ops(&dm);
+        sp.delete_subvol_by_name("a").expect("delete");
+        assert_eq!(ops(&dm), ["suspend hg-a", "remove hg-a"]);
+        assert!(dm.list().expect("list").is_empty());
+    }
+
+    #[test]
+    fn delete_of_an_open_device_fails() {
+        let (mut sp, dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create");
+        dm.set_open_count("hg-a", 1).expect("open count");
+        assert!(sp.delete_subvol_by_name("a").is_err());
+        assert!(dm.exists("hg-a").expect("exists"));
+    }
+
+    #[test]
+    fn snapshot_stacks_on_the_origin_and_unstacks_on_delete() {
+        let (mut sp, dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create");
+        ops(&dm);
+        sp.snapshot_subvol("a", "s", 2 * MIB).expect("snapshot");
+        assert_eq!(ops(&dm), [
+            "create hg-a-real", "load hg-a-real", "resume hg-a-real",
+            "create hg-s-cow", "load hg-s-cow", "resume hg-s-cow",
+            "suspend hg-a", "create hg-s", "load hg-s", "load hg-a", "resume hg-s", "resume hg-a",
+        ]);
+        let tables = dm.tables();
+        assert_eq!(tables["hg-a"], ["0 8192 snapshot-origin 253:1"]);
+        assert_eq!(tables["hg-s"], ["0 8192 snapshot 253:1 253:2 P 8"]);
+
+        sp.delete_subvol_by_name("s").expect("delete");
+        assert_eq!(ops(&dm), [
+            "suspend hg-s", "remove hg-s", "suspend hg-s-cow", "remove hg-s-cow",
+            "suspend hg-a", "load hg-a", "resume hg-a", "suspend hg-a-real", "remove hg-a-real",
+        ]);
+        assert_eq!(dm.tables()["hg-a"], ["0 8192 linear 0:0 0"]);
+    }
+
+    #[test]
+    fn thin_volumes_go_on_a_pool_after_its_devices() {
+        let (mut sp, dm) = format();
+        sp.create_thin_pool("p", 8 * MIB, 2 * MIB).expect("pool");
+        assert_eq!(ops(&dm), [
+            "create hg-p-tmeta", "load hg-p-tmeta", "resume hg-p-tmeta",
+            "create hg-p-tdata", "load hg-p-tdata", "resume hg-p-tdata",
+            "create hg-p", "load hg-p", "resume hg-p",
+        ]);
+        sp.create_thin("p", "t", 16 * MIB).expect("thin");
+        assert_eq!(ops(&dm), ["message hg-p create_thin 0", "create hg-t", "load hg-t", "resume hg-t"]);
+        let pool = dm.info("hg-p").expect("info");
+        assert_eq!(dm.tables()["hg-t"], [format!("0 32768 thin {}:{} 0", pool.major, pool.minor)]);
+    }
+
+    #[test]
+    fn activation_brings_up_lower_layers_first() {
+        let (mut sp, _dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create");
+        sp.snapshot_subvol("a", "s", 2 * MIB).expect("snapshot");
+        sp.create_thin_pool("p", 8 * MIB, 2 * MIB).expect("pool");
+        sp.create_thin("p", "t", 16 * MIB).expect("thin");
+
+        let (sp, dm) = reopen(sp);
+        sp.activate_all().expect("activate");
+
+        let created: Vec<_> = ops(&dm).into_iter()
+            .filter_map(|op| op.strip_prefix("create ").map(str::to_string))
+            .collect();
+        let at = |name: &str| created.iter().position(|n| n == name).unwrap_or_else(|| panic!("{} not created", name));
+        for (lower, upper) in [("hg-a-real", "hg-a"), ("hg-a-real", "hg-s"), ("hg-s-cow", "hg-s"),
+                ("hg-p-tmeta", "hg-p"), ("hg-p-tdata", "hg-p"), ("hg-p", "hg-t")] {
+            assert!(at(lower) < at(upper), "{} created after {}", lower, upper);
+        }
+    }
+}
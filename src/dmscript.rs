@@ -0,0 +1,252 @@
+//! Device mapper as a shell script.
+//!
+//! `ScriptDm` keeps its devices in memory like a `MockDm`, and writes each
+//! change as the dmsetup command that would make it, so the metadata work
+//! can be done on an image without privileges and the devices set up from
+//! the script later, or elsewhere.  A device created, loaded and resumed
+//! in one go comes out as a single `dmsetup create`.  Members that are
+//! regular files are attached to loop devices by the script itself, and
+//! tables refer to DM devices by the path of their node, as the device
+//! numbers aren't known until the script runs.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+
+use crate::dm::{table_lines, DEV_MAPPER};
+use crate::{DmBackend, DmDeviceInfo, MapperError, MockDm, RawTable};
+
+/// A device created but not yet written out, waiting to see whether it
+/// gets its table and is resumed straight away
+struct Pending {
+    name: String,
+    uuid: Option<String>,
+    table: Option<(RawTable, bool)>,
+}
+
+/// Device mapper that writes dmsetup commands instead of making ioctls
+pub struct ScriptDm {
+    state: MockDm,
+    out: RefCell<Box<dyn Write>>,
+    pending: RefCell<Option<Pending>>,
+    /// Shell variable holding the loop device of each member that is a
+    /// regular file, by member index
+    loops: RefCell<BTreeMap<u32, String>>,
+}
+
+impl ScriptDm {
+    /// Write the commands to `out`, starting with no devices at all
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self {
+            state: MockDm::new(),
+            out: RefCell::new(out),
+            pending: RefCell::new(None),
+            loops: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Quote `s` for the shell, leaving the loop device variables in it
+    /// to be expanded
+    fn quote(&self, s: &str) -> String {
+        let mut quoted = format!("'{}'", s.replace('\'', r"'\''"));
+        // The braces keep ${LOOP1} from matching inside ${LOOP10}
+        for var in self.loops.borrow().values() {
+            quoted = quoted.replace(&format!("${{{}}}", var), &format!("'\"${{{}}}\"'", var));
+        }
+        quoted
+    }
+
+    fn emit(&self, line: &str) -> Result<(), MapperError> {
+        writeln!(self.out.borrow_mut(), "{}", line)?;
+        Ok(())
+    }
+
+    /// `command` with `table` on its standard input
+    fn emit_with_table(&self, table: &RawTable, command: &str) -> Result<(), MapperError> {
+        let lines: Vec<_> = table_lines(table).iter().map(|line| self.quote(line)).collect();
+        self.emit(&format!("printf '%s\\n' {} | {}", lines.join(" "), command))
+    }
+
+    /// `dmsetup create` for `pending`, with `flags` such as `--readonly`
+    fn create_command(&self, pending: &Pending, flags: &str) -> String {
+        let mut command = "dmsetup create".to_string();
+        if let Some(uuid) = &pending.uuid {
+            command += &format!(" --uuid {}", self.quote(uuid));
+        }
+        format!("{}{} {}", command, flags, self.quote(&pending.name))
+    }
+
+    /// Write out the device left pending, which is not to be resumed yet
+    fn flush(&self) -> Result<(), MapperError> {
+        let Some(pending) = self.pending.borrow_mut().take() else {
+            return Ok(());
+        };
+        self.emit(&self.create_command(&pending, " --notable"))?;
+        if let Some((table, read_only)) = &pending.table {
+            let read_only = if *read_only { " --readonly" } else { "" };
+            self.emit_with_table(table, &format!("dmsetup load{} {}", read_only, self.quote(&pending.name)))?;
+        }
+        Ok(())
+    }
+
+    /// Whether `name` is the device left pending
+    fn is_pending(&self, name: &str) -> bool {
+        self.pending.borrow().as_ref().is_some_and(|pending| pending.name == name)
+    }
+
+    /// Emit `command` after whatever was pending
+    fn run(&self, command: &str) -> Result<(), MapperError> {
+        self.flush()?;
+        self.emit(command)
+    }
+}
+
+impl fmt::Debug for ScriptDm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ScriptDm").field("state", &self.state).finish_non_exhaustive()
+    }
+}
+
+impl DmBackend for ScriptDm {
+    fn exists(&self, name: &str) -> Result<bool, MapperError> {
+        self.state.exists(name)
+    }
+
+    fn info(&self, name: &str) -> Result<DmDeviceInfo, MapperError> {
+        self.state.info(name)
+    }
+
+    fn create(&self, name: &str, uuid: Option<&str>) -> Result<(), MapperError> {
+        self.state.create(name, uuid)?;
+        self.flush()?;
+        *self.pending.borrow_mut() = Some(Pending {
+            name: name.to_string(),
+            uuid: uuid.map(str::to_string),
+            table: None,
+        });
+        Ok(())
+    }
+
+    fn load(&self, name: &str, table: &RawTable, read_only: bool) -> Result<(), MapperError> {
+        self.state.load(name, table, read_only)?;
+        if let Some(pending) = self.pending.borrow_mut().as_mut().filter(|pending| pending.name == name && pending.table.is_none()) {
+            pending.table = Some((table.clone(), read_only));
+            return Ok(());
+        }
+        self.flush()?;
+        let read_only = if read_only { " --readonly" } else { "" };
+        self.emit_with_table(table, &format!("dmsetup load{} {}", read_only, self.quote(name)))
+    }
+
+    fn table(&self, name: &str) -> Result<RawTable, MapperError> {
+        self.state.table(name)
+    }
+
+    fn status(&self, name: &str) -> Result<RawTable, MapperError> {
+        self.state.status(name)
+    }
+
+    fn suspend(&self, name: &str) -> Result<(), MapperError> {
+        self.state.suspend(name)?;
+        self.run(&format!("dmsetup suspend {}", self.quote(name)))
+    }
+
+    fn resume(&self, name: &str) -> Result<(), MapperError> {
+        self.state.resume(name)?;
+        if self.is_pending(name) {
+            let pending = self.pending.borrow_mut().take().expect("pending device");
+            if let Some((table, read_only)) = &pending.table {
+                // Creating a device with a table makes it live at once
+                let flags = if *read_only { " --readonly" } else { "" };
+                return self.emit_with_table(table, &self.create_command(&pending, flags));
+            }
+            *self.pending.borrow_mut() = Some(pending);
+        }
+        self.run(&format!("dmsetup resume {}", self.quote(name)))
+    }
+
+    fn remove(&self, name: &str, deferred: bool) -> Result<(), MapperError> {
+        self.state.remove(name, deferred)?;
+        if self.is_pending(name) {
+            // Never written out, so there is nothing to undo
+            self.pending.borrow_mut().take();
+            return Ok(());
+        }
+        let deferred = if deferred { " --deferred" } else { "" };
+        self.run(&format!("dmsetup remove{} {}", deferred, self.quote(name)))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), MapperError> {
+        self.state.rename(from, to)?;
+        self.run(&format!("dmsetup rename {} {}", self.quote(from), self.quote(to)))
+    }
+
+    fn message(&self, name: &str, message: &str) -> Result<(), MapperError> {
+        self.state.message(name, message)?;
+        self.run(&format!("dmsetup message {} 0 {}", self.quote(name), self.quote(message)))
+    }
+
+    fn list(&self) -> Result<Vec<String>, MapperError> {
+        self.state.list()
+    }
+
+    fn device_ref(&self, name: &str) -> Result<String, MapperError> {
+        self.state.info(name)?;
+        Ok(Path::new(DEV_MAPPER).join(name).display().to_string())
+    }
+
+    fn member_ref(&self, index: u32, path: &str) -> Result<Option<String>, MapperError> {
+        let file_type = fs::metadata(path)?.file_type();
+        if file_type.is_block_device() {
+            return Ok(Some(path.to_string()));
+        }
+        if !file_type.is_file() {
+            return Ok(None);
+        }
+        if let Some(var) = self.loops.borrow().get(&index) {
+            return Ok(Some(format!("${{{}}}", var)));
+        }
+        let var = format!("LOOP{}", index);
+        self.flush()?;
+        self.emit(&format!("{}=$(losetup --show -f {})", var, self.quote(path)))?;
+        self.loops.borrow_mut().insert(index, var.clone());
+        Ok(Some(format!("${{{}}}", var)))
+    }
+}
+
+// Whatever is still pending was meant to stay inactive
+impl Drop for ScriptDm {
+    fn drop(&mut self) {
+        let _ = self.flush();
+        let _ = self.out.borrow_mut().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn many_loop_devices_stay_apart() {
+        let dir = std::env::temp_dir().join(format!("hgmap-dmscript-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("temp dir");
+        let dm = ScriptDm::new(Box::new(std::io::sink()));
+        let refs: Vec<_> = (0..12).map(|index| {
+            let path = dir.join(format!("member{}", index));
+            fs::write(&path, b"").expect("member");
+            dm.member_ref(index, &path.display().to_string()).expect("member ref").expect("loop device")
+        }).collect();
+        fs::remove_dir_all(&dir).expect("remove temp dir");
+
+        let quoted = dm.quote(&format!("0 8 linear {} 0 {} 0", refs[1], refs[10]));
+        let script = format!("LOOP1=/dev/loop1 LOOP10=/dev/loop10; printf %s {}", quoted);
+        let out = Command::new("sh").arg("-c").arg(script).output().expect("sh");
+        assert_eq!(String::from_utf8_lossy(&out.stdout), "0 8 linear /dev/loop1 0 /dev/loop10 0");
+    }
+}
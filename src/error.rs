@@ -0,0 +1,74 @@
+use std::io;
+
+use devicemapper::DmError;
+use thiserror::Error;
+
+use crate::CorruptionReport;
+
+#[derive(Error, Debug)]
+pub enum MapperError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("device-mapper error: {0}")]
+    Dm(#[from] DmError),
+
+    #[error("metadata is corrupt: {0}")]
+    MetadataCorrupt(String),
+
+    #[error("metadata has bad extents: {0}")]
+    BadExtents(CorruptionReport),
+
+    #[error("metadata CRC mismatch: stored {stored:#010x}, computed {computed:#010x}")]
+    CrcMismatch { stored: u32, computed: u32 },
+
+    #[error("metadata format version {found} is newer than supported version {supported}")]
+    UnsupportedFormat { found: u32, supported: u32 },
+
+    #[error("metadata needs {size} bytes but a slot only holds {capacity}")]
+    MetadataTooLarge { size: usize, capacity: usize },
+
+    #[error("super partition was opened read-only")]
+    ReadOnly,
+
+    #[error("no valid metadata found")]
+    NoMetadata,
+
+    #[error("not enough space: {needed} blocks needed, {available} available")]
+    NoSpace { needed: u64, available: u64 },
+
+    #[error("no contiguous run of {needed} blocks, largest is {largest}")]
+    NoContiguousSpace { needed: u64, largest: u64 },
+
+    #[error("subvolume {0} already exists")]
+    AlreadyExists(String),
+
+    #[error("invalid subvolume name {name:?}: {reason}")]
+    InvalidName { name: String, reason: String },
+
+    #[error("no such subvolume: {0}")]
+    NotFound(String),
+
+    #[error("{0} is in use")]
+    DeviceBusy(String),
+
+    #[error("metadata changed on disk: expected generation {expected}, found {found}")]
+    ConcurrentModification { expected: u32, found: u32 },
+
+    #[error("{0} is protected")]
+    Protected(String),
+
+    #[error("{0} is locked by another process")]
+    Locked(String),
+
+    #[error("{0} did not read back as written")]
+    VerifyFailed(String),
+
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+
+    /// Extents past where the metadata would go; `needed` is the smallest
+    /// size they leave room for
+    #[error("shrinking to {size} bytes would cut off {}; it can go down to {needed}", .blockers.join(", "))]
+    ShrinkBlocked { size: u64, needed: u64, blockers: Vec<String> },
+}
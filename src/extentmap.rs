@@ -0,0 +1,97 @@
+//! The whole layout of a super partition as runs of blocks.
+//!
+//! Every block of every member device is either held by something in the
+//! metadata or free, so the runs of each device, in order, add up to the
+//! device.  Bookkeeping such as the metadata replicas shows up as an
+//! owner like any subvolume.  `export_extents` gives the same in bytes, for
+//! tools checking a layout against what it should be.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{is_zero, Extent, SuperPartition};
+
+/// A run of blocks on one member device and what holds it
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    /// Index of the member device, 0 for the one holding the metadata
+    pub device: u32,
+    pub block_offset: u64,
+    pub block_length: u64,
+    /// The subvolume or bookkeeping holding the run, `None` if it is free
+    pub owner: Option<String>,
+}
+
+/// A run of bytes on one member device
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ByteRange {
+    /// Index of the member device, left out for the one holding the
+    /// metadata
+    #[serde(skip_serializing_if = "is_zero")]
+    pub device: u32,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Where everything is, in bytes
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExtentMap {
+    pub block_size: u64,
+    /// The ranges of each subvolume or bookkeeping owner.  A subvolume's
+    /// come in the order it maps them, followed by any backing its
+    /// internal structures.
+    pub owners: BTreeMap<String, Vec<ByteRange>>,
+    /// Unallocated ranges by device and then offset
+    pub free: Vec<ByteRange>,
+}
+
+impl SuperPartition {
+    fn byte_range(&self, e: &Extent) -> ByteRange {
+        ByteRange {
+            device: e.device,
+            offset: e.block_offset * self.iosize,
+            length: e.block_length * self.iosize,
+        }
+    }
+
+    /// Every extent and hole, in bytes and by owner
+    pub fn export_extents(&self) -> ExtentMap {
+        let mut owners: BTreeMap<_, _> = self.subvols.iter()
+            .map(|(name, sv)| {
+                let ranges = sv.all_extents().filter(|e| e.block_length > 0).map(|e| self.byte_range(e)).collect();
+                (name.clone(), ranges)
+            })
+            .collect();
+        for (owner, e) in self.owned_extents() {
+            if !self.subvols.contains_key(&owner) {
+                owners.entry(owner).or_insert_with(Vec::new).push(self.byte_range(e));
+            }
+        }
+        ExtentMap {
+            block_size: self.iosize,
+            owners,
+            free: self.free_extents().iter().map(|e| self.byte_range(e)).collect(),
+        }
+    }
+
+    /// Every run of blocks, allocated or free, by device and then offset
+    pub fn regions(&self) -> Vec<Region> {
+        let mut regions: Vec<_> = self.owned_extents().into_iter()
+            .map(|(owner, e)| Region {
+                device: e.device,
+                block_offset: e.block_offset,
+                block_length: e.block_length,
+                owner: Some(owner),
+            })
+            .collect();
+        regions.extend(self.free_extents().into_iter().map(|e| Region {
+            device: e.device,
+            block_offset: e.block_offset,
+            block_length: e.block_length,
+            owner: None,
+        }));
+        regions.sort_by_key(|r| (r.device, r.block_offset));
+        regions
+    }
+}
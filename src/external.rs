@@ -0,0 +1,51 @@
+//! Records for data kept outside the super partition.
+//!
+//! An external record is a subvolume entry with no extents, describing
+//! something stored elsewhere, such as a GPT partition alongside the super
+//! partition, so its version and author can be tracked with everything
+//! else.  It is never given blocks or DM devices: activating, resizing,
+//! snapshotting or reading and writing one is refused, and everything
+//! that goes over all subvolumes passes it by.  Deleting or renaming one
+//! only changes the metadata.
+
+use crate::{MapperError, SubVolume, SuperPartition};
+
+/// What an external record says about the data it stands for
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExternalMeta {
+    pub version: String,
+    pub author: String,
+    /// Left empty for the current time
+    pub timedate: String,
+}
+
+impl SuperPartition {
+    /// Record `name` as standing for data kept outside the super
+    /// partition, and commit
+    pub fn register_external(&mut self, name: &str, meta: &ExternalMeta) -> Result<(), MapperError> {
+        self.check_writable()?;
+        self.check_new_name(name)?;
+        let mut sv = SubVolume::new(vec![], self.iosize);
+        sv.external = true;
+        sv.version = meta.version.clone();
+        sv.author = meta.author.clone();
+        if !meta.timedate.is_empty() {
+            sv.timedate = meta.timedate.clone();
+        }
+        self.subvols.insert(name.to_string(), sv);
+        self.commit()
+    }
+
+    /// Whether `name` is an external record
+    pub fn is_external(&self, name: &str) -> bool {
+        self.subvols.get(name).is_some_and(|sv| sv.external)
+    }
+
+    /// Refuse to act on the data of an external record, as there is none
+    pub(crate) fn check_not_external(&self, name: &str) -> Result<(), MapperError> {
+        if self.is_external(name) {
+            return Err(MapperError::InvalidArgument(format!("{} is an external record with no data here", name)));
+        }
+        Ok(())
+    }
+}
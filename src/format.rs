@@ -0,0 +1,179 @@
+//! Versioning of the on-disk metadata schema.
+//!
+//! Every commit stamps the metadata with `FORMAT_VERSION`.  Metadata from
+//! an older version is brought up to date one version at a time while it is
+//! still untyped JSON, so the upgrade steps can reshape anything the
+//! current structs cannot deserialize directly; the next commit then writes
+//! it back in the current format.  Metadata from a newer version is refused
+//! rather than risk dropping fields this build does not know about.
+//!
+//! A metadata slot starts with a big-endian CRC32 of its payload.  Legacy
+//! slots follow that with the JSON itself, terminated by a newline and a
+//! NUL.  Otherwise the next byte names the encoding, followed by a
+//! big-endian u32 payload length and the payload.  CBOR keeps the same
+//! schema as the JSON but drops the quoting and punctuation, so more
+//! subvolumes fit in a slot.
+
+use std::io::{BufRead, BufReader, Read};
+
+use serde_json::Value;
+
+use crate::{MapperError, SuperPartition};
+
+/// How metadata is serialized in its slots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Readable by every version, and the default for new super partitions
+    #[default]
+    Json,
+    Cbor,
+}
+
+/// Encoding byte of a CBOR slot.  Legacy JSON slots have a '{' here.
+const CBOR_TAG: u8 = 0x01;
+
+/// Version written by this build.  Bump it whenever a change to the
+/// metadata could not be read correctly by an older build, and add a step
+/// to `upgrade` if older metadata needs more than serde defaults.
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+/// Rewrite `value` from version `from` to version `from + 1`
+fn upgrade(_value: &mut Value, from: u32) {
+    match from {
+        // Metadata from before versioning; every field added since then
+        // has a default
+        0 => {}
+        _ => unreachable!("no upgrade from format version {}", from),
+    }
+}
+
+fn check_crc(stored: u32, payload: &[u8]) -> Result<(), MapperError> {
+    let crc_algo = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
+    let computed = crc_algo.checksum(payload);
+    if stored != computed {
+        return Err(MapperError::CrcMismatch { stored, computed });
+    }
+    Ok(())
+}
+
+/// Parse the contents of a metadata slot
+pub(crate) fn read_slot(slot: &[u8]) -> Result<SuperPartition, MapperError> {
+    let f = &mut &slot[..];
+    let mut disk_crc = [0; 4];
+    f.read_exact(&mut disk_crc)?;
+    let disk_crc = u32::from_be_bytes(disk_crc);
+    let mut tag = [0];
+    f.read_exact(&mut tag)?;
+
+    match tag[0] {
+        CBOR_TAG => {
+            let mut len = [0; 4];
+            f.read_exact(&mut len)?;
+            // Checked before allocating, as the CRC can't be until after
+            let len = u32::from_be_bytes(len) as usize;
+            if len > f.len() {
+                return Err(MapperError::MetadataCorrupt(format!("cbor payload of {} bytes overruns its slot", len)));
+            }
+            let mut payload = vec![0; len];
+            f.read_exact(&mut payload)?;
+            check_crc(disk_crc, &payload)?;
+            let value: Value = ciborium::from_reader(&payload[..])
+                .map_err(|e| MapperError::MetadataCorrupt(format!("can't parse cbor: {}", e)))?;
+            decode(value, Encoding::Cbor)
+        }
+        _ => {
+            let mut json_metadata = tag.to_vec();
+            BufReader::new(f).read_until(b'\n', &mut json_metadata)?;
+            check_crc(disk_crc, json_metadata.trim_ascii())?;
+            let value: Value = serde_json::from_slice(&json_metadata)
+                .map_err(|e| MapperError::MetadataCorrupt(format!("can't parse json: {}", e)))?;
+            decode(value, Encoding::Json)
+        }
+    }
+}
+
+/// Serialize `meta` into the contents of a metadata slot
+pub(crate) fn write_slot(meta: &SuperPartition) -> Vec<u8> {
+    let crc_algo = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
+    match meta.encoding {
+        Encoding::Json => {
+            let json = serde_json::to_string(meta).expect("json to_string");
+            let mut slot = crc_algo.checksum(json.as_bytes()).to_be_bytes().to_vec();
+            slot.extend(json.as_bytes());
+            slot.extend(b"\n\0");
+            slot
+        }
+        Encoding::Cbor => {
+            let mut payload = vec![];
+            ciborium::into_writer(meta, &mut payload).expect("cbor into_writer");
+            let mut slot = crc_algo.checksum(&payload).to_be_bytes().to_vec();
+            slot.push(CBOR_TAG);
+            slot.extend((payload.len() as u32).to_be_bytes());
+            slot.extend(payload);
+            slot
+        }
+    }
+}
+
+/// Parse metadata of any supported version from JSON text
+pub(crate) fn decode_json(json: &str) -> Result<SuperPartition, MapperError> {
+    let value: Value = serde_json::from_str(json)
+        .map_err(|e| MapperError::MetadataCorrupt(format!("can't parse json: {}", e)))?;
+    decode(value, Encoding::Json)
+}
+
+/// Bring metadata of any supported version up to date
+fn decode(mut value: Value, encoding: Encoding) -> Result<SuperPartition, MapperError> {
+    let version = match value.get("format_version") {
+        None => 0,
+        Some(v) => v.as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| MapperError::MetadataCorrupt(format!("bad format version {}", v)))?,
+    };
+    if version > FORMAT_VERSION {
+        return Err(MapperError::UnsupportedFormat { found: version, supported: FORMAT_VERSION });
+    }
+
+    for from in version..FORMAT_VERSION {
+        upgrade(&mut value, from);
+    }
+    let mut meta: SuperPartition = serde_json::from_value(value)
+        .map_err(|e| MapperError::MetadataCorrupt(format!("can't parse metadata: {}", e)))?;
+    // Remember what is actually on disk until the next commit rewrites it
+    meta.format_version = version;
+    meta.encoding = encoding;
+    Ok(meta)
+}
+
+impl SuperPartition {
+    /// Schema version of the metadata as it was last read or written
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// Encoding the metadata is read and written in
+    pub fn metadata_encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Switch the encoding used from the next commit on.  Older builds can
+    /// only read JSON.
+    pub fn set_metadata_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// Whether the metadata on disk predates this build's format
+    pub fn needs_migration(&self) -> bool {
+        self.format_version < FORMAT_VERSION
+    }
+
+    /// Rewrite the metadata in the current format if it is older.  Returns
+    /// whether anything was written.
+    pub fn migrate(&mut self) -> Result<bool, MapperError> {
+        if !self.needs_migration() {
+            return Ok(false);
+        }
+        self.commit()?;
+        Ok(true)
+    }
+}
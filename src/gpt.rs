@@ -0,0 +1,568 @@
+//! GUID partition tables.
+//!
+//! A whole disk partitioned with GPT can be adopted in one go.  Each
+//! partition becomes a subvolume mapping the same blocks, named after the
+//! partition and keeping its type, and the protective MBR and primary
+//! table at the start of the disk are kept out of the allocator by the
+//! reserved `gpt` entry.  The backup table at the end makes way for the
+//! metadata.  Once the metadata is committed the table is wiped, so that
+//! nothing reaches the data through partition devices behind device
+//! mapper's back, and the kernel is asked to forget the partitions.
+//!
+//! Partitions have to start on a block boundary.  One that ends partway
+//! through a block is rounded up to the end of it, if that space is free.
+//!
+//! Exporting goes the other way, for when the data has to be reachable
+//! without mercury-mapper.  Every subvolume has to be plain and a single
+//! extent on the first device, clear of where the two tables go; each
+//! becomes a partition of its recorded type, or of the generic Linux data
+//! type, numbered in disk order, and the metadata is wiped once the tables
+//! are written.
+//!
+//! In hybrid mode the super partition stays, and every commit also writes
+//! a shadow GPT with an entry for each subvolume that could be exported,
+//! so that bootloaders and recovery tools that only know GPT can still
+//! find the images.  Both copies of the shadow table live in the reserved
+//! `gpt` entry at the start of the disk, since the metadata has the end;
+//! tools that want the backup at the very end will offer to move it, which
+//! has to be declined.  Partition numbers follow disk order, so they can
+//! change as subvolumes come and go.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+
+use nix::libc::c_int;
+
+use crate::label::ReplicaPlacement;
+use crate::{blksszget, is_reserved, Extent, FormatOptions, MapperError, Step, SubVolume, SuperPartition,
+    METADATA_SUBVOL};
+
+/// Reserved entry covering the start of the disk, where the protective
+/// MBR and the primary table are
+pub(crate) const GPT_SUBVOL: &str = "gpt";
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const HEADER_SIZE: usize = 92;
+const ENTRY_NAME_UNITS: usize = 36;
+const ENTRY_SIZE: u32 = 128;
+/// Entries in a table we write, the smallest the specification allows
+const ENTRY_COUNT: u32 = 128;
+/// "Linux filesystem data", for partitions of any other type
+const LINUX_DATA_GUID: &str = "0fc63daf-8483-4772-8e79-3d69d8477de4";
+
+nix::ioctl_none_bad!(blkrrpart, nix::request_code_none!(0x12, 95));
+
+/// One used entry of a partition table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct GptPartition {
+    /// Position in the table, from 1 as partition devices are numbered
+    pub(crate) number: u32,
+    pub(crate) name: String,
+    pub(crate) type_guid: [u8; 16],
+    pub(crate) unique_guid: [u8; 16],
+    /// First and last sector, inclusive
+    pub(crate) first_lba: u64,
+    pub(crate) last_lba: u64,
+}
+
+/// A partition table as read from a disk
+#[derive(Debug, Clone)]
+pub(crate) struct Gpt {
+    pub(crate) sector_size: u64,
+    pub(crate) disk_guid: [u8; 16],
+    /// Sector the protective MBR covers up to
+    pub(crate) last_lba: u64,
+    pub(crate) first_usable_lba: u64,
+    pub(crate) last_usable_lba: u64,
+    pub(crate) backup_lba: u64,
+    pub(crate) entry_count: u32,
+    pub(crate) entry_size: u32,
+    pub(crate) partitions: Vec<GptPartition>,
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(bytes)
+}
+
+fn le32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().expect("slice length"))
+}
+
+fn le64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().expect("slice length"))
+}
+
+/// The on-disk form of a UUID given as text, whose first three fields
+/// GPT stores little-endian
+pub(crate) fn guid_from_uuid(uuid: &str) -> Option<[u8; 16]> {
+    let hex: String = uuid.chars().filter(|c| *c != '-').collect();
+    if hex.len() != 32 || uuid.len() != 36 {
+        return None;
+    }
+    let mut b = [0; 16];
+    for (i, byte) in b.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    b[..4].reverse();
+    b[4..6].reverse();
+    b[6..8].reverse();
+    Some(b)
+}
+
+/// The text form of a GUID as stored on disk, in lowercase
+pub(crate) fn uuid_from_guid(guid: &[u8; 16]) -> String {
+    let mut b = *guid;
+    b[..4].reverse();
+    b[4..6].reverse();
+    b[6..8].reverse();
+    let hex: String = b.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+fn random_guid() -> std::io::Result<[u8; 16]> {
+    Ok(guid_from_uuid(&crate::probe::new_uuid()?).expect("well-formed UUID"))
+}
+
+/// Size of the sectors the partition table is addressed in.  Regular
+/// files don't have one, and get the usual 512.
+fn sector_size(f: &File) -> u64 {
+    let mut size: c_int = 0;
+    match unsafe { blksszget(f.as_raw_fd(), &mut size) } {
+        Ok(_) if size > 0 => size as u64,
+        _ => 512,
+    }
+}
+
+impl Gpt {
+    /// The primary partition table on `f`, or None if it has no valid one
+    pub(crate) fn read(f: &mut File) -> Result<Option<Self>, MapperError> {
+        let sector_size = sector_size(f);
+        let mut header = vec![0; sector_size as usize];
+        f.seek(SeekFrom::Start(sector_size))?;
+        if f.read_exact(&mut header).is_err() || &header[..8] != GPT_SIGNATURE {
+            return Ok(None);
+        }
+        let header_size = le32(&header, 12) as usize;
+        if !(HEADER_SIZE..=header.len()).contains(&header_size) {
+            return Ok(None);
+        }
+        let mut check = header[..header_size].to_vec();
+        check[16..20].fill(0);
+        if crc32(&check) != le32(&header, 16) {
+            return Ok(None);
+        }
+
+        let entries_lba = le64(&header, 72);
+        let entry_count = le32(&header, 80);
+        let entry_size = le32(&header, 84);
+        if entry_size < 128 || entry_count > 4096 {
+            return Ok(None);
+        }
+        let mut entries = vec![0; entry_count as usize * entry_size as usize];
+        f.seek(SeekFrom::Start(entries_lba * sector_size))?;
+        f.read_exact(&mut entries)?;
+        if crc32(&entries) != le32(&header, 88) {
+            return Ok(None);
+        }
+
+        let mut partitions = vec![];
+        for (i, entry) in entries.chunks(entry_size as usize).enumerate() {
+            let type_guid: [u8; 16] = entry[..16].try_into().expect("slice length");
+            if type_guid == [0; 16] {
+                continue;
+            }
+            let units: Vec<u16> = entry[56..56 + 2 * ENTRY_NAME_UNITS].chunks(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|u| *u != 0)
+                .collect();
+            partitions.push(GptPartition {
+                number: i as u32 + 1,
+                name: String::from_utf16_lossy(&units),
+                type_guid,
+                unique_guid: entry[16..32].try_into().expect("slice length"),
+                first_lba: le64(entry, 32),
+                last_lba: le64(entry, 40),
+            });
+        }
+        partitions.sort_by_key(|p| p.first_lba);
+
+        Ok(Some(Self {
+            sector_size,
+            disk_guid: header[56..72].try_into().expect("slice length"),
+            last_lba: le64(&header, 32),
+            first_usable_lba: le64(&header, 40),
+            last_usable_lba: le64(&header, 48),
+            backup_lba: le64(&header, 32),
+            entry_count,
+            entry_size,
+            partitions,
+        }))
+    }
+
+    /// An empty table for a disk of `sectors` sectors, with the backup at
+    /// the end
+    fn new(sector_size: u64, sectors: u64, disk_guid: [u8; 16]) -> Self {
+        let mut gpt = Self {
+            sector_size,
+            disk_guid,
+            last_lba: sectors - 1,
+            first_usable_lba: 0,
+            last_usable_lba: 0,
+            backup_lba: sectors - 1,
+            entry_count: ENTRY_COUNT,
+            entry_size: ENTRY_SIZE,
+            partitions: vec![],
+        };
+        gpt.first_usable_lba = 2 + gpt.entries_sectors();
+        gpt.last_usable_lba = gpt.backup_lba - gpt.entries_sectors() - 1;
+        gpt
+    }
+
+    /// Bytes at the start of the disk taken by a shadow table, both copies
+    fn shadow_bytes(sector_size: u64) -> u64 {
+        let entries = (ENTRY_COUNT as u64 * ENTRY_SIZE as u64).next_multiple_of(sector_size);
+        3 * sector_size + 2 * entries
+    }
+
+    /// Bytes taken by the table entries
+    fn entries_bytes(&self) -> u64 {
+        (self.entry_count as u64 * self.entry_size as u64).next_multiple_of(self.sector_size)
+    }
+
+    fn entries_sectors(&self) -> u64 {
+        self.entries_bytes() / self.sector_size
+    }
+
+    fn encode_entries(&self) -> Vec<u8> {
+        let mut entries = vec![0; self.entries_bytes() as usize];
+        for part in &self.partitions {
+            let at = (part.number - 1) as usize * self.entry_size as usize;
+            let entry = &mut entries[at..at + self.entry_size as usize];
+            entry[..16].copy_from_slice(&part.type_guid);
+            entry[16..32].copy_from_slice(&part.unique_guid);
+            entry[32..40].copy_from_slice(&part.first_lba.to_le_bytes());
+            entry[40..48].copy_from_slice(&part.last_lba.to_le_bytes());
+            for (i, unit) in part.name.encode_utf16().take(ENTRY_NAME_UNITS).enumerate() {
+                entry[56 + 2 * i..58 + 2 * i].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        entries
+    }
+
+    /// The header at `lba`, the other copy being at `alternate_lba` and the
+    /// entries at `entries_lba`
+    fn encode_header(&self, lba: u64, alternate_lba: u64, entries_lba: u64, entries: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; self.sector_size as usize];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&self.first_usable_lba.to_le_bytes());
+        header[48..56].copy_from_slice(&self.last_usable_lba.to_le_bytes());
+        header[56..72].copy_from_slice(&self.disk_guid);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&self.entry_count.to_le_bytes());
+        header[84..88].copy_from_slice(&self.entry_size.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&entries[..(self.entry_count * self.entry_size) as usize]).to_le_bytes());
+        let crc = crc32(&header[..HEADER_SIZE]);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    /// The protective MBR, primary header and entries, which go at the
+    /// start of the disk
+    fn encode_primary(&self) -> Vec<u8> {
+        let entries = self.encode_entries();
+        let mut mbr = vec![0; self.sector_size as usize];
+        let sectors = self.last_lba.min(u32::MAX as u64) as u32;
+        // One partition of type 0xee covering the disk, CHS fields maxed out
+        mbr[446..462].copy_from_slice(&[0, 0, 2, 0, 0xee, 0xff, 0xff, 0xff, 1, 0, 0, 0,
+            sectors as u8, (sectors >> 8) as u8, (sectors >> 16) as u8, (sectors >> 24) as u8]);
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+        let mut primary = mbr;
+        primary.extend(self.encode_header(1, self.backup_lba, 2, &entries));
+        primary.extend(entries);
+        primary
+    }
+
+    /// The backup entries and header, which end with the backup header,
+    /// and the byte offset they start at
+    fn encode_backup(&self) -> (u64, Vec<u8>) {
+        let entries = self.encode_entries();
+        let entries_lba = self.backup_lba - self.entries_sectors();
+        let header = self.encode_header(self.backup_lba, 1, entries_lba, &entries);
+        let mut backup = entries;
+        backup.extend(header);
+        (entries_lba * self.sector_size, backup)
+    }
+}
+
+impl SuperPartition {
+    /// Convert a disk partitioned with GPT into a new super partition,
+    /// with a subvolume for each partition.  Unlike `adopt` this commits
+    /// the new metadata itself, as the partition table is only wiped once
+    /// the subvolumes standing in for it are safely recorded.  Partitions
+    /// without a name are called `partN` after their number.
+    pub fn adopt_gpt(device: String, options: &FormatOptions) -> Result<Self, MapperError> {
+        if options.placement != ReplicaPlacement::End {
+            return Err(MapperError::InvalidArgument("the partition table occupies the start of the device \
+                where the label would go".to_string()));
+        }
+        let mut sp = Self::new_layout(device, None, options)?;
+        let gpt = Gpt::read(&mut File::open(&sp.device)?)?
+            .ok_or_else(|| MapperError::InvalidArgument(format!("{} has no valid GPT", sp.device)))?;
+        let iosize = sp.iosize;
+
+        let table_blocks = (gpt.first_usable_lba * gpt.sector_size).div_ceil(iosize);
+        sp.subvols.insert(GPT_SUBVOL.to_string(), SubVolume::new(vec![Extent::new(0, 0, table_blocks)], iosize));
+
+        for part in &gpt.partitions {
+            let name = match part.name.is_empty() {
+                true => format!("part{}", part.number),
+                false => part.name.clone(),
+            };
+            let start = part.first_lba * gpt.sector_size;
+            let end = (part.last_lba + 1) * gpt.sector_size;
+            if !start.is_multiple_of(iosize) {
+                return Err(MapperError::InvalidArgument(format!("partition {} ({}) does not start on a {}-byte boundary",
+                    part.number, name, iosize)));
+            }
+            let extent = Extent::new(0, start / iosize, end.div_ceil(iosize) - start / iosize);
+            sp.check_new_name(&name)?;
+            sp.check_free(std::slice::from_ref(&extent)).map_err(|_e| {
+                MapperError::InvalidArgument(format!("partition {} ({}) overlaps the metadata or another partition \
+                    once rounded to {}-byte blocks", part.number, name, iosize))
+            })?;
+            let mut sv = SubVolume::new(vec![extent], iosize);
+            sv.type_guid = Some(uuid_from_guid(&part.type_guid));
+            sp.subvols.insert(name, sv);
+        }
+
+        // The backup table goes first, while the primary can still be
+        // relied on should the commit fail
+        let entries = gpt.entries_bytes();
+        let backup_entries = gpt.backup_lba * gpt.sector_size - entries;
+        sp.zero_range(0, backup_entries, entries + gpt.sector_size)?;
+        sp.commit()?;
+        sp.zero_range(0, 0, gpt.first_usable_lba * gpt.sector_size)?;
+        if !sp.is_dry_run() {
+            // Fails for regular files, and while a partition is open
+            let _ = unsafe { blkrrpart(File::open(&sp.device)?.as_raw_fd()) };
+        }
+        Ok(sp)
+    }
+
+    /// Write a GPT onto the device with a partition for each subvolume,
+    /// then wipe the metadata, leaving a plain partitioned disk with the
+    /// data where it was.  Returns the partitions in the order they are
+    /// numbered.  Active subvolumes are refused, as nothing could take
+    /// their devices down afterwards, and nothing more can be committed
+    /// once this returns.
+    pub fn export_gpt(&mut self) -> Result<Vec<(u32, String)>, MapperError> {
+        self.check_writable()?;
+        if let Some(name) = self.pending_relocation() {
+            return Err(MapperError::InvalidArgument(format!("{} is being relocated; resume or roll back defrag first", name)));
+        }
+        let mut f = File::open(&self.device)?;
+        let sector_size = sector_size(&f);
+        let sectors = f.seek(SeekFrom::End(0))? / sector_size;
+        if !self.iosize.is_multiple_of(sector_size) {
+            return Err(MapperError::InvalidArgument(format!("{}-byte blocks are not whole {}-byte sectors",
+                self.iosize, sector_size)));
+        }
+        let disk_guid = match guid_from_uuid(&self.uuid) {
+            Some(guid) => guid,
+            None => random_guid()?,
+        };
+        let mut gpt = Gpt::new(sector_size, sectors, disk_guid);
+
+        let mut names: Vec<_> = self.subvols.keys().filter(|name| !is_reserved(name) && !self.is_external(name)).collect();
+        names.sort_by_key(|name| self.subvols[*name].extents.first().map(|e| (e.device, e.block_offset)));
+        for (number, name) in (1..).zip(names) {
+            if name.encode_utf16().count() > ENTRY_NAME_UNITS {
+                return Err(MapperError::InvalidArgument(format!("{} is too long for a partition name", name)));
+            }
+            if number > ENTRY_COUNT {
+                return Err(MapperError::InvalidArgument(format!("a GPT holds at most {} partitions", ENTRY_COUNT)));
+            }
+            let mut part = self.gpt_partition(number, name, &gpt)
+                .map_err(|why| MapperError::InvalidArgument(format!("{} {}", name, why)))?;
+            if part.unique_guid == [0; 16] {
+                part.unique_guid = random_guid()?;
+            }
+            if !self.is_dry_run() && self.dm_active(name)? {
+                return Err(MapperError::InvalidArgument(format!("{} is active; deactivate it first", name)));
+            }
+            gpt.partitions.push(part);
+        }
+
+        // The primary table goes first, so that a crash leaves the data
+        // reachable one way or the other
+        let primary = gpt.encode_primary();
+        let (backup_offset, backup) = gpt.encode_backup();
+        self.write_table(0, &primary)?;
+        let tables = [(0, primary.len() as u64), (backup_offset, backup.len() as u64)];
+        let mut metadata = self.subvols[METADATA_SUBVOL].extents.clone();
+        metadata.extend(self.history.iter().map(|h| h.extent().clone()));
+        for e in metadata {
+            let (start, end) = (e.block_offset * self.iosize, (e.block_offset + e.block_length) * self.iosize);
+            // Zero what the tables don't overwrite anyway
+            let start = tables.iter().fold(start, |pos, &(at, len)| match at <= pos && pos < at + len {
+                true => at + len,
+                false => pos,
+            });
+            let end = tables.iter().fold(end, |pos, &(at, len)| match at < pos && pos <= at + len {
+                true => at,
+                false => pos,
+            });
+            if start < end {
+                self.zero_range(0, start, end - start)?;
+            }
+        }
+        self.write_table(backup_offset, &backup)?;
+        self.read_only = true;
+
+        if !self.is_dry_run() {
+            // As for adopting, the kernel may not be able to reread it yet
+            let _ = unsafe { blkrrpart(f.as_raw_fd()) };
+        }
+        Ok(gpt.partitions.into_iter().map(|p| (p.number, p.name)).collect())
+    }
+
+    /// The entry for subvolume `name` as partition `number` of `gpt`, or
+    /// why it can't have one
+    fn gpt_partition(&self, number: u32, name: &str, gpt: &Gpt) -> Result<GptPartition, &'static str> {
+        let sv = &self.subvols[name];
+        let [extent] = sv.extents.as_slice() else {
+            return Err("is not a single extent; defrag it first");
+        };
+        if !sv.is_plain() || extent.device != 0 {
+            return Err("is not a plain subvol on the first device");
+        }
+        let first_lba = extent.block_offset * self.iosize / gpt.sector_size;
+        let last_lba = (extent.block_offset + extent.block_length) * self.iosize / gpt.sector_size - 1;
+        if first_lba < gpt.first_usable_lba || last_lba > gpt.last_usable_lba {
+            return Err("overlaps where the partition table goes");
+        }
+        Ok(GptPartition {
+            number,
+            name: name.to_string(),
+            type_guid: sv.type_guid.as_deref().and_then(guid_from_uuid)
+                .unwrap_or_else(|| guid_from_uuid(LINUX_DATA_GUID).expect("well-formed UUID")),
+            // Assigned on the first commit, before anything is exported
+            unique_guid: guid_from_uuid(&sv.uuid).unwrap_or_default(),
+            first_lba,
+            last_lba,
+        })
+    }
+
+    /// Record the GPT partition type subvolume `name` is exported with, or
+    /// clear it to have the generic Linux data type
+    pub fn set_type_guid(&mut self, name: &str, type_guid: Option<&str>) -> Result<(), MapperError> {
+        self.check_not_reserved(name)?;
+        let type_guid = match type_guid {
+            Some(text) => {
+                let guid = guid_from_uuid(text)
+                    .ok_or_else(|| MapperError::InvalidArgument(format!("{} is not a GUID", text)))?;
+                Some(uuid_from_guid(&guid))
+            }
+            None => None,
+        };
+        self.subvol_mut(name)?.type_guid = type_guid;
+        self.commit()
+    }
+
+    /// Keep a shadow GPT at the start of the device from now on, rewritten
+    /// on every commit.  The start has to be free, or already reserved by
+    /// adopting a GPT disk, so this can't be used with a label.
+    pub fn enable_hybrid_gpt(&mut self) -> Result<(), MapperError> {
+        if self.hybrid_gpt {
+            return Err(MapperError::AlreadyExists("hybrid GPT".to_string()));
+        }
+        if self.label.is_some() {
+            return Err(MapperError::InvalidArgument("the label occupies the start of the device \
+                where the table would go".to_string()));
+        }
+        let sector_size = sector_size(&File::open(&self.device)?);
+        if !self.iosize.is_multiple_of(sector_size) {
+            return Err(MapperError::InvalidArgument(format!("{}-byte blocks are not whole {}-byte sectors",
+                self.iosize, sector_size)));
+        }
+        let needed = Gpt::shadow_bytes(sector_size);
+        match self.subvols.get(GPT_SUBVOL) {
+            Some(sv) if sv.extents.first().is_some_and(|e| e.block_offset == 0) && sv.size_bytes() >= needed => (),
+            Some(_sv) => {
+                return Err(MapperError::InvalidArgument("the reserved gpt entry is too small for the table".to_string()));
+            }
+            None => {
+                let extent = Extent::new(0, 0, needed.div_ceil(self.iosize));
+                self.check_free(std::slice::from_ref(&extent))?;
+                self.subvols.insert(GPT_SUBVOL.to_string(), SubVolume::new(vec![extent], self.iosize));
+            }
+        }
+        self.hybrid_gpt = true;
+        self.commit()
+    }
+
+    /// Whether a shadow GPT is kept
+    pub fn hybrid_gpt(&self) -> bool {
+        self.hybrid_gpt
+    }
+
+    /// Stop keeping a shadow GPT, wiping it and freeing its space
+    pub fn disable_hybrid_gpt(&mut self) -> Result<(), MapperError> {
+        if !self.hybrid_gpt {
+            return Ok(());
+        }
+        self.check_writable()?;
+        self.zero_range(0, 0, Gpt::shadow_bytes(sector_size(&File::open(&self.device)?)))?;
+        self.hybrid_gpt = false;
+        self.subvols.remove(GPT_SUBVOL);
+        self.commit()
+    }
+
+    /// Rewrite the shadow GPT from the subvolumes as committed.  Those that
+    /// couldn't be exported are left out, and long names are cut short.
+    pub(crate) fn write_shadow_gpt(&self) -> Result<(), MapperError> {
+        let mut f = File::open(&self.device)?;
+        let sector_size = sector_size(&f);
+        let sectors = f.seek(SeekFrom::End(0))? / sector_size;
+        let reserved = &self.subvols[GPT_SUBVOL];
+        let metadata_start = self.subvols[METADATA_SUBVOL].extents.iter().map(|e| e.block_offset).min()
+            .expect("metadata has extents");
+        let mut gpt = Gpt::new(sector_size, sectors, guid_from_uuid(&self.uuid).unwrap_or_default());
+        gpt.backup_lba = 2 + 2 * gpt.entries_sectors();
+        gpt.first_usable_lba = reserved.size_bytes() / sector_size;
+        gpt.last_usable_lba = metadata_start * self.iosize / sector_size - 1;
+
+        let mut names: Vec<_> = self.subvols.keys().filter(|name| !is_reserved(name) && !self.is_external(name)).collect();
+        names.sort_by_key(|name| self.subvols[*name].extents.first().map(|e| (e.device, e.block_offset)));
+        for name in names {
+            let number = gpt.partitions.len() as u32 + 1;
+            if number > ENTRY_COUNT {
+                break;
+            }
+            if let Ok(part) = self.gpt_partition(number, name, &gpt) {
+                gpt.partitions.push(part);
+            }
+        }
+
+        let (backup_offset, backup) = gpt.encode_backup();
+        self.write_table(0, &gpt.encode_primary())?;
+        self.write_table(backup_offset, &backup)
+    }
+
+    fn write_table(&self, offset: u64, data: &[u8]) -> Result<(), MapperError> {
+        if self.plan_step(|| Step::Write { path: self.device.clone(), offset, len: data.len() as u64 }) {
+            return Ok(());
+        }
+        let blockdev = OpenOptions::new().write(true).open(&self.device)?;
+        blockdev.write_all_at(data, offset)?;
+        blockdev.sync_all()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+//! A ring of past metadata generations.
+//!
+//! Once enabled, every commit also writes the new generation into the next
+//! entry of a ring reserved on the metadata device, so the last few
+//! generations can be listed with their commit times and any one of them
+//! switched back to.  Entries are laid out exactly like a replica.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{slots, CreateOptions, Extent, MapperError, SuperPartition};
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub(crate) struct History {
+    extent: Extent,
+    /// Blocks per entry
+    slot_blocks: u64,
+    entries: u64,
+    /// Count of generations written so far; the next goes in entry
+    /// `next % entries`
+    next: u64,
+}
+
+impl History {
+    pub(crate) fn extent(&self) -> &Extent {
+        &self.extent
+    }
+
+    pub(crate) fn slot_blocks(&self) -> u64 {
+        self.slot_blocks
+    }
+}
+
+/// A generation kept in the history ring
+#[derive(Serialize,Debug,Clone,PartialEq)]
+pub struct HistoryEntry {
+    pub generation: u32,
+    /// Seconds since the Unix epoch, or 0 if unknown
+    pub committed_at: u64,
+    pub subvol_count: usize,
+}
+
+/// Current time for stamping commits
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl SuperPartition {
+    /// Start keeping the last `entries` generations
+    pub fn enable_history(&mut self, entries: u64) -> Result<(), MapperError> {
+        if self.history.is_some() {
+            return Err(MapperError::AlreadyExists("history".to_string()));
+        }
+        if entries == 0 {
+            return Err(MapperError::InvalidArgument("history needs at least one entry".to_string()));
+        }
+
+        // The entries have to be in one piece on the metadata device
+        let needed = entries * self.metadata_blocks;
+        let holes: Vec<_> = self.free_extents().into_iter().filter(|h| h.device == 0).collect();
+        let largest = holes.iter().map(|h| h.block_length).max().unwrap_or(0);
+        if largest < needed {
+            return Err(MapperError::NoContiguousSpace { needed, largest });
+        }
+        let holes = holes.into_iter().filter(|h| h.block_length >= needed).collect();
+        let extent = self.allocate_from(CreateOptions::default().strategy.order(holes, needed), needed)?;
+
+        self.history = Some(History {
+            extent: extent[0].clone(),
+            slot_blocks: self.metadata_blocks,
+            entries,
+            next: 0,
+        });
+        self.commit()
+    }
+
+    /// Stop keeping history and release its space
+    pub fn disable_history(&mut self) -> Result<(), MapperError> {
+        if self.history.take().is_some() {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Claim the ring entry for the generation about to be written
+    pub(crate) fn next_history_entry(&mut self) -> Option<u64> {
+        let history = self.history.as_mut()?;
+        let entry = history.next % history.entries;
+        history.next += 1;
+        Some(history.extent.block_offset + entry * history.slot_blocks)
+    }
+
+    /// Write the current generation into the ring at `start`
+    pub(crate) fn write_history_entry(&self, start: u64) -> Result<(), MapperError> {
+        let history = self.history.as_ref().expect("history not enabled");
+        let data = slots::encode_replica(self, history.slot_blocks, self.iosize)?;
+        self.with_store(true, |blockdev| {
+            blockdev.write_bytes(start * self.iosize, &data)?;
+            Ok(blockdev.sync()?)
+        })
+    }
+
+    fn history_generations(&self) -> Result<Vec<SuperPartition>, MapperError> {
+        let history = match &self.history {
+            Some(history) => history,
+            None => return Ok(vec![]),
+        };
+        let used = std::cmp::min(history.next, history.entries);
+        let mut generations: Vec<_> = self.with_store(false, |blockdev| Ok((0..used)
+            .filter_map(|entry| {
+                let start = history.extent.block_offset + entry * history.slot_blocks;
+                slots::read_replica(blockdev, start, history.slot_blocks, self.iosize).ok()
+            })
+            .collect()))?;
+        generations.sort_by_key(|m| std::cmp::Reverse(m.generation));
+        Ok(generations)
+    }
+
+    /// The generations in the ring, newest first.  Entries that no longer
+    /// read back correctly are left out.
+    pub fn history(&self) -> Result<Vec<HistoryEntry>, MapperError> {
+        Ok(self.history_generations()?.iter().map(|m| {
+            HistoryEntry {
+                generation: m.generation,
+                committed_at: m.committed_at,
+                subvol_count: m.subvols.len(),
+            }
+        }).collect())
+    }
+
+    /// Switch back to the layout of `generation` from the ring
+    pub fn restore_generation(&mut self, generation: u32) -> Result<(), MapperError> {
+        let target = self.history_generations()?.into_iter()
+            .find(|m| m.generation == generation)
+            .ok_or_else(|| MapperError::NotFound(format!("generation {}", generation)))?;
+        self.switch_layout(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{format, MIB};
+
+    #[test]
+    fn history_skips_holes_too_small_for_it() {
+        let (mut sp, _dm) = format();
+        sp.create_subvol("a".to_string(), MIB).expect("create a");
+        sp.create_subvol("b".to_string(), 4 * MIB).expect("create b");
+        sp.delete_subvol_by_name("a").expect("delete a");
+
+        sp.enable_history(4).expect("enable history");
+        let extent = &sp.history.as_ref().expect("history").extent;
+        assert_eq!(extent.block_length, 4);
+        assert_ne!(extent.block_offset, 0);
+
+        sp.disable_history().expect("disable history");
+        assert!(matches!(sp.enable_history(1000), Err(MapperError::NoContiguousSpace { needed: 1000, largest })
+            if largest == sp.free_extents().iter().map(|h| h.block_length).max().unwrap_or(0)));
+    }
+}
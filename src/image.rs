@@ -0,0 +1,650 @@
+//! Streaming images into and out of subvolumes.
+//!
+//! Images go through the subvolume's device node rather than straight to
+//! its extents, so whatever is stacked on them, such as encryption or
+//! integrity, applies as it would to any other writer.  For writing the
+//! node is opened exclusively, which fails while something has it
+//! mounted.  Images compressed with zstd, gzip or xz are decompressed,
+//! and Android sparse images, as fastboot takes them, are expanded on the
+//! way; see the sparse module.  Reading can stop at the end of the
+//! filesystem at the start of the subvolume, so a captured image is no
+//! bigger than it has to be; ext2/3/4, squashfs and EROFS are recognised.
+//! The type of the filesystem can also be told from its superblock, which
+//! XFS and F2FS are recognised for as well.
+//!
+//! A write can be verified by reading the subvolume back once it has been
+//! synced, with the page cache dropped so the data comes from the device.
+//! The SHA-256 of what was read back is then kept in the metadata.  For a
+//! sparse image that covers the regions it left alone as well, as they
+//! read back, so it is the digest of the subvolume rather than of the
+//! file.  A digest can also be recorded for whatever a subvolume holds,
+//! and checked later, which for a read-only image gives an end-to-end
+//! check of its data without setting up dm-verity.
+//!
+//! Writing a plain image commits a journal entry every so often with how
+//! far the write has got and the SHA-256 of what it has written.  An
+//! interrupted write can then carry on from there, once what is on the
+//! subvolume has been checked against that hash, rather than starting
+//! over.  Compressed and sparse images are not journaled, as there is no
+//! picking up a stream in the middle.
+
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::time::Duration;
+
+use flate2::read::MultiGzDecoder;
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+use nix::libc::O_EXCL;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use xz2::read::XzDecoder;
+
+use crate::sparse::{SparseHeader, SPARSE_MAGIC};
+use crate::verity::hex;
+use crate::{MapperError, Step, SuperPartition};
+
+/// How much of an image is read and written at a time
+const IMAGE_CHUNK: usize = 1024 * 1024;
+
+/// Bytes written between commits of the journal of a plain image
+const CHECKPOINT_BYTES: u64 = 64 * IMAGE_CHUNK as u64;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0];
+
+/// How `SuperPartition::write_image_with` writes an image
+#[derive(Default)]
+pub struct WriteOptions<'a> {
+    /// Length of the image as read, compressed or not, if known in
+    /// advance.  It is checked against what the reader held once it runs
+    /// out, and unless compressed, against the subvolume before anything
+    /// is written.
+    pub length: Option<u64>,
+    /// Called with the number of bytes of the image read so far after
+    /// each chunk is written
+    pub progress: Option<&'a mut dyn FnMut(u64)>,
+    /// Read the subvolume back afterwards to check that it holds what was
+    /// written, and record its digest.  Skipped on a dry run.
+    pub verify: bool,
+    /// Carry on with the interrupted write to the subvolume.  The image
+    /// is then the rest of what was being written, starting where the
+    /// journal says the write got to; see
+    /// `SuperPartition::interrupted_write`.
+    pub resume: bool,
+    /// Write the image as it is, without looking for compression or a
+    /// sparse header, as for data copied from another subvolume
+    pub raw: bool,
+}
+
+/// Journal of a write of a plain image, committed every so often so that
+/// it can be resumed if interrupted
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ImageWrite {
+    /// UUID of the subvolume being written, so a rename doesn't lose track
+    uuid: String,
+    /// Bytes known to have reached the subvolume
+    written: u64,
+    /// SHA-256 of those bytes, checked against what is there on resuming
+    sha256: String,
+}
+
+/// SHA-256 of the first `length` bytes of a subvolume
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContentDigest {
+    pub sha256: String,
+    pub length: u64,
+}
+
+/// How `SuperPartition::read_image_with` reads a subvolume
+#[derive(Default)]
+pub struct ReadOptions<'a> {
+    /// Read no more than this many bytes
+    pub length: Option<u64>,
+    /// Stop at the end of the filesystem the subvolume starts with.  It is
+    /// an error if no filesystem is recognised.
+    pub filesystem: bool,
+    /// Called with the number of bytes read so far after each chunk
+    pub progress: Option<&'a mut dyn FnMut(u64)>,
+}
+
+// Little-endian fields of on-disk headers.  The callers read fixed-size
+// header buffers at constant offsets that lie within them, and the slice
+// taken is exactly as long as the integer, so the conversion can't fail.
+pub(crate) fn le16(buf: &[u8], at: usize) -> u64 {
+    u16::from_le_bytes(buf[at..at + 2].try_into().expect("slice length")).into()
+}
+
+pub(crate) fn le32(buf: &[u8], at: usize) -> u64 {
+    u32::from_le_bytes(buf[at..at + 4].try_into().expect("slice length")).into()
+}
+
+fn le64(buf: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(buf[at..at + 8].try_into().expect("slice length"))
+}
+
+/// How long to wait for udev to create the node of a subvolume activated
+/// to be read
+pub(crate) const NODE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes at the start of a device that filesystems are recognised from
+const HEAD_SIZE: usize = 4096;
+
+const EXT_SUPER: usize = 1024;
+const EXT_MAGIC: u64 = 0xef53;
+const EXT_INCOMPAT_64BIT: u64 = 0x80;
+const SQUASHFS_MAGIC: u64 = 0x7371_7368;
+const EROFS_SUPER: usize = 1024;
+const EROFS_MAGIC: u64 = 0xe0f5_e1e2;
+const XFS_MAGIC: &[u8] = b"XFSB";
+const F2FS_SUPER: usize = 1024;
+const F2FS_MAGIC: u64 = 0xf2f5_2010;
+
+/// Whether `head` starts with a FAT boot sector
+fn is_fat(head: &[u8]) -> bool {
+    let sector_size = le16(head, 11);
+    matches!(head[0], 0xeb | 0xe9) && head[510..512] == [0x55, 0xaa]
+        && sector_size.is_power_of_two() && (512..=4096).contains(&sector_size)
+        && (head[54..57] == *b"FAT" || head[82..87] == *b"FAT32")
+}
+
+/// Size in bytes of the filesystem whose superblock is in `head`, the
+/// first 4KiB of a device
+fn filesystem_size(head: &[u8]) -> Option<u64> {
+    if le16(head, EXT_SUPER + 0x38) == EXT_MAGIC {
+        let mut blocks = le32(head, EXT_SUPER + 0x4);
+        if le32(head, EXT_SUPER + 0x60) & EXT_INCOMPAT_64BIT != 0 {
+            blocks |= le32(head, EXT_SUPER + 0x150) << 32;
+        }
+        return blocks.checked_shl(10 + le32(head, EXT_SUPER + 0x18) as u32);
+    }
+    if le32(head, 0) == SQUASHFS_MAGIC {
+        return Some(le64(head, 0x28));
+    }
+    if le32(head, EROFS_SUPER) == EROFS_MAGIC {
+        return le32(head, EROFS_SUPER + 36).checked_shl(head[EROFS_SUPER + 12].into());
+    }
+    if le32(head, F2FS_SUPER) == F2FS_MAGIC {
+        return le64(head, F2FS_SUPER + 36).checked_shl(le32(head, F2FS_SUPER + 16) as u32);
+    }
+    if is_fat(head) {
+        // The 16-bit count is zero when the 32-bit one is needed
+        let sectors = match le16(head, 19) {
+            0 => le32(head, 32),
+            sectors => sectors,
+        };
+        return Some(sectors * le16(head, 11));
+    }
+    None
+}
+
+/// Type of the filesystem whose superblock is in `head`, the first 4KiB
+/// of a device, as mount knows it.  ext2 and ext3 are given as ext4,
+/// which mounts them all.
+fn filesystem_type(head: &[u8]) -> Option<&'static str> {
+    if le16(head, EXT_SUPER + 0x38) == EXT_MAGIC {
+        return Some("ext4");
+    }
+    if le32(head, 0) == SQUASHFS_MAGIC {
+        return Some("squashfs");
+    }
+    if le32(head, EROFS_SUPER) == EROFS_MAGIC {
+        return Some("erofs");
+    }
+    if head.starts_with(XFS_MAGIC) {
+        return Some("xfs");
+    }
+    if le32(head, F2FS_SUPER) == F2FS_MAGIC {
+        return Some("f2fs");
+    }
+    if is_fat(head) {
+        return Some("vfat");
+    }
+    None
+}
+
+/// Size in bytes of the filesystem at the start of `device`, if one of
+/// the filesystems whose size can be told from its superblock is there
+pub fn probe_filesystem_size(device: &str) -> Result<Option<u64>, MapperError> {
+    let mut head = [0; HEAD_SIZE];
+    let n = read_chunk(&mut File::open(device)?, &mut head)?;
+    Ok((n == HEAD_SIZE).then(|| filesystem_size(&head)).flatten())
+}
+
+/// Reader that counts what it has given out
+struct Counted<'a, R> {
+    inner: R,
+    count: &'a Cell<u64>,
+}
+
+impl<R: Read> Read for Counted<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// Passes on how much of an image has been read, as counted by `Counted`
+pub(crate) struct Progress<'a> {
+    count: &'a Cell<u64>,
+    callback: Option<&'a mut dyn FnMut(u64)>,
+}
+
+impl Progress<'_> {
+    pub(crate) fn report(&mut self) {
+        if let Some(callback) = &mut self.callback {
+            callback(self.count.get());
+        }
+    }
+}
+
+/// Read up to `N` bytes from the start of `reader`, returning them along
+/// with how many there were
+fn peek<const N: usize>(reader: &mut impl Read) -> io::Result<([u8; N], usize)> {
+    let mut buf = [0; N];
+    let n = read_chunk(reader, &mut buf)?;
+    Ok((buf, n))
+}
+
+/// `image` decompressed if it is a zstd, gzip or xz stream, and whether it
+/// was
+fn decompress<'a>(mut image: impl Read + 'a) -> io::Result<(Box<dyn Read + 'a>, bool)> {
+    let (magic, n) = peek::<6>(&mut image)?;
+    let head = &magic[..n];
+    let image = io::Cursor::new(magic).take(n as u64).chain(image);
+    let decoded: Box<dyn Read> = if head.starts_with(&ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::new(image)?)
+    } else if head.starts_with(&GZIP_MAGIC) {
+        Box::new(MultiGzDecoder::new(image))
+    } else if head == XZ_MAGIC {
+        Box::new(XzDecoder::new_multi_decoder(image))
+    } else {
+        return Ok((Box::new(image), false));
+    };
+    Ok((decoded, true))
+}
+
+/// Where an image's data goes: the subvolume's device node, or on a dry
+/// run, the plan, with neighbouring writes recorded as one
+pub(crate) struct Output {
+    node: Option<File>,
+    path: String,
+    /// Offset and length of each write to be planned, in order
+    planned: Vec<(u64, u64)>,
+    /// Hash of everything written, and where it went, if the write is to
+    /// be verified
+    written: Option<Written>,
+}
+
+pub(crate) struct Written {
+    hash: Sha256,
+    /// Offset and length of each run written, in order
+    ranges: Vec<(u64, u64)>,
+}
+
+impl Output {
+    pub(crate) fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), MapperError> {
+        let len = data.len() as u64;
+        if let Some(written) = &mut self.written {
+            written.hash.update(data);
+            match written.ranges.last_mut() {
+                Some((start, run)) if *start + *run == offset => *run += len,
+                _ => written.ranges.push((offset, len)),
+            }
+        }
+        match &mut self.node {
+            Some(node) => {
+                node.seek(SeekFrom::Start(offset))?;
+                node.write_all(data)?;
+            }
+            None => match self.planned.last_mut() {
+                Some((start, planned)) if *start + *planned == offset => *planned += len,
+                _ => self.planned.push((offset, len)),
+            },
+        }
+        Ok(())
+    }
+
+    /// Make sure everything written so far has reached the device
+    fn sync(&self) -> Result<(), MapperError> {
+        if let Some(node) = &self.node {
+            node.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Like `sync`, also planning the writes on a dry run, and if they are
+    /// to be verified, making sure they will be read from the device
+    /// rather than from the page cache
+    fn finish(self, sp: &SuperPartition) -> Result<Option<Written>, MapperError> {
+        for (offset, len) in self.planned {
+            sp.plan_step(|| Step::Write { path: self.path.clone(), offset, len });
+        }
+        if let Some(node) = &self.node {
+            node.sync_all()?;
+            if self.written.is_some() {
+                posix_fadvise(node.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)
+                    .map_err(io::Error::from)?;
+            }
+        }
+        Ok(self.written)
+    }
+}
+
+/// Read from `reader` until `buf` is full or the reader runs out,
+/// returning how much was read
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Hash the first `length` bytes of `node`, and separately just the bytes
+/// in `ranges`, which are in order and don't overlap
+fn hash_back(node: &mut File, length: u64, ranges: &[(u64, u64)]) -> Result<(Sha256, Sha256), MapperError> {
+    let mut whole = Sha256::new();
+    let mut written = Sha256::new();
+    let mut buf = vec![0; IMAGE_CHUNK];
+    let mut ranges = ranges.iter().peekable();
+    let mut pos = 0;
+    while pos < length {
+        let n = read_chunk(node, &mut buf[..(length - pos).min(IMAGE_CHUNK as u64) as usize])?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let end = pos + n as u64;
+        whole.update(&buf[..n]);
+        while let Some(&&(start, len)) = ranges.peek() {
+            let (from, to) = (start.max(pos), (start + len).min(end));
+            if from < to {
+                written.update(&buf[(from - pos) as usize..(to - pos) as usize]);
+            }
+            if start + len > end {
+                break;
+            }
+            ranges.next();
+        }
+        pos = end;
+    }
+    Ok((whole, written))
+}
+
+impl SuperPartition {
+    /// Copy everything `image` holds to the start of subvolume `name`,
+    /// activating it if needed, and returning how many bytes were written
+    pub fn write_image(&mut self, name: &str, image: impl Read) -> Result<u64, MapperError> {
+        self.write_image_with(name, image, &mut WriteOptions::default())
+    }
+
+    /// Like `write_image`, as described by `options`.  Compressed images
+    /// are decompressed and Android sparse images expanded as they are
+    /// written.  An image that turns out
+    /// to be bigger than the subvolume is an error once the subvolume is
+    /// full, with the rest left unread.  Any digest recorded for the
+    /// subvolume is dropped, and replaced if the write is verified.
+    ///
+    /// Writes of plain images are journaled as they go, so that if one is
+    /// interrupted it can be resumed with `options.resume`.  Only the
+    /// latest interrupted write can be; starting another drops its
+    /// journal.
+    pub fn write_image_with(&mut self, name: &str, image: impl Read, options: &mut WriteOptions) -> Result<u64, MapperError> {
+        self.check_writable()?;
+        self.check_not_reserved(name)?;
+        self.check_not_external(name)?;
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        if sv.read_only {
+            return Err(MapperError::InvalidArgument(format!("{} is read-only", name)));
+        }
+        let capacity = sv.size_bytes();
+        let uuid = sv.uuid.clone();
+        let too_large = || MapperError::InvalidArgument(format!("image is larger than {} ({} bytes)", name, capacity));
+        let resume = match options.resume {
+            true => Some(self.image_write.as_ref().filter(|w| w.uuid == uuid).cloned()
+                .ok_or_else(|| MapperError::InvalidArgument(format!("no interrupted write to {} to resume", name)))?),
+            false => None,
+        };
+        let offset = resume.as_ref().map_or(0, |w| w.written);
+
+        // Count what comes in before anything is decompressed or
+        // expanded, since that is what `length` and progress are about
+        let count = Cell::new(0);
+        let image = Counted { inner: image, count: &count };
+        let (mut image, compressed) = match options.raw {
+            true => (Box::new(image) as Box<dyn Read>, false),
+            false => decompress(image)?,
+        };
+        let (magic, peeked) = peek::<4>(&mut image)?;
+        let sparse = match !options.raw && peeked == magic.len() && u32::from_le_bytes(magic) == SPARSE_MAGIC {
+            true => Some(SparseHeader::read(&mut image)?),
+            false => None,
+        };
+        if resume.is_some() && (compressed || sparse.is_some()) {
+            return Err(MapperError::InvalidArgument("the rest of an interrupted write must be a plain image".to_string()));
+        }
+        let expanded = match &sparse {
+            Some(header) => Some(header.size()),
+            None => options.length.filter(|_len| !compressed).map(|len| offset + len),
+        };
+        if expanded.is_some_and(|len| len > capacity) {
+            return Err(too_large());
+        }
+
+        let stale = self.image_write.is_some() && resume.is_none();
+        if sv.digest.is_some() || stale {
+            self.subvol_mut(name)?.digest = None;
+            if stale {
+                self.image_write = None;
+            }
+            self.commit()?;
+        }
+        let journal = !compressed && sparse.is_none() && !self.is_dry_run();
+        if !self.dm_active(name)? {
+            self.activate(name)?;
+        }
+        let path = self.subvol_path(name);
+        let verify = options.verify && !self.is_dry_run();
+        let mut out = Output {
+            node: match self.is_dry_run() {
+                true => None,
+                false => Some(OpenOptions::new().write(true).custom_flags(O_EXCL).open(&path)?),
+            },
+            path: path.display().to_string(),
+            planned: vec![],
+            written: verify.then(|| Written { hash: Sha256::new(), ranges: vec![] }),
+        };
+
+        // What is already on the subvolume has to be what the journal says
+        // was written, and its hash is where the running hash carries on
+        let mut running = Sha256::new();
+        if let Some(resume) = resume.filter(|_resume| !self.is_dry_run()) {
+            (running, _) = hash_back(&mut File::open(&path)?, offset, &[])?;
+            if hex(&running.clone().finalize()) != resume.sha256 {
+                return Err(MapperError::InvalidArgument(format!("{} has changed since the write to it was interrupted", name)));
+            }
+        }
+
+        // Unless it was the start of a sparse header, what was peeked at
+        // is part of the image
+        let replay = if sparse.is_some() { 0 } else { peeked };
+        let mut image = (&magic[..replay]).chain(image);
+        let mut progress = Progress {
+            count: &count,
+            callback: options.progress.as_mut().map(|progress| &mut **progress as &mut dyn FnMut(u64)),
+        };
+        let mut buf = vec![0; IMAGE_CHUNK];
+
+        let written = match &sparse {
+            Some(header) => out.write_sparse(header, &mut image, &mut buf, &mut progress)?,
+            None => {
+                let mut written = offset;
+                loop {
+                    let n = read_chunk(&mut image, &mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    if written + n as u64 > capacity {
+                        return Err(too_large());
+                    }
+                    out.write_at(written, &buf[..n])?;
+                    written += n as u64;
+                    progress.report();
+
+                    if journal {
+                        running.update(&buf[..n]);
+                        if written % CHECKPOINT_BYTES == 0 {
+                            out.sync()?;
+                            let sha256 = hex(&running.clone().finalize());
+                            self.image_write = Some(ImageWrite { uuid: uuid.clone(), written, sha256 });
+                            self.commit()?;
+                        }
+                    }
+                }
+                written
+            }
+        };
+        let verified = out.finish(self)?;
+
+        if let Some(len) = options.length.filter(|len| *len != count.get()) {
+            return Err(MapperError::InvalidArgument(format!("image was {} bytes, expected {}", count.get(), len)));
+        }
+        let mut changed = self.image_write.take_if(|w| w.uuid == uuid).is_some();
+        if let Some(verified) = verified {
+            let (whole, ranges) = hash_back(&mut File::open(&path)?, written, &verified.ranges)?;
+            if ranges.finalize() != verified.hash.finalize() {
+                return Err(MapperError::VerifyFailed(name.to_string()));
+            }
+            let sha256 = hex(&whole.finalize());
+            self.subvol_mut(name)?.digest = Some(ContentDigest { sha256, length: written });
+            changed = true;
+        }
+        if changed {
+            self.commit()?;
+        }
+        Ok(written)
+    }
+
+    /// SHA-256 of the first `length` bytes of subvolume `name` as the
+    /// device has them, activating it if needed
+    fn digest_of(&self, name: &str, length: u64) -> Result<String, MapperError> {
+        if !self.dm_active(name)? {
+            self.activate(name)?;
+        }
+        let mut node = File::open(self.subvol_path(name))?;
+        posix_fadvise(node.as_raw_fd(), 0, 0, PosixFadviseAdvice::POSIX_FADV_DONTNEED)
+            .map_err(io::Error::from)?;
+        let (whole, _written) = hash_back(&mut node, length, &[])?;
+        Ok(hex(&whole.finalize()))
+    }
+
+    /// Read all of subvolume `name` and record the digest of what it
+    /// holds, returning that
+    pub fn hash_subvol(&mut self, name: &str) -> Result<ContentDigest, MapperError> {
+        self.check_writable()?;
+        self.check_not_reserved(name)?;
+        self.check_not_external(name)?;
+        let length = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?
+            .size_bytes();
+        let digest = ContentDigest { sha256: self.digest_of(name, length)?, length };
+        self.subvol_mut(name)?.digest = Some(digest.clone());
+        self.commit()?;
+        Ok(digest)
+    }
+
+    /// Read subvolume `name` back and compare it with the digest recorded
+    /// for it, returning whether it still matches.  A subvolume shrunk
+    /// since is a mismatch.
+    pub fn verify_subvol(&self, name: &str) -> Result<bool, MapperError> {
+        self.check_not_reserved(name)?;
+        self.check_not_external(name)?;
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        let digest = sv.digest.as_ref()
+            .ok_or_else(|| MapperError::InvalidArgument(format!("no digest recorded for {}", name)))?;
+        if digest.length > sv.size_bytes() {
+            return Ok(false);
+        }
+        Ok(self.digest_of(name, digest.length)? == digest.sha256)
+    }
+
+    /// How many bytes of the interrupted write to subvolume `name` are
+    /// known to have been written, if it has one that can be resumed
+    pub fn interrupted_write(&self, name: &str) -> Option<u64> {
+        let sv = self.subvols.get(name)?;
+        self.image_write.as_ref().filter(|w| w.uuid == sv.uuid).map(|w| w.written)
+    }
+
+    /// Type of the filesystem at the start of subvolume `name`, activating
+    /// it and waiting for its node if needed, or None if none is recognised
+    pub fn detect_filesystem(&self, name: &str) -> Result<Option<&'static str>, MapperError> {
+        self.check_not_reserved(name)?;
+        self.check_not_external(name)?;
+        if !self.subvols.contains_key(name) {
+            return Err(MapperError::NotFound(name.to_string()));
+        }
+        if !self.dm_active(name)? {
+            self.activate(name)?;
+        }
+        let path = self.wait_for_subvol(name, NODE_TIMEOUT)?;
+        let mut head = [0; HEAD_SIZE];
+        let n = read_chunk(&mut File::open(path)?, &mut head)?;
+        Ok((n == HEAD_SIZE).then(|| filesystem_type(&head)).flatten())
+    }
+
+    /// Copy the contents of subvolume `name` to `out`, activating it if
+    /// needed, and returning how many bytes were read
+    pub fn read_image(&self, name: &str, out: impl Write) -> Result<u64, MapperError> {
+        self.read_image_with(name, out, &mut ReadOptions::default())
+    }
+
+    /// Like `read_image`, as described by `options`
+    pub fn read_image_with(&self, name: &str, mut out: impl Write, options: &mut ReadOptions) -> Result<u64, MapperError> {
+        self.check_not_reserved(name)?;
+        self.check_not_external(name)?;
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        let mut length = sv.size_bytes();
+        if !self.dm_active(name)? {
+            self.activate(name)?;
+        }
+        let mut node = File::open(self.subvol_path(name))?;
+
+        let mut buf = vec![0; IMAGE_CHUNK];
+        let mut filled = read_chunk(&mut node, &mut buf[..length.min(IMAGE_CHUNK as u64) as usize])?;
+        if options.filesystem {
+            let head = &buf[..filled.min(HEAD_SIZE)];
+            let size = (head.len() == HEAD_SIZE).then(|| filesystem_size(head)).flatten()
+                .ok_or_else(|| MapperError::InvalidArgument(format!("no filesystem recognised on {}", name)))?;
+            length = length.min(size);
+        }
+        if let Some(limit) = options.length {
+            length = length.min(limit);
+        }
+
+        let mut read = 0;
+        while read < length && filled > 0 {
+            let n = filled.min((length - read) as usize);
+            out.write_all(&buf[..n])?;
+            read += n as u64;
+            if let Some(progress) = &mut options.progress {
+                progress(read);
+            }
+            let want = (length - read).min(IMAGE_CHUNK as u64) as usize;
+            filled = read_chunk(&mut node, &mut buf[..want])?;
+        }
+        out.flush()?;
+        Ok(read)
+    }
+}
@@ -0,0 +1,83 @@
+//! Descriptive fields on subvolumes.
+//!
+//! The version, author and timedate strings, and any tags, are free-form
+//! and never interpreted.  Subvolumes get a timedate when they are created, in RFC
+//! 3339 form and UTC; anything can be set in its place afterwards.
+
+use crate::{history, MapperError, SubVolume, SubVolumeInfo, SuperPartition};
+
+/// Format `secs` since the epoch as an RFC 3339 UTC timestamp
+pub(crate) fn rfc3339(secs: u64) -> String {
+    let days = secs / 86400;
+    let rem = secs % 86400;
+
+    // Howard Hinnant's civil_from_days, which counts years from March so
+    // that leap days fall at the end
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
+
+/// The current time as an RFC 3339 UTC timestamp
+pub(crate) fn timestamp() -> String {
+    rfc3339(history::now())
+}
+
+impl SuperPartition {
+    /// Summary of one subvolume
+    pub fn subvol_info(&self, name: &str) -> Option<SubVolumeInfo<'_>> {
+        self.subvols().find(|info| info.name == name)
+    }
+
+    pub fn set_version(&mut self, name: &str, version: &str) -> Result<(), MapperError> {
+        self.subvol_mut(name)?.version = version.to_string();
+        self.commit()
+    }
+
+    pub fn set_author(&mut self, name: &str, author: &str) -> Result<(), MapperError> {
+        self.subvol_mut(name)?.author = author.to_string();
+        self.commit()
+    }
+
+    pub fn set_timedate(&mut self, name: &str, timedate: &str) -> Result<(), MapperError> {
+        self.subvol_mut(name)?.timedate = timedate.to_string();
+        self.commit()
+    }
+
+    /// Attach `value` to a subvolume under `key`, replacing any value
+    /// already there
+    pub fn set_tag(&mut self, name: &str, key: &str, value: &str) -> Result<(), MapperError> {
+        if key.is_empty() {
+            return Err(MapperError::InvalidArgument("tag key is empty".to_string()));
+        }
+        self.subvol_mut(name)?.tags.insert(key.to_string(), value.to_string());
+        self.commit()
+    }
+
+    pub fn get_tag(&self, name: &str, key: &str) -> Result<Option<&str>, MapperError> {
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        Ok(sv.tags.get(key).map(String::as_str))
+    }
+
+    /// Remove the tag `key` from a subvolume, returning whether it had one
+    pub fn remove_tag(&mut self, name: &str, key: &str) -> Result<bool, MapperError> {
+        if self.subvol_mut(name)?.tags.remove(key).is_none() {
+            return Ok(false);
+        }
+        self.commit()?;
+        Ok(true)
+    }
+
+    pub(crate) fn subvol_mut(&mut self, name: &str) -> Result<&mut SubVolume, MapperError> {
+        self.subvols.get_mut(name).ok_or_else(|| MapperError::NotFound(name.to_string()))
+    }
+}
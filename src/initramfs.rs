@@ -0,0 +1,126 @@
+//! Hooks that activate subvolumes from an initramfs.
+//!
+//! The generated scripts find the super partition by its UUID with
+//! `hgmap scan` and activate the chosen subvolumes with `hgmap activate`,
+//! so the hgmap binary is copied into the initramfs with them.  Both
+//! dracut and initramfs-tools run the activation repeatedly while they
+//! wait for the root device, so it gives up quietly until the super
+//! partition shows up, and does nothing once it has succeeded.  The base
+//! name of an A/B pair activates its subvolume in the slot `hgmap
+//! boot-attempt` picks, which counts the boot against that slot.
+
+use crate::{is_reserved, MapperError, SuperPartition};
+
+/// Marker left once the subvolumes are active
+const DONE_MARKER: &str = "/run/hgmap-activated";
+
+/// Where the slot picked for this boot is kept, so a retry doesn't count
+/// the boot twice
+const SLOT_MARKER: &str = "/run/hgmap-slot";
+
+/// Which initramfs generator the hook is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitramfsFlavor {
+    /// A module for /usr/lib/dracut/modules.d
+    Dracut,
+    /// Scripts for /etc/initramfs-tools
+    InitramfsTools,
+}
+
+/// One executable file of an initramfs hook
+#[derive(Debug, Clone)]
+pub struct InitramfsFile {
+    /// Relative to the directory the flavor keeps its hooks in
+    pub path: &'static str,
+    pub contents: String,
+}
+
+/// `s` quoted for a POSIX shell
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+impl SuperPartition {
+    /// Files making up a hook that activates subvolumes `names` at early
+    /// boot, or every subvolume if none are given
+    pub fn initramfs_hook(&self, flavor: InitramfsFlavor, names: &[&str]) -> Result<Vec<InitramfsFile>, MapperError> {
+        let activate = self.activation_script(names)?;
+        let header = format!("# Generated by hgmap for super partition {}", self.uuid);
+        Ok(match flavor {
+            InitramfsFlavor::Dracut => vec![
+                InitramfsFile {
+                    path: "90hgmap/module-setup.sh",
+                    contents: format!("#!/bin/bash\n{}\n\n\
+                        check() {{\n    require_binaries hgmap || return 1\n    return 0\n}}\n\n\
+                        depends() {{\n    echo dm\n    return 0\n}}\n\n\
+                        install() {{\n    inst_multiple hgmap\n    \
+                        inst_script \"$moddir/hgmap-activate.sh\" /sbin/hgmap-activate\n    \
+                        inst_hook initqueue/settled 50 \"$moddir/hgmap-settled.sh\"\n}}\n", header),
+                },
+                InitramfsFile {
+                    path: "90hgmap/hgmap-activate.sh",
+                    contents: format!("#!/bin/sh\n{}\n\n{}", header, activate),
+                },
+                InitramfsFile {
+                    path: "90hgmap/hgmap-settled.sh",
+                    contents: format!("#!/bin/sh\n{}\n\n[ -e {} ] || /sbin/hgmap-activate\n", header, DONE_MARKER),
+                },
+            ],
+            InitramfsFlavor::InitramfsTools => {
+                let prereqs = "PREREQ=\"\"\nprereqs() {\n    echo \"$PREREQ\"\n}\n\
+                    case \"$1\" in\nprereqs)\n    prereqs\n    exit 0\n    ;;\nesac\n";
+                vec![
+                    InitramfsFile {
+                        path: "hooks/hgmap",
+                        contents: format!("#!/bin/sh\n{}\n\n{}\n. /usr/share/initramfs-tools/hook-functions\n\
+                            copy_exec \"$(command -v hgmap)\" /sbin\n", header, prereqs),
+                    },
+                    InitramfsFile {
+                        path: "scripts/local-block/hgmap",
+                        contents: format!("#!/bin/sh\n{}\n\n{}\n{}", header, prereqs, activate),
+                    },
+                ]
+            }
+        })
+    }
+
+    /// Shell commands that find the super partition and activate `names`
+    /// on it, exiting non-zero if that can't be done yet
+    fn activation_script(&self, names: &[&str]) -> Result<String, MapperError> {
+        let slotted = self.slotted();
+        let mut subvols = vec![];
+        let mut needs_slot = false;
+        for name in names {
+            if self.subvols.contains_key(*name) {
+                self.check_not_reserved(name)?;
+                self.check_not_external(name)?;
+                subvols.push(sh_quote(name));
+            } else if slotted.contains(name) {
+                subvols.push(format!("{}\"_$slot\"", sh_quote(name)));
+                needs_slot = true;
+            } else {
+                return Err(MapperError::NotFound(name.to_string()));
+            }
+        }
+        if names.is_empty() {
+            let mut all: Vec<_> = self.subvols.keys()
+                .filter(|name| !is_reserved(name) && !self.is_external(name))
+                .collect();
+            all.sort();
+            subvols.extend(all.into_iter().map(|name| sh_quote(name)));
+        }
+
+        let mut script = format!("[ -e {done} ] && exit 0\n\n\
+            device=$(hgmap scan | while read -r dev uuid; do\n    \
+            if [ \"$uuid\" = {uuid} ]; then\n        echo \"$dev\"\n        break\n    fi\ndone)\n\
+            [ -n \"$device\" ] || exit 1\n",
+            done = DONE_MARKER, uuid = sh_quote(&self.uuid));
+        if needs_slot {
+            script.push_str(&format!("\nif [ -e {marker} ]; then\n    slot=$(cat {marker})\nelse\n    \
+                slot=$(hgmap boot-attempt \"$device\") || exit 1\n    echo \"$slot\" > {marker}\nfi\n",
+                marker = SLOT_MARKER));
+        }
+        script.push_str(&format!("\nhgmap activate \"$device\" {} || exit 1\n: > {}\n", subvols.join(" "), DONE_MARKER));
+        Ok(script)
+    }
+}
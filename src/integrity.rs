@@ -0,0 +1,54 @@
+//! Silent corruption detection with dm-integrity.
+//!
+//! The subvolume's extents are mapped by `<name>-integ` and a standalone
+//! integrity target on top keeps a checksum for every sector alongside the
+//! data, failing reads whose checksum does not match.  The checksums and
+//! the target's journal live inside the subvolume's own extents, so the
+//! extents are sized for the data plus that overhead and the allocator sees
+//! the true footprint.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{MapperError, SuperPartition};
+
+pub(crate) const INTEGRITY_SUFFIX: &str = "integ";
+
+/// Bytes of tag stored per sector; crc32c
+pub(crate) const TAG_SIZE: u64 = 4;
+/// Size of the journal, in 512-byte sectors
+pub(crate) const JOURNAL_SECTORS: u64 = 8192;
+/// Room for the superblock and the rounding of the tag areas
+const SLACK_BYTES: u64 = 1 << 20;
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub(crate) struct Integrity {
+    /// Bytes available for data once the overhead is taken out
+    data_size: u64,
+}
+
+impl Integrity {
+    pub(crate) fn data_size(&self) -> u64 {
+        self.data_size
+    }
+}
+
+/// Space taken by the integrity metadata for `data_size` bytes of data
+fn overhead_bytes(data_size: u64) -> u64 {
+    let tag_sectors = (data_size / 512 * TAG_SIZE).div_ceil(512);
+    (tag_sectors + JOURNAL_SECTORS) * 512 + SLACK_BYTES
+}
+
+impl SuperPartition {
+    /// Create a subvolume with `size` bytes of checksummed space
+    pub fn create_integrity_subvol(&mut self, name: String, size: u64) -> Result<(), MapperError> {
+        let data_size = size.div_ceil(512) * 512;
+        let mut sv = self.new_subvol(&name, data_size + overhead_bytes(data_size))?;
+
+        // The target formats itself on first activation if it finds no
+        // superblock, rather than trusting whatever was left there
+        self.zero_range(sv.extents[0].device, sv.extents[0].block_offset * self.iosize, 4096)?;
+
+        sv.integrity = Some(Integrity { data_size });
+        self.insert_subvol(name, sv)
+    }
+}
@@ -0,0 +1,98 @@
+//! Intent journal for layout changes that take more than one step.
+//!
+//! Shrinking or deleting a subvolume has to stop its DM mapping from
+//! reaching the released blocks before the new layout is committed, or
+//! they could be handed out while still mapped.  The layout being moved to
+//! is recorded in the metadata before the mapping is touched, and cleared
+//! by the commit that makes it current.  If that commit never happens,
+//! `open` finds the record and finishes the change.  Defrag keeps its own
+//! relocation journal, since its data copy is resumed rather than replayed.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{MapperError, SubVolume, SuperPartition};
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub(crate) struct Intent {
+    /// Layout each touched subvolume is moving to, or None if it is being
+    /// deleted
+    subvols: BTreeMap<String, Option<SubVolume>>,
+}
+
+impl SuperPartition {
+    /// Change the subvolumes in `subvols` to the given layouts, running
+    /// `step` to bring their mappings in line first.  The change is
+    /// recorded before `step` runs and made current once it succeeds; if
+    /// it fails, the record is dropped and the layout stays as it was.
+    pub(crate) fn journaled<F>(&mut self, subvols: BTreeMap<String, Option<SubVolume>>, step: F) -> Result<(), MapperError>
+    where
+        F: FnOnce(&Self) -> Result<(), MapperError>,
+    {
+        self.intent = Some(Intent { subvols });
+        self.commit()?;
+        if let Err(e) = step(self) {
+            self.intent = None;
+            let _ = self.commit();
+            return Err(e);
+        }
+        self.finish_intent();
+        self.commit()
+    }
+
+    /// Switch to the recorded layouts and drop the record
+    fn finish_intent(&mut self) {
+        let Some(intent) = self.intent.take() else {
+            return;
+        };
+        for (name, sv) in intent.subvols {
+            match sv {
+                Some(sv) => self.subvols.insert(name, sv),
+                None => self.subvols.remove(&name),
+            };
+        }
+    }
+
+    /// Complete a change that was interrupted before its final commit
+    pub(crate) fn recover_intent(&mut self) -> Result<(), MapperError> {
+        let Some(intent) = &self.intent else {
+            return Ok(());
+        };
+        // The interruption may have come before a deleted subvolume's
+        // mapping was torn down, and nothing would remove it afterwards
+        let deleted: Vec<_> = intent.subvols.iter()
+            .filter(|(_, sv)| sv.is_none())
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &deleted {
+            if self.dm_active(name)? {
+                self.remove_dm(name)?;
+            }
+        }
+        self.finish_intent();
+        self.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{format, MIB};
+    use crate::DmBackend;
+
+    #[test]
+    fn recovering_a_delete_removes_the_device() {
+        let (mut sp, dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create");
+
+        // As left by a delete interrupted before its mapping went away
+        sp.intent = Some(Intent { subvols: [("a".to_string(), None)].into() });
+        sp.commit().expect("commit");
+        sp.recover_intent().expect("recover");
+
+        assert!(!dm.exists("hg-a").expect("exists"));
+        assert!(!sp.subvols.contains_key("a"));
+        assert!(sp.intent.is_none());
+    }
+}
@@ -0,0 +1,184 @@
+//! Placing the metadata replicas somewhere other than the end of the device.
+//!
+//! Without a label the two replicas sit at the very end of the device.
+//! `format` can instead put them at the start and end, or at offsets of the
+//! caller's choosing, recording where in a small label at the start of the
+//! device.  The label is written once and never changes afterwards, so
+//! nothing about finding the metadata depends on the metadata itself.
+//!
+//! The label is a 4 KiB area holding the magic, a big-endian version, the
+//! block size, the number of blocks in each replica and the starting block
+//! of each replica, followed by a CRC32 of all of that.
+
+use crate::{BlockStore, MapperError, Step, SuperPartition, METADATA_SUBVOL};
+
+const LABEL_MAGIC: [u8; 8] = *b"HGMAPLB\0";
+const LABEL_VERSION: u32 = 1;
+pub(crate) const LABEL_SIZE: usize = 4096;
+
+/// Where the metadata replicas go on a new super partition
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ReplicaPlacement {
+    /// Both replicas in the last blocks of the device, with no label
+    #[default]
+    End,
+    /// One replica just after the label at the start of the device, the
+    /// other in the last blocks
+    BeginEnd,
+    /// Replicas starting at these byte offsets, which must be multiples of
+    /// the block size
+    Offsets([u64; 2]),
+}
+
+/// Layout choices for a new super partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatOptions {
+    pub placement: ReplicaPlacement,
+    /// Blocks in each metadata replica
+    pub metadata_blocks: u64,
+    /// Record the writes in a plan rather than making them
+    pub dry_run: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            placement: ReplicaPlacement::End,
+            metadata_blocks: 1,
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Label {
+    pub(crate) iosize: u64,
+    pub(crate) slot_blocks: u64,
+    /// First block of replica 1 and 2
+    pub(crate) slots: [u64; 2],
+}
+
+impl Label {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = LABEL_MAGIC.to_vec();
+        buf.extend(LABEL_VERSION.to_be_bytes());
+        buf.extend(self.iosize.to_be_bytes());
+        buf.extend(self.slot_blocks.to_be_bytes());
+        buf.extend(self.slots[0].to_be_bytes());
+        buf.extend(self.slots[1].to_be_bytes());
+        let crc_algo = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
+        buf.extend(crc_algo.checksum(&buf).to_be_bytes());
+        buf.resize(LABEL_SIZE, 0);
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf[..8] != LABEL_MAGIC {
+            return None;
+        }
+        let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().expect("slice length"));
+        let version = u32::from_be_bytes(buf[8..12].try_into().expect("slice length"));
+        let crc = u32::from_be_bytes(buf[44..48].try_into().expect("slice length"));
+        let crc_algo = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
+        if version != LABEL_VERSION || crc != crc_algo.checksum(&buf[..44]) {
+            return None;
+        }
+        Some(Self {
+            iosize: u64_at(12),
+            slot_blocks: u64_at(20),
+            slots: [u64_at(28), u64_at(36)],
+        })
+    }
+}
+
+/// The label at the start of `f`, if it has one
+pub(crate) fn read_label(f: &mut dyn BlockStore) -> Result<Option<Label>, MapperError> {
+    let mut buf = vec![0; LABEL_SIZE];
+    match f.read_bytes(0, &mut buf) {
+        Ok(()) => Ok(Label::decode(&buf)),
+        // Too small to hold a label
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl SuperPartition {
+    /// Create a new, empty super partition on `device`, discarding
+    /// whatever it held.  As with `adopt`, nothing is written until the
+    /// first commit, apart from the label if the placement needs one.
+    pub fn format(device: String, options: &FormatOptions) -> Result<Self, MapperError> {
+        let sp = Self::new_layout(device, None, options)?;
+        sp.clear_old_metadata()?;
+        Ok(sp)
+    }
+
+    /// Zero where the replicas are to go and write the label, if there is
+    /// one, for a super partition that has just been laid out
+    pub(crate) fn clear_old_metadata(&self) -> Result<(), MapperError> {
+        // Leftover metadata from an earlier life of the device could have a
+        // higher generation than ours and win when it is next opened
+        for e in &self.subvols[METADATA_SUBVOL].extents {
+            self.zero_range(0, e.block_offset * self.iosize, e.block_length * self.iosize)?;
+        }
+        if let Some(label) = &self.label {
+            let planned = self.plan_step(|| Step::Write { path: self.device.clone(), offset: 0, len: LABEL_SIZE as u64 });
+            if !planned {
+                self.with_store(true, |store| {
+                    store.write_bytes(0, &label.encode())?;
+                    Ok(store.sync()?)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lay out the label and replicas for `options` on a device of
+    /// `device_blocks` blocks, returning the label if one is needed and the
+    /// extents the metadata occupies
+    pub(crate) fn plan_replicas(iosize: u64, device_blocks: u64, options: &FormatOptions) -> Result<(Option<Label>, Vec<crate::Extent>), MapperError> {
+        let n = options.metadata_blocks;
+        if n == 0 || n > crate::slots::MAX_SLOT_BLOCKS {
+            return Err(MapperError::InvalidArgument(format!("metadata slots must be 1 to {} blocks",
+                crate::slots::MAX_SLOT_BLOCKS)));
+        }
+        let extent = |block_offset, block_length| crate::Extent {
+            device: 0,
+            block_offset,
+            block_length,
+        };
+
+        let slots = match &options.placement {
+            ReplicaPlacement::End => {
+                if 2 * n > device_blocks {
+                    return Err(MapperError::NoSpace { needed: 2 * n, available: device_blocks });
+                }
+                return Ok((None, vec![extent(device_blocks - 2 * n, 2 * n)]));
+            }
+            ReplicaPlacement::BeginEnd => [1, device_blocks.saturating_sub(n)],
+            ReplicaPlacement::Offsets(offsets) => {
+                if offsets.iter().any(|o| !o.is_multiple_of(iosize)) {
+                    return Err(MapperError::InvalidArgument(format!("replica offsets must be multiples of {}", iosize)));
+                }
+                [offsets[0] / iosize, offsets[1] / iosize]
+            }
+        };
+
+        // The label gets the first block to itself
+        let mut extents = vec![extent(0, 1)];
+        for start in slots {
+            extents.push(extent(start, n));
+        }
+        extents.sort();
+        let overlapping = extents.windows(2).any(|w| w[0].block_offset + w[0].block_length > w[1].block_offset);
+        if overlapping || slots.iter().any(|s| s + n > device_blocks) {
+            return Err(MapperError::InvalidArgument("metadata replicas overlap or run off the device".to_string()));
+        }
+
+        let label = Label {
+            iosize,
+            slot_blocks: n,
+            slots,
+        };
+        Ok((Some(label), extents))
+    }
+}
@@ -0,0 +1,214 @@
+//! Converging a super partition on a declared layout.
+//!
+//! A layout file lists the subvolumes that should exist, with their sizes
+//! and optionally their flags and tags:
+//!
+//! ```toml
+//! [subvols.system]
+//! size = 1073741824
+//! read_only = true
+//! tags = { build = "1234" }
+//! ```
+//!
+//! Applying it creates whatever is missing and brings flags and tags in
+//! line.  Resizing and removing subvolumes the layout doesn't mention lose
+//! data, so they only happen when asked for and are otherwise reported.
+//! All the changes go through one transaction.  Diffing against a layout
+//! lists the same changes without making them, and another super
+//! partition can stand in for the file by taking its layout.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::{is_reserved, CreateOptions, MapperError, SuperPartition};
+
+/// Desired set of subvolumes
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    #[serde(default)]
+    pub subvols: BTreeMap<String, SubvolSpec>,
+}
+
+/// Desired state of one subvolume.  Flags and tags left out are not
+/// changed.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SubvolSpec {
+    /// In bytes
+    pub size: u64,
+    /// Only used when creating the subvolume
+    #[serde(default)]
+    pub contiguous: bool,
+    pub read_only: Option<bool>,
+    pub protected: Option<bool>,
+    /// The complete set of tags, replacing any others
+    pub tags: Option<BTreeMap<String, String>>,
+}
+
+/// One step towards a layout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Create { name: String, size: u64 },
+    /// A subvolume the layout doesn't mention
+    Remove { name: String },
+    Resize { name: String, from: u64, to: u64 },
+    SetReadOnly { name: String, read_only: bool },
+    SetProtected { name: String, protected: bool },
+    /// Set a tag, or remove it if `value` is None
+    SetTag { name: String, key: String, value: Option<String> },
+}
+
+/// Which of the changes that lose data `apply_layout` may make
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ApplyOptions {
+    pub resize: bool,
+    pub remove_extras: bool,
+}
+
+/// What `apply_layout` did
+#[derive(Debug, Clone, Default)]
+pub struct ApplyReport {
+    pub applied: Vec<Change>,
+    /// Changes needed to match the layout that `ApplyOptions` ruled out
+    pub skipped: Vec<Change>,
+}
+
+impl Layout {
+    pub fn from_toml(text: &str) -> Result<Self, MapperError> {
+        toml::from_str(text).map_err(|e| MapperError::InvalidArgument(format!("bad layout: {}", e)))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, MapperError> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Change::Create { name, size } => write!(f, "create {} ({} bytes)", name, size),
+            Change::Remove { name } => write!(f, "remove {}", name),
+            Change::Resize { name, from, to } => write!(f, "resize {} from {} to {} bytes", name, from, to),
+            Change::SetReadOnly { name, read_only: true } => write!(f, "make {} read-only", name),
+            Change::SetReadOnly { name, read_only: false } => write!(f, "make {} writable", name),
+            Change::SetProtected { name, protected: true } => write!(f, "protect {}", name),
+            Change::SetProtected { name, protected: false } => write!(f, "unprotect {}", name),
+            Change::SetTag { name, key, value: Some(value) } => write!(f, "tag {} {}={}", name, key, value),
+            Change::SetTag { name, key, value: None } => write!(f, "untag {} {}", name, key),
+        }
+    }
+}
+
+impl SuperPartition {
+    /// The subvolumes on this super partition as a layout, with every flag
+    /// and tag spelled out.  External records are left out, and left alone
+    /// when applying one.
+    pub fn layout(&self) -> Layout {
+        let subvols = self.subvols.iter()
+            .filter(|(name, sv)| !is_reserved(name) && !sv.external)
+            .map(|(name, sv)| {
+                let spec = SubvolSpec {
+                    size: sv.size_bytes(),
+                    contiguous: sv.contiguous,
+                    read_only: Some(sv.read_only),
+                    protected: Some(sv.protected),
+                    tags: Some(sv.tags.clone().into_iter().collect()),
+                };
+                (name.clone(), spec)
+            })
+            .collect();
+        Layout { subvols }
+    }
+
+    /// Every change needed to turn this super partition into `layout`,
+    /// without making any: removals first, then resizes, creations, and
+    /// flag and tag changes
+    pub fn diff_layout(&self, layout: &Layout) -> Vec<Change> {
+        let mut changes = vec![];
+        let mut extras: Vec<_> = self.subvols.keys()
+            .filter(|name| !is_reserved(name) && !self.is_external(name) && !layout.subvols.contains_key(*name))
+            .collect();
+        extras.sort();
+        changes.extend(extras.into_iter().map(|name| Change::Remove { name: name.clone() }));
+
+        let mut attributes = vec![];
+        for (name, spec) in &layout.subvols {
+            let size = spec.size.div_ceil(self.iosize) * self.iosize;
+            let (read_only, protected, tags): (_, _, BTreeMap<String, String>) = match self.subvols.get(name) {
+                Some(sv) => {
+                    if sv.size_bytes() != size {
+                        changes.push(Change::Resize { name: name.clone(), from: sv.size_bytes(), to: size });
+                    }
+                    (sv.read_only, sv.protected, sv.tags.clone().into_iter().collect())
+                }
+                None => {
+                    changes.push(Change::Create { name: name.clone(), size });
+                    (false, false, BTreeMap::new())
+                }
+            };
+
+            if let Some(want) = spec.read_only.filter(|want| *want != read_only) {
+                attributes.push(Change::SetReadOnly { name: name.clone(), read_only: want });
+            }
+            if let Some(want) = spec.protected.filter(|want| *want != protected) {
+                attributes.push(Change::SetProtected { name: name.clone(), protected: want });
+            }
+            if let Some(want) = &spec.tags {
+                for (key, value) in want.iter().filter(|(key, value)| tags.get(*key) != Some(*value)) {
+                    attributes.push(Change::SetTag { name: name.clone(), key: key.clone(), value: Some(value.clone()) });
+                }
+                for key in tags.keys().filter(|key| !want.contains_key(*key)) {
+                    attributes.push(Change::SetTag { name: name.clone(), key: key.clone(), value: None });
+                }
+            }
+        }
+        changes.extend(attributes);
+        changes
+    }
+
+    /// Bring this super partition in line with `layout` in a single
+    /// transaction
+    pub fn apply_layout(&mut self, layout: &Layout, options: &ApplyOptions) -> Result<ApplyReport, MapperError> {
+        let mut report = ApplyReport::default();
+        let changes = self.diff_layout(layout);
+        let mut txn = self.transaction();
+        for change in changes {
+            let allowed = match &change {
+                Change::Remove { .. } => options.remove_extras,
+                Change::Resize { .. } => options.resize,
+                _ => true,
+            };
+            if !allowed {
+                report.skipped.push(change);
+                continue;
+            }
+
+            match &change {
+                Change::Create { name, size } => {
+                    let create = CreateOptions {
+                        contiguous: layout.subvols[name].contiguous,
+                        ..CreateOptions::default()
+                    };
+                    txn.create_with(name, *size, &create)?;
+                }
+                Change::Remove { name } => txn.delete(name)?,
+                Change::Resize { name, to, .. } => txn.resize(name, *to)?,
+                Change::SetReadOnly { name, read_only } => txn.modify(name, |sv| sv.read_only = *read_only)?,
+                Change::SetProtected { name, protected } => txn.modify(name, |sv| sv.protected = *protected)?,
+                Change::SetTag { name, key, value } => txn.modify(name, |sv| {
+                    match value {
+                        Some(value) => sv.tags.insert(key.clone(), value.clone()),
+                        None => sv.tags.remove(key),
+                    };
+                })?,
+            }
+            report.applied.push(change);
+        }
+        txn.commit()?;
+        Ok(report)
+    }
+}
@@ -1,23 +1,182 @@
-use std::collections::HashMap;
-use std::io::{prelude::*, BufReader};
-use std::io::{self, ErrorKind, SeekFrom};
+use std::cell::{OnceCell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
 use std::fs::{File, OpenOptions};
-use std::ops::Sub;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::os::fd::AsRawFd;
 
 use serde::{Deserialize, Serialize};
-use devicemapper::{DM, Device, DevId, DmName, DmOptions, DmError, Sectors, TargetTable};
-use nix::sys::stat;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::libc::{c_int, c_uint};
+
+mod ab;
+mod alloc;
+mod audit;
+mod backup;
+mod bootargs;
+mod capacity;
+mod check;
+mod clone;
+mod crypt;
+mod defrag;
+mod dm;
+mod dmbackend;
+mod dmscript;
+mod error;
+mod extentmap;
+mod external;
+mod format;
+mod gpt;
+mod history;
+mod image;
+mod info;
+mod initramfs;
+mod integrity;
+mod journal;
+mod label;
+mod layout;
+mod loopdev;
+mod migrate;
+mod mirror;
+mod multidev;
+mod plan;
+mod probe;
+mod rollback;
+mod size;
+mod slots;
+mod snapshot;
+mod sparse;
+mod store;
+#[cfg(test)]
+mod testutil;
+mod thin;
+mod transaction;
+mod udev;
+mod units;
+mod update;
+mod validate;
+mod verity;
+mod wipe;
+
+pub use ab::{BootState, Slot, DEFAULT_BOOT_TRIES};
+pub use alloc::{AllocationStrategy, BestFit, CreateOptions, FirstFit, LargestHoleFirst, WorstFit};
+pub use audit::Drift;
+pub use check::{CheckRepairs, CheckReport, Problem};
+pub use crypt::{CryptParams, KeySource};
+pub use defrag::{FragReport, SubVolumeFrag};
+pub use dm::RawTable;
+pub use dmbackend::{DmBackend, DmCall, DmDeviceInfo, KernelDm, MockDm};
+pub use dmscript::ScriptDm;
+pub use error::MapperError;
+pub use extentmap::{ByteRange, ExtentMap, Region};
+pub use external::ExternalMeta;
+pub use format::Encoding;
+pub use history::HistoryEntry;
+pub use image::{ContentDigest, probe_filesystem_size, ReadOptions, WriteOptions};
+pub use initramfs::{InitramfsFile, InitramfsFlavor};
+pub use label::{FormatOptions, ReplicaPlacement};
+pub use layout::{ApplyOptions, ApplyReport, Change, Layout, SubvolSpec};
+pub use slots::{Repair, ReplicaState};
+pub use store::{BlockStore, MemoryStore};
+pub use mirror::MirrorStatus;
+pub use plan::{Plan, Step};
+pub use probe::{probe, probe_uuid, scan};
+pub use size::Size;
+pub use transaction::Transaction;
+pub use udev::NodeAccess;
+pub use units::{MountPoint, Unit};
+pub use update::{UpdateImage, UpdateManifest};
+pub use validate::{CorruptionReport, ExtentProblem};
+pub use wipe::Wipe;
 
 #[derive(Serialize,Deserialize,Debug)]
 pub struct SuperPartition {
+    /// Schema version, see the format module
+    #[serde(default)]
+    format_version: u32,
     device: String,
+    /// Identifies the super partition independent of the device path;
+    /// assigned on the first commit if missing
+    #[serde(default)]
+    uuid: String,
+    /// Put in front of every DM device name.  Super partitions from before
+    /// there was a prefix have none, so their devices keep their names.
+    #[serde(default)]
+    dm_prefix: String,
+    /// Short name for the super partition, used for its directory of
+    /// device links
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    name: String,
     generation: u32,
-    pub subvols: HashMap<String, SubVolume>
+    /// When this generation was committed, in seconds since the epoch
+    #[serde(default, skip_serializing_if = "is_zero")]
+    committed_at: u64,
+    pub subvols: HashMap<String, SubVolume>,
+    /// Devices beyond the one holding the metadata, for extents with a
+    /// non-zero device index
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    members: Vec<String>,
+    /// Journal of a relocation started by defrag
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relocation: Option<defrag::Relocation>,
+    /// Slot that paired subvolumes are used from, if ever set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    active_slot: Option<ab::Slot>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    boot: ab::BootStates,
+    /// Journal of the latest plain image written, until it completes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image_write: Option<image::ImageWrite>,
+    /// Layout change that was in progress when this was committed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    intent: Option<journal::Intent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    history: Option<history::History>,
+    /// Whether every commit also writes a shadow GPT, see the gpt module
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    hybrid_gpt: bool,
+    #[serde(default = "legacy_io_size")]
+    iosize: u64,
+    #[serde(default = "legacy_metadata_blocks")]
+    metadata_blocks: u64,
+    /// Size of each device, indexed like extents refer to them
+    #[serde(skip)]
+    device_blocks: Vec<u64>,
+    #[serde(skip)]
+    encoding: Encoding,
+    /// Where the replicas are, if not at the end of the device
+    #[serde(skip)]
+    label: Option<label::Label>,
+    /// Exclusive lock on the device, held while this is open
+    #[serde(skip)]
+    lock: Option<Flock<File>>,
+    /// Generation expected to be the newest on disk, if this was loaded
+    /// from there or has been committed since
+    #[serde(skip)]
+    on_disk: Option<u32>,
+    /// Set by `open_readonly`
+    #[serde(skip)]
+    read_only: bool,
+    /// What a dry run would have done, if this is one
+    #[serde(skip)]
+    plan: Option<RefCell<plan::Plan>>,
+    /// Loop devices attached for members that are regular files, by
+    /// device index
+    #[serde(skip)]
+    loops: RefCell<HashMap<u32, loopdev::LoopDevice>>,
+    /// Where the metadata is kept, if not on the device; see the store
+    /// module
+    #[serde(skip)]
+    store: Option<RefCell<Box<dyn BlockStore>>>,
+    /// Device mapper, as set with `set_dm_backend` or else the kernel's
+    /// once it is first needed; see the dmbackend module
+    #[serde(skip)]
+    dm_backend: OnceCell<Rc<dyn DmBackend>>,
 }
 
-// Can describe metadata for GPT partitions by creating a subvolume with
-// the same name and no extents
-//
 // Encode a "metadata" partition for the last two blocks so we don't
 #[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
 // have to special-case them for block allocation
@@ -26,255 +185,1155 @@ pub struct SubVolume {
     version: String,
     author: String,
     timedate: String,
+    /// Stays the same across renames; assigned on the first commit if
+    /// missing
+    #[serde(default)]
+    uuid: String,
+    /// Origin subvolume, if this is a snapshot.  The extents then hold
+    /// its copy-on-write store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    snapshot_of: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thin_pool: Option<thin::ThinPool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thin: Option<thin::ThinVolume>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    crypt: Option<CryptParams>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    verity: Option<verity::Verity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    integrity: Option<integrity::Integrity>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mirror: Option<mirror::Mirror>,
+    /// Must stay in a single extent
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    contiguous: bool,
+    /// Byte boundary the extents were aligned to when allocated
+    #[serde(default, skip_serializing_if = "is_zero")]
+    alignment: u64,
+    /// Ownership and permissions for the device node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    access: Option<NodeAccess>,
+    /// Type of the filesystem made on it, if recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filesystem: Option<String>,
+    /// Where its filesystem goes, for generated mount units
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mount: Option<MountPoint>,
+    /// Stands for data outside the super partition, such as a GPT
+    /// partition, and has no extents; see the external module
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    external: bool,
+    /// GPT partition type, as a lowercase UUID, for when it is exported
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    type_guid: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    tags: HashMap<String, String>,
+    /// Activated so that the kernel refuses writes
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    read_only: bool,
+    /// Refuses to be deleted or resized unless forced
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    protected: bool,
+    /// Digest of the last image written with verification, until
+    /// something else is written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<ContentDigest>,
+    /// Subvolume this one holds an update for, until the update is
+    /// applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    staged_for: Option<String>,
+    #[serde(skip)]
+    iosize: u64,
+}
+
+impl SubVolume {
+    fn new(extents: Vec<Extent>, iosize: u64) -> Self {
+        Self {
+            extents,
+            version: "".to_string(),
+            author: "".to_string(),
+            timedate: info::timestamp(),
+            uuid: "".to_string(),
+            snapshot_of: None,
+            thin_pool: None,
+            thin: None,
+            crypt: None,
+            verity: None,
+            integrity: None,
+            mirror: None,
+            contiguous: false,
+            alignment: 0,
+            access: None,
+            filesystem: None,
+            mount: None,
+            external: false,
+            type_guid: None,
+            tags: HashMap::new(),
+            read_only: false,
+            protected: false,
+            digest: None,
+            staged_for: None,
+            iosize,
+        }
+    }
+
+    fn size_blocks(&self) -> u64 {
+        self.extents.iter().map(|e| e.block_length).sum()
+    }
+
+    /// Every extent reserved for this subvolume, including any that back
+    /// internal structures rather than its data
+    fn all_extents(&self) -> impl Iterator<Item = &Extent> {
+        let pool_metadata = self.thin_pool.iter().flat_map(|p| p.metadata_extents());
+        let hash_tree = self.verity.iter().flat_map(|v| v.hash_extents());
+        let mirror_leg = self.mirror.iter().flat_map(|m| m.extents());
+        self.extents.iter().chain(pool_metadata).chain(hash_tree).chain(mirror_leg)
+    }
+
+    fn all_extents_mut(&mut self) -> impl Iterator<Item = &mut Extent> {
+        let pool_metadata = self.thin_pool.iter_mut().flat_map(|p| p.metadata_extents_mut());
+        let hash_tree = self.verity.iter_mut().flat_map(|v| v.hash_extents_mut());
+        let mirror_leg = self.mirror.iter_mut().flat_map(|m| m.extents_mut());
+        self.extents.iter_mut().chain(pool_metadata).chain(hash_tree).chain(mirror_leg)
+    }
+
+    /// Whether the subvolume maps its extents straight through, with
+    /// nothing stacked on them and nothing built on it
+    fn is_plain(&self) -> bool {
+        !self.external && self.snapshot_of.is_none() && self.thin_pool.is_none() && self.thin.is_none() && self.crypt.is_none()
+            && self.verity.is_none() && self.integrity.is_none() && self.mirror.is_none()
+    }
+
+    /// Subvolume that must be active before this one can be
+    fn depends_on(&self) -> Option<&str> {
+        match (&self.snapshot_of, &self.thin) {
+            (Some(origin), _) => Some(origin),
+            (None, Some(thin)) => Some(&thin.pool),
+            (None, None) => None,
+        }
+    }
+
+    pub fn size_bytes(&self) -> u64 {
+        match (&self.thin, &self.integrity) {
+            (Some(thin), _) => thin.size(),
+            (None, Some(integrity)) => integrity.data_size(),
+            (None, None) => self.size_blocks() * self.iosize,
+        }
+    }
+
+    pub fn extent_count(&self) -> usize {
+        self.extents.len()
+    }
+
+    /// Whether the subvolume occupies a single run of blocks on the device
+    pub fn is_contiguous(&self) -> bool {
+        std::iter::zip(&self.extents, self.extents.iter().skip(1))
+            .all(|(a, b)| a.device == b.device && a.block_offset + a.block_length == b.block_offset)
+    }
+}
+
+/// Summary of a subvolume as reported by `SuperPartition::subvols`
+#[derive(Serialize,Debug,Clone)]
+pub struct SubVolumeInfo<'a> {
+    pub name: &'a str,
+    pub size: u64,
+    pub extent_count: usize,
+    pub version: &'a str,
+    pub author: &'a str,
+    pub timedate: &'a str,
+    pub uuid: &'a str,
+    pub read_only: bool,
+    pub protected: bool,
+    pub snapshot_of: Option<&'a str>,
+    pub external: bool,
+    pub digest: Option<&'a ContentDigest>,
+    pub filesystem: Option<&'a str>,
+    pub mount: Option<&'a MountPoint>,
+    pub type_guid: Option<&'a str>,
+    /// Serialized sorted by key, so output is the same from run to run
+    #[serde(serialize_with = "serialize_sorted")]
+    pub tags: &'a HashMap<String, String>,
+}
+
+fn serialize_sorted<S: serde::Serializer>(map: &&HashMap<String, String>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.iter().collect::<std::collections::BTreeMap<_, _>>())
+}
+
+/// Space accounting for a super partition, in blocks of `block_size`
+#[derive(Serialize,Debug,Clone)]
+pub struct Usage<'a> {
+    pub block_size: u64,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    pub largest_free_extent: u64,
+    pub subvols: Vec<SubVolumeUsage<'a>>,
+}
+
+#[derive(Serialize,Debug,Clone)]
+pub struct SubVolumeUsage<'a> {
+    pub name: &'a str,
+    pub blocks: u64,
 }
 
 #[derive(Serialize,Deserialize,PartialEq,Debug,Eq,PartialOrd,Ord,Clone)]
-struct Extent {
+pub struct Extent {
+    /// Index of the member device holding the extent
+    #[serde(default, skip_serializing_if = "is_zero")]
+    device: u32,
     block_offset: u64,
     block_length: u64,
 }
 
-// XXX: this needs to be something reliably derived from an intrinsic
-// property of the hardware, not something that can change over time
-fn get_io_size(device: &str) -> Result<u64, io::Error> {
-    // FIXME
-    Ok(1024 * 1024)
+fn is_zero<T: Default + PartialEq>(n: &T) -> bool {
+    *n == T::default()
 }
 
-fn load_metadata(f: &mut File) -> Result<SuperPartition, io::Error> {
-    let mut disk_crc = [0; 4];
-    f.read_exact(&mut disk_crc)?;
-    let disk_crc = u32::from_be_bytes(disk_crc);
-
-    let mut buf = BufReader::new(f);
-    let mut json_metadata = "".to_string();
-    buf.read_line(&mut json_metadata)?;
-    let crc_algo = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
-    let actual_crc = crc_algo.checksum(json_metadata.trim().as_bytes());
-    if disk_crc == actual_crc {
-        let metadata = serde_json::from_str::<SuperPartition>(&json_metadata);
-        metadata.map_err(|_x| io::Error::new(ErrorKind::InvalidData, "can't parse json"))
-    } else {
-        Err(io::Error::new(ErrorKind::InvalidData, "crc doesn't match"))
+impl Extent {
+    pub fn new(device: u32, block_offset: u64, block_length: u64) -> Self {
+        Extent {
+            device,
+            block_offset,
+            block_length,
+        }
     }
+
+    pub fn device(&self) -> u32 {
+        self.device
+    }
+
+    pub fn block_offset(&self) -> u64 {
+        self.block_offset
+    }
+
+    pub fn block_length(&self) -> u64 {
+        self.block_length
+    }
+}
+
+/// Entry covering the metadata replicas
+pub(crate) const METADATA_SUBVOL: &str = "metadata";
+
+/// Subvolume names used for bookkeeping, which have no DM devices and
+/// cannot be created, changed or deleted by name
+pub(crate) const RESERVED_NAMES: [&str; 2] = [METADATA_SUBVOL, gpt::GPT_SUBVOL];
+
+pub(crate) fn is_reserved(name: &str) -> bool {
+    RESERVED_NAMES.contains(&name)
+}
+
+/// Smallest allocation unit, and the one used by metadata written before
+/// the unit was recorded
+pub(crate) const DEFAULT_IO_SIZE: u64 = 1024 * 1024;
+
+fn legacy_io_size() -> u64 {
+    DEFAULT_IO_SIZE
+}
+
+fn legacy_metadata_blocks() -> u64 {
+    1
+}
+
+nix::ioctl_read_bad!(blksszget, nix::request_code_none!(0x12, 104), c_int);
+nix::ioctl_read_bad!(blkpbszget, nix::request_code_none!(0x12, 123), c_uint);
+nix::ioctl_read_bad!(blkioopt, nix::request_code_none!(0x12, 121), c_uint);
+
+fn round_up(value: u64, multiple: u64) -> u64 {
+    value.div_ceil(multiple) * multiple
+}
+
+/// Take an exclusive lock on `device` that lasts as long as the result,
+/// waiting for any other holder to let go unless `wait` is false
+fn lock_device(device: &str, wait: bool) -> Result<Flock<File>, MapperError> {
+    let file = File::open(device)?;
+    let arg = if wait { FlockArg::LockExclusive } else { FlockArg::LockExclusiveNonblock };
+    Flock::lock(file, arg).map_err(|(_file, errno)| match errno {
+        Errno::EWOULDBLOCK => MapperError::Locked(device.to_string()),
+        errno => io::Error::from(errno).into(),
+    })
 }
 
-fn load_both_metadata(mut blockdev: &mut File, iosize: u64) -> Result<(Option<SuperPartition>, Option<SuperPartition>), io::Error> {
-    let device_size = blockdev.seek(SeekFrom::End(0))?;
+// Derive the allocation unit from the physical geometry of the device.
+// This is only consulted when a super partition is created; afterwards the
+// unit recorded in the metadata is authoritative.
+fn get_io_size(device: &str) -> Result<u64, io::Error> {
+    let blockdev = File::open(device)?;
+    let fd = blockdev.as_raw_fd();
+
+    let mut logical: c_int = 0;
+    let mut physical: c_uint = 0;
+    let mut optimal: c_uint = 0;
+    // Regular files don't support these; they just get the default
+    if unsafe { blksszget(fd, &mut logical) }.is_err() {
+        return Ok(DEFAULT_IO_SIZE);
+    }
+    unsafe { blkpbszget(fd, &mut physical) }.map_err(io::Error::from)?;
+    unsafe { blkioopt(fd, &mut optimal) }.map_err(io::Error::from)?;
+
+    let mut iosize = DEFAULT_IO_SIZE;
+    for unit in [logical as u64, physical as u64, optimal as u64] {
+        if unit > 0 {
+            iosize = round_up(iosize, unit);
+        }
+    }
+    Ok(iosize)
+}
+
+/// Blocks per replica and the first block of each, if the device is big
+/// enough to hold them
+fn replica_layout(blockdev: &mut dyn BlockStore, iosize: u64) -> Result<Option<(u64, [u64; 2])>, MapperError> {
+    let device_size = blockdev.size()?;
     let device_size_blocks = device_size / iosize;
+    if device_size_blocks < 2 {
+        return Ok(None);
+    }
+    match label::read_label(blockdev)? {
+        Some(label) if label.iosize == iosize => Ok(Some((label.slot_blocks, label.slots))),
+        _ => {
+            let count = slots::probe_slot_blocks(blockdev, device_size_blocks, iosize)?;
+            if device_size_blocks < 2 * count {
+                return Ok(None);
+            }
+            Ok(Some((count, [device_size_blocks - count, device_size_blocks - 2 * count])))
+        }
+    }
+}
 
-    blockdev.seek(SeekFrom::Start((device_size_blocks-1) * iosize))?;
-    let meta1 = load_metadata(&mut blockdev).ok();
-    blockdev.seek(SeekFrom::Start((device_size_blocks-2) * iosize))?;
-    let meta2 = load_metadata(&mut blockdev).ok();
+fn load_both_metadata(blockdev: &mut dyn BlockStore, iosize: u64) -> Result<(Option<SuperPartition>, Option<SuperPartition>), MapperError> {
+    let (count, starts) = match replica_layout(blockdev, iosize)? {
+        Some(layout) => layout,
+        None => return Ok((None, None)),
+    };
+
+    // A damaged slot is expected and the other one used instead, but
+    // metadata from a newer build must not be mistaken for no metadata
+    let mut load = |slot: usize| {
+        match slots::read_replica(blockdev, starts[slot - 1], count, iosize) {
+            Ok(meta) => Ok(Some(meta)),
+            Err(e @ MapperError::UnsupportedFormat { .. }) => Err(e),
+            Err(_) => Ok(None),
+        }
+    };
+    let meta1 = load(1)?;
+    let meta2 = load(2)?;
 
     Ok((meta1, meta2))
 }
 
 impl SuperPartition {
     /// Open an existing super partition with on-disk metadata
-    pub fn open(device: String) -> Result<Self, io::Error> {
+    pub fn open(device: String) -> Result<Self, MapperError> {
+        Self::open_locked(device, true)
+    }
+
+    /// Like `open`, but fail with `Locked` rather than waiting if another
+    /// process has the super partition open
+    pub fn try_open(device: String) -> Result<Self, MapperError> {
+        Self::open_locked(device, false)
+    }
+
+    /// Like `open`, but leave every subvolume inactive so that only the
+    /// ones needed can be brought up with `activate`
+    pub fn open_inactive(device: String) -> Result<Self, MapperError> {
+        let lock = lock_device(&device, true)?;
+        Self::open_unactivated(device, lock)
+    }
+
+    /// Like `open`, but reach device mapper through `backend` from the
+    /// start, so not even activation goes to the kernel's
+    pub fn open_with_dm(device: String, backend: Rc<dyn DmBackend>) -> Result<Self, MapperError> {
+        let meta = Self::open_inactive_with_dm(device, backend)?;
+        meta.activate_all()?;
+        Ok(meta)
+    }
+
+    /// Like `open_inactive`, with device mapper reached through `backend`
+    pub fn open_inactive_with_dm(device: String, backend: Rc<dyn DmBackend>) -> Result<Self, MapperError> {
+        let mut meta = Self::open_inactive(device)?;
+        meta.set_dm_backend(backend);
+        Ok(meta)
+    }
+
+    fn open_locked(device: String, wait: bool) -> Result<Self, MapperError> {
+        // Held from before the metadata is read, so nobody can commit
+        // behind our back
+        let lock = lock_device(&device, wait)?;
+        let meta = Self::open_unactivated(device, lock)?;
+        meta.activate_all()?;
+        Ok(meta)
+    }
+
+    fn open_unactivated(device: String, lock: Flock<File>) -> Result<Self, MapperError> {
+        let mut meta = Self::load(device)?;
+        meta.lock = Some(lock);
+        meta.on_disk = Some(meta.generation);
+        meta.recover_intent()?;
+        Ok(meta)
+    }
+
+    /// Parse the metadata on `device` without activating anything, taking
+    /// the lock, or finishing interrupted changes.  The result refuses to
+    /// commit or touch device mapper, so this works without privileges
+    /// and alongside a process that has the super partition open.
+    pub fn open_readonly(device: String) -> Result<Self, MapperError> {
+        let mut meta = Self::load(device)?;
+        meta.read_only = true;
+        Ok(meta)
+    }
+
+    /// Find the newest metadata on `device`
+    fn load(device: String) -> Result<Self, MapperError> {
+        let meta = Self::load_unchecked(device)?;
+        meta.check_extents()?;
+        Ok(meta)
+    }
+
+    /// Like `load`, but without refusing a layout with bad extents
+    pub(crate) fn load_unchecked(device: String) -> Result<Self, MapperError> {
         let mut blockdev = File::open(&device)?;
-        let iosize = get_io_size(&device)?;
-        let (meta1, meta2) = load_both_metadata(&mut blockdev, iosize)?;
 
-        let mut meta = match (meta1,meta2) {
-            (Some(meta), None) => meta,
-            (None, Some(meta)) => meta,
-            (None, None) => return Err(io::Error::new(ErrorKind::NotFound, "no valid metadata")),
-            (Some(meta1), Some(meta2)) => {
-                if meta1.generation > meta2.generation {
-                    meta1
-                } else {
-                    meta2
+        // The metadata location depends on the io size it was written
+        // with.  A label records it; otherwise try the one derived from
+        // the device and fall back to the historical default.
+        let label = label::read_label(&mut blockdev)?;
+        let mut candidates = vec![get_io_size(&device)?];
+        if let Some(label) = &label {
+            candidates = vec![label.iosize];
+        } else if candidates[0] != DEFAULT_IO_SIZE {
+            candidates.push(DEFAULT_IO_SIZE);
+        }
+        let mut meta = Self::find_newest(&mut blockdev, &candidates)?;
+        meta.bind_devices(device, label)?;
+        Ok(meta)
+    }
+
+    /// The newest metadata in `blockdev` written with one of the block
+    /// sizes in `candidates`, trying them in turn
+    fn find_newest(blockdev: &mut dyn BlockStore, candidates: &[u64]) -> Result<Self, MapperError> {
+        let mut found = None;
+        for &iosize in candidates {
+            let (meta1, meta2) = load_both_metadata(blockdev, iosize)?;
+            let meta1 = meta1.filter(|m| m.iosize == iosize);
+            let meta2 = meta2.filter(|m| m.iosize == iosize);
+            found = match (meta1,meta2) {
+                (Some(meta), None) => Some(meta),
+                (None, Some(meta)) => Some(meta),
+                (None, None) => continue,
+                (Some(meta1), Some(meta2)) => {
+                    if meta1.generation > meta2.generation {
+                        Some(meta1)
+                    } else {
+                        Some(meta2)
+                    }
                 }
+            };
+            break;
+        }
+        found.ok_or(MapperError::NoMetadata)
+    }
+
+    /// Fill in everything about freshly parsed metadata that depends on the
+    /// device it was found on rather than being stored, and check its
+    /// extents are safe to use there
+    fn bind(&mut self, device: String, label: Option<label::Label>) -> Result<(), MapperError> {
+        self.bind_devices(device, label)?;
+        self.check_extents()
+    }
+
+    fn bind_devices(&mut self, device: String, label: Option<label::Label>) -> Result<(), MapperError> {
+        let mut blockdev = File::open(&device)?;
+        self.device = device;
+        self.label = label;
+        self.device_blocks = vec![blockdev.seek(SeekFrom::End(0))? / self.iosize];
+        for sv in self.subvols.values_mut() {
+            sv.iosize = self.iosize;
+        }
+        self.size_members()
+    }
+
+    /// Create the DM devices for every subvolume
+    pub fn activate_all(&self) -> Result<(), MapperError> {
+        // Snapshots and thin volumes stack on top of another subvol, so
+        // bring those up first.  Anything caught in an interrupted
+        // relocation stays down until that is resolved.
+        let relocating = self.pending_relocation();
+        let mut names: Vec<_> = self.subvols.keys()
+            .filter(|name| !is_reserved(name) && !self.subvols[*name].external)
+            .filter(|name| Some(name.as_str()) != relocating)
+            .filter(|name| self.subvols[*name].depends_on().is_none_or(|dep| Some(dep) != relocating))
+            .collect();
+        names.sort_by_key(|name| self.subvols[*name].depends_on().is_some());
+        for name in names {
+            self.create_dm(name, &self.subvols[name])?;
+        }
+        Ok(())
+    }
+
+    /// Bring up the DM devices for one subvolume, along with whatever it
+    /// stacks on
+    pub fn activate(&self, name: &str) -> Result<(), MapperError> {
+        self.check_not_reserved(name)?;
+        self.check_not_external(name)?;
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        if self.pending_relocation().is_some_and(|r| Some(r) == sv.depends_on() || r == name) {
+            return Err(MapperError::InvalidArgument(format!("{} is being relocated; resume or roll back defrag first", name)));
+        }
+        if let Some(dep) = sv.depends_on() {
+            if !self.dm_active(dep)? {
+                self.activate(dep)?;
             }
-        };
-        meta.device = device;
+        }
+        self.create_dm(name, sv)
+    }
 
-        for (name, sv) in &meta.subvols {
-            meta.create_dm(name, sv, iosize).map_err(|e| {
-                eprintln!("create_dm {:?}", e);
-                io::Error::new(ErrorKind::Other, "create dm")
-            })?;
+    /// Tear down the DM devices for one subvolume.  Snapshots of it and
+    /// thin volumes in it have to be deactivated first.
+    pub fn deactivate(&self, name: &str) -> Result<(), MapperError> {
+        if !self.subvols.contains_key(name) {
+            return Err(MapperError::NotFound(name.to_string()));
         }
-        Ok(meta)
+        for dependant in self.snapshots_of(name).into_iter().chain(self.thins_in(name)) {
+            if self.dm_active(dependant)? {
+                return Err(MapperError::DeviceBusy(format!("{} (needed by {})", name, dependant)));
+            }
+        }
+        self.remove_dm(name)
+    }
+
+    /// Tear down the DM devices for every subvolume, dependants first,
+    /// and give up the lock.  Unless `force` is set this stops at the
+    /// first subvolume that is in use, e.g. mounted; with it, busy devices
+    /// are removed as soon as they are closed.
+    pub fn close(self, force: bool) -> Result<(), MapperError> {
+        self.deactivate_all(force)
+    }
+
+    /// Tear down the DM devices for every subvolume like `close` does,
+    /// but keep the super partition open
+    pub fn deactivate_all(&self, force: bool) -> Result<(), MapperError> {
+        let mut names: Vec<_> = self.subvols.keys()
+            .filter(|name| !is_reserved(name))
+            .collect();
+        names.sort_by_key(|name| self.subvols[*name].depends_on().is_none());
+        for name in names {
+            if force {
+                self.remove_dm_deferred(name)?;
+            } else {
+                self.remove_dm(name)?;
+            }
+        }
+        Ok(())
     }
 
     /// Convert an existing partition into a new super partition.  There
     /// must be enough difference between the partition size and
     /// original_size to allow for 2 blocks for metadata storage.
-    pub fn adopt(device: String, name: String, original_size: u64) -> Result<Self, io::Error> {
+    pub fn adopt(device: String, name: String, original_size: u64) -> Result<Self, MapperError> {
+        Self::adopt_with(device, name, original_size, &FormatOptions::default())
+    }
+
+    /// Like `adopt`, with control over the metadata layout.  The adopted
+    /// data starts at the beginning of the device, so there is no room for
+    /// a label there and the replicas must stay at the end.
+    pub fn adopt_with(device: String, name: String, original_size: u64, options: &FormatOptions) -> Result<Self, MapperError> {
+        if options.placement != ReplicaPlacement::End {
+            return Err(MapperError::InvalidArgument("adopted data occupies the start of the device \
+                where the label would go".to_string()));
+        }
+        Self::new_layout(device, Some((name, original_size)), options)
+    }
+
+    /// Convert a device already holding data at known places into a new
+    /// super partition, with a subvolume for each `(name, offset, length)`
+    /// region, in bytes.  Offsets must fall on block boundaries, and the
+    /// regions must stay clear of each other and of where `options` puts
+    /// the metadata.  Nothing is written until the first commit, which
+    /// records every region at once.
+    pub fn adopt_regions(device: String, regions: &[(String, u64, u64)], options: &FormatOptions) -> Result<Self, MapperError> {
+        if regions.is_empty() {
+            return Err(MapperError::InvalidArgument("no regions given".to_string()));
+        }
+        let mut sp = Self::new_layout(device, None, options)?;
+        let iosize = sp.iosize;
+        for (name, offset, length) in regions {
+            if !offset.is_multiple_of(iosize) {
+                return Err(MapperError::InvalidArgument(format!("{} does not start on a {}-byte boundary", name, iosize)));
+            }
+            let extent = Extent::new(0, offset / iosize, length.div_ceil(iosize));
+            sp.check_new_name(name)?;
+            sp.check_free(std::slice::from_ref(&extent)).map_err(|e| match e {
+                MapperError::InvalidArgument(why) => MapperError::InvalidArgument(format!("{}: {}", name, why)),
+                e => e,
+            })?;
+            sp.subvols.insert(name.clone(), SubVolume::new(vec![extent], iosize));
+        }
+        Ok(sp)
+    }
+
+    /// Build a fresh super partition, optionally with a subvolume covering
+    /// `original_size` bytes of existing data at the start of the device
+    fn new_layout(device: String, adopted: Option<(String, u64)>, options: &FormatOptions) -> Result<Self, MapperError> {
+        let lock = lock_device(&device, true)?;
         let mut blockdev = File::open(&device)?;
 
         let device_size = blockdev.seek(SeekFrom::End(0))?;
         let iosize = get_io_size(&device)?;
         let device_size_blocks = device_size / iosize;
-        let original_size_blocks = (original_size + iosize - 1) / iosize;
+        let mut sp = Self::blank(device, iosize, device_size_blocks, options)?;
+        sp.lock = Some(lock);
+
+        if let Some((name, original_size)) = adopted {
+            let original_size_blocks = original_size.div_ceil(iosize);
+            let reserved = 2 * options.metadata_blocks;
+            if original_size_blocks + reserved > device_size_blocks {
+                return Err(MapperError::NoSpace {
+                    needed: original_size_blocks + reserved,
+                    available: device_size_blocks,
+                });
+            }
 
-        if original_size_blocks + 2 > device_size_blocks {
-            return Err(io::Error::new(ErrorKind::OutOfMemory, "not enough room for metadata"));
+            let extent = Extent {
+                device: 0,
+                block_offset: 0,
+                block_length: original_size_blocks,
+            };
+            let subvol = SubVolume::new(vec![extent], iosize);
+            sp.check_new_name(&name)?;
+            sp.subvols.insert(name, subvol);
         }
 
-        let extent = Extent {
-            block_offset: device_size_blocks - 2,
-            block_length: 2,
-        };
-        let subvol = SubVolume {
-            extents: vec![extent],
-            version: "".to_string(),
-            author: "".to_string(),
-            timedate: "".to_string(),
-        };
+        Ok(sp)
+    }
 
-        let mut subvols = HashMap::new();
-        subvols.insert("metadata".to_string(), subvol);
+    /// A super partition with nothing but its metadata, laid out for a
+    /// device of `device_size_blocks` blocks, and not yet written
+    fn blank(device: String, iosize: u64, device_size_blocks: u64, options: &FormatOptions) -> Result<Self, MapperError> {
+        let (label, extents) = Self::plan_replicas(iosize, device_size_blocks, options)?;
+        let subvol = SubVolume::new(extents, iosize);
 
-        let extent = Extent {
-            block_offset: 0,
-            block_length: original_size_blocks,
-        };
-        let subvol = SubVolume {
-            extents: vec![extent],
-            version: "".to_string(),
-            author: "".to_string(),
-            timedate: "".to_string(),
-        };
-        subvols.insert(name, subvol);
+        let mut subvols = HashMap::new();
+        subvols.insert(METADATA_SUBVOL.to_string(), subvol);
 
-        Ok(Self {
+        let mut sp = Self {
+            format_version: format::FORMAT_VERSION,
             device,
+            uuid: probe::new_uuid()?,
+            dm_prefix: dm::DEFAULT_DM_PREFIX.to_string(),
+            name: String::new(),
             generation: 1,
-            subvols
-        })
+            committed_at: 0,
+            subvols,
+            members: vec![],
+            relocation: None,
+            active_slot: None,
+            boot: BTreeMap::new(),
+            image_write: None,
+            intent: None,
+            history: None,
+            hybrid_gpt: false,
+            iosize,
+            metadata_blocks: options.metadata_blocks,
+            device_blocks: vec![device_size_blocks],
+            encoding: Encoding::default(),
+            label,
+            lock: None,
+            on_disk: None,
+            read_only: false,
+            plan: None,
+            loops: RefCell::default(),
+            store: None,
+            dm_backend: OnceCell::new(),
+        };
+        if options.dry_run {
+            sp.start_plan();
+        }
+        Ok(sp)
+    }
+
+    /// Path of the block device holding the metadata
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    /// Allocation unit in bytes
+    pub fn block_size(&self) -> u64 {
+        self.iosize
+    }
+
+    /// Identifier of this super partition, also written in its signature
+    pub fn uuid(&self) -> &str {
+        &self.uuid
+    }
+
+    /// Number of times the metadata has been committed
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Usable size of all member devices in bytes, including the blocks
+    /// reserved for metadata
+    pub fn total_size(&self) -> u64 {
+        self.device_blocks.iter().sum::<u64>() * self.iosize
+    }
+
+    /// Enumerate the subvolumes on this super partition, sorted by name
+    pub fn subvols(&self) -> impl Iterator<Item = SubVolumeInfo<'_>> {
+        let mut infos: Vec<_> = self.subvols.iter().map(|(name, sv)| {
+            SubVolumeInfo {
+                name,
+                size: sv.size_bytes(),
+                extent_count: sv.extent_count(),
+                version: &sv.version,
+                author: &sv.author,
+                timedate: &sv.timedate,
+                uuid: &sv.uuid,
+                read_only: sv.read_only,
+                protected: sv.protected,
+                snapshot_of: sv.snapshot_of.as_deref(),
+                external: sv.external,
+                digest: sv.digest.as_ref(),
+                filesystem: sv.filesystem.as_deref(),
+                mount: sv.mount.as_ref(),
+                type_guid: sv.type_guid.as_deref(),
+                tags: &sv.tags,
+            }
+        }).collect();
+        infos.sort_by_key(|info| info.name);
+        infos.into_iter()
+    }
+
+    /// Name of the subvolume with UUID `uuid`
+    pub fn subvol_by_uuid(&self, uuid: &str) -> Option<&str> {
+        self.subvols.iter()
+            .find(|(_name, sv)| !uuid.is_empty() && sv.uuid == uuid)
+            .map(|(name, _sv)| name.as_str())
     }
 
     fn get_all_extents(&self) -> Vec<&Extent> {
         let mut extents = vec![];
 
-        for (_k,v) in &self.subvols {
-            extents.extend(&v.extents);
+        for v in self.subvols.values() {
+            extents.extend(v.all_extents());
         }
+        extents.extend(self.relocation.iter().map(|r| &r.to));
+        extents.extend(self.history.iter().map(|h| h.extent()));
         extents.sort();
 
         extents
     }
 
-    pub fn create_subvol(&mut self, name: String, size: u64) -> Result<(), io::Error> {
-        if self.subvols.contains_key(&name) {
-            return Err(io::Error::new(ErrorKind::AlreadyExists, "subvol already exists"));
-        }
-        let iosize = get_io_size(&self.device)?;
-        let mut size_blocks = (size + iosize - 1) / iosize;
+    /// Unallocated runs of blocks, in device order
+    fn free_extents(&self) -> Vec<Extent> {
+        let mut holes = vec![];
+        let extents = self.get_all_extents();
 
-        let all_extents = self.get_all_extents();
-        let mut my_extents = vec![];
+        for (device, blocks) in self.device_blocks.iter().enumerate() {
+            let device = device as u32;
+            let mut pos = 0;
+            for e in extents.iter().filter(|e| e.device == device) {
+                if e.block_offset > pos {
+                    holes.push(Extent {
+                        device,
+                        block_offset: pos,
+                        block_length: e.block_offset - pos,
+                    });
+                }
+                pos = std::cmp::max(pos, e.block_offset + e.block_length);
+            }
+            if *blocks > pos {
+                holes.push(Extent {
+                    device,
+                    block_offset: pos,
+                    block_length: blocks - pos,
+                });
+            }
+        }
 
-        for (a, b) in std::iter::zip(&all_extents, &all_extents[1..]) {
-            let hole_start = a.block_offset + a.block_length;
-            let hole_len = b.block_offset - hole_start;
+        holes
+    }
 
-            let extent = Extent {
-                block_offset: hole_start,
-                block_length: std::cmp::min(hole_len, size_blocks),
-            };
+    /// Total number of unallocated blocks
+    pub fn free_blocks(&self) -> u64 {
+        self.free_extents().iter().map(|e| e.block_length).sum()
+    }
 
-            size_blocks -= extent.block_length;
-            my_extents.push(extent);
+    /// The biggest run of unallocated blocks, if there is any free space
+    pub fn largest_free_extent(&self) -> Option<Extent> {
+        self.free_extents().into_iter().max_by_key(|e| e.block_length)
+    }
 
-            if size_blocks == 0 {
-                break;
+    /// Break down how the device's blocks are used
+    pub fn usage(&self) -> Usage<'_> {
+        let mut subvols: Vec<_> = self.subvols.iter().map(|(name, sv)| {
+            SubVolumeUsage {
+                name,
+                blocks: sv.all_extents().map(|e| e.block_length).sum(),
             }
+        }).collect();
+        subvols.sort_by_key(|u| u.name);
+
+        Usage {
+            block_size: self.iosize,
+            total_blocks: self.device_blocks.iter().sum(),
+            free_blocks: self.free_blocks(),
+            largest_free_extent: self.largest_free_extent().map_or(0, |e| e.block_length),
+            subvols,
         }
+    }
 
-        if size_blocks > 0 {
-            return Err(io::Error::new(ErrorKind::OutOfMemory, "not enough space for subvol"));
+    /// Create a subvolume, returning the path its device node will have.
+    /// udev may not have created the node yet; see `wait_for_subvol`.
+    pub fn create_subvol(&mut self, name: String, size: u64) -> Result<PathBuf, MapperError> {
+        self.create_subvol_with(name, size, &CreateOptions::default())
+    }
+
+    /// Create a subvolume, choosing its space as described by `options`
+    pub fn create_subvol_with(&mut self, name: String, size: u64, options: &CreateOptions) -> Result<PathBuf, MapperError> {
+        let sv = self.new_subvol_with(&name, size, options)?;
+        if let Some(how) = options.wipe {
+            self.wipe_ends(&sv, how)?;
         }
+        let path = self.subvol_path(&name);
+        self.insert_subvol(name, sv)?;
+        Ok(path)
+    }
 
-        let sv = SubVolume {
-            extents: my_extents,
-            version: "".to_string(),
-            author: "".to_string(),
-            timedate: "".to_string(),
-        };
+    /// Create a subvolume mapping exactly `extents`, in order, for
+    /// reproducing a known layout.  Every extent has to be free.
+    pub fn create_subvol_at(&mut self, name: String, extents: Vec<Extent>) -> Result<PathBuf, MapperError> {
+        self.check_new_name(&name)?;
+        self.check_free(&extents)?;
+        let sv = SubVolume::new(extents, self.iosize);
+        let path = self.subvol_path(&name);
+        self.insert_subvol(name, sv)?;
+        Ok(path)
+    }
+
+    /// Allocate space for a new plain subvolume of `size` bytes
+    fn new_subvol(&self, name: &str, size: u64) -> Result<SubVolume, MapperError> {
+        self.new_subvol_with(name, size, &CreateOptions::default())
+    }
+
+    pub(crate) fn new_subvol_with(&self, name: &str, size: u64, options: &CreateOptions) -> Result<SubVolume, MapperError> {
+        self.check_new_name(name)?;
+        let iosize = self.iosize;
+        let size_blocks = size.div_ceil(iosize);
+        let my_extents = self.allocate_with(size_blocks, options)?;
+
+        let mut sv = SubVolume::new(my_extents, iosize);
+        sv.contiguous = options.contiguous;
+        sv.alignment = options.alignment;
+        Ok(sv)
+    }
+
+    /// Record a newly allocated subvolume and bring it up
+    fn insert_subvol(&mut self, name: String, sv: SubVolume) -> Result<(), MapperError> {
         self.subvols.insert(name.clone(), sv.clone());
         self.commit()?;
-        self.create_dm(&name, &sv, iosize).map_err(|e| {
-            eprintln!("create_dm {:?}", e);
-            io::Error::new(ErrorKind::Other, "create dm")
-        })?;
+        self.create_dm(&name, &sv)?;
         Ok(())
     }
 
-    fn get_major_minor(&self) -> Result<(u32, u32), io::Error> {
-        let st = stat::stat(std::path::Path::new(&self.device))?;
-        let major = stat::major(st.st_rdev);
-        let minor = stat::minor(st.st_rdev);
-        Ok((major as u32, minor as u32))
+    /// Grow a subvolume to `new_size` bytes.  The additional space is
+    /// appended to the end of the subvolume and the live mapping is
+    /// reloaded in place, so it does not need to be deactivated.
+    pub fn resize_subvol(&mut self, name: &str, new_size: u64) -> Result<(), MapperError> {
+        self.grow_subvol(name, new_size, false)
     }
 
-    fn create_dm(&self, name: &str, sv: &SubVolume, iosize: u64) -> Result<(), DmError> {
-        let name = DmName::new(name)?;
-        let options = DmOptions::default();
-        let dm = DM::new()?;
+    /// Like `resize_subvol`, but also grows protected subvolumes
+    pub fn force_resize_subvol(&mut self, name: &str, new_size: u64) -> Result<(), MapperError> {
+        self.grow_subvol(name, new_size, true)
+    }
 
-        let mut table = vec![];
-        let mut start = 0;
-        for e in &sv.extents {
-            if e.block_length == 0 {
-                continue;
-            }
+    fn grow_subvol(&mut self, name: &str, new_size: u64, force: bool) -> Result<(), MapperError> {
+        self.check_unprotected(name, force)?;
+        let sv = match self.grown_subvol(name, new_size)? {
+            Some(sv) => sv,
+            None => return Ok(()),
+        };
+        self.subvols.insert(name.to_string(), sv.clone());
+        self.commit()?;
+        self.reload_dm(name, &sv)?;
+        Ok(())
+    }
+
+    /// Work out the layout of `name` grown to `new_size` bytes, or None if
+    /// it is already that size
+    pub(crate) fn grown_subvol(&self, name: &str, new_size: u64) -> Result<Option<SubVolume>, MapperError> {
+        self.check_not_external(name)?;
+        let iosize = self.iosize;
+        let new_blocks = new_size.div_ceil(iosize);
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        let cur_blocks = sv.size_blocks();
+
+        if sv.snapshot_of.is_some() || self.has_snapshots(name) {
+            return Err(MapperError::InvalidArgument("cannot resize a subvol with snapshots".to_string()));
+        }
+        if sv.thin_pool.is_some() || sv.thin.is_some() {
+            return Err(MapperError::InvalidArgument("cannot resize thin pools or volumes".to_string()));
+        }
+        if sv.verity.is_some() || sv.integrity.is_some() || sv.mirror.is_some() {
+            return Err(MapperError::InvalidArgument("cannot resize verity, integrity or mirrored subvols".to_string()));
+        }
+        if new_blocks < cur_blocks {
+            return Err(MapperError::InvalidArgument("use shrink_subvol to shrink".to_string()));
+        }
+        if new_blocks == cur_blocks {
+            return Ok(None);
+        }
 
-            let (major, minor) = self.get_major_minor().expect("major minor");
-            let source_dev = Device {
-                major,
-                minor,
+        let new_extents = if sv.contiguous {
+            // Only the space directly after the subvolume will do
+            let last = sv.extents.last().expect("contiguous subvol without extents");
+            let needed = new_blocks - cur_blocks;
+            let after = self.free_extents().into_iter()
+                .find(|h| h.device == last.device && h.block_offset == last.block_offset + last.block_length);
+            let largest = after.as_ref().map_or(0, |h| h.block_length);
+            if largest < needed {
+                return Err(MapperError::NoContiguousSpace { needed, largest });
+            }
+            self.allocate_from(after.into_iter().collect(), needed)?
+        } else {
+            let options = CreateOptions {
+                alignment: sv.alignment,
+                ..CreateOptions::default()
             };
-            let params = devicemapper::LinearTargetParams::new(source_dev, Sectors(e.block_offset * iosize / 512));
+            self.allocate_with(new_blocks - cur_blocks, &options)?
+        };
+        let mut sv = sv.clone();
+        for e in new_extents {
+            match sv.extents.last_mut() {
+                Some(last) if last.device == e.device && last.block_offset + last.block_length == e.block_offset => {
+                    last.block_length += e.block_length;
+                }
+                _ => sv.extents.push(e),
+            }
+        }
+        Ok(Some(sv))
+    }
+
+    /// Shrink a subvolume to `new_size` bytes, releasing its trailing
+    /// extents back to free space.  Anything stored past the new size is
+    /// lost.  Unless `force` is set this refuses to touch a subvolume
+    /// that is protected or currently open, e.g. mounted.
+    pub fn shrink_subvol(&mut self, name: &str, new_size: u64, force: bool) -> Result<(), MapperError> {
+        self.check_unprotected(name, force)?;
+        let shrunk = match self.shrunk_subvol(name, new_size)? {
+            Some(sv) => sv,
+            None => return Ok(()),
+        };
+        if !force && self.dm_in_use(name)? {
+            return Err(MapperError::DeviceBusy(name.to_string()));
+        }
+
+        // Stop the mapping from reaching the released blocks before they
+        // are handed back to the allocator
+        self.journaled([(name.to_string(), Some(shrunk.clone()))].into(), |sp| sp.reload_dm(name, &shrunk))
+    }
 
-            let start_sectors = Sectors(start * iosize / 512);
-            let length_sectors = Sectors(e.block_length * iosize / 512);
-            let line = devicemapper::TargetLine::new(start_sectors, length_sectors,
-                devicemapper::LinearDevTargetParams::Linear(params));
-            table.push(line);
+    /// Work out the layout of `name` cut down to `new_size` bytes, or None
+    /// if it is already that size
+    pub(crate) fn shrunk_subvol(&self, name: &str, new_size: u64) -> Result<Option<SubVolume>, MapperError> {
+        self.check_not_external(name)?;
+        let iosize = self.iosize;
+        let new_blocks = new_size.div_ceil(iosize);
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        let cur_blocks = sv.size_blocks();
 
-            start += e.block_length;
+        if sv.snapshot_of.is_some() || self.has_snapshots(name) {
+            return Err(MapperError::InvalidArgument("cannot resize a subvol with snapshots".to_string()));
+        }
+        if sv.thin_pool.is_some() || sv.thin.is_some() {
+            return Err(MapperError::InvalidArgument("cannot resize thin pools or volumes".to_string()));
+        }
+        if sv.verity.is_some() || sv.integrity.is_some() || sv.mirror.is_some() {
+            return Err(MapperError::InvalidArgument("cannot resize verity, integrity or mirrored subvols".to_string()));
+        }
+        if new_blocks > cur_blocks {
+            return Err(MapperError::InvalidArgument("use resize_subvol to grow".to_string()));
+        }
+        if new_blocks == cur_blocks {
+            return Ok(None);
+        }
+        if new_blocks == 0 {
+            return Err(MapperError::InvalidArgument("cannot shrink to nothing, delete instead".to_string()));
         }
 
-        let id = DevId::Name(name);
-        let target = devicemapper::LinearDevTargetTable::new(table);
-        dm.device_create(name, None, options)?;
-        dm.table_load(&id, &target.to_raw_table(), options)?;
-        // Un-suspend the device
-        dm.device_suspend(&id, DmOptions::default())?;
+        let mut shrunk = sv.clone();
+        let mut remaining = new_blocks;
+        shrunk.extents.retain_mut(|e| {
+            if remaining == 0 {
+                return false;
+            }
+            e.block_length = std::cmp::min(e.block_length, remaining);
+            remaining -= e.block_length;
+            true
+        });
+        Ok(Some(shrunk))
+    }
 
+    /// Rename a subvolume, along with its DM device if it is active
+    pub fn rename_subvol(&mut self, old: &str, new: &str) -> Result<(), MapperError> {
+        self.check_not_reserved(old)?;
+        if !self.subvols.contains_key(old) {
+            return Err(MapperError::NotFound(old.to_string()));
+        }
+        self.check_new_name(new)?;
+
+        let suffixes: Vec<_> = self.layers(old, &self.subvols[old]).iter()
+            .map(|layer| layer.suffix)
+            .collect();
+        let renamed = self.rename_dm(old, new, &suffixes)?;
+        self.rename_entry(old, new);
+        if let Err(e) = self.commit() {
+            self.rename_entry(new, old);
+            if renamed {
+                let _ = self.rename_dm(new, old, &suffixes);
+            }
+            return Err(e);
+        }
         Ok(())
     }
 
-    pub fn delete_subvol(&mut self, sv: SubVolume) -> Result<(), io::Error> {
-        self.remove_dm(&sv);
-        self.commit()?;
-        self.subvols.retain(|_k, v| *v != sv);
+    /// Move a metadata entry to a new name, keeping references to it intact
+    fn rename_entry(&mut self, old: &str, new: &str) {
+        let sv = self.subvols.remove(old).expect("subvol vanished");
+        self.subvols.insert(new.to_string(), sv);
+        for sv in self.subvols.values_mut() {
+            if sv.snapshot_of.as_deref() == Some(old) {
+                sv.snapshot_of = Some(new.to_string());
+            }
+            if let Some(thin) = sv.thin.as_mut().filter(|t| t.pool == old) {
+                thin.pool = new.to_string();
+            }
+        }
+    }
+
+    #[deprecated(note = "subvols can have identical contents; use delete_subvol_by_name")]
+    pub fn delete_subvol(&mut self, sv: SubVolume) -> Result<(), MapperError> {
+        let name = match self.subvols.iter().find(|(_k, v)| **v == sv) {
+            Some((name, _v)) => name.clone(),
+            None => return Err(MapperError::NotFound("<unnamed>".to_string())),
+        };
+        self.delete_subvol_by_name(&name)
+    }
+
+    pub fn delete_subvol_by_name(&mut self, name: &str) -> Result<(), MapperError> {
+        self.remove_subvol(name, false, None)
+    }
+
+    /// Like `delete_subvol_by_name`, but also deletes protected subvolumes
+    pub fn force_delete_subvol(&mut self, name: &str) -> Result<(), MapperError> {
+        self.remove_subvol(name, true, None)
+    }
+
+    /// Delete a subvolume and clear every block it gave up once its
+    /// mapping is gone, so none of its data is left for whoever is given
+    /// the blocks next.  `force` overrides protection as for
+    /// `force_delete_subvol`.  Thin volumes can't be shredded, as their
+    /// blocks stay with the pool.
+    pub fn delete_subvol_secure(&mut self, name: &str, how: Wipe, force: bool) -> Result<(), MapperError> {
+        self.remove_subvol(name, force, Some(how))
+    }
+
+    fn remove_subvol(&mut self, name: &str, force: bool, shred: Option<Wipe>) -> Result<(), MapperError> {
+        self.check_unprotected(name, force)?;
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        if sv.snapshot_of.is_some() {
+            return self.delete_snapshot(name, shred);
+        }
+        if self.has_snapshots(name) {
+            return Err(MapperError::InvalidArgument(format!("{} has snapshots", name)));
+        }
+        if sv.thin.is_some() {
+            if shred.is_some() {
+                return Err(MapperError::InvalidArgument(format!("{} is a thin volume; its blocks belong to the pool", name)));
+            }
+            return self.delete_thin(name);
+        }
+        if !self.thins_in(name).is_empty() {
+            return Err(MapperError::InvalidArgument(format!("{} has thin volumes", name)));
+        }
+
+        // Tear down the mapping before the extents are released, so
+        // nothing can keep writing to blocks that may be reallocated
+        if self.dm_in_use(name)? {
+            return Err(MapperError::DeviceBusy(name.to_string()));
+        }
+        // Shredding is part of the journaled step, so the blocks are not
+        // released unless it finishes
+        self.journaled([(name.to_string(), None)].into(), |sp| {
+            sp.remove_dm(name)?;
+            match shred {
+                Some(how) => sp.wipe_extents(sp.subvols[name].all_extents(), how),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Guard a subvolume against being deleted or resized unless forced
+    pub fn set_protected(&mut self, name: &str, protected: bool) -> Result<(), MapperError> {
+        self.check_not_reserved(name)?;
+        let sv = self.subvols.get_mut(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        sv.protected = protected;
+        self.commit()
+    }
+
+    /// Refuse changes to a protected subvolume unless `force` is set, and
+    /// to reserved entries always
+    pub(crate) fn check_unprotected(&self, name: &str, force: bool) -> Result<(), MapperError> {
+        self.check_not_reserved(name)?;
+        if !force && self.subvols.get(name).is_some_and(|sv| sv.protected) {
+            return Err(MapperError::Protected(name.to_string()));
+        }
         Ok(())
     }
 
-    fn remove_dm(&self, sv: &SubVolume) {
+    pub(crate) fn check_not_reserved(&self, name: &str) -> Result<(), MapperError> {
+        if is_reserved(name) {
+            return Err(MapperError::InvalidArgument(format!("{} is reserved for internal use", name)));
+        }
+        Ok(())
+    }
+
+    /// Overwrite `len` bytes of member `device` at byte `offset` with
+    /// zeroes
+    fn zero_range(&self, device: u32, offset: u64, len: u64) -> Result<(), MapperError> {
+        self.check_writable()?;
+        if self.plan_step(|| Step::Write { path: self.device_path(device).to_string(), offset, len }) {
+            return Ok(());
+        }
+        let zero = |blockdev: &mut dyn BlockStore| {
+            let zeroes = vec![0; std::cmp::min(len, self.iosize) as usize];
+            let mut done = 0;
+            while done < len {
+                let chunk = std::cmp::min(len - done, zeroes.len() as u64) as usize;
+                blockdev.write_bytes(offset + done, &zeroes[..chunk])?;
+                done += chunk as u64;
+            }
+            Ok(blockdev.sync()?)
+        };
+        match device {
+            0 => self.with_store(true, zero),
+            _ => zero(&mut OpenOptions::new().write(true).open(self.device_path(device))?),
+        }
     }
 
     /// Commit metadata back to storage
-    pub fn commit(&mut self) -> Result<(), io::Error> {
-        let mut blockdev = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&self.device)?;
-        let device_size = blockdev.seek(SeekFrom::End(0))?;
-        let iosize = get_io_size(&self.device)?;
-        let device_size_blocks = device_size / iosize;
+    pub fn commit(&mut self) -> Result<(), MapperError> {
+        self.check_writable()?;
+        let (meta1, meta2) = self.with_store(false, |blockdev| load_both_metadata(blockdev, self.iosize))?;
 
-        let (meta1, meta2) = load_both_metadata(&mut blockdev, iosize)?;
+        self.check_unchanged(&meta1, &meta2)?;
 
         // Decide which slot to write the new metadata to
-        let md_block = match (meta1, meta2) {
+        let slot = match (meta1, meta2) {
             (Some(_meta), None) => 2,
             (None, Some(_meta)) => 1,
             (None, None) => 1,
@@ -286,22 +1345,74 @@ impl SuperPartition {
                 }
             }
         };
+        self.write_replica(slot)?;
+        if self.hybrid_gpt {
+            self.write_shadow_gpt()?;
+        }
+        Ok(())
+    }
 
-        self.generation += 1;
+    /// Refuse to change anything through a super partition opened with
+    /// `open_readonly`
+    fn check_writable(&self) -> Result<(), MapperError> {
+        if self.read_only {
+            return Err(MapperError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Make sure nobody else has committed since this was loaded, as
+    /// writing our copy over theirs would silently undo it
+    fn check_unchanged(&self, meta1: &Option<SuperPartition>, meta2: &Option<SuperPartition>) -> Result<(), MapperError> {
+        let Some(expected) = self.on_disk else {
+            return Ok(());
+        };
+        let found = [meta1, meta2].into_iter().flatten().map(|m| m.generation).max().unwrap_or(0);
+        if found != expected {
+            return Err(MapperError::ConcurrentModification { expected, found });
+        }
+        Ok(())
+    }
 
-        let json = serde_json::to_string(&self).expect("json to_string");
-        // 4 byte CRC plus newline plus NUL
-        assert!(json.len() + 6 < iosize as usize);
-        let crc_algo = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
-        let actual_crc = crc_algo.checksum(json.as_bytes());
-        let crc_bytes = actual_crc.to_be_bytes();
+    /// Write the metadata as a new generation into `slot`, 1 being the
+    /// slot at the very end of the device
+    fn write_replica(&mut self, slot: u64) -> Result<(), MapperError> {
+        let iosize = self.iosize;
+        self.generation += 1;
+        self.format_version = format::FORMAT_VERSION;
+        if self.uuid.is_empty() {
+            self.uuid = probe::new_uuid()?;
+        }
+        for sv in self.subvols.values_mut().filter(|sv| sv.uuid.is_empty()) {
+            sv.uuid = probe::new_uuid()?;
+        }
+        self.committed_at = history::now();
+        let history_entry = self.next_history_entry();
+        let data = match slots::encode_replica(self, self.metadata_blocks, iosize) {
+            Ok(data) => data,
+            Err(e) => {
+                self.generation -= 1;
+                return Err(e);
+            }
+        };
+        if self.is_dry_run() {
+            self.plan_commit();
+            return Ok(());
+        }
 
-        blockdev.seek(SeekFrom::Start((device_size_blocks-md_block) * iosize))?;
-        blockdev.write_all(&crc_bytes)?;
-        blockdev.write_all(json.as_bytes())?;
-        blockdev.write_all("\n\0".as_bytes())?;
-        blockdev.sync_all()?;
+        let start = match &self.label {
+            Some(label) => label.slots[slot as usize - 1],
+            None => self.device_blocks[0] - slot * self.metadata_blocks,
+        };
+        self.with_store(true, |blockdev| {
+            blockdev.write_bytes(start * iosize, &data)?;
+            Ok(blockdev.sync()?)
+        })?;
+        self.on_disk = Some(self.generation);
 
+        if let Some(entry) = history_entry {
+            self.write_history_entry(entry)?;
+        }
         Ok(())
     }
 }
\ No newline at end of file
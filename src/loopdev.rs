@@ -0,0 +1,178 @@
+//! Super partitions kept in regular files.
+//!
+//! The metadata and the data of a super partition in a file are read and
+//! written like those on a block device, but device mapper can only map
+//! block devices.  The first time a table needs a member that is a
+//! regular file, a loop device is attached to it, or one already attached
+//! to the whole file is reused, and the table refers to that instead.
+//!
+//! Loop devices are attached with autoclear set, so the kernel detaches
+//! one as soon as nothing has it open.  The super partition holds each one
+//! open until it is dropped; after that, any DM devices mapped on it keep
+//! it attached until they are removed.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::Path;
+
+use nix::errno::Errno;
+use nix::libc::c_int;
+use nix::sys::stat;
+
+use crate::{MapperError, SuperPartition};
+
+const LOOP_CONTROL: &str = "/dev/loop-control";
+/// Set in `lo_flags` to detach on the last close
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+/// Times to look for a free loop device when others are taking them too
+const ATTACH_ATTEMPTS: usize = 8;
+
+/// `struct loop_info64` from linux/loop.h
+#[repr(C)]
+struct LoopInfo64 {
+    device: u64,
+    inode: u64,
+    rdevice: u64,
+    offset: u64,
+    sizelimit: u64,
+    number: u32,
+    encrypt_type: u32,
+    encrypt_key_size: u32,
+    flags: u32,
+    file_name: [u8; 64],
+    crypt_name: [u8; 64],
+    encrypt_key: [u8; 32],
+    init: [u64; 2],
+}
+
+nix::ioctl_none_bad!(loop_ctl_get_free, 0x4c82);
+nix::ioctl_write_int_bad!(loop_set_fd, 0x4c00);
+nix::ioctl_write_ptr_bad!(loop_set_status64, 0x4c04, LoopInfo64);
+nix::ioctl_none_bad!(loop_clr_fd, 0x4c01);
+
+/// A loop device attached to a member file, held open
+#[derive(Debug)]
+pub(crate) struct LoopDevice {
+    node: File,
+    /// Device number
+    major: u32,
+    minor: u32,
+}
+
+impl LoopDevice {
+    fn from_node(node: File) -> Result<Self, MapperError> {
+        let rdev = node.metadata()?.rdev();
+        Ok(Self { node, major: stat::major(rdev) as u32, minor: stat::minor(rdev) as u32 })
+    }
+
+    /// Attach a new loop device to the whole of `backing`
+    fn attach(backing: &str) -> Result<Self, MapperError> {
+        let file = OpenOptions::new().read(true).write(true).open(backing)?;
+        let control = File::open(LOOP_CONTROL)?;
+        let mut info = LoopInfo64 {
+            device: 0,
+            inode: 0,
+            rdevice: 0,
+            offset: 0,
+            sizelimit: 0,
+            number: 0,
+            encrypt_type: 0,
+            encrypt_key_size: 0,
+            flags: LO_FLAGS_AUTOCLEAR,
+            file_name: [0; 64],
+            crypt_name: [0; 64],
+            encrypt_key: [0; 32],
+            init: [0; 2],
+        };
+        let name = backing.as_bytes();
+        let n = name.len().min(info.file_name.len() - 1);
+        info.file_name[..n].copy_from_slice(&name[..n]);
+
+        for _ in 0..ATTACH_ATTEMPTS {
+            let number = unsafe { loop_ctl_get_free(control.as_raw_fd()) }.map_err(io::Error::from)?;
+            let node = OpenOptions::new().read(true).write(true).open(format!("/dev/loop{}", number))?;
+            match unsafe { loop_set_fd(node.as_raw_fd(), file.as_raw_fd() as c_int) } {
+                // Someone else got there between finding it and taking it
+                Err(Errno::EBUSY) => continue,
+                result => result.map_err(io::Error::from)?,
+            };
+            if let Err(e) = unsafe { loop_set_status64(node.as_raw_fd(), &info) } {
+                let _ = unsafe { loop_clr_fd(node.as_raw_fd()) };
+                return Err(io::Error::from(e).into());
+            }
+            return Self::from_node(node);
+        }
+        Err(MapperError::DeviceBusy(format!("no free loop device for {}", backing)))
+    }
+
+    /// A loop device already attached to the whole of `backing`, such as
+    /// one left attached by the DM devices of an earlier open
+    fn find_attached(backing: &str) -> Result<Option<Self>, MapperError> {
+        let backing = fs::canonicalize(backing)?;
+        let Ok(entries) = fs::read_dir("/sys/block") else {
+            return Ok(None);
+        };
+        for entry in entries.flatten() {
+            let sys = entry.path().join("loop");
+            let attr = |name: &str| fs::read_to_string(sys.join(name)).map(|s| s.trim().to_string());
+            let Ok(file) = attr("backing_file") else {
+                continue;
+            };
+            let whole = attr("offset").is_ok_and(|s| s == "0") && attr("sizelimit").is_ok_and(|s| s == "0");
+            if Path::new(&file) == backing && whole {
+                let node = OpenOptions::new().read(true).write(true).open(Path::new("/dev").join(entry.file_name()))?;
+                return Self::from_node(node).map(Some);
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl SuperPartition {
+    /// How a DM table refers to member `index`: as the DM backend says,
+    /// or else by device number, attaching a loop device first if the
+    /// member is a regular file.  Without `attach`, a file with no loop
+    /// device attached yet is given by its path, for tables only shown.
+    pub(crate) fn member_ref(&self, index: u32, attach: bool) -> Result<String, MapperError> {
+        // A store has no device number of its own
+        if index == 0 && self.store.is_some() {
+            return Ok("0:0".to_string());
+        }
+        let path = self.device_path(index);
+        if let Some(backend) = self.dm_backend.get() {
+            if let Some(member) = backend.member_ref(index, path)? {
+                return Ok(member);
+            }
+        }
+        let st = fs::metadata(path)?;
+        if st.file_type().is_block_device() {
+            return Ok(format!("{}:{}", stat::major(st.rdev()), stat::minor(st.rdev())));
+        }
+        if !st.file_type().is_file() {
+            return Err(MapperError::InvalidArgument(format!("{} is neither a block device nor a regular file", path)));
+        }
+
+        let mut loops = self.loops.borrow_mut();
+        if let Some(dev) = loops.get(&index) {
+            return Ok(format!("{}:{}", dev.major, dev.minor));
+        }
+        let dev = match LoopDevice::find_attached(path)? {
+            Some(dev) => dev,
+            None if attach => LoopDevice::attach(path)?,
+            None => return Ok(path.to_string()),
+        };
+        let devnum = format!("{}:{}", dev.major, dev.minor);
+        loops.insert(index, dev);
+        Ok(devnum)
+    }
+}
+
+// Closing the node detaches the loop device, courtesy of autoclear, once
+// the DM devices on it are gone too; what went through it is flushed first
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        let _ = self.node.sync_all();
+    }
+}
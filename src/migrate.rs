@@ -0,0 +1,83 @@
+//! Recreating a whole super partition on another device.
+//!
+//! Migration lays every subvolume out afresh on a newly formatted target,
+//! which can be bigger or smaller than the source so long as everything
+//! fits, copies the blocks straight from one set of devices to the other,
+//! and only then commits the target's metadata for the first time.  Until
+//! that commit the target holds no subvolumes, so an interrupted migration
+//! is simply started over.
+//!
+//! The target stands in for the source: it gets the same UUID, name, DM
+//! prefix and slot state, and the subvolumes keep their names and UUIDs.
+//! It is left inactive, and the two should not be used side by side
+//! afterwards, as their DM devices would have the same names.  Only plain
+//! subvolumes are migrated.
+
+use crate::{is_reserved, CreateOptions, MapperError, SuperPartition};
+
+impl SuperPartition {
+    /// Recreate every subvolume on `dst`, which must have none yet, copy
+    /// their data across and commit `dst`.  `progress` is called with the
+    /// subvolume being copied and the bytes of it copied so far.  Returns
+    /// the names of the subvolumes, in the order they were copied.
+    pub fn migrate_to(&self, dst: &mut SuperPartition, progress: &mut dyn FnMut(&str, u64)) -> Result<Vec<String>, MapperError> {
+        dst.check_writable()?;
+        if dst.subvols.keys().any(|name| !is_reserved(name)) {
+            return Err(MapperError::InvalidArgument(format!("{} already has subvols", dst.device())));
+        }
+        if let Some(name) = self.pending_relocation() {
+            return Err(MapperError::InvalidArgument(format!("relocation of {} is pending", name)));
+        }
+
+        // External records have nothing to copy and come along as they are
+        let mut names: Vec<_> = self.subvols.keys()
+            .filter(|name| !is_reserved(name) && !self.is_external(name))
+            .cloned()
+            .collect();
+        let mut not_plain: Vec<_> = names.iter()
+            .filter(|name| !self.subvols[*name].is_plain())
+            .map(String::as_str)
+            .collect();
+        if !not_plain.is_empty() {
+            not_plain.sort();
+            return Err(MapperError::InvalidArgument(format!("only plain subvols can be migrated, not {}", not_plain.join(", "))));
+        }
+        // Placing the biggest first gives a tight fit the best chance
+        names.sort_by(|a, b| self.subvols[b].size_bytes().cmp(&self.subvols[a].size_bytes()).then(a.cmp(b)));
+
+        // Everything is allocated before anything is copied, so a target
+        // that is too small is found out straight away
+        for name in &names {
+            let orig = &self.subvols[name];
+            let options = CreateOptions {
+                contiguous: orig.contiguous,
+                alignment: orig.alignment,
+                ..CreateOptions::default()
+            };
+            let fresh = dst.new_subvol_with(name, orig.size_bytes(), &options)?;
+            let mut sv = orig.clone();
+            sv.extents = fresh.extents;
+            sv.iosize = dst.iosize;
+            dst.subvols.insert(name.clone(), sv);
+        }
+
+        for name in &names {
+            let from = &self.subvols[name].extents;
+            let to = &dst.subvols[name].extents;
+            self.quiesced(name, || self.copy_extents_to(from, dst, to, &mut |copied| progress(name, copied)))?;
+        }
+
+        for (name, sv) in self.subvols.iter().filter(|(_name, sv)| sv.external) {
+            let mut sv = sv.clone();
+            sv.iosize = dst.iosize;
+            dst.subvols.insert(name.clone(), sv);
+        }
+        dst.uuid = self.uuid.clone();
+        dst.name = self.name.clone();
+        dst.dm_prefix = self.dm_prefix.clone();
+        dst.active_slot = self.active_slot;
+        dst.boot = self.boot.clone();
+        dst.commit()?;
+        Ok(names)
+    }
+}
@@ -0,0 +1,124 @@
+//! Mirrored subvolumes using dm-raid1.
+//!
+//! A mirror has a second extent set holding a full copy of its data,
+//! placed on a different member device than the first where there is room.
+//! The two legs are mapped by `<name>-mimage0` and `<name>-mimage1` and a
+//! mirror target on top writes to both and keeps serving reads if one of
+//! them fails.  The region log is kept in memory, so the legs are resynced
+//! each time the mirror is activated.
+
+use serde::{Deserialize, Serialize};
+
+use crate::dm::{Layer, Target};
+use crate::{Extent, MapperError, SubVolume, SuperPartition};
+
+pub(crate) const LEG_SUFFIXES: [&str; 2] = ["mimage0", "mimage1"];
+
+/// Resync granularity, in 512-byte sectors
+pub(crate) const REGION_SECTORS: u64 = 1024;
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub(crate) struct Mirror {
+    /// Extents of the second leg; the first is the subvolume's own
+    extents: Vec<Extent>,
+}
+
+impl Mirror {
+    pub(crate) fn extents(&self) -> &[Extent] {
+        &self.extents
+    }
+
+    pub(crate) fn extents_mut(&mut self) -> &mut [Extent] {
+        &mut self.extents
+    }
+}
+
+/// State of a mirrored subvolume as reported by the kernel
+#[derive(Serialize,Debug,Clone,PartialEq)]
+pub struct MirrorStatus {
+    /// Whether each leg is alive, in leg order
+    pub legs_alive: Vec<bool>,
+    pub synced_regions: u64,
+    pub total_regions: u64,
+}
+
+impl MirrorStatus {
+    /// Whether a leg has failed, leaving the data without redundancy
+    pub fn is_degraded(&self) -> bool {
+        self.legs_alive.iter().any(|alive| !alive)
+    }
+
+    /// Whether both legs are known to hold the same data
+    pub fn is_synced(&self) -> bool {
+        self.synced_regions == self.total_regions
+    }
+}
+
+impl SuperPartition {
+    pub(crate) fn mirror_layers(&self, name: &str, sv: &SubVolume, mirror: &Mirror) -> Vec<Layer> {
+        vec![
+            Layer {
+                suffix: Some(LEG_SUFFIXES[0]),
+                target: Target::Linear(sv.extents.clone()),
+            },
+            Layer {
+                suffix: Some(LEG_SUFFIXES[1]),
+                target: Target::Linear(mirror.extents.clone()),
+            },
+            Layer {
+                suffix: None,
+                target: Target::Mirror {
+                    legs: LEG_SUFFIXES.iter().map(|s| self.layer_name(name, Some(s))).collect(),
+                    sectors: sv.size_bytes() / 512,
+                },
+            },
+        ]
+    }
+
+    /// Create a subvolume of `size` bytes with its data kept twice
+    pub fn create_mirrored_subvol(&mut self, name: String, size: u64) -> Result<(), MapperError> {
+        let mut sv = self.new_subvol(&name, size)?;
+        let needed = sv.size_blocks();
+
+        // Reserve the first leg while finding room for the second, away
+        // from the devices the first leg landed on if possible
+        self.subvols.insert(name.clone(), sv.clone());
+        let holes = self.free_extents().into_iter()
+            .filter(|hole| sv.extents.iter().all(|e| e.device != hole.device))
+            .collect();
+        let second = self.allocate_from(holes, needed)
+            .or_else(|_| self.allocate(needed));
+        self.subvols.remove(&name);
+
+        sv.mirror = Some(Mirror { extents: second? });
+        self.insert_subvol(name, sv)
+    }
+
+    /// Report the health of mirrored subvolume `name`, which must be active
+    pub fn mirror_status(&self, name: &str) -> Result<MirrorStatus, MapperError> {
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        if sv.mirror.is_none() {
+            return Err(MapperError::InvalidArgument(format!("{} is not mirrored", name)));
+        }
+
+        let status = self.dm()?.status(&self.layer_name(name, None))?;
+        let line = status.first()
+            .ok_or_else(|| MapperError::InvalidArgument(format!("{} has no table", name)))?;
+
+        // <#legs> <leg>... <synced>/<total> <#health args> <health> ...
+        let malformed = || MapperError::InvalidArgument(format!("unexpected mirror status: {}", line.3));
+        let fields: Vec<_> = line.3.split_whitespace().collect();
+        let legs: usize = fields.first().and_then(|n| n.parse().ok()).ok_or_else(malformed)?;
+        let (synced, total) = fields.get(legs + 1)
+            .and_then(|s| s.split_once('/'))
+            .ok_or_else(malformed)?;
+        let health = fields.get(legs + 3).ok_or_else(malformed)?;
+
+        Ok(MirrorStatus {
+            legs_alive: health.chars().map(|c| c == 'A').collect(),
+            synced_regions: synced.parse().map_err(|_| malformed())?,
+            total_regions: total.parse().map_err(|_| malformed())?,
+        })
+    }
+}
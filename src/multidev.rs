@@ -0,0 +1,220 @@
+//! Super partitions spanning more than one block device.
+//!
+//! The device the super partition was opened from holds the metadata and
+//! is device 0.  Further member devices only hold subvolume data; each
+//! extent records the index of the device it lives on, so a subvolume can
+//! be allocated across several of them.
+//!
+//! A member can be evacuated, moving every extent on it to the other
+//! members, and then removed.  Each subvolume is moved in one go: its
+//! data is copied while it is suspended, and its new extents committed
+//! before it is resumed on them.  Until that commit the old extents are
+//! the ones in use, so an interrupted evacuation loses nothing, and
+//! running it again carries on with whatever is left.  Snapshots, their
+//! origins and thin pools are not moved; those have to be deleted or
+//! taken elsewhere first.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+
+use crate::{get_io_size, is_reserved, CreateOptions, Extent, LargestHoleFirst, MapperError, SubVolume, SuperPartition};
+
+impl SuperPartition {
+    /// Path of member device `index`
+    pub(crate) fn device_path(&self, index: u32) -> &str {
+        match index {
+            0 => &self.device,
+            n => &self.members[n as usize - 1],
+        }
+    }
+
+    /// Paths of every member device, starting with the one holding the
+    /// metadata
+    pub fn devices(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.device.as_str()).chain(self.members.iter().map(String::as_str))
+    }
+
+    /// Number of whole blocks on `device`, after checking it can be
+    /// addressed in this super partition's block size
+    fn member_blocks(&self, device: &str) -> Result<u64, MapperError> {
+        let mut blockdev = File::open(device)?;
+        if !self.iosize.is_multiple_of(get_io_size(device)?) {
+            return Err(MapperError::InvalidArgument(format!("{} needs a larger block size than {}", device, self.iosize)));
+        }
+        Ok(blockdev.seek(SeekFrom::End(0))? / self.iosize)
+    }
+
+    /// Add `device` as a new member, making its space available to the
+    /// allocator.  Anything already on it is lost.
+    pub fn add_device(&mut self, device: String) -> Result<(), MapperError> {
+        if self.devices().any(|d| d == device) {
+            return Err(MapperError::AlreadyExists(device));
+        }
+        let blocks = self.member_blocks(&device)?;
+        self.members.push(device);
+        self.device_blocks.push(blocks);
+        if let Err(e) = self.commit() {
+            self.members.pop();
+            self.device_blocks.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Index of member `device`, which has to be one besides the
+    /// metadata device
+    fn member_index(&self, device: &str) -> Result<u32, MapperError> {
+        match self.devices().position(|d| d == device) {
+            Some(0) => Err(MapperError::InvalidArgument(format!("{} holds the metadata", device))),
+            Some(index) => Ok(index as u32),
+            None => Err(MapperError::NotFound(device.to_string())),
+        }
+    }
+
+    /// Move every extent on member `device` to the other members,
+    /// returning the names of the subvolumes that were moved.  Whether
+    /// there is room for them all is checked before anything is moved.
+    pub fn evacuate(&mut self, device: &str) -> Result<Vec<String>, MapperError> {
+        self.check_writable()?;
+        let index = self.member_index(device)?;
+        if let Some(name) = self.pending_relocation() {
+            return Err(MapperError::InvalidArgument(format!("relocation of {} is pending", name)));
+        }
+
+        let mut names = vec![];
+        let mut needed = 0;
+        for (name, sv) in &self.subvols {
+            if is_reserved(name) || !sv.all_extents().any(|e| e.device == index) {
+                continue;
+            }
+            let data_only = sv.all_extents().filter(|e| e.device == index).count()
+                == sv.extents.iter().filter(|e| e.device == index).count();
+            if sv.snapshot_of.is_some() || self.has_snapshots(name) || sv.thin_pool.is_some() || !data_only {
+                return Err(MapperError::InvalidArgument(format!("{} on {} cannot be moved", name, device)));
+            }
+            names.push(name.clone());
+            needed += sv.extents.iter().filter(|e| e.device == index).map(|e| e.block_length).sum::<u64>();
+        }
+        let available = self.free_extents().iter()
+            .filter(|h| h.device != index)
+            .map(|h| h.block_length)
+            .sum();
+        if needed > available {
+            return Err(MapperError::NoSpace { needed, available });
+        }
+
+        names.sort();
+        for name in &names {
+            self.evacuate_subvol(name, index)?;
+        }
+        Ok(names)
+    }
+
+    /// Move the extents of `name` on member `index` elsewhere
+    fn evacuate_subvol(&mut self, name: &str, index: u32) -> Result<(), MapperError> {
+        let sv = self.subvols[name].clone();
+        let options = CreateOptions {
+            strategy: &LargestHoleFirst,
+            contiguous: sv.contiguous,
+            alignment: sv.alignment,
+            avoid_device: Some(index),
+            ..CreateOptions::default()
+        };
+        let from: Vec<_> = sv.extents.iter().filter(|e| e.device == index).cloned().collect();
+        let to = self.allocate_with(from.iter().map(|e| e.block_length).sum(), &options)?;
+
+        // Each extent being moved is replaced by as much of the new space
+        // as it covered, splitting that up where need be
+        let mut spare: VecDeque<_> = to.iter().cloned().collect();
+        let mut moved = SubVolume { extents: vec![], ..sv.clone() };
+        for e in &sv.extents {
+            if e.device != index {
+                moved.extents.push(e.clone());
+                continue;
+            }
+            let mut left = e.block_length;
+            while left > 0 {
+                let mut piece = spare.pop_front().expect("allocated too little");
+                if piece.block_length > left {
+                    spare.push_front(Extent {
+                        device: piece.device,
+                        block_offset: piece.block_offset + left,
+                        block_length: piece.block_length - left,
+                    });
+                    piece.block_length = left;
+                }
+                left -= piece.block_length;
+                moved.extents.push(piece);
+            }
+        }
+
+        // Nothing may be written to the old extents after they have been
+        // copied, until the subvolume is on the new ones
+        let live = self.dm_active(name)?;
+        let active = live && !self.is_dry_run();
+        if active {
+            File::open(self.subvol_path(name))?.sync_all()?;
+            self.suspend_dm(name, &sv)?;
+        }
+        let switched = self.copy_extents(&from, &to).and_then(|()| {
+            self.subvols.insert(name.to_string(), moved.clone());
+            self.commit()
+        });
+        if let Err(e) = switched {
+            self.subvols.insert(name.to_string(), sv.clone());
+            if active {
+                self.resume_dm(name, &sv)?;
+            }
+            return Err(e);
+        }
+        if live {
+            self.reload_dm(name, &moved)?;
+        }
+        Ok(())
+    }
+
+    /// Remove member `device`, which must have nothing left on it; see
+    /// `evacuate`.  The members after it move up a place.
+    pub fn remove_device(&mut self, device: &str) -> Result<(), MapperError> {
+        self.check_writable()?;
+        let index = self.member_index(device)?;
+        if let Some((name, _sv)) = self.subvols.iter().find(|(_name, sv)| sv.all_extents().any(|e| e.device == index)) {
+            return Err(MapperError::InvalidArgument(format!("{} still has extents on {}; evacuate it first", name, device)));
+        }
+        if self.relocation.as_ref().is_some_and(|r| r.to.device == index) {
+            return Err(MapperError::InvalidArgument(format!("a relocation is pending onto {}", device)));
+        }
+
+        let members = self.members.clone();
+        let device_blocks = self.device_blocks.clone();
+        let subvols = self.subvols.clone();
+        let relocation = self.relocation.clone();
+        self.members.remove(index as usize - 1);
+        self.device_blocks.remove(index as usize);
+        let renumber = |e: &mut Extent| if e.device > index {
+            e.device -= 1;
+        };
+        self.subvols.values_mut().flat_map(SubVolume::all_extents_mut).for_each(renumber);
+        if let Some(reloc) = &mut self.relocation {
+            renumber(&mut reloc.to);
+        }
+        if let Err(e) = self.commit() {
+            self.members = members;
+            self.device_blocks = device_blocks;
+            self.subvols = subvols;
+            self.relocation = relocation;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Look up the size of every member besides the metadata device
+    pub(crate) fn size_members(&mut self) -> Result<(), MapperError> {
+        for member in 0..self.members.len() {
+            let blocks = self.member_blocks(&self.members[member])?;
+            self.device_blocks.push(blocks);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,183 @@
+//! Recording what a change would do instead of doing it.
+//!
+//! A super partition opened for a dry run behaves as usual up to the point
+//! where something would reach the device or the kernel: metadata commits,
+//! table loads, removals, renames, pool messages and writes to the member
+//! devices are each recorded as a step in a plan and skipped.  Everything
+//! in memory still changes, so later steps see the state the earlier ones
+//! would have left, and commits keep checking that nobody else has
+//! committed meanwhile.  Nothing is locked either, so the plan is only as
+//! good as long as the device isn't changed before the real run.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use crate::dm::{table_lines, Layer};
+use crate::{is_reserved, Extent, MapperError, SuperPartition};
+
+/// Something a dry run would have done
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Write metadata generation `generation`.  `allocated` gives the new
+    /// extents of every subvolume created or moved since the previous
+    /// commit, and `freed` the subvolumes that are gone.
+    Commit { generation: u32, allocated: BTreeMap<String, Vec<Extent>>, freed: Vec<String>, metadata: String },
+    /// Create DM device `device` if it doesn't exist and make `table` its
+    /// live table, one line per target
+    Load { device: String, table: Vec<String>, read_only: bool },
+    /// Remove DM device `device`, once it is closed if `deferred`
+    Remove { device: String, deferred: bool },
+    Rename { from: String, to: String },
+    /// Send `message` to the target of DM device `device`
+    Message { device: String, message: String },
+    /// Overwrite `len` bytes of `path` at byte `offset`
+    Write { path: String, offset: u64, len: u64 },
+    /// Discard `len` bytes of `path` at byte `offset`
+    Discard { path: String, offset: u64, len: u64 },
+}
+
+/// Steps recorded by a dry run, in the order they would have happened
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+    /// Name and extents of each subvolume as of the last commit, by UUID
+    /// so that renames are not mistaken for new subvolumes
+    committed: HashMap<String, (String, Vec<Extent>)>,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::Commit { generation, allocated, freed, metadata } => {
+                write!(f, "commit generation {}", generation)?;
+                for (name, extents) in allocated {
+                    let extents: Vec<_> = extents.iter()
+                        .map(|e| format!("{}:{}:{}", e.device, e.block_offset, e.block_length))
+                        .collect();
+                    write!(f, "\n  {}: {}", name, extents.join(" "))?;
+                }
+                for name in freed {
+                    write!(f, "\n  {}: freed", name)?;
+                }
+                write!(f, "\n{}", metadata)
+            }
+            Step::Load { device, table, read_only } => {
+                write!(f, "load {}{}", device, if *read_only { " (read-only)" } else { "" })?;
+                for line in table {
+                    write!(f, "\n  {}", line)?;
+                }
+                Ok(())
+            }
+            Step::Remove { device, deferred: false } => write!(f, "remove {}", device),
+            Step::Remove { device, deferred: true } => write!(f, "remove {} once closed", device),
+            Step::Rename { from, to } => write!(f, "rename {} to {}", from, to),
+            Step::Message { device, message } => write!(f, "send {:?} to {}", message, device),
+            Step::Write { path, offset, len } => write!(f, "write {} bytes to {} at {}", len, path, offset),
+            Step::Discard { path, offset, len } => write!(f, "discard {} bytes of {} at {}", len, path, offset),
+        }
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for step in &self.steps {
+            writeln!(f, "{}", step)?;
+        }
+        Ok(())
+    }
+}
+
+impl SuperPartition {
+    /// Like `open_inactive`, but without taking the lock, and recording
+    /// everything that would touch the device or device mapper in a plan
+    /// rather than doing it.  An interrupted change is finished in the
+    /// plan too, as `open` would.
+    pub fn open_dry_run(device: String) -> Result<Self, MapperError> {
+        let mut meta = Self::load(device)?;
+        meta.on_disk = Some(meta.generation);
+        meta.start_plan();
+        meta.recover_intent()?;
+        Ok(meta)
+    }
+
+    /// Whether changes are recorded in a plan rather than made
+    pub fn is_dry_run(&self) -> bool {
+        self.plan.is_some()
+    }
+
+    /// The steps recorded since the plan was started or last taken, or
+    /// None if this is not a dry run
+    pub fn take_plan(&mut self) -> Option<Plan> {
+        let plan = self.plan.as_mut()?.get_mut();
+        let committed = plan.committed.clone();
+        Some(std::mem::replace(plan, Plan { steps: vec![], committed }))
+    }
+
+    pub(crate) fn start_plan(&mut self) {
+        let committed = self.extent_map();
+        self.plan = Some(RefCell::new(Plan { steps: vec![], committed }));
+    }
+
+    /// Record the step made by `step` if this is a dry run, returning
+    /// whether it was recorded rather than to be carried out
+    pub(crate) fn plan_step(&self, step: impl FnOnce() -> Step) -> bool {
+        match &self.plan {
+            Some(plan) => {
+                plan.borrow_mut().steps.push(step());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record committing the metadata as it stands now
+    pub(crate) fn plan_commit(&self) {
+        let Some(plan) = &self.plan else { return };
+        let extents = self.extent_map();
+        let mut plan = plan.borrow_mut();
+        let allocated = extents.iter()
+            .filter(|(uuid, (_name, e))| plan.committed.get(*uuid).is_none_or(|(_name, old)| old != e))
+            .map(|(_uuid, entry)| entry.clone())
+            .collect();
+        let mut freed: Vec<_> = plan.committed.iter()
+            .filter(|(uuid, _entry)| !extents.contains_key(*uuid))
+            .map(|(_uuid, (name, _e))| name.clone())
+            .collect();
+        freed.sort();
+        plan.steps.push(Step::Commit {
+            generation: self.generation,
+            allocated,
+            freed,
+            metadata: self.dump(),
+        });
+        plan.committed = extents;
+    }
+
+    /// Record loading each of `layers` for subvolume `name`
+    pub(crate) fn plan_layers(&self, name: &str, layers: &[Layer]) -> Result<(), MapperError> {
+        for layer in layers {
+            let table = self.table(None, &layer.target)?;
+            let read_only = self.load_read_only(name, layer.suffix);
+            self.plan_step(|| Step::Load {
+                device: self.layer_name(name, layer.suffix),
+                table: table_lines(&table),
+                read_only,
+            });
+        }
+        Ok(())
+    }
+
+    /// Every extent of each subvolume by UUID, bookkeeping entries aside.
+    /// Subvolumes only get a UUID when first committed, so until then the
+    /// name stands in for it.
+    fn extent_map(&self) -> HashMap<String, (String, Vec<Extent>)> {
+        self.subvols.iter()
+            .filter(|(name, _sv)| !is_reserved(name))
+            .map(|(name, sv)| {
+                let key = if sv.uuid.is_empty() { name.clone() } else { sv.uuid.clone() };
+                (key, (name.clone(), sv.all_extents().cloned().collect()))
+            })
+            .collect()
+    }
+}
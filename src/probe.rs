@@ -0,0 +1,78 @@
+//! Recognising super partitions without parsing their metadata.
+//!
+//! The last bytes of every metadata block carry a fixed signature: an
+//! 8-byte magic followed by the super partition's UUID in its usual text
+//! form.  With the default placement that puts the magic at a fixed offset
+//! from the end of the device, where tools like blkid can look for it;
+//! devices with a label can also be recognised by the label's magic at
+//! the very start.
+
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::label;
+
+const SIGNATURE_MAGIC: [u8; 8] = *b"HGMAPSB\0";
+const UUID_LEN: usize = 36;
+/// Bytes reserved at the end of each metadata block
+pub(crate) const SIGNATURE_SIZE: usize = 48;
+
+/// The signature block tail for a super partition with `uuid`
+pub(crate) fn signature(uuid: &str) -> Vec<u8> {
+    let mut sig = SIGNATURE_MAGIC.to_vec();
+    sig.extend(uuid.as_bytes().iter().take(UUID_LEN));
+    sig.resize(SIGNATURE_SIZE, 0);
+    sig
+}
+
+/// Whether `tail`, the last bytes of a block, starts like a signature
+pub(crate) fn has_signature(tail: &[u8]) -> bool {
+    tail.starts_with(&SIGNATURE_MAGIC)
+}
+
+/// A random version 4 UUID, formatted as text
+pub(crate) fn new_uuid() -> std::io::Result<String> {
+    let mut b = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut b)?;
+    b[6] = (b[6] & 0x0f) | 0x40;
+    b[8] = (b[8] & 0x3f) | 0x80;
+    let hex: String = b.iter().map(|b| format!("{:02x}", b)).collect();
+    Ok(format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
+}
+
+/// UUID of the super partition on `device`, if it carries a signature at
+/// its end
+pub fn probe_uuid(device: &str) -> Option<String> {
+    let mut f = File::open(device).ok()?;
+    let size = f.seek(SeekFrom::End(0)).ok()?;
+    f.seek(SeekFrom::Start(size.checked_sub(SIGNATURE_SIZE as u64)?)).ok()?;
+    let mut sig = [0; SIGNATURE_SIZE];
+    f.read_exact(&mut sig).ok()?;
+    if sig[..8] != SIGNATURE_MAGIC {
+        return None;
+    }
+    let uuid = &sig[8..8 + UUID_LEN];
+    Some(String::from_utf8_lossy(uuid).trim_end_matches('\0').to_string())
+}
+
+/// Whether `device` looks like it holds a super partition
+pub fn probe(device: &str) -> bool {
+    if probe_uuid(device).is_some() {
+        return true;
+    }
+    File::open(device).ok()
+        .and_then(|mut f| label::read_label(&mut f).ok().flatten())
+        .is_some()
+}
+
+/// Block devices on this system that hold a super partition
+pub fn scan() -> Vec<String> {
+    let mut found: Vec<_> = fs::read_dir("/sys/class/block").into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| format!("/dev/{}", entry.file_name().to_string_lossy()))
+        .filter(|device| probe(device))
+        .collect();
+    found.sort();
+    found
+}
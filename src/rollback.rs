@@ -0,0 +1,157 @@
+//! Going back to an earlier metadata generation.
+//!
+//! Both replicas are kept, so the generation before the latest commit is
+//! normally still on disk.  Switching back to it tears down the DM devices
+//! of every subvolume whose mapping differs, rewrites the old layout as a
+//! new generation, and brings those subvolumes back up as they were.
+
+use std::collections::BTreeSet;
+
+use crate::{load_both_metadata, MapperError, SuperPartition};
+
+impl SuperPartition {
+    /// Generation of the other replica, if it holds an older layout that
+    /// `rollback` could return to
+    pub fn previous_generation(&self) -> Result<Option<u32>, MapperError> {
+        Ok(self.previous()?.map(|m| m.generation))
+    }
+
+    fn previous(&self) -> Result<Option<SuperPartition>, MapperError> {
+        let (meta1, meta2) = self.with_store(false, |blockdev| load_both_metadata(blockdev, self.iosize))?;
+        Ok([meta1, meta2].into_iter()
+            .flatten()
+            .filter(|m| m.generation < self.generation)
+            .max_by_key(|m| m.generation))
+    }
+
+    /// Return to the layout of the previous generation.  Fails without
+    /// changing anything if a subvolume that would change is in use.
+    pub fn rollback(&mut self) -> Result<(), MapperError> {
+        let previous = self.previous()?
+            .ok_or_else(|| MapperError::InvalidArgument("no previous generation on disk".to_string()))?;
+        self.switch_layout(previous)
+    }
+
+    /// Replace the current layout with `target`, reconciling the DM
+    /// devices, and commit it as a new generation
+    pub(crate) fn switch_layout(&mut self, mut target: SuperPartition) -> Result<(), MapperError> {
+        self.check_writable()?;
+        if self.relocation.is_some() || target.relocation.is_some() {
+            return Err(MapperError::InvalidArgument("cannot switch layouts during a relocation".to_string()));
+        }
+        match &self.store {
+            // A store has no path to bind to, and no further members
+            Some(_store) => {
+                target.device = self.device.clone();
+                target.label = self.label.clone();
+                target.device_blocks = self.device_blocks.clone();
+                let iosize = target.iosize;
+                for sv in target.subvols.values_mut() {
+                    sv.iosize = iosize;
+                }
+                target.check_extents()?;
+            }
+            None => target.bind(self.device.clone(), self.label.clone())?,
+        }
+
+        // Subvolumes whose DM stack differs between the two layouts.  An
+        // origin's stack depends on whether it has snapshots, so a change
+        // to a snapshot changes its origin too.
+        let mut changed: BTreeSet<String> = self.subvols.keys().chain(target.subvols.keys())
+            .filter(|name| self.subvols.get(*name) != target.subvols.get(*name))
+            .cloned()
+            .collect();
+        let origins: Vec<_> = changed.iter()
+            .flat_map(|name| [self.subvols.get(name), target.subvols.get(name)])
+            .flatten()
+            .filter_map(|sv| sv.snapshot_of.clone())
+            .collect();
+        changed.extend(origins);
+
+        for name in &changed {
+            let thin = |sp: &SuperPartition| sp.subvols.get(name).is_some_and(|sv| sv.thin.is_some() || sv.thin_pool.is_some());
+            if thin(self) || thin(&target) {
+                return Err(MapperError::InvalidArgument(format!("cannot switch layouts of thin pool or volume {}", name)));
+            }
+            if self.subvols.contains_key(name) && self.dm_in_use(name)? {
+                return Err(MapperError::DeviceBusy(name.clone()));
+            }
+        }
+
+        // Dependants come down before what they stack on, and go up after
+        let mut down: Vec<_> = changed.iter().filter(|name| self.subvols.contains_key(*name)).collect();
+        down.sort_by_key(|name| self.subvols[*name].depends_on().is_none());
+        for name in down {
+            self.remove_dm(name)?;
+        }
+
+        target.generation = self.generation;
+        target.encoding = self.encoding;
+        // The ring keeps going regardless of which layout is current
+        target.history = self.history.clone();
+        // Nor does anything about how this one was opened
+        target.lock = self.lock.take();
+        target.on_disk = self.on_disk;
+        target.read_only = self.read_only;
+        target.plan = self.plan.take();
+        target.loops = std::mem::take(&mut self.loops);
+        target.store = self.store.take();
+        target.dm_backend = std::mem::take(&mut self.dm_backend);
+        *self = target;
+        self.commit()?;
+
+        let mut up: Vec<_> = changed.iter().filter(|name| self.subvols.contains_key(*name)).collect();
+        up.sort_by_key(|name| self.subvols[*name].depends_on().is_some());
+        for name in up {
+            self.create_dm(name, &self.subvols[name])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::testutil::{format, image, MIB};
+    use crate::{DmBackend, MockDm, Step, SuperPartition};
+
+    fn with_two_subvols() -> (SuperPartition, Rc<MockDm>) {
+        let (mut sp, dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create a");
+        sp.create_subvol("b".to_string(), 4 * MIB).expect("create b");
+        (sp, dm)
+    }
+
+    #[test]
+    fn rollback_drops_the_last_change() {
+        let (mut sp, dm) = with_two_subvols();
+        sp.rollback().expect("rollback");
+        assert!(sp.subvols.contains_key("a"));
+        assert!(!sp.subvols.contains_key("b"));
+        assert!(!dm.exists("hg-b").expect("exists"));
+    }
+
+    #[test]
+    fn dry_run_rollback_writes_nothing() {
+        let (mut sp, dm) = with_two_subvols();
+        let before = image(&sp);
+        dm.clear_calls();
+        sp.start_plan();
+
+        sp.rollback().expect("rollback");
+        assert!(sp.is_dry_run());
+        assert_eq!(image(&sp), before);
+        assert_eq!(dm.calls(), vec![]);
+        let steps = sp.take_plan().expect("plan").steps;
+        assert!(steps.iter().any(|step| matches!(step, Step::Remove { device, .. } if device == "hg-b")));
+        assert!(steps.iter().any(|step| matches!(step, Step::Commit { .. })));
+    }
+
+    #[test]
+    fn read_only_rollback_is_refused() {
+        let (mut sp, _dm) = with_two_subvols();
+        sp.read_only = true;
+        assert!(matches!(sp.rollback(), Err(crate::MapperError::ReadOnly)));
+    }
+}
@@ -0,0 +1,99 @@
+//! Sizes written the way people write them.
+//!
+//! Besides a plain byte count, a size can carry a binary suffix (`512M`,
+//! `4GiB`), or be a share of the whole device (`25%`) or of its free space
+//! (`100%FREE`).  Shares are only resolved when the size is used, against
+//! the free space as it is at that point, and are rounded down to whole
+//! blocks.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{MapperError, SuperPartition};
+
+const SUFFIXES: [(char, u32); 5] = [('K', 10), ('M', 20), ('G', 30), ('T', 40), ('P', 50)];
+
+/// A requested size, not yet resolved against a super partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    Bytes(u64),
+    /// Percentage of every block on the device, allocated or not
+    Percent(u64),
+    /// Percentage of the unallocated blocks
+    PercentFree(u64),
+}
+
+impl FromStr for Size {
+    type Err = MapperError;
+
+    fn from_str(s: &str) -> Result<Self, MapperError> {
+        let bad = |reason: &str| MapperError::InvalidArgument(format!("bad size {:?}: {}", s, reason));
+        let number = |n: &str| n.parse::<u64>().map_err(|_| bad("expected a whole number"));
+
+        if let Some((pct, of)) = s.split_once('%') {
+            let pct = number(pct)?;
+            if pct > 100 {
+                return Err(bad("more than 100%"));
+            }
+            return match of {
+                "" => Ok(Size::Percent(pct)),
+                "FREE" => Ok(Size::PercentFree(pct)),
+                _ => Err(bad("expected % or %FREE")),
+            };
+        }
+
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (n, suffix) = s.split_at(digits);
+        let n = number(n)?;
+        let shift = match suffix {
+            "" | "B" => 0,
+            _ => {
+                let unit = suffix.strip_suffix("iB").unwrap_or(suffix);
+                SUFFIXES.iter()
+                    .find(|(c, _shift)| unit.len() == 1 && unit.starts_with(*c))
+                    .map(|(_c, shift)| *shift)
+                    .ok_or_else(|| bad("expected a suffix of K, M, G, T or P"))?
+            }
+        };
+        n.checked_mul(1 << shift).map(Size::Bytes).ok_or_else(|| bad("too large"))
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Size::Bytes(bytes) => write!(f, "{}", bytes),
+            Size::Percent(pct) => write!(f, "{}%", pct),
+            Size::PercentFree(pct) => write!(f, "{}%FREE", pct),
+        }
+    }
+}
+
+impl From<u64> for Size {
+    fn from(bytes: u64) -> Self {
+        Size::Bytes(bytes)
+    }
+}
+
+impl SuperPartition {
+    /// `size` in bytes for a new subvolume, or for growing `subvol` if
+    /// given.  A share of the free space is then added to what the
+    /// subvolume already has, so resizing it to `100%FREE` grows it into
+    /// all of the remaining space.
+    pub fn resolve_size(&self, size: Size, subvol: Option<&str>) -> Result<u64, MapperError> {
+        let share = |blocks: u64, pct: u64| blocks * pct / 100 * self.iosize;
+        match size {
+            Size::Bytes(bytes) => Ok(bytes),
+            Size::Percent(pct) => Ok(share(self.device_blocks.iter().sum(), pct)),
+            Size::PercentFree(pct) => {
+                let current = match subvol {
+                    Some(name) => self.subvols.get(name)
+                        .ok_or_else(|| MapperError::NotFound(name.to_string()))?
+                        .size_bytes(),
+                    None => 0,
+                };
+                Ok(current + share(self.free_blocks(), pct))
+            }
+        }
+    }
+}
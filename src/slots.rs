@@ -0,0 +1,270 @@
+//! Metadata replicas spanning several blocks.
+//!
+//! The metadata is kept twice at the end of the device, in two slots of
+//! `metadata_blocks` blocks each, the first being the very last blocks.  A
+//! single-block slot holds the encoded metadata directly, as it always has.
+//! When a slot spans more blocks, each block starts with a header carrying
+//! a magic, the generation being written, the block's index within the slot
+//! and the slot's length, so the slot size can be found from any intact
+//! block, and a CRC over the header and that block's share of the data, so
+//! a torn write is caught block by block.  Every block ends with the
+//! signature described in the probe module.
+
+use serde::Serialize;
+
+use crate::probe::{signature, SIGNATURE_SIZE};
+use crate::{format, BlockStore, load_both_metadata, replica_layout, MapperError, Step, SuperPartition, METADATA_SUBVOL};
+
+const BLOCK_MAGIC: [u8; 8] = *b"HGMAPMD\0";
+/// Magic, generation, index, count, payload length and CRC
+const HEADER_SIZE: usize = 24;
+/// Largest slot, and how far back to look for a header when the last
+/// block of the device is damaged
+pub(crate) const MAX_SLOT_BLOCKS: u64 = 256;
+
+/// What was wrong with a replica before it was repaired
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ReplicaState {
+    /// Unreadable or failing its CRC
+    Corrupt,
+    /// Intact, but holding an older generation
+    Stale { generation: u32 },
+}
+
+/// A replica rewritten by `SuperPartition::repair_replicas`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Repair {
+    /// 1 for the replica at the end of the device
+    pub slot: u64,
+    pub previous: ReplicaState,
+}
+
+struct BlockHeader {
+    generation: u32,
+    index: u16,
+    count: u16,
+    len: u32,
+}
+
+/// Validate the header of `block`, returning it and the block's payload
+fn parse_block(block: &[u8]) -> Option<(BlockHeader, &[u8])> {
+    if block.len() < HEADER_SIZE || block[..8] != BLOCK_MAGIC {
+        return None;
+    }
+    let u16_at = |i: usize| u16::from_be_bytes([block[i], block[i + 1]]);
+    let u32_at = |i: usize| u32::from_be_bytes(block[i..i + 4].try_into().expect("slice length"));
+    let header = BlockHeader {
+        generation: u32_at(8),
+        index: u16_at(12),
+        count: u16_at(14),
+        len: u32_at(16),
+    };
+    let payload = block.get(HEADER_SIZE..HEADER_SIZE + header.len as usize)?;
+
+    let crc_algo = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
+    let mut digest = crc_algo.digest();
+    digest.update(&block[..20]);
+    digest.update(payload);
+    (digest.finalize() == u32_at(20)).then_some((header, payload))
+}
+
+fn read_blocks(f: &mut dyn BlockStore, start: u64, count: u64, iosize: u64) -> Result<Vec<u8>, MapperError> {
+    let mut buf = vec![0; (count * iosize) as usize];
+    f.read_bytes(start * iosize, &mut buf)?;
+    Ok(buf)
+}
+
+/// Number of blocks per slot of the metadata on `f`, judging by the first
+/// intact block header found from the end of the device.  Single-block
+/// slots have no headers, so finding none means there is one block.
+pub(crate) fn probe_slot_blocks(f: &mut dyn BlockStore, device_blocks: u64, iosize: u64) -> Result<u64, MapperError> {
+    for back in 1..=std::cmp::min(device_blocks, 2 * MAX_SLOT_BLOCKS) {
+        let mut magic = [0; 8];
+        f.read_bytes((device_blocks - back) * iosize, &mut magic)?;
+        if magic != BLOCK_MAGIC {
+            continue;
+        }
+
+        let block = read_blocks(f, device_blocks - back, 1, iosize)?;
+        if let Some((header, _payload)) = parse_block(&block) {
+            // Guard against a stray header inside subvolume data by
+            // checking it sits where its slot says it should
+            let (count, index) = (header.count as u64, header.index as u64);
+            if index < count && (back == count - index || back == 2 * count - index) {
+                return Ok(count);
+            }
+        }
+    }
+    Ok(1)
+}
+
+/// Read the slot of `count` blocks starting at block `start`
+pub(crate) fn read_replica(f: &mut dyn BlockStore, start: u64, count: u64, iosize: u64) -> Result<SuperPartition, MapperError> {
+    let buf = read_blocks(f, start, count, iosize)?;
+    if count == 1 {
+        return format::read_slot(&buf);
+    }
+
+    let mut data = vec![];
+    let mut generation = None;
+    for (index, block) in buf.chunks(iosize as usize).enumerate() {
+        let (header, payload) = parse_block(block)
+            .ok_or_else(|| MapperError::MetadataCorrupt(format!("bad header in metadata block {}", start + index as u64)))?;
+        if header.index as usize != index || header.count as u64 != count
+                || *generation.get_or_insert(header.generation) != header.generation {
+            return Err(MapperError::MetadataCorrupt(format!("metadata block {} is from another write", start + index as u64)));
+        }
+        data.extend(payload);
+    }
+    format::read_slot(&data)
+}
+
+/// Lay `meta` out as the contents of a slot of `count` blocks
+pub(crate) fn encode_replica(meta: &SuperPartition, count: u64, iosize: u64) -> Result<Vec<u8>, MapperError> {
+    let slot = format::write_slot(meta);
+    let sig = signature(&meta.uuid);
+    let usable = iosize as usize - SIGNATURE_SIZE;
+    if count == 1 {
+        if slot.len() > usable {
+            return Err(MapperError::MetadataTooLarge { size: slot.len(), capacity: usable });
+        }
+        let mut buf = slot;
+        buf.resize(usable, 0);
+        buf.extend(sig);
+        return Ok(buf);
+    }
+
+    let per_block = usable - HEADER_SIZE;
+    let capacity = per_block * count as usize;
+    if slot.len() > capacity {
+        return Err(MapperError::MetadataTooLarge { size: slot.len(), capacity });
+    }
+
+    let crc_algo = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
+    let mut buf = Vec::with_capacity((count * iosize) as usize);
+    let mut chunks = slot.chunks(per_block);
+    for index in 0..count {
+        let payload = chunks.next().unwrap_or(&[]);
+        let mut header = BLOCK_MAGIC.to_vec();
+        header.extend(meta.generation.to_be_bytes());
+        header.extend((index as u16).to_be_bytes());
+        header.extend((count as u16).to_be_bytes());
+        header.extend((payload.len() as u32).to_be_bytes());
+        let mut digest = crc_algo.digest();
+        digest.update(&header);
+        digest.update(payload);
+        header.extend(digest.finalize().to_be_bytes());
+
+        let block_start = buf.len();
+        buf.extend(header);
+        buf.extend(payload);
+        buf.resize(block_start + usable, 0);
+        buf.extend(&sig);
+    }
+    Ok(buf)
+}
+
+impl SuperPartition {
+    /// Open `device` as with `open`, then restore redundancy if one replica
+    /// is damaged or behind, reporting what was rewritten
+    pub fn open_with_repair(device: String) -> Result<(Self, Option<Repair>), MapperError> {
+        let sp = Self::open(device)?;
+        let repair = sp.repair_replicas()?;
+        Ok((sp, repair))
+    }
+
+    /// Bring the replica with the older generation, or which no longer
+    /// reads back correctly, up to date with the newest one.  Its blocks
+    /// are copied verbatim, so both replicas end up identical.
+    pub fn repair_replicas(&self) -> Result<Option<Repair>, MapperError> {
+        self.check_writable()?;
+        self.with_store(!self.is_dry_run(), |blockdev| self.repair_replicas_in(blockdev))
+    }
+
+    fn repair_replicas_in(&self, blockdev: &mut dyn BlockStore) -> Result<Option<Repair>, MapperError> {
+        let (count, starts) = replica_layout(blockdev, self.iosize)?.ok_or(MapperError::NoMetadata)?;
+        let (from, to, previous) = match self.replica_states(blockdev)? {
+            [None, Some(previous)] => (1, 2, previous),
+            [Some(previous), None] => (2, 1, previous),
+            [None, None] => return Ok(None),
+            [Some(_), Some(_)] => return Err(MapperError::NoMetadata),
+        };
+
+        let repair = Repair {
+            slot: to as u64,
+            previous,
+        };
+        if self.plan_step(|| Step::Write { path: self.device.clone(), offset: starts[to - 1] * self.iosize, len: count * self.iosize }) {
+            return Ok(Some(repair));
+        }
+
+        let data = read_blocks(blockdev, starts[from - 1], count, self.iosize)?;
+        blockdev.write_bytes(starts[to - 1] * self.iosize, &data)?;
+        blockdev.sync()?;
+        Ok(Some(repair))
+    }
+
+    /// What is wrong with each replica on `blockdev` compared with this
+    /// generation, if anything
+    pub(crate) fn replica_states(&self, blockdev: &mut dyn BlockStore) -> Result<[Option<ReplicaState>; 2], MapperError> {
+        let (meta1, meta2) = load_both_metadata(blockdev, self.iosize)?;
+        let state = |meta: Option<SuperPartition>| match meta {
+            None => Some(ReplicaState::Corrupt),
+            Some(m) if m.generation < self.generation => Some(ReplicaState::Stale { generation: m.generation }),
+            Some(_) => None,
+        };
+        Ok([state(meta1), state(meta2)])
+    }
+
+    /// Number of blocks in each of the two metadata replicas
+    pub fn metadata_blocks(&self) -> u64 {
+        self.metadata_blocks
+    }
+
+    /// Change how many blocks each metadata replica spans, to make room for
+    /// more subvolumes.  The blocks have to be free at the end of the
+    /// device holding the metadata.  Both replicas are rewritten.
+    pub fn set_metadata_blocks(&mut self, blocks: u64) -> Result<(), MapperError> {
+        if self.label.is_some() {
+            return Err(MapperError::InvalidArgument("replica size is fixed by the label".to_string()));
+        }
+        if self.history.as_ref().is_some_and(|h| h.slot_blocks() < blocks) {
+            return Err(MapperError::InvalidArgument("history entries are too small; disable history first".to_string()));
+        }
+        if blocks == 0 || blocks > MAX_SLOT_BLOCKS {
+            return Err(MapperError::InvalidArgument(format!("metadata slots must be 1 to {} blocks", MAX_SLOT_BLOCKS)));
+        }
+        let device_blocks = self.device_blocks[0];
+        if 2 * blocks > device_blocks {
+            return Err(MapperError::NoSpace { needed: 2 * blocks, available: device_blocks });
+        }
+        let start = device_blocks - 2 * blocks;
+        let conflict = self.subvols.iter()
+            .filter(|(name, _sv)| *name != METADATA_SUBVOL)
+            .flat_map(|(_name, sv)| sv.all_extents())
+            .any(|e| e.device == 0 && e.block_offset + e.block_length > start);
+        if conflict {
+            return Err(MapperError::InvalidArgument("blocks at the end of the device are in use".to_string()));
+        }
+
+        let (meta1, meta2) = self.with_store(false, |blockdev| load_both_metadata(blockdev, self.iosize))?;
+        self.check_unchanged(&meta1, &meta2)?;
+
+        let metadata = self.subvols.get_mut(METADATA_SUBVOL)
+            .ok_or_else(|| MapperError::NotFound(METADATA_SUBVOL.to_string()))?;
+        metadata.extents = vec![crate::Extent {
+            device: 0,
+            block_offset: start,
+            block_length: 2 * blocks,
+        }];
+        self.metadata_blocks = blocks;
+
+        // Neither old replica is in a usable place any more, so write
+        // both.  The second slot goes first: for more than one block it
+        // doesn't overlap where the old replicas were.
+        self.write_replica(2)?;
+        self.write_replica(1)?;
+        Ok(())
+    }
+}
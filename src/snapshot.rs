@@ -0,0 +1,211 @@
+//! Point-in-time snapshots of subvolumes using dm-snapshot.
+//!
+//! While an origin has snapshots its extents are mapped by an `<origin>-real`
+//! device, with a snapshot-origin target on top that copies chunks out to
+//! every snapshot before they are overwritten.  Each snapshot's own extents
+//! hold its copy-on-write store and are mapped by `<snapshot>-cow`.  Once the
+//! last snapshot is deleted the origin collapses back to a plain linear
+//! mapping.
+
+use crate::dm::{Layer, Target};
+use crate::{MapperError, Step, SubVolume, SuperPartition, Wipe};
+
+pub(crate) const REAL_SUFFIX: &str = "real";
+pub(crate) const COW_SUFFIX: &str = "cow";
+
+impl SuperPartition {
+    /// Whether any snapshot was taken of `name`
+    pub(crate) fn has_snapshots(&self, name: &str) -> bool {
+        self.subvols.values().any(|sv| sv.snapshot_of.as_deref() == Some(name))
+    }
+
+    /// Names of the snapshots taken of `origin`
+    pub fn snapshots_of(&self, origin: &str) -> Vec<&str> {
+        let mut snaps: Vec<_> = self.subvols.iter()
+            .filter(|(_name, sv)| sv.snapshot_of.as_deref() == Some(origin))
+            .map(|(name, _sv)| name.as_str())
+            .collect();
+        snaps.sort();
+        snaps
+    }
+
+    pub(crate) fn origin_layers(&self, name: &str, sv: &SubVolume) -> Vec<Layer> {
+        vec![
+            Layer {
+                suffix: Some(REAL_SUFFIX),
+                target: Target::Linear(sv.extents.clone()),
+            },
+            Layer {
+                suffix: None,
+                target: Target::SnapshotOrigin {
+                    real: self.layer_name(name, Some(REAL_SUFFIX)),
+                    sectors: sv.size_bytes() / 512,
+                },
+            },
+        ]
+    }
+
+    pub(crate) fn snapshot_layers(&self, name: &str, sv: &SubVolume, origin: &str) -> Vec<Layer> {
+        let origin_sectors = self.subvols.get(origin).map_or(0, |o| o.size_bytes() / 512);
+        vec![
+            Layer {
+                suffix: Some(COW_SUFFIX),
+                target: Target::Linear(sv.extents.clone()),
+            },
+            Layer {
+                suffix: None,
+                target: Target::Snapshot {
+                    real: self.layer_name(origin, Some(REAL_SUFFIX)),
+                    cow: self.layer_name(name, Some(COW_SUFFIX)),
+                    sectors: origin_sectors,
+                },
+            },
+        ]
+    }
+
+    /// Take a point-in-time snapshot of `origin` named `snap_name`.  Up to
+    /// `cow_size` bytes of changes, to either the origin or the snapshot,
+    /// can be absorbed before the snapshot becomes invalid.
+    pub fn snapshot_subvol(&mut self, origin: &str, snap_name: &str, cow_size: u64) -> Result<(), MapperError> {
+        self.snapshot_subvol_with(origin, snap_name, cow_size, |_snap, _origin| ())
+    }
+
+    /// Like `snapshot_subvol`, letting `fill` set up the snapshot's
+    /// metadata from the origin's before it is committed
+    pub(crate) fn snapshot_subvol_with(&mut self, origin: &str, snap_name: &str, cow_size: u64,
+            fill: impl FnOnce(&mut SubVolume, &SubVolume)) -> Result<(), MapperError> {
+        self.check_not_reserved(origin)?;
+        self.check_not_external(origin)?;
+        let origin_sv = self.subvols.get(origin)
+            .ok_or_else(|| MapperError::NotFound(origin.to_string()))?;
+        if origin_sv.snapshot_of.is_some() {
+            return Err(MapperError::InvalidArgument("cannot snapshot a snapshot".to_string()));
+        }
+        if origin_sv.thin_pool.is_some() || origin_sv.thin.is_some() {
+            return Err(MapperError::InvalidArgument("cannot snapshot thin pools or volumes".to_string()));
+        }
+        if origin_sv.crypt.is_some() {
+            return Err(MapperError::InvalidArgument("cannot snapshot encrypted subvols".to_string()));
+        }
+        if origin_sv.verity.is_some() || origin_sv.integrity.is_some() || origin_sv.mirror.is_some() {
+            return Err(MapperError::InvalidArgument("cannot snapshot verity, integrity or mirrored subvols".to_string()));
+        }
+        self.check_new_name(snap_name)?;
+
+        let extents = self.allocate(cow_size.div_ceil(self.iosize))?;
+        let mut snap = SubVolume::new(extents, self.iosize);
+        snap.snapshot_of = Some(origin.to_string());
+        fill(&mut snap, &self.subvols[origin]);
+
+        // A zeroed header tells dm-snapshot this is a fresh store rather
+        // than stale exceptions from whatever used these blocks before
+        self.zero_range(snap.extents[0].device, snap.extents[0].block_offset * self.iosize, 4096)?;
+
+        let was_origin = self.has_snapshots(origin);
+        self.subvols.insert(snap_name.to_string(), snap.clone());
+        self.commit()?;
+
+        let origin_sv = self.subvols[origin].clone();
+        let origin_layers = self.origin_layers(origin, &origin_sv);
+        let snap_layers = self.snapshot_layers(snap_name, &snap, origin);
+        if self.is_dry_run() {
+            if !was_origin {
+                self.plan_layers(origin, &origin_layers[..1])?;
+            }
+            self.plan_layers(snap_name, &snap_layers)?;
+            if !was_origin {
+                self.plan_layers(origin, &origin_layers[1..])?;
+            }
+            return Ok(());
+        }
+
+        let dm = self.dm()?;
+        if !was_origin {
+            self.create_layer(&*dm, origin, origin_layers[0].suffix, &origin_layers[0].target, true)?;
+        }
+
+        self.create_layer(&*dm, snap_name, snap_layers[0].suffix, &snap_layers[0].target, true)?;
+
+        // The origin must be quiesced while the snapshot is set up, or
+        // writes could land without being copied out first
+        let origin_dm = self.layer_name(origin, None);
+        let snap_dm = self.layer_name(snap_name, None);
+        dm.suspend(&origin_dm)?;
+        let result = self.create_layer(&*dm, snap_name, None, &snap_layers[1].target, false)
+            .and_then(|_| {
+                if was_origin {
+                    Ok(())
+                } else {
+                    self.load_layer(&*dm, origin, None, &origin_layers[1].target)
+                }
+            })
+            .and_then(|_| dm.resume(&snap_dm));
+        dm.resume(&origin_dm)?;
+        result
+    }
+
+    /// Remove a snapshot and its copy-on-write store, clearing the store as
+    /// described by `shred` once it is unmapped.  If it was the last
+    /// snapshot of its origin, the origin goes back to a plain mapping.
+    pub(crate) fn delete_snapshot(&mut self, name: &str, shred: Option<Wipe>) -> Result<(), MapperError> {
+        let snap = self.subvols[name].clone();
+        let origin = snap.snapshot_of.clone().expect("not a snapshot");
+        self.check_writable()?;
+        if self.dm_in_use(name)? {
+            return Err(MapperError::DeviceBusy(name.to_string()));
+        }
+        if self.is_dry_run() {
+            self.plan_snapshot_removal(name, &snap, &origin)?;
+        } else {
+            self.remove_snapshot_dm(name, &snap, &origin)?;
+        }
+
+        if let Some(how) = shred {
+            self.wipe_extents(snap.all_extents(), how)?;
+        }
+        self.subvols.remove(name);
+        self.commit()?;
+        Ok(())
+    }
+
+    /// Tear down the devices for snapshot `name` of `origin`, and put the
+    /// origin back to a plain mapping if this is its last snapshot
+    fn remove_snapshot_dm(&self, name: &str, snap: &SubVolume, origin: &str) -> Result<(), MapperError> {
+        let dm = self.dm()?;
+        for layer in self.snapshot_layers(name, snap, origin).iter().rev() {
+            Self::remove_layer(&*dm, &self.layer_name(name, layer.suffix))?;
+        }
+
+        if self.snapshots_of(origin).len() == 1 {
+            if let Some(origin_sv) = self.subvols.get(origin) {
+                let target = Target::Linear(origin_sv.extents.clone());
+                let origin_dm = self.layer_name(origin, None);
+                dm.suspend(&origin_dm)?;
+                let loaded = self.load_layer(&*dm, origin, None, &target);
+                dm.resume(&origin_dm)?;
+                loaded?;
+                Self::remove_layer(&*dm, &self.layer_name(origin, Some(REAL_SUFFIX)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record what `remove_snapshot_dm` would do
+    fn plan_snapshot_removal(&self, name: &str, snap: &SubVolume, origin: &str) -> Result<(), MapperError> {
+        if !self.dm_active(name)? {
+            return Ok(());
+        }
+        for layer in self.snapshot_layers(name, snap, origin).iter().rev() {
+            self.plan_step(|| Step::Remove { device: self.layer_name(name, layer.suffix), deferred: false });
+        }
+        if self.snapshots_of(origin).len() == 1 && self.subvols.contains_key(origin) {
+            let linear = Layer {
+                suffix: None,
+                target: Target::Linear(self.subvols[origin].extents.clone()),
+            };
+            self.plan_layers(origin, &[linear])?;
+            self.plan_step(|| Step::Remove { device: self.layer_name(origin, Some(REAL_SUFFIX)), deferred: false });
+        }
+        Ok(())
+    }
+}
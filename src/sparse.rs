@@ -0,0 +1,144 @@
+//! Android sparse images.
+//!
+//! Our build emits filesystem images in the sparse format fastboot takes:
+//! a header, then chunks that each hold raw blocks, fill blocks with a
+//! repeated four-byte value, or skip blocks whose contents don't matter.
+//! Writing one only touches the blocks covered by raw and fill chunks;
+//! skipped blocks keep whatever the subvolume held before.
+
+use std::io::{self, Read};
+
+use crate::image::{le16, le32, Output, Progress};
+use crate::MapperError;
+
+pub(crate) const SPARSE_MAGIC: u32 = 0xed26_ff3a;
+
+/// Size of the header fields this understands, starting with the magic
+const HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_RAW: u64 = 0xcac1;
+const CHUNK_FILL: u64 = 0xcac2;
+const CHUNK_DONT_CARE: u64 = 0xcac3;
+const CHUNK_CRC32: u64 = 0xcac4;
+
+fn bad(reason: impl std::fmt::Display) -> MapperError {
+    MapperError::InvalidArgument(format!("bad sparse image: {}", reason))
+}
+
+/// Skip `len` bytes of `reader`
+fn skip(reader: &mut impl Read, len: u64) -> io::Result<()> {
+    io::copy(&mut reader.take(len), &mut io::sink())?;
+    Ok(())
+}
+
+pub(crate) struct SparseHeader {
+    /// Bytes taken up by the header, which later versions may extend
+    header_size: u64,
+    chunk_header_size: u64,
+    block_size: u64,
+    total_blocks: u64,
+    total_chunks: u64,
+}
+
+impl SparseHeader {
+    /// Read the header of a sparse image whose magic has already been
+    /// read from `image`
+    pub(crate) fn read(image: &mut impl Read) -> Result<Self, MapperError> {
+        let mut buf = [0; HEADER_SIZE];
+        image.read_exact(&mut buf[4..])?;
+        let header = SparseHeader {
+            header_size: le16(&buf, 8),
+            chunk_header_size: le16(&buf, 10),
+            block_size: le32(&buf, 12),
+            total_blocks: le32(&buf, 16),
+            total_chunks: le32(&buf, 20),
+        };
+        if le16(&buf, 4) != 1 {
+            return Err(bad(format!("unsupported version {}", le16(&buf, 4))));
+        }
+        if header.header_size < HEADER_SIZE as u64 || header.chunk_header_size < CHUNK_HEADER_SIZE as u64 {
+            return Err(bad("headers are too short"));
+        }
+        if header.block_size == 0 || !header.block_size.is_multiple_of(4) {
+            return Err(bad(format!("block size {} is not a multiple of 4", header.block_size)));
+        }
+        skip(image, header.header_size - HEADER_SIZE as u64)?;
+        Ok(header)
+    }
+
+    /// Size of the image once expanded
+    pub(crate) fn size(&self) -> u64 {
+        self.total_blocks * self.block_size
+    }
+}
+
+impl Output {
+    /// Write out the chunks following `header`, using `buf` for copying,
+    /// and return the expanded size
+    pub(crate) fn write_sparse(&mut self, header: &SparseHeader, image: &mut impl Read, buf: &mut [u8],
+        progress: &mut Progress) -> Result<u64, MapperError>
+    {
+        let mut block = 0;
+        for _ in 0..header.total_chunks {
+            let mut chunk = [0; CHUNK_HEADER_SIZE];
+            image.read_exact(&mut chunk)?;
+            skip(image, header.chunk_header_size - CHUNK_HEADER_SIZE as u64)?;
+            let (kind, blocks, total_size) = (le16(&chunk, 0), le32(&chunk, 4), le32(&chunk, 8));
+            if block + blocks > header.total_blocks {
+                return Err(bad("chunks run past the end of the image"));
+            }
+            let offset = block * header.block_size;
+            let len = blocks * header.block_size;
+
+            match kind {
+                CHUNK_RAW => {
+                    if total_size != header.chunk_header_size + len {
+                        return Err(bad(format!("raw chunk at block {} has the wrong size", block)));
+                    }
+                    let mut done = 0;
+                    while done < len {
+                        let n = (len - done).min(buf.len() as u64) as usize;
+                        image.read_exact(&mut buf[..n])?;
+                        self.write_at(offset + done, &buf[..n])?;
+                        done += n as u64;
+                        progress.report();
+                    }
+                }
+                CHUNK_FILL => {
+                    if total_size != header.chunk_header_size + 4 {
+                        return Err(bad(format!("fill chunk at block {} has the wrong size", block)));
+                    }
+                    let mut value = [0; 4];
+                    image.read_exact(&mut value)?;
+                    // Keep the pattern lined up at the start of each write
+                    let whole = buf.len() - buf.len() % value.len();
+                    for word in buf[..whole].chunks_exact_mut(value.len()) {
+                        word.copy_from_slice(&value);
+                    }
+                    let mut done = 0;
+                    while done < len {
+                        let n = (len - done).min(whole as u64) as usize;
+                        self.write_at(offset + done, &buf[..n])?;
+                        done += n as u64;
+                    }
+                    progress.report();
+                }
+                CHUNK_DONT_CARE => {
+                    if total_size != header.chunk_header_size {
+                        return Err(bad(format!("don't care chunk at block {} has the wrong size", block)));
+                    }
+                }
+                CHUNK_CRC32 => {
+                    if total_size != header.chunk_header_size + 4 {
+                        return Err(bad(format!("CRC32 chunk at block {} has the wrong size", block)));
+                    }
+                    skip(image, 4)?;
+                }
+                _ => return Err(bad(format!("unknown chunk type {:#x}", kind))),
+            }
+            block += blocks;
+        }
+        Ok(header.size())
+    }
+}
@@ -0,0 +1,292 @@
+//! Where the metadata is kept.
+//!
+//! Everything that reads or writes the metadata replicas, the label and
+//! the history ring goes through a `BlockStore`.  Normally that is the
+//! device the super partition was opened from, opened afresh for each
+//! access; `format_store` and `open_store` take one of the caller's
+//! instead, such as a `MemoryStore`, so the allocator, commits and the
+//! choice between replicas can be exercised without a block device or
+//! root.
+//!
+//! Only the metadata goes through the store.  Subvolume data and further
+//! member devices are still reached by path, and DM tables map the store
+//! as device 0:0, which only a mock device mapper takes, so a super
+//! partition in a store is for working on the layout, not for using it.
+
+use std::cell::RefCell;
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+
+use crate::{label, MapperError, SuperPartition, DEFAULT_IO_SIZE};
+
+/// What a super partition in a store gives as its device path
+const STORE_DEVICE: &str = "<store>";
+
+/// Byte-addressed storage of a fixed size holding the metadata
+pub trait BlockStore: Debug {
+    /// Size in bytes
+    fn size(&mut self) -> io::Result<u64>;
+
+    /// Fill `buf` from byte `offset`, failing with `UnexpectedEof` if it
+    /// runs past the end
+    fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()>;
+
+    /// Write all of `data` at byte `offset`
+    fn write_bytes(&mut self, offset: u64, data: &[u8]) -> io::Result<()>;
+
+    /// Make what was written durable
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl BlockStore for File {
+    fn size(&mut self) -> io::Result<u64> {
+        // Block devices report no length in their metadata
+        self.seek(SeekFrom::End(0))
+    }
+
+    fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.read_exact_at(buf, offset)
+    }
+
+    fn write_bytes(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.write_all_at(data, offset)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+/// A store held in memory, as big as it was made
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStore {
+    data: Vec<u8>,
+}
+
+impl MemoryStore {
+    /// A store of `size` zero bytes
+    pub fn new(size: u64) -> Self {
+        Self { data: vec![0; size as usize] }
+    }
+
+    /// A store holding `data`, such as an image read from a device
+    pub fn from_bytes(data: Vec<u8>) -> Self {
+        Self { data }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn range(&self, offset: u64, len: usize) -> io::Result<std::ops::Range<usize>> {
+        let start = usize::try_from(offset).unwrap_or(usize::MAX);
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(start..end),
+            _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "past the end of the store")),
+        }
+    }
+}
+
+impl BlockStore for MemoryStore {
+    fn size(&mut self) -> io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        // Like a block device, it doesn't grow when written past the end
+        let range = self.range(offset, data.len())
+            .map_err(|_e| io::Error::new(io::ErrorKind::WriteZero, "past the end of the store"))?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SuperPartition {
+    /// Create a new, empty super partition in `store`, with blocks of
+    /// `iosize` bytes, as `format` would on a device.  A store has no
+    /// block size of its own to go by when it is opened again, so any
+    /// other than the default needs a label to record it.
+    pub fn format_store(store: Box<dyn BlockStore>, iosize: u64, options: &crate::FormatOptions) -> Result<Self, MapperError> {
+        let mut store = store;
+        let device_blocks = store.size()? / iosize;
+        let mut sp = Self::blank(STORE_DEVICE.to_string(), iosize, device_blocks, options)?;
+        if sp.label.is_none() && iosize != DEFAULT_IO_SIZE {
+            return Err(MapperError::InvalidArgument(format!(
+                "blocks of other than {} bytes need replicas placed with a label", DEFAULT_IO_SIZE)));
+        }
+        sp.store = Some(RefCell::new(store));
+        sp.clear_old_metadata()?;
+        Ok(sp)
+    }
+
+    /// Open the super partition whose metadata is in `store`.  Nothing is
+    /// activated and interrupted changes are left as they are; anything
+    /// else works as after `open_inactive`, within what a store allows.
+    pub fn open_store(store: Box<dyn BlockStore>) -> Result<Self, MapperError> {
+        let mut store = store;
+        let label = label::read_label(&mut *store)?;
+        let candidates = match &label {
+            Some(label) => vec![label.iosize],
+            None => vec![DEFAULT_IO_SIZE],
+        };
+        let mut meta = Self::find_newest(&mut *store, &candidates)?;
+        if !meta.members.is_empty() {
+            return Err(MapperError::InvalidArgument("the metadata in the store spans further member devices".to_string()));
+        }
+        meta.device = STORE_DEVICE.to_string();
+        meta.label = label;
+        meta.device_blocks = vec![store.size()? / meta.iosize];
+        let iosize = meta.iosize;
+        for sv in meta.subvols.values_mut() {
+            sv.iosize = iosize;
+        }
+        meta.store = Some(RefCell::new(store));
+        meta.check_extents()?;
+        meta.on_disk = Some(meta.generation);
+        Ok(meta)
+    }
+
+    /// The store given to `format_store` or `open_store`, to open again
+    /// or look at
+    pub fn into_store(self) -> Option<Box<dyn BlockStore>> {
+        self.store.map(RefCell::into_inner)
+    }
+
+    /// Run `f` on where the metadata is kept: the store, or else the
+    /// device, opened for writing too if `write` is set
+    pub(crate) fn with_store<T>(&self, write: bool, f: impl FnOnce(&mut dyn BlockStore) -> Result<T, MapperError>)
+            -> Result<T, MapperError> {
+        match &self.store {
+            Some(store) => f(&mut **store.borrow_mut()),
+            None => f(&mut OpenOptions::new().read(true).write(write).open(&self.device)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{format, image, reopen, MIB};
+    use crate::{load_both_metadata, replica_layout, CreateOptions, FormatOptions, ReplicaPlacement};
+
+    /// Generations in the two replica slots, `None` for one that doesn't load
+    fn generations(sp: &SuperPartition) -> [Option<u32>; 2] {
+        let (meta1, meta2) = sp.with_store(false, |store| load_both_metadata(store, MIB)).expect("load");
+        [meta1.map(|m| m.generation), meta2.map(|m| m.generation)]
+    }
+
+    #[test]
+    fn format_and_open_round_trip() {
+        let (mut sp, _dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create a");
+        sp.create_subvol("b".to_string(), 8 * MIB).expect("create b");
+        let generation = sp.generation;
+        let subvols = sp.subvols.clone();
+
+        let (sp, _dm) = reopen(sp);
+        assert_eq!(sp.generation, generation);
+        assert_eq!(sp.subvols, subvols);
+        assert_eq!(sp.block_size(), MIB);
+        assert_eq!(sp.usage().free_blocks, 64 - 12 - 2 * sp.metadata_blocks);
+    }
+
+    #[test]
+    fn block_size_survives_reopening() {
+        let store = Box::new(MemoryStore::new(64 * MIB));
+        assert!(matches!(SuperPartition::format_store(store, 4096, &FormatOptions::default()),
+            Err(MapperError::InvalidArgument(_))));
+
+        let store = Box::new(MemoryStore::new(64 * MIB));
+        let options = FormatOptions { placement: ReplicaPlacement::BeginEnd, ..FormatOptions::default() };
+        let mut sp = SuperPartition::format_store(store, 4096, &options).expect("format");
+        sp.commit().expect("commit");
+        let (sp, _dm) = reopen(sp);
+        assert_eq!(sp.block_size(), 4096);
+    }
+
+    #[test]
+    fn commits_alternate_between_replicas() {
+        let (mut sp, _dm) = format();
+        sp.commit().expect("commit");
+        let mut last = generations(&sp);
+        let mut last_slot = None;
+        for _ in 0..4 {
+            sp.commit().expect("commit");
+            let now = generations(&sp);
+            // One slot gets the new generation; the other keeps the one before
+            let written: Vec<_> = (0..2).filter(|&slot| now[slot] != last[slot]).collect();
+            assert_eq!(written.len(), 1);
+            let slot = written[0];
+            assert_eq!(now[slot], Some(sp.generation));
+            assert_eq!(now[1 - slot], Some(sp.generation - 1));
+            assert_ne!(Some(slot), last_slot);
+            last = now;
+            last_slot = Some(slot);
+        }
+    }
+
+    #[test]
+    fn open_takes_the_newest_replica_that_is_intact() {
+        let (mut sp, _dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create a");
+        sp.create_subvol("b".to_string(), 4 * MIB).expect("create b");
+        let newest = sp.generation;
+        let gens = generations(&sp);
+        let (_count, starts) = sp.with_store(false, |store| replica_layout(store, MIB)).expect("layout").expect("replicas");
+        let pristine = image(&sp);
+
+        for slot in 0..2 {
+            let mut data = pristine.clone();
+            let start = (starts[slot] * MIB) as usize;
+            data[start..start + 512].fill(0xa5);
+            let sp = SuperPartition::open_store(Box::new(MemoryStore::from_bytes(data))).expect("open");
+            let other = gens[1 - slot].expect("other replica");
+            assert_eq!(sp.generation, other);
+            if other == newest {
+                // The stale copy was the damaged one, so nothing is lost
+                assert!(sp.subvols.contains_key("b"));
+            } else {
+                assert!(!sp.subvols.contains_key("b"));
+            }
+        }
+
+        let sp = SuperPartition::open_store(Box::new(MemoryStore::from_bytes(pristine))).expect("open");
+        assert_eq!(sp.generation, newest);
+    }
+
+    #[test]
+    fn allocator_reports_running_out() {
+        let (mut sp, _dm) = format();
+        assert!(matches!(sp.create_subvol("big".to_string(), 128 * MIB), Err(MapperError::NoSpace { .. })));
+
+        for name in ["a", "b", "c"] {
+            sp.create_subvol(name.to_string(), 16 * MIB).expect("create");
+        }
+        sp.delete_subvol_by_name("b").expect("delete");
+        // Enough in total, but not in one piece
+        let free = sp.usage().free_blocks * MIB;
+        assert!(free > 16 * MIB);
+        let options = CreateOptions { contiguous: true, ..CreateOptions::default() };
+        assert!(matches!(sp.create_subvol_with("d".to_string(), free, &options),
+            Err(MapperError::NoContiguousSpace { .. })));
+        sp.create_subvol_with("d".to_string(), 16 * MIB, &options).expect("fits the hole");
+        sp.create_subvol("e".to_string(), free - 16 * MIB).expect("spread over what is left");
+        assert_eq!(sp.usage().free_blocks, 0);
+    }
+}
@@ -0,0 +1,34 @@
+//! Fixtures shared by the unit tests: a super partition formatted on a
+//! `MemoryStore`, with a `MockDm` in place of the kernel's device mapper.
+
+use std::rc::Rc;
+
+use crate::{FormatOptions, MemoryStore, MockDm, SuperPartition};
+
+pub(crate) const MIB: u64 = 1 << 20;
+
+/// A fresh 64MiB super partition with 1MiB blocks
+pub(crate) fn format() -> (SuperPartition, Rc<MockDm>) {
+    let store = Box::new(MemoryStore::new(64 * MIB));
+    let mut sp = SuperPartition::format_store(store, MIB, &FormatOptions::default()).expect("format");
+    let dm = Rc::new(MockDm::new());
+    sp.set_dm_backend(dm.clone());
+    (sp, dm)
+}
+
+/// Open the store of `sp` again, with no devices active
+pub(crate) fn reopen(sp: SuperPartition) -> (SuperPartition, Rc<MockDm>) {
+    let mut sp = SuperPartition::open_store(sp.into_store().expect("store")).expect("open");
+    let dm = Rc::new(MockDm::new());
+    sp.set_dm_backend(dm.clone());
+    (sp, dm)
+}
+
+/// A copy of everything in the store
+pub(crate) fn image(sp: &SuperPartition) -> Vec<u8> {
+    sp.with_store(false, |store| {
+        let mut buf = vec![0; store.size()? as usize];
+        store.read_bytes(0, &mut buf)?;
+        Ok(buf)
+    }).expect("read store")
+}
@@ -0,0 +1,177 @@
+//! Thin provisioning with dm-thin.
+//!
+//! A pool is a subvolume whose extents hold the pool's data, with a second
+//! extent set for the pool's own metadata.  Thin volumes carry no extents
+//! of their own; blocks for them are handed out by the pool on first write,
+//! so the sum of their sizes may exceed the space behind the pool.
+
+use serde::{Deserialize, Serialize};
+
+use crate::dm::{Layer, Target};
+use crate::{Extent, MapperError, Step, SubVolume, SuperPartition};
+
+pub(crate) const TMETA_SUFFIX: &str = "tmeta";
+pub(crate) const TDATA_SUFFIX: &str = "tdata";
+
+/// Pool allocation granularity, in 512-byte sectors
+const POOL_BLOCK_SECTORS: u64 = 128;
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub(crate) struct ThinPool {
+    metadata_extents: Vec<Extent>,
+    block_sectors: u64,
+    /// Device id to hand to the next thin volume
+    next_id: u32,
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub(crate) struct ThinVolume {
+    pub(crate) pool: String,
+    id: u32,
+    size: u64,
+}
+
+impl ThinPool {
+    pub(crate) fn metadata_extents(&self) -> &[Extent] {
+        &self.metadata_extents
+    }
+
+    pub(crate) fn metadata_extents_mut(&mut self) -> &mut [Extent] {
+        &mut self.metadata_extents
+    }
+}
+
+impl ThinVolume {
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl SuperPartition {
+    pub(crate) fn pool_layers(&self, name: &str, sv: &SubVolume, pool: &ThinPool) -> Vec<Layer> {
+        vec![
+            Layer {
+                suffix: Some(TMETA_SUFFIX),
+                target: Target::Linear(pool.metadata_extents.clone()),
+            },
+            Layer {
+                suffix: Some(TDATA_SUFFIX),
+                target: Target::Linear(sv.extents.clone()),
+            },
+            Layer {
+                suffix: None,
+                target: Target::ThinPool {
+                    metadata: self.layer_name(name, Some(TMETA_SUFFIX)),
+                    data: self.layer_name(name, Some(TDATA_SUFFIX)),
+                    block_sectors: pool.block_sectors,
+                    sectors: sv.size_bytes() / 512,
+                },
+            },
+        ]
+    }
+
+    pub(crate) fn thin_layers(&self, thin: &ThinVolume) -> Vec<Layer> {
+        vec![Layer {
+            suffix: None,
+            target: Target::Thin {
+                pool: self.layer_name(&thin.pool, None),
+                id: thin.id,
+                sectors: thin.size / 512,
+            },
+        }]
+    }
+
+    /// Thin volumes provisioned from `pool`
+    pub fn thins_in(&self, pool: &str) -> Vec<&str> {
+        let mut thins: Vec<_> = self.subvols.iter()
+            .filter(|(_name, sv)| sv.thin.as_ref().is_some_and(|t| t.pool == pool))
+            .map(|(name, _sv)| name.as_str())
+            .collect();
+        thins.sort();
+        thins
+    }
+
+    /// Create a thin pool with `data_size` bytes of shared space, and
+    /// `metadata_size` bytes for the pool to track its mappings in
+    pub fn create_thin_pool(&mut self, name: &str, data_size: u64, metadata_size: u64) -> Result<(), MapperError> {
+        self.check_new_name(name)?;
+        let block_bytes = POOL_BLOCK_SECTORS * 512;
+        if data_size < block_bytes {
+            return Err(MapperError::InvalidArgument("pool is smaller than one pool block".to_string()));
+        }
+
+        let data = self.allocate(data_size.div_ceil(self.iosize))?;
+        let mut sv = SubVolume::new(data, self.iosize);
+        // Reserve the data extents while finding room for the metadata
+        self.subvols.insert(name.to_string(), sv.clone());
+        let metadata = self.allocate(metadata_size.div_ceil(self.iosize));
+        self.subvols.remove(name);
+        let metadata = metadata?;
+
+        // The pool formats its metadata device if it starts out zeroed,
+        // rather than trying to make sense of whatever was there
+        self.zero_range(metadata[0].device, metadata[0].block_offset * self.iosize, 4096)?;
+
+        sv.thin_pool = Some(ThinPool {
+            metadata_extents: metadata,
+            block_sectors: POOL_BLOCK_SECTORS,
+            next_id: 0,
+        });
+        self.subvols.insert(name.to_string(), sv.clone());
+        self.commit()?;
+        self.create_dm(name, &sv)?;
+        Ok(())
+    }
+
+    /// Create a thin volume of `size` bytes backed by `pool`
+    pub fn create_thin(&mut self, pool: &str, name: &str, size: u64) -> Result<(), MapperError> {
+        self.check_writable()?;
+        self.check_new_name(name)?;
+        if size == 0 {
+            return Err(MapperError::InvalidArgument("thin volumes need a size".to_string()));
+        }
+        let pool_sv = self.subvols.get(pool)
+            .ok_or_else(|| MapperError::NotFound(pool.to_string()))?;
+        let id = pool_sv.thin_pool.as_ref()
+            .ok_or_else(|| MapperError::InvalidArgument(format!("{} is not a thin pool", pool)))?
+            .next_id;
+
+        // The ID is only used up once the pool has a device with it
+        self.pool_message(pool, &format!("create_thin {}", id))?;
+        let pool_info = self.subvols.get_mut(pool).and_then(|sv| sv.thin_pool.as_mut()).expect("pool vanished");
+        pool_info.next_id += 1;
+
+        let mut sv = SubVolume::new(vec![], self.iosize);
+        sv.thin = Some(ThinVolume {
+            pool: pool.to_string(),
+            id,
+            // Thin devices are sized in whole sectors
+            size: size.div_ceil(512) * 512,
+        });
+        self.subvols.insert(name.to_string(), sv.clone());
+        self.commit()?;
+        self.create_dm(name, &sv)?;
+        Ok(())
+    }
+
+    /// Remove a thin volume and release its blocks back to the pool
+    pub(crate) fn delete_thin(&mut self, name: &str) -> Result<(), MapperError> {
+        let thin = self.subvols[name].thin.clone().expect("not a thin volume");
+        self.check_writable()?;
+        self.remove_dm(name)?;
+        self.pool_message(&thin.pool, &format!("delete {}", thin.id))?;
+
+        self.subvols.remove(name);
+        self.commit()?;
+        Ok(())
+    }
+
+    /// Send `message` to the pool target of `pool`
+    fn pool_message(&self, pool: &str, message: &str) -> Result<(), MapperError> {
+        let pool_dm = self.layer_name(pool, None);
+        if self.plan_step(|| Step::Message { device: pool_dm.clone(), message: message.to_string() }) {
+            return Ok(());
+        }
+        self.dm()?.message(&pool_dm, message)
+    }
+}
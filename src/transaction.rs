@@ -0,0 +1,218 @@
+//! Staging several layout changes and committing them together.
+//!
+//! Each staged operation is applied to the in-memory layout straight
+//! away, so later operations see the space earlier ones took or released.
+//! Nothing reaches the disk or device mapper until `commit`, which writes
+//! the metadata once, or twice through the journal if any subvolume gives
+//! up space.  Dropping the transaction without committing puts
+//! the layout back as it was.
+
+use std::collections::HashMap;
+use std::mem;
+
+use crate::{CreateOptions, Extent, MapperError, SubVolume, SuperPartition, Wipe};
+
+/// Layout changes staged against a `SuperPartition`
+pub struct Transaction<'a> {
+    sp: &'a mut SuperPartition,
+    /// Subvolumes as they were when the transaction started
+    saved: HashMap<String, SubVolume>,
+    /// New subvolumes to clear before they are activated
+    wipes: Vec<(String, Wipe)>,
+    done: bool,
+}
+
+/// Whether `new` maps everything `old` does at the same offsets, and
+/// perhaps more after it.  Growing can lengthen the last extent rather
+/// than add one, when the new space follows straight on.
+fn extends(old: &[Extent], new: &[Extent]) -> bool {
+    let Some((last, rest)) = old.split_last() else {
+        return true;
+    };
+    new.starts_with(rest) && new.get(rest.len()).is_some_and(|e| {
+        e.device == last.device && e.block_offset == last.block_offset && e.block_length >= last.block_length
+    })
+}
+
+impl SuperPartition {
+    /// Start staging changes to be committed with a single metadata write
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        let saved = self.subvols.clone();
+        Transaction {
+            sp: self,
+            saved,
+            wipes: vec![],
+            done: false,
+        }
+    }
+}
+
+impl Transaction<'_> {
+    /// Stage creating a plain subvolume of `size` bytes
+    pub fn create(&mut self, name: &str, size: u64) -> Result<(), MapperError> {
+        self.create_with(name, size, &CreateOptions::default())
+    }
+
+    /// Stage creating a plain subvolume, choosing its space as described
+    /// by `options`
+    pub fn create_with(&mut self, name: &str, size: u64, options: &CreateOptions) -> Result<(), MapperError> {
+        let sv = self.sp.new_subvol_with(name, size, options)?;
+        self.sp.subvols.insert(name.to_string(), sv);
+        if let Some(how) = options.wipe {
+            self.wipes.push((name.to_string(), how));
+        }
+        Ok(())
+    }
+
+    /// Stage deleting a subvolume.  Snapshots, thin volumes and anything
+    /// they depend on have to be deleted on their own, as do protected
+    /// subvolumes.
+    pub fn delete(&mut self, name: &str) -> Result<(), MapperError> {
+        self.sp.check_unprotected(name, false)?;
+        let sv = self.sp.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        if sv.snapshot_of.is_some() || self.sp.has_snapshots(name) {
+            return Err(MapperError::InvalidArgument(format!("cannot delete {} in a transaction, it has snapshots", name)));
+        }
+        if sv.thin.is_some() || sv.thin_pool.is_some() {
+            return Err(MapperError::InvalidArgument(format!("cannot delete thin pool or volume {} in a transaction", name)));
+        }
+        self.sp.subvols.remove(name);
+        Ok(())
+    }
+
+    /// Stage growing or shrinking a subvolume to `new_size` bytes
+    pub fn resize(&mut self, name: &str, new_size: u64) -> Result<(), MapperError> {
+        self.sp.check_unprotected(name, false)?;
+        let cur_size = self.sp.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?
+            .size_bytes();
+        let sv = if new_size < cur_size {
+            self.sp.shrunk_subvol(name, new_size)?
+        } else {
+            self.sp.grown_subvol(name, new_size)?
+        };
+        if let Some(sv) = sv {
+            self.sp.subvols.insert(name.to_string(), sv);
+        }
+        Ok(())
+    }
+
+    /// Stage a change to the flags or description of a subvolume
+    pub(crate) fn modify(&mut self, name: &str, f: impl FnOnce(&mut SubVolume)) -> Result<(), MapperError> {
+        self.sp.check_not_reserved(name)?;
+        f(self.sp.subvol_mut(name)?);
+        Ok(())
+    }
+
+    /// Write the staged layout as one new metadata generation and bring
+    /// the DM devices in line with it.  Fails without changing anything
+    /// if a subvolume that loses space is in use.
+    pub fn commit(mut self) -> Result<(), MapperError> {
+        self.done = true;
+        let staged = mem::replace(&mut self.sp.subvols, self.saved.clone());
+
+        // Subvolumes that lose blocks they map today must let go of them
+        // before the new layout can hand them to anyone else
+        let mut released = Vec::new();
+        let mut grown = Vec::new();
+        for (name, old) in &self.saved {
+            match staged.get(name) {
+                Some(new) if new == old => {}
+                Some(new) if extends(&old.extents, &new.extents) => grown.push(name.clone()),
+                _ => released.push(name.clone()),
+            }
+        }
+        for name in &released {
+            if self.sp.dm_in_use(name)? {
+                return Err(MapperError::DeviceBusy(name.clone()));
+            }
+        }
+
+        let result = if released.is_empty() {
+            self.sp.subvols = staged;
+            self.sp.commit()
+        } else {
+            // Taking the released space away is journaled, so a crash
+            // part way through is finished off by the next open
+            let changes = self.saved.keys().chain(staged.keys())
+                .filter(|name| self.saved.get(*name) != staged.get(*name))
+                .map(|name| (name.clone(), staged.get(name).cloned()))
+                .collect();
+            self.sp.journaled(changes, |sp| Self::release(sp, &released, &staged))
+        };
+        if let Err(e) = result {
+            // Put back whatever was already taken down
+            self.sp.subvols = mem::take(&mut self.saved);
+            for name in &released {
+                let _ = match self.sp.dm_active(name) {
+                    Ok(true) => self.sp.reload_dm(name, &self.sp.subvols[name]),
+                    _ => self.sp.create_dm(name, &self.sp.subvols[name]),
+                };
+            }
+            return Err(e);
+        }
+
+        for name in grown {
+            self.sp.reload_dm(&name, &self.sp.subvols[&name])?;
+        }
+        let created: Vec<_> = self.sp.subvols.keys()
+            .filter(|name| !self.saved.contains_key(*name))
+            .cloned()
+            .collect();
+        for (name, how) in &self.wipes {
+            if let Some(sv) = self.sp.subvols.get(name) {
+                self.sp.wipe_ends(sv, *how)?;
+            }
+        }
+        for name in created {
+            self.sp.create_dm(&name, &self.sp.subvols[&name])?;
+        }
+        Ok(())
+    }
+
+    /// Drop everything staged so far
+    pub fn rollback(self) {}
+
+    /// Tear down deleted subvolumes and reload shrunk ones against the
+    /// layout they are leaving
+    fn release(sp: &SuperPartition, released: &[String], staged: &HashMap<String, SubVolume>) -> Result<(), MapperError> {
+        for name in released {
+            match staged.get(name) {
+                Some(sv) => sp.reload_dm(name, sv)?,
+                None => sp.remove_dm(name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.sp.subvols = mem::take(&mut self.saved);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil::{format, MIB};
+
+    #[test]
+    fn growing_an_open_contiguous_subvolume() {
+        let (mut sp, dm) = format();
+        sp.create_subvol("a".to_string(), 4 * MIB).expect("create");
+        dm.set_open_count("hg-a", 1).expect("open count");
+
+        let mut txn = sp.transaction();
+        txn.resize("a", 8 * MIB).expect("resize");
+        txn.commit().expect("commit");
+
+        // One extent, just longer, and no intent left behind
+        assert_eq!(sp.subvols["a"].extents.len(), 1);
+        assert_eq!(sp.subvols["a"].size_bytes(), 8 * MIB);
+        assert!(sp.intent.is_none());
+        assert_eq!(dm.tables()["hg-a"], ["0 16384 linear 0:0 0"]);
+    }
+}
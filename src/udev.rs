@@ -0,0 +1,92 @@
+//! udev rules for stable device node names and permissions.
+//!
+//! DM device names depend on the prefix and change on rename, but the DM
+//! UUID carries the subvolume's UUID.  The generated rules match on it to
+//! give every subvolume a symlink at
+//! `/dev/hg/<super partition name>/<subvolume>`, and to apply any owner,
+//! group or mode recorded for the subvolume.
+
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{is_reserved, MapperError, SuperPartition};
+
+/// Directory under /dev for the symlinks
+const LINK_DIR: &str = "hg";
+
+/// Ownership and permissions for a subvolume's device node
+#[derive(Serialize,Deserialize,PartialEq,Eq,Debug,Clone,Default)]
+pub struct NodeAccess {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+impl SuperPartition {
+    /// Name used for the super partition's directory under /dev/hg, which
+    /// is its UUID unless one has been set
+    pub fn name(&self) -> &str {
+        if self.name.is_empty() {
+            &self.uuid
+        } else {
+            &self.name
+        }
+    }
+
+    pub fn set_name(&mut self, name: &str) -> Result<(), MapperError> {
+        if name.contains('/') || name.contains('"') || name.chars().any(char::is_control) {
+            return Err(MapperError::InvalidArgument(format!("{:?} is not usable as a directory name", name)));
+        }
+        self.name = name.to_string();
+        self.commit()
+    }
+
+    /// Set who may open the device node for subvolume `name`.  This takes
+    /// effect through the rules from `udev_rules`.
+    pub fn set_node_access(&mut self, name: &str, access: NodeAccess) -> Result<(), MapperError> {
+        let quoted = [&access.owner, &access.group].into_iter().flatten()
+            .any(|s| s.contains('"') || s.chars().any(char::is_control));
+        if quoted {
+            return Err(MapperError::InvalidArgument("owner and group cannot contain quotes".to_string()));
+        }
+        let sv = self.subvols.get_mut(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        sv.access = Some(access).filter(|a| *a != NodeAccess::default());
+        self.commit()
+    }
+
+    /// udev rules matching the DM devices of every subvolume by UUID
+    pub fn udev_rules(&self) -> String {
+        let mut names: Vec<_> = self.subvols.keys()
+            .filter(|name| !is_reserved(name) && !self.is_external(name))
+            .collect();
+        names.sort();
+
+        let mut rules = format!("# Generated by hgmap for super partition {}\n", self.uuid);
+        for name in names {
+            let Some(uuid) = self.layer_uuid(name, None) else {
+                continue;
+            };
+            let mut rule = format!("SUBSYSTEM==\"block\", ENV{{DM_UUID}}==\"{}\", SYMLINK+=\"{}/{}/{}\"",
+                uuid, LINK_DIR, self.name(), name);
+            if let Some(access) = &self.subvols[name].access {
+                if let Some(owner) = &access.owner {
+                    let _ = write!(rule, ", OWNER=\"{}\"", owner);
+                }
+                if let Some(group) = &access.group {
+                    let _ = write!(rule, ", GROUP=\"{}\"", group);
+                }
+                if let Some(mode) = access.mode {
+                    let _ = write!(rule, ", MODE=\"{:04o}\"", mode);
+                }
+            }
+            rules.push_str(&rule);
+            rules.push('\n');
+        }
+        rules
+    }
+}
@@ -0,0 +1,156 @@
+//! systemd mount units and fstab lines for subvolumes.
+//!
+//! A subvolume can record where its filesystem is to be mounted, of what
+//! type and with what options, as well as the type of filesystem made on
+//! it, to go by when no type is given for the mount point.  Units and
+//! fstab lines are generated from that for every subvolume that has one,
+//! mounting its node under /dev/mapper.  Read-only subvolumes are mounted
+//! read-only whatever the options say.
+
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{is_reserved, MapperError, SuperPartition};
+
+/// Where and how a subvolume's filesystem is mounted
+#[derive(Serialize,Deserialize,PartialEq,Eq,Debug,Clone)]
+pub struct MountPoint {
+    /// Absolute path to mount it on
+    pub path: String,
+    pub fstype: String,
+    /// Comma-separated mount options, or empty for the defaults
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub options: String,
+    /// Mount it when first accessed rather than at boot
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub automount: bool,
+}
+
+/// A generated systemd unit
+#[derive(Debug, Clone)]
+pub struct Unit {
+    /// File name, such as `mnt-data.mount`
+    pub name: String,
+    pub contents: String,
+}
+
+/// `path` escaped the way systemd names the units for it
+fn unit_name(path: &str, suffix: &str) -> String {
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return format!("-.{}", suffix);
+    }
+    let mut name = String::new();
+    for (i, b) in trimmed.bytes().enumerate() {
+        match b {
+            b'/' => name.push('-'),
+            b'.' if i == 0 => name.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => name.push(b as char),
+            _ => {
+                let _ = write!(name, "\\x{:02x}", b);
+            }
+        }
+    }
+    format!("{}.{}", name, suffix)
+}
+
+impl SuperPartition {
+    /// Record where subvolume `name` is mounted, or with None, that it
+    /// isn't
+    pub fn set_mount_point(&mut self, name: &str, mount: Option<MountPoint>) -> Result<(), MapperError> {
+        self.check_not_reserved(name)?;
+        self.check_not_external(name)?;
+        if let Some(mount) = &mount {
+            if !mount.path.starts_with('/') {
+                return Err(MapperError::InvalidArgument(format!("mount point {:?} is not absolute", mount.path)));
+            }
+            let unusable = [&mount.path, &mount.fstype, &mount.options].into_iter()
+                .any(|s| s.chars().any(|c| c.is_whitespace() || c.is_control()));
+            if mount.fstype.is_empty() || unusable {
+                return Err(MapperError::InvalidArgument("mount point, type and options cannot be empty or contain spaces".to_string()));
+            }
+        }
+        let sv = self.subvols.get_mut(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        sv.mount = mount;
+        self.commit()
+    }
+
+    /// Record the type of filesystem on subvolume `name`, or with None,
+    /// forget it
+    pub fn set_filesystem(&mut self, name: &str, fstype: Option<&str>) -> Result<(), MapperError> {
+        self.check_not_reserved(name)?;
+        if fstype.is_some_and(|t| t.is_empty() || t.chars().any(|c| c.is_whitespace() || c.is_control())) {
+            return Err(MapperError::InvalidArgument("filesystem type cannot be empty or contain spaces".to_string()));
+        }
+        let sv = self.subvols.get_mut(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        sv.filesystem = fstype.map(str::to_string);
+        self.commit()
+    }
+
+    /// Subvolumes with a mount point, sorted by it so parents come first,
+    /// with the options to mount each with
+    fn mounts(&self) -> Vec<(&str, &MountPoint, String)> {
+        let mut mounts: Vec<_> = self.subvols.iter()
+            .filter(|(name, _sv)| !is_reserved(name))
+            .filter_map(|(name, sv)| {
+                let mount = sv.mount.as_ref()?;
+                let mut options: Vec<_> = mount.options.split(',').filter(|o| !o.is_empty()).collect();
+                if sv.read_only && !options.contains(&"ro") {
+                    options.retain(|o| *o != "rw");
+                    options.push("ro");
+                }
+                Some((name.as_str(), mount, options.join(",")))
+            })
+            .collect();
+        mounts.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+        mounts
+    }
+
+    /// A `.mount` unit for every subvolume with a mount point, and an
+    /// `.automount` unit next to it for those mounted on access
+    pub fn mount_units(&self) -> Vec<Unit> {
+        let mut units = vec![];
+        for (name, mount, options) in self.mounts() {
+            let header = format!("# Generated by hgmap for super partition {}\n\n\
+                [Unit]\nDescription=hgmap subvolume {}\n", self.uuid, name);
+            let mut contents = format!("{}\n[Mount]\nWhat={}\nWhere={}\nType={}\n",
+                header, self.subvol_path(name).display(), mount.path, mount.fstype);
+            if !options.is_empty() {
+                let _ = writeln!(contents, "Options={}", options);
+            }
+            let install = "\n[Install]\nWantedBy=local-fs.target\n";
+            if mount.automount {
+                units.push(Unit {
+                    name: unit_name(&mount.path, "automount"),
+                    contents: format!("{}\n[Automount]\nWhere={}\n{}", header, mount.path, install),
+                });
+            } else {
+                contents.push_str(install);
+            }
+            units.push(Unit { name: unit_name(&mount.path, "mount"), contents });
+        }
+        units
+    }
+
+    /// fstab lines for every subvolume with a mount point
+    pub fn fstab(&self) -> String {
+        let mut fstab = format!("# Generated by hgmap for super partition {}\n", self.uuid);
+        for (name, mount, mut options) in self.mounts() {
+            if mount.automount {
+                options = [options.as_str(), "noauto,x-systemd.automount"].iter()
+                    .filter(|o| !o.is_empty())
+                    .copied()
+                    .collect::<Vec<_>>()
+                    .join(",");
+            }
+            if options.is_empty() {
+                options = "defaults".to_string();
+            }
+            let _ = writeln!(fstab, "{} {} {} {} 0 0", self.subvol_path(name).display(), mount.path, mount.fstype, options);
+        }
+        fstab
+    }
+}
@@ -0,0 +1,195 @@
+//! Updating several subvolumes at once from a manifest.
+//!
+//! Each image the manifest names is written to a staging subvolume of its
+//! own, the size of the one it replaces, and checked twice: the file has
+//! to have the SHA-256 the manifest gives, and the staging subvolume has
+//! to read back as written.  Only once every image has passed are the
+//! targets switched over to their staged blocks, in one journaled commit,
+//! so the update is applied entirely or not at all.  Staging subvolumes
+//! are committed as they are created so that their space stays reserved;
+//! those of an update that fails are deleted, and any an interrupted one
+//! left behind are deleted when the next starts.
+//!
+//! Targets have to be plain subvolumes without snapshots, and not in use,
+//! since their blocks change underneath their DM devices.  A manifest can
+//! also name the base of an A/B pair, which means its subvolume in the
+//! slot that is not active.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::verity::hex;
+use crate::{CreateOptions, MapperError, SuperPartition, WriteOptions};
+
+/// Images to write to subvolumes, as listed in an update manifest
+#[derive(Deserialize, Debug, Clone)]
+pub struct UpdateManifest {
+    pub images: Vec<UpdateImage>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct UpdateImage {
+    /// Subvolume the image replaces, or base name of a pair of them
+    pub subvol: String,
+    /// Image file, relative to the manifest when loaded from one
+    pub image: PathBuf,
+    /// SHA-256 of the file as it is, compressed or not
+    pub sha256: String,
+    /// Version to record for the subvolume once updated
+    #[serde(default)]
+    pub version: Option<String>,
+}
+
+impl UpdateManifest {
+    /// Read a manifest from the JSON file at `path`
+    pub fn load(path: &Path) -> Result<Self, MapperError> {
+        let json = std::fs::read(path)?;
+        let mut manifest: Self = serde_json::from_slice(&json)
+            .map_err(|e| MapperError::InvalidArgument(format!("bad update manifest {}: {}", path.display(), e)))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for image in &mut manifest.images {
+            image.image = dir.join(&image.image);
+        }
+        Ok(manifest)
+    }
+}
+
+/// Reader that hashes what it has given out
+struct Hashed<R> {
+    inner: R,
+    hash: Sha256,
+}
+
+impl<R: Read> Read for Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hash.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+fn staging_name(subvol: &str) -> String {
+    format!("{}.update", subvol)
+}
+
+impl SuperPartition {
+    /// Write every image in `manifest` to the subvolume it names, all or
+    /// none of them, returning the subvolumes updated in the order given
+    pub fn apply_update(&mut self, manifest: &UpdateManifest) -> Result<Vec<String>, MapperError> {
+        self.check_writable()?;
+        let mut manifest = manifest.clone();
+        let inactive = self.active_slot().other();
+        for image in &mut manifest.images {
+            if !self.subvols.contains_key(&image.subvol) && self.slotted().contains(&image.subvol.as_str()) {
+                image.subvol = inactive.subvol(&image.subvol);
+            }
+        }
+
+        let leftover: Vec<_> = self.subvols.iter()
+            .filter(|(_name, sv)| sv.staged_for.is_some())
+            .map(|(name, _sv)| name.clone())
+            .collect();
+        for name in leftover {
+            self.delete_subvol_by_name(&name)?;
+        }
+
+        let mut targets = HashSet::new();
+        for image in &manifest.images {
+            self.check_not_reserved(&image.subvol)?;
+            let sv = self.subvols.get(&image.subvol)
+                .ok_or_else(|| MapperError::NotFound(image.subvol.clone()))?;
+            if !targets.insert(&image.subvol) {
+                return Err(MapperError::InvalidArgument(format!("{} is in the manifest more than once", image.subvol)));
+            }
+            if !sv.is_plain() || self.has_snapshots(&image.subvol) {
+                return Err(MapperError::InvalidArgument(format!("{} is not a plain subvolume", image.subvol)));
+            }
+            if self.dm_in_use(&image.subvol)? {
+                return Err(MapperError::DeviceBusy(image.subvol.clone()));
+            }
+        }
+
+        let mut staged = vec![];
+        let result = self.stage_update(&manifest, &mut staged)
+            .and_then(|()| self.switch_to_staged(&manifest));
+        if let Err(e) = result {
+            for name in &staged {
+                let _ = self.delete_subvol_by_name(name);
+            }
+            return Err(e);
+        }
+        Ok(manifest.images.into_iter().map(|image| image.subvol).collect())
+    }
+
+    /// Write each image to a new staging subvolume, recording their names
+    /// in `staged`, and check them
+    fn stage_update(&mut self, manifest: &UpdateManifest, staged: &mut Vec<String>) -> Result<(), MapperError> {
+        for image in &manifest.images {
+            let target = &self.subvols[&image.subvol];
+            let options = CreateOptions {
+                contiguous: target.contiguous,
+                alignment: target.alignment,
+                ..CreateOptions::default()
+            };
+            let name = staging_name(&image.subvol);
+            let mut sv = self.new_subvol_with(&name, target.size_bytes(), &options)?;
+            sv.staged_for = Some(image.subvol.clone());
+            self.insert_subvol(name.clone(), sv)?;
+            staged.push(name.clone());
+
+            let mut file = Hashed { inner: File::open(&image.image)?, hash: Sha256::new() };
+            self.write_image_with(&name, &mut file, &mut WriteOptions { verify: true, ..WriteOptions::default() })?;
+            // Whatever follows the end of a compressed stream is part of
+            // the file too
+            io::copy(&mut file, &mut io::sink())?;
+            if !hex(&file.hash.finalize()).eq_ignore_ascii_case(&image.sha256) {
+                return Err(MapperError::InvalidArgument(format!("{} does not have the SHA-256 the manifest gives", image.image.display())));
+            }
+        }
+        Ok(())
+    }
+
+    /// Move every target onto the blocks of its staging subvolume, which
+    /// goes away
+    fn switch_to_staged(&mut self, manifest: &UpdateManifest) -> Result<(), MapperError> {
+        let mut changes = BTreeMap::new();
+        for image in &manifest.images {
+            let name = staging_name(&image.subvol);
+            let staged = &self.subvols[&name];
+            let mut sv = self.subvols[&image.subvol].clone();
+            sv.extents = staged.extents.clone();
+            sv.digest = staged.digest.clone();
+            if let Some(version) = &image.version {
+                sv.version = version.clone();
+            }
+            changes.insert(image.subvol.clone(), Some(sv));
+            changes.insert(name, None);
+        }
+
+        let step = changes.clone();
+        let result = self.journaled(changes, |sp| {
+            for (name, sv) in &step {
+                match sv {
+                    Some(sv) if sp.dm_active(name)? => sp.reload_dm(name, sv)?,
+                    Some(_sv) => {}
+                    None => sp.remove_dm(name)?,
+                }
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            // Put back any target already reloaded
+            for image in &manifest.images {
+                if self.dm_active(&image.subvol).unwrap_or(false) {
+                    let _ = self.reload_dm(&image.subvol, &self.subvols[&image.subvol]);
+                }
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,191 @@
+//! Checking the extents in freshly loaded metadata.
+//!
+//! Metadata that parses can still describe a layout that would be
+//! dangerous to activate: two subvolumes sharing blocks, an extent over
+//! the metadata replicas, or one running off the end of its device.  Every
+//! extent is checked before anything is built from it, and all problems
+//! found are reported together rather than stopping at the first.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{Extent, MapperError, SuperPartition, METADATA_SUBVOL};
+
+/// Owner given for the extent holding the metadata history
+pub(crate) const HISTORY_OWNER: &str = "metadata history";
+/// Put in front of the subvolume name for a relocation's destination
+pub(crate) const RELOCATION_OWNER: &str = "relocation of ";
+
+/// One thing wrong with the extents recorded in the metadata.  Owners
+/// are subvolume names, or describe the bookkeeping that holds the extent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExtentProblem {
+    /// Two owners map the same blocks
+    Overlap { first: String, second: String, extent: Extent },
+    /// An extent covers blocks holding the metadata replicas or label
+    Metadata { owner: String, extent: Extent },
+    /// An extent runs past the end of its device
+    OutOfBounds { owner: String, extent: Extent, device_blocks: u64 },
+    /// An extent is on a device that is not a member
+    NoSuchDevice { owner: String, extent: Extent },
+}
+
+/// Every problem found in the extents of a super partition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptionReport {
+    pub problems: Vec<ExtentProblem>,
+}
+
+impl fmt::Display for ExtentProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtentProblem::Overlap { first, second, extent } => {
+                write!(f, "{} and {} both map {}", first, second, describe(extent))
+            }
+            ExtentProblem::Metadata { owner, extent } => {
+                write!(f, "{} maps metadata {}", owner, describe(extent))
+            }
+            ExtentProblem::OutOfBounds { owner, extent, device_blocks } => {
+                write!(f, "{} maps {}, past the end at block {}", owner, describe(extent), device_blocks)
+            }
+            ExtentProblem::NoSuchDevice { owner, extent } => {
+                write!(f, "{} maps {}, which is not a member", owner, describe(extent))
+            }
+        }
+    }
+}
+
+impl fmt::Display for CorruptionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+fn describe(extent: &Extent) -> String {
+    format!("blocks {}-{} of device {}", extent.block_offset,
+        extent.block_offset + extent.block_length - 1, extent.device)
+}
+
+/// Blocks shared by `a` and `b`, if any
+pub(crate) fn intersection(a: &Extent, b: &Extent) -> Option<Extent> {
+    let start = a.block_offset.max(b.block_offset);
+    let end = (a.block_offset + a.block_length).min(b.block_offset + b.block_length);
+    (a.device == b.device && start < end).then(|| Extent {
+        device: a.device,
+        block_offset: start,
+        block_length: end - start,
+    })
+}
+
+impl SuperPartition {
+    /// Every extent in the metadata with a description of what holds it,
+    /// in device order
+    pub(crate) fn owned_extents(&self) -> Vec<(String, &Extent)> {
+        let mut extents = vec![];
+        for (name, sv) in &self.subvols {
+            extents.extend(sv.all_extents().filter(|e| e.block_length > 0).map(|e| (name.clone(), e)));
+        }
+        if let Some(reloc) = &self.relocation {
+            extents.push((format!("{}{}", RELOCATION_OWNER, reloc.subvol), &reloc.to));
+        }
+        if let Some(history) = &self.history {
+            extents.push((HISTORY_OWNER.to_string(), history.extent()));
+        }
+        extents.sort_by(|a, b| a.1.cmp(b.1));
+        extents
+    }
+
+    /// Blocks on the metadata device holding the label and replicas
+    pub(crate) fn metadata_regions(&self) -> Vec<Extent> {
+        let extent = |block_offset, block_length| Extent {
+            device: 0,
+            block_offset,
+            block_length,
+        };
+        match &self.label {
+            Some(label) => {
+                let mut regions = vec![extent(0, 1)];
+                regions.extend(label.slots.iter().map(|start| extent(*start, label.slot_blocks)));
+                regions.sort();
+                regions
+            }
+            None => {
+                let reserved = 2 * self.metadata_blocks;
+                vec![extent(self.device_blocks[0].saturating_sub(reserved), reserved)]
+            }
+        }
+    }
+
+    /// Check that the extents fit on their devices and stay clear of each
+    /// other and of the metadata.  Needs `device_blocks` filled in.
+    pub(crate) fn validate_extents(&self) -> CorruptionReport {
+        let extents = self.owned_extents();
+        let metadata = self.metadata_regions();
+        let mut problems = vec![];
+
+        for (owner, extent) in &extents {
+            match self.device_blocks.get(extent.device as usize) {
+                None => problems.push(ExtentProblem::NoSuchDevice {
+                    owner: owner.clone(),
+                    extent: (*extent).clone(),
+                }),
+                Some(&blocks) if extent.block_offset + extent.block_length > blocks => {
+                    problems.push(ExtentProblem::OutOfBounds {
+                        owner: owner.clone(),
+                        extent: (*extent).clone(),
+                        device_blocks: blocks,
+                    })
+                }
+                Some(_) => {}
+            }
+            // The metadata entry is what keeps the allocator off these
+            // blocks, so it is expected to cover them
+            if owner != METADATA_SUBVOL {
+                for overlap in metadata.iter().filter_map(|m| intersection(m, extent)) {
+                    problems.push(ExtentProblem::Metadata {
+                        owner: owner.clone(),
+                        extent: overlap,
+                    });
+                }
+            }
+        }
+
+        // Sorted by position, so each extent only needs comparing with the
+        // ones after it until they start beyond its end
+        for (i, (first, a)) in extents.iter().enumerate() {
+            for (second, b) in &extents[i + 1..] {
+                if b.device != a.device || b.block_offset >= a.block_offset + a.block_length {
+                    break;
+                }
+                if first == METADATA_SUBVOL || second == METADATA_SUBVOL {
+                    // Already reported against the metadata itself
+                    continue;
+                }
+                problems.push(ExtentProblem::Overlap {
+                    first: first.clone(),
+                    second: second.clone(),
+                    extent: intersection(a, b).expect("sorted extents overlap"),
+                });
+            }
+        }
+
+        CorruptionReport { problems }
+    }
+
+    /// Fail with `BadExtents` unless `validate_extents` finds nothing
+    pub(crate) fn check_extents(&self) -> Result<(), MapperError> {
+        let report = self.validate_extents();
+        if !report.problems.is_empty() {
+            return Err(MapperError::BadExtents(report));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+//! Read-only subvolumes protected by dm-verity.
+//!
+//! A verity subvolume has a second extent set holding a hash tree over its
+//! data.  Until `verity_format` has been run it is mapped as a plain
+//! writable linear device, so the image can be written to it.  Formatting
+//! builds the hash tree and records its root hash, after which the data
+//! and hash extents are mapped by `<name>-vdata` and `<name>-vhash` and a
+//! verity target on top rejects any block that does not match the tree.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::dm::{Layer, Target};
+use crate::{Extent, MapperError, Step, SubVolume, SuperPartition};
+
+pub(crate) const VDATA_SUFFIX: &str = "vdata";
+pub(crate) const VHASH_SUFFIX: &str = "vhash";
+
+/// Size of both data and hash blocks
+pub(crate) const VERITY_BLOCK_SIZE: u64 = 4096;
+const DIGEST_SIZE: usize = 32;
+const SALT_SIZE: usize = 32;
+
+#[derive(Serialize,Deserialize,PartialEq,Debug,Clone)]
+pub(crate) struct Verity {
+    hash_extents: Vec<Extent>,
+    /// Hex encoded
+    salt: String,
+    /// Hex encoded, present once the hash tree has been generated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root_hash: Option<String>,
+}
+
+impl Verity {
+    pub(crate) fn hash_extents(&self) -> &[Extent] {
+        &self.hash_extents
+    }
+
+    pub(crate) fn hash_extents_mut(&mut self) -> &mut [Extent] {
+        &mut self.hash_extents
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>, MapperError> {
+    let bad = || MapperError::MetadataCorrupt(format!("bad hex {:?} in metadata", s));
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(bad());
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| bad()))
+        .collect()
+}
+
+/// Number of hash blocks in each level of the tree over `data_blocks`
+/// blocks, starting from the level closest to the data.  This follows the
+/// kernel's layout, where a single data block needs no hash blocks at all.
+fn level_sizes(data_blocks: u64) -> Vec<u64> {
+    let per_block = VERITY_BLOCK_SIZE / DIGEST_SIZE as u64;
+    let mut sizes = vec![];
+    let mut blocks = data_blocks;
+    while blocks > 1 {
+        blocks = blocks.div_ceil(per_block);
+        sizes.push(blocks);
+    }
+    sizes
+}
+
+fn salted_digest(salt: &[u8], block: &[u8]) -> [u8; DIGEST_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(block);
+    hasher.finalize().into()
+}
+
+/// Member device, byte offset on it and length of each of `extents`, in
+/// order
+fn byte_ranges(extents: &[Extent], iosize: u64) -> impl Iterator<Item = (u32, u64, u64)> + '_ {
+    extents.iter().map(move |e| (e.device(), e.block_offset() * iosize, e.block_length() * iosize))
+}
+
+impl SuperPartition {
+    pub(crate) fn verity_layers(&self, name: &str, sv: &SubVolume, verity: &Verity) -> Vec<Layer> {
+        let root_hash = match &verity.root_hash {
+            Some(root_hash) => root_hash.clone(),
+            None => return vec![Layer {
+                suffix: None,
+                target: Target::Linear(sv.extents.clone()),
+            }],
+        };
+
+        vec![
+            Layer {
+                suffix: Some(VDATA_SUFFIX),
+                target: Target::Linear(sv.extents.clone()),
+            },
+            Layer {
+                suffix: Some(VHASH_SUFFIX),
+                target: Target::Linear(verity.hash_extents.clone()),
+            },
+            Layer {
+                suffix: None,
+                target: Target::Verity {
+                    data: self.layer_name(name, Some(VDATA_SUFFIX)),
+                    hash: self.layer_name(name, Some(VHASH_SUFFIX)),
+                    data_blocks: sv.size_bytes() / VERITY_BLOCK_SIZE,
+                    root_hash,
+                    salt: verity.salt.clone(),
+                },
+            },
+        ]
+    }
+
+    /// Create a subvolume of `size` bytes that can be sealed with
+    /// `verity_format` once its contents have been written
+    pub fn create_verity_subvol(&mut self, name: String, size: u64) -> Result<(), MapperError> {
+        let mut sv = self.new_subvol(&name, size)?;
+        let data_blocks = sv.size_bytes() / VERITY_BLOCK_SIZE;
+        if data_blocks == 0 {
+            return Err(MapperError::InvalidArgument("verity subvol is smaller than one block".to_string()));
+        }
+        let hash_bytes = level_sizes(data_blocks).iter().sum::<u64>() * VERITY_BLOCK_SIZE;
+
+        // Reserve the data extents while finding room for the hash tree
+        self.subvols.insert(name.clone(), sv.clone());
+        let hash_extents = self.allocate(hash_bytes.div_ceil(self.iosize).max(1));
+        self.subvols.remove(&name);
+        let hash_extents = hash_extents?;
+
+        let mut salt = [0; SALT_SIZE];
+        File::open("/dev/urandom")?.read_exact(&mut salt)?;
+        sv.verity = Some(Verity {
+            hash_extents,
+            salt: hex(&salt),
+            root_hash: None,
+        });
+        self.insert_subvol(name, sv)
+    }
+
+    /// Generate the hash tree over the current contents of verity subvolume
+    /// `name` and switch it to a read-only verified mapping.  Returns the
+    /// root hash, which should be recorded somewhere trusted to
+    /// authenticate the subvolume at boot.
+    pub fn verity_format(&mut self, name: &str) -> Result<String, MapperError> {
+        self.check_writable()?;
+        let sv = self.subvols.get(name)
+            .ok_or_else(|| MapperError::NotFound(name.to_string()))?;
+        let verity = sv.verity.as_ref()
+            .ok_or_else(|| MapperError::InvalidArgument(format!("{} is not a verity subvol", name)))?;
+        let salt = unhex(&verity.salt)?;
+        let data_blocks = sv.size_bytes() / VERITY_BLOCK_SIZE;
+
+        // Nothing may change the data while the tree is built over it
+        self.remove_dm(name)?;
+
+        let mut digests = Vec::with_capacity(data_blocks as usize);
+        let mut block = vec![0; VERITY_BLOCK_SIZE as usize];
+        for (device, offset, len) in byte_ranges(&sv.extents, self.iosize) {
+            let mut blockdev = File::open(self.device_path(device))?;
+            blockdev.seek(SeekFrom::Start(offset))?;
+            for _ in 0..len / VERITY_BLOCK_SIZE {
+                if digests.len() as u64 == data_blocks {
+                    break;
+                }
+                blockdev.read_exact(&mut block)?;
+                digests.push(salted_digest(&salt, &block));
+            }
+        }
+
+        // Each level packs the digests of the one below into zero-padded
+        // hash blocks.  The levels are stored top first.
+        let mut levels = vec![];
+        for _ in level_sizes(data_blocks) {
+            let per_block = VERITY_BLOCK_SIZE as usize / DIGEST_SIZE;
+            let mut level = vec![];
+            let mut next = vec![];
+            for chunk in digests.chunks(per_block) {
+                let mut hash_block = chunk.concat();
+                hash_block.resize(VERITY_BLOCK_SIZE as usize, 0);
+                next.push(salted_digest(&salt, &hash_block));
+                level.extend(hash_block);
+            }
+            levels.push(level);
+            digests = next;
+        }
+        let tree: Vec<u8> = levels.into_iter().rev().flatten().collect();
+        let root_hash = hex(&digests[0]);
+
+        let mut written = 0;
+        for (device, offset, len) in byte_ranges(&verity.hash_extents, self.iosize) {
+            if written == tree.len() {
+                break;
+            }
+            let chunk = std::cmp::min(len as usize, tree.len() - written);
+            if self.plan_step(|| Step::Write { path: self.device_path(device).to_string(), offset, len: chunk as u64 }) {
+                written += chunk;
+                continue;
+            }
+            let mut blockdev = OpenOptions::new().write(true).open(self.device_path(device))?;
+            blockdev.seek(SeekFrom::Start(offset))?;
+            blockdev.write_all(&tree[written..written + chunk])?;
+            blockdev.sync_all()?;
+            written += chunk;
+        }
+
+        let sv = self.subvols.get_mut(name).expect("subvol vanished");
+        sv.verity.as_mut().expect("verity vanished").root_hash = Some(root_hash.clone());
+        let sv = sv.clone();
+        self.commit()?;
+        self.create_dm(name, &sv)?;
+        Ok(root_hash)
+    }
+}
@@ -0,0 +1,115 @@
+//! Clearing blocks as they are handed out or given up.
+//!
+//! Freed blocks keep whatever was written to them, so a new subvolume can
+//! start out looking like the filesystem that used to be there, and a
+//! deleted subvolume's data can be read back by whoever gets its blocks
+//! next.  Clearing either writes zeroes or asks the device to discard the
+//! blocks; a discard is much cheaper on flash, but what reads back
+//! afterwards depends on the device.  On regular files a discard punches a
+//! hole instead.  Trimming discards all the free space at once, so flash
+//! can reclaim blocks that were written to and then freed.
+
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+
+use nix::errno::Errno;
+use nix::fcntl::{fallocate, FallocateFlags};
+
+use crate::{Extent, MapperError, Step, SubVolume, SuperPartition};
+
+/// How much of each end of a new subvolume is wiped, which covers the
+/// signatures of every common filesystem and partition table
+const WIPE_BYTES: u64 = 1024 * 1024;
+
+nix::ioctl_write_ptr_bad!(blkdiscard, nix::request_code_none!(0x12, 119), [u64; 2]);
+
+/// How blocks are cleared
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wipe {
+    Zero,
+    Discard,
+}
+
+/// Discard `len` bytes of `file` at byte `offset`
+fn discard(file: &File, offset: u64, len: u64) -> Result<(), MapperError> {
+    let range = [offset, len];
+    match unsafe { blkdiscard(file.as_raw_fd(), &range) } {
+        Ok(_) => Ok(()),
+        Err(Errno::ENOTTY) => {
+            let flags = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+            fallocate(file.as_raw_fd(), flags, offset as i64, len as i64).map_err(std::io::Error::from)?;
+            Ok(())
+        }
+        Err(e) => Err(std::io::Error::from(e).into()),
+    }
+}
+
+impl SuperPartition {
+    /// Byte ranges on the member devices behind `len` bytes of the space
+    /// `extents` map, starting `offset` bytes in
+    pub(crate) fn physical_ranges(&self, extents: &[Extent], offset: u64, len: u64) -> Vec<(u32, u64, u64)> {
+        let mut ranges = vec![];
+        let end = offset + len;
+        let mut pos = 0;
+        for e in extents {
+            let e_start = pos;
+            let e_end = pos + e.block_length * self.iosize;
+            pos = e_end;
+            let start = offset.max(e_start);
+            let stop = end.min(e_end);
+            if start < stop {
+                ranges.push((e.device, e.block_offset * self.iosize + start - e_start, stop - start));
+            }
+        }
+        ranges
+    }
+
+    /// Clear `len` bytes of member `device` at byte `offset`
+    pub(crate) fn wipe_range(&self, device: u32, offset: u64, len: u64, how: Wipe) -> Result<(), MapperError> {
+        match how {
+            Wipe::Zero => self.zero_range(device, offset, len),
+            Wipe::Discard => {
+                self.check_writable()?;
+                if self.plan_step(|| Step::Discard { path: self.device_path(device).to_string(), offset, len }) {
+                    return Ok(());
+                }
+                let file = OpenOptions::new().write(true).open(self.device_path(device))?;
+                discard(&file, offset, len)
+            }
+        }
+    }
+
+    /// Clear every block in `extents`
+    pub(crate) fn wipe_extents<'a>(&self, extents: impl Iterator<Item = &'a Extent>, how: Wipe) -> Result<(), MapperError> {
+        for e in extents.filter(|e| e.block_length > 0) {
+            self.wipe_range(e.device, e.block_offset * self.iosize, e.block_length * self.iosize, how)?;
+        }
+        Ok(())
+    }
+
+    /// Discard every unallocated block, returning how many bytes that
+    /// covered
+    pub fn trim(&self) -> Result<u64, MapperError> {
+        let mut trimmed = 0;
+        for hole in self.free_extents() {
+            let len = hole.block_length * self.iosize;
+            self.wipe_range(hole.device, hole.block_offset * self.iosize, len, Wipe::Discard)?;
+            trimmed += len;
+        }
+        Ok(trimmed)
+    }
+
+    /// Clear the start and end of a new subvolume, where filesystems and
+    /// partition tables keep the signatures that identify them
+    pub(crate) fn wipe_ends(&self, sv: &SubVolume, how: Wipe) -> Result<(), MapperError> {
+        let size = sv.size_blocks() * self.iosize;
+        let head = size.min(WIPE_BYTES);
+        let tail_start = size.saturating_sub(WIPE_BYTES).max(head);
+        let ranges = self.physical_ranges(&sv.extents, 0, head).into_iter()
+            .chain(self.physical_ranges(&sv.extents, tail_start, size - tail_start));
+        for (device, offset, len) in ranges {
+            self.wipe_range(device, offset, len, how)?;
+        }
+        Ok(())
+    }
+}